@@ -111,6 +111,42 @@ pub enum MangoErrorCode {
     InvalidPriceCache,
     #[error("MangoErrorCode::InvalidPerpMarketCache Cache the perp market to resolve")]
     InvalidPerpMarketCache,
+    #[error("MangoErrorCode::NoPnlToSettle Both accounts have pnl of the same sign")]
+    NoPnlToSettle,
+    #[error("MangoErrorCode::NotWhitelisted The owner is not on the group's deposit whitelist")]
+    NotWhitelisted,
+    #[error("MangoErrorCode::WithdrawCapExceeded Quantity exceeds the per-tx withdrawal cap")]
+    WithdrawCapExceeded,
+    #[error("MangoErrorCode::InsufficientMngoStake MNGO deposit is below min_mngo_to_trade")]
+    InsufficientMngoStake,
+    #[error("MangoErrorCode::UseWithdrawWithBorrow Use Withdraw with allow_borrow instead")]
+    UseWithdrawWithBorrow,
+    #[error("MangoErrorCode::InvalidSignerNonce The signer nonce does not derive the signer key")]
+    InvalidSignerNonce,
+    #[error("MangoErrorCode::PerpMarketMismatch The perp accounts are not the group's market")]
+    PerpMarketMismatch,
+    #[error(
+        "MangoErrorCode::InstructionDataTooShort The instruction data is shorter than its layout"
+    )]
+    InstructionDataTooShort,
+    #[error("MangoErrorCode::NotBankrupt The account still has collateral or positive equity")]
+    NotBankrupt,
+    #[error(
+        "MangoErrorCode::AccountNotEmpty The mango account still holds funds, positions or orders"
+    )]
+    AccountNotEmpty,
+    #[error(
+        "MangoErrorCode::IndexJumpTooLarge The deposit index grew by more than max_index_growth"
+    )]
+    IndexJumpTooLarge,
+    #[error("MangoErrorCode::EventQueueMismatch The event queue is not the perp market's")]
+    EventQueueMismatch,
+    #[error("MangoErrorCode::NothingToClaim There are no recovered funds left to claim")]
+    NothingToClaim,
+    #[error(
+        "MangoErrorCode::MissingWithdrawCaps The WithdrawCaps of a MangoAccount was not passed"
+    )]
+    MissingWithdrawCaps,
 
     #[error("MangoErrorCode::Default Check the source code for more info")]
     Default = u32::MAX_VALUE,
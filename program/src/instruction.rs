@@ -1,16 +1,33 @@
-use crate::matching::{OrderType, Side};
-use crate::state::MAX_PAIRS;
-use crate::state::{AssetType, INFO_LEN};
+use crate::error::MangoErrorCode;
+use crate::matching::{OrderType, Side, DEFAULT_MATCH_LIMIT};
+use crate::state::{
+    AssetType, DepositWhitelist, GroupName, MangoAccount, PerpMarketConfig, PnlTracker,
+    WithdrawCaps, GROUP_NAME_LEN, INFO_LEN,
+};
+use crate::state::{MAX_PAIRS, MAX_PERP_OPEN_ORDERS, MAX_TOKENS};
 use arrayref::{array_ref, array_refs};
 use fixed::types::I80F48;
 use num_enum::TryFromPrimitive;
 use serde::{Deserialize, Serialize};
 use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::msg;
 use solana_program::program_error::ProgramError;
 use solana_program::pubkey::Pubkey;
 use std::convert::{TryFrom, TryInto};
 use std::num::NonZeroU64;
 
+/// array_ref! over the first `len` bytes of instruction data that returns InstructionDataTooShort
+/// from the enclosing function, instead of panicking, if there are fewer
+macro_rules! data_ref {
+    ($data:expr, $len:expr) => {{
+        let data: &[u8] = $data;
+        if data.len() < $len {
+            return Err(ProgramError::Custom(MangoErrorCode::InstructionDataTooShort.into()));
+        }
+        array_ref![data, 0, $len]
+    }};
+}
+
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MangoInstruction {
@@ -61,6 +78,7 @@ pub enum MangoInstruction {
     /// 6. `[writable]` vault_ai - TokenAccount owned by MangoGroup
     /// 7. `[]` token_prog_ai - acc pointed to by SPL token program id
     /// 8. `[writable]` owner_token_account_ai - TokenAccount owned by user which will be sending the funds
+    /// 9. `[]` deposit_whitelist_ai - DepositWhitelist; only needed if the deposit whitelist is enabled
     Deposit {
         quantity: u64,
     },
@@ -81,6 +99,10 @@ pub enum MangoInstruction {
     /// 9. `[read]` token_prog_ai,    -
     /// 10. `[read]` clock_ai,         -
     /// 11..+ `[]` open_orders_accs - open orders for each of the spot market
+    /// Optional, after the open orders, in any order:
+    /// `[write]` insurance_vault_ai - required for quote withdrawals if the group charges
+    ///     withdraw_fee_bps; the fee is taken from the account on top of quantity
+    /// `[]` withdraw_caps_ai - required if the MangoAccount has WithdrawCaps
     Withdraw {
         quantity: u64,
         allow_borrow: bool,
@@ -114,6 +136,8 @@ pub enum MangoInstruction {
     },
 
     /// DEPRECATED - use Withdraw with allow_borrow = true
+    /// Always fails with UseWithdrawWithBorrow. Its accounts don't include the vault, token account
+    /// or signer a Withdraw needs, so it can't be carried out on a legacy client's behalf
     Borrow {
         quantity: u64,
     },
@@ -219,7 +243,7 @@ pub enum MangoInstruction {
         quantity: i64,
         client_order_id: u64,
         side: Side,
-        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly
+        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> PostOnlySlide
         order_type: OrderType,
     },
 
@@ -233,6 +257,10 @@ pub enum MangoInstruction {
         invalid_id_ok: bool,
     },
 
+    /// Apply up to `limit` events of the EventQueue to the MangoAccounts they name. The
+    /// accounts, the PnlTrackers of accounts that have one and the referrers of takers that have
+    /// one are passed after the fixed accounts in any order. A referrer that is left out or
+    /// closed isn't credited and its share stays in fees_accrued
     ConsumeEvents {
         limit: usize,
     },
@@ -245,6 +273,15 @@ pub enum MangoInstruction {
     CachePerpMarkets,
 
     /// Update funding related variables
+    ///
+    /// Accounts expected by this instruction (6):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    /// 3. `[]` bids_ai - Bids acc
+    /// 4. `[]` asks_ai - Asks acc
+    /// 5. `[]` perp_market_config_ai - PerpMarketConfig PDA of the PerpMarket, even if it was
+    ///     never created
     UpdateFunding,
 
     /// Can only be used on a stub oracle in devnet
@@ -310,6 +347,8 @@ pub enum MangoInstruction {
     },
 
     /// Force cancellation of open orders for a user being liquidated
+    /// The funds freed by the cancels are settled into the node banks in the same instruction,
+    /// so a liqor can seize them right after
     ///
     /// Accounts expected: 19 + Liqee open orders accounts (MAX_PAIRS)
     /// 0. `[]` mango_group_ai - MangoGroup
@@ -324,14 +363,14 @@ pub enum MangoInstruction {
     /// 9. `[writable]` spot_market_ai - SpotMarket
     /// 10. `[writable]` bids_ai - SpotMarket bids acc
     /// 11. `[writable]` asks_ai - SpotMarket asks acc
-    /// 12. `[signer]` signer_ai - Signer
+    /// 12. `[]` signer_ai - MangoGroup signer key
     /// 13. `[writable]` dex_event_queue_ai - Market event queue acc
     /// 14. `[writable]` dex_base_ai -
     /// 15. `[writable]` dex_quote_ai -
     /// 16. `[]` dex_signer_ai -
     /// 17. `[]` dex_prog_ai - Dex Program acc
     /// 18. `[]` token_prog_ai - Token Program acc
-    /// 19+... `[]` liqee_open_orders_ais - Liqee open orders accs
+    /// 19+... `[]` liqee_open_orders_ais - Liqee open orders accs; the one for this spot market is writable
     ForceCancelSpotOrders {
         limit: u8,
     },
@@ -401,6 +440,8 @@ pub enum MangoInstruction {
     /// 6. `[signer]` liqor_ai - Liqor Account
     /// 7+... `[]` liqee_open_orders_ais - Liqee open orders accs
     /// 7+MAX_PAIRS... `[]` liqor_open_orders_ais - Liqor open orders accs
+    /// 7+2*MAX_PAIRS... `[writable]` pnl_tracker_ais - PnlTrackers of the liqee and liqor, if
+    ///     they have one
     LiquidatePerpMarket {
         base_transfer_request: i64,
     },
@@ -433,7 +474,7 @@ pub enum MangoInstruction {
     /// 7. `[writable]` vault_ai - ?
     /// 8. `[writable]` dao_vault_ai - DAO Vault
     /// 9. `[]` signer_ai - Group Signer Account
-    /// 10. `[]` perp_market_ai - PerpMarket
+    /// 10. `[writable]` perp_market_ai - PerpMarket
     /// 11. `[]` token_prog_ai - Token Program Account
     /// 12+... `[]` liqor_open_orders_ais - Liqor open orders accs
     ResolvePerpBankruptcy {
@@ -455,11 +496,11 @@ pub enum MangoInstruction {
     /// 7. `[writable]` quote_vault_ai - ?
     /// 8. `[writable]` dao_vault_ai - DAO Vault
     /// 9. `[]` signer_ai - Group Signer Account
-    /// 10. `[]` liab_root_bank_ai - RootBank
+    /// 10. `[writable]` liab_root_bank_ai - RootBank
     /// 11. `[writable]` liab_node_bank_ai - NodeBank
     /// 12. `[]` token_prog_ai - Token Program Account
     /// 13+... `[]` liqor_open_orders_ais - Liqor open orders accs
-    /// 14+MAX_PAIRS... `[]` liab_node_bank_ais - Lib token node banks
+    /// 13+MAX_PAIRS... `[writable]` liab_node_bank_ais - Lib token node banks
     ResolveTokenBankruptcy {
         max_liab_transfer: I80F48,
     },
@@ -479,6 +520,8 @@ pub enum MangoInstruction {
     InitSpotOpenOrders,
 
     /// Redeem the mngo_accrued in a PerpAccount for MNGO in MangoAccount deposits
+    /// The MNGO is credited before this instruction returns, so any later instruction in the same
+    /// transaction counts it as collateral, provided the group has a spot market for MNGO
     ///
     /// Accounts expected by this instruction (11):
     /// 0. `[]` mango_group_ai - MangoGroup that this mango account is for
@@ -591,425 +634,3744 @@ pub enum MangoInstruction {
     /// 6. `[writable]` node_bank_ai - NodeBank
     /// 7+... `[]` liqee_open_orders_ais - Liqee open orders accs
     ForceSettleQuotePositions,
-}
 
-impl MangoInstruction {
-    pub fn unpack(input: &[u8]) -> Option<Self> {
-        let (&discrim, data) = array_refs![input, 4; ..;];
-        let discrim = u32::from_le_bytes(discrim);
-        Some(match discrim {
-            0 => {
-                let data = array_ref![data, 0, 64];
-                let (
-                    signer_nonce,
-                    valid_interval,
-                    quote_optimal_util,
-                    quote_optimal_rate,
-                    quote_max_rate,
-                ) = array_refs![data, 8, 8, 16, 16, 16];
+    /// Move deposits of a token from one MangoAccount to another MangoAccount of the same owner
+    /// The source MangoAccount must remain above init health after the transfer
+    ///
+    /// Accounts expected: 7 + MAX_PAIRS
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2. `[writable]` src_mango_account_ai - MangoAccount to take the deposits from
+    /// 3. `[writable]` dst_mango_account_ai - MangoAccount to credit the deposits to
+    /// 4. `[signer]` owner_ai - owner of both MangoAccounts
+    /// 5. `[]` root_bank_ai - RootBank of the token at token_index
+    /// 6. `[writable]` node_bank_ai - NodeBank
+    /// 7+... `[]` src_open_orders_ais - open orders accs of the source MangoAccount
+    TransferCollateral {
+        token_index: usize,
+        quantity: u64,
+    },
 
-                MangoInstruction::InitMangoGroup {
-                    signer_nonce: u64::from_le_bytes(*signer_nonce),
-                    valid_interval: u64::from_le_bytes(*valid_interval),
-                    quote_optimal_util: I80F48::from_le_bytes(*quote_optimal_util),
-                    quote_optimal_rate: I80F48::from_le_bytes(*quote_optimal_rate),
-                    quote_max_rate: I80F48::from_le_bytes(*quote_max_rate),
-                }
-            }
-            1 => MangoInstruction::InitMangoAccount,
-            2 => {
-                let quantity = array_ref![data, 0, 8];
-                MangoInstruction::Deposit { quantity: u64::from_le_bytes(*quantity) }
-            }
-            3 => {
-                let data = array_ref![data, 0, 9];
-                let (quantity, allow_borrow) = array_refs![data, 8, 1];
+    /// Place an order on a perp market
+    /// Same as PlacePerpOrder but with `safe_mode`: if true, the order is rejected if the account
+    /// would be below maintenance health were the order fully filled at `price`
+    ///
+    /// Accounts expected by this instruction (8 + MAX_PAIRS):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4. `[writable]` perp_market_ai
+    /// 5. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 6. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 8+... `[]` open_orders_ais - open orders accs of the MangoAccount
+    PlacePerpOrder2 {
+        price: i64,
+        quantity: i64,
+        client_order_id: u64,
+        side: Side,
+        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> PostOnlySlide
+        order_type: OrderType,
+        safe_mode: bool,
+        /// Opaque tag kept on the resting order and echoed in the FillEvent log of its fills;
+        /// zeros for none. Data without it, from before it was added, leaves it zeroed
+        tag: [u8; 8],
+        /// Most maker orders this order may cross; expired orders taken off the book count too.
+        /// Once reached, the rest of the order rests if it no longer crosses the book and is
+        /// dropped otherwise. Data without it gets DEFAULT_MATCH_LIMIT
+        match_limit: u8,
+    },
 
-                let allow_borrow = match allow_borrow {
-                    [0] => false,
-                    [1] => true,
-                    _ => return None,
-                };
-                MangoInstruction::Withdraw { quantity: u64::from_le_bytes(*quantity), allow_borrow }
-            }
-            4 => {
-                let data = array_ref![data, 0, 96];
-                let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    optimal_util,
-                    optimal_rate,
-                    max_rate,
-                ) = array_refs![data, 16, 16, 16, 16, 16, 16];
-                MangoInstruction::AddSpotMarket {
-                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
-                    init_leverage: I80F48::from_le_bytes(*init_leverage),
-                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
-                    optimal_util: I80F48::from_le_bytes(*optimal_util),
-                    optimal_rate: I80F48::from_le_bytes(*optimal_rate),
-                    max_rate: I80F48::from_le_bytes(*max_rate),
-                }
-            }
-            5 => {
-                let market_index = array_ref![data, 0, 8];
-                MangoInstruction::AddToBasket { market_index: usize::from_le_bytes(*market_index) }
-            }
-            6 => {
-                let quantity = array_ref![data, 0, 8];
-                MangoInstruction::Borrow { quantity: u64::from_le_bytes(*quantity) }
-            }
-            7 => MangoInstruction::CachePrices,
-            8 => MangoInstruction::CacheRootBanks,
-            9 => {
-                let data_arr = array_ref![data, 0, 46];
-                let order = unpack_dex_new_order_v3(data_arr)?;
-                MangoInstruction::PlaceSpotOrder { order }
-            }
-            10 => MangoInstruction::AddOracle,
-            11 => {
-                let data_arr = array_ref![data, 0, 144];
-                let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    base_lot_size,
-                    quote_lot_size,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    mngo_per_period,
-                ) = array_refs![data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8];
-                MangoInstruction::AddPerpMarket {
-                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
-                    init_leverage: I80F48::from_le_bytes(*init_leverage),
-                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
-                    maker_fee: I80F48::from_le_bytes(*maker_fee),
-                    taker_fee: I80F48::from_le_bytes(*taker_fee),
-                    base_lot_size: i64::from_le_bytes(*base_lot_size),
-                    quote_lot_size: i64::from_le_bytes(*quote_lot_size),
-                    rate: I80F48::from_le_bytes(*rate),
-                    max_depth_bps: I80F48::from_le_bytes(*max_depth_bps),
-                    target_period_length: u64::from_le_bytes(*target_period_length),
-                    mngo_per_period: u64::from_le_bytes(*mngo_per_period),
-                }
-            }
-            12 => {
-                let data_arr = array_ref![data, 0, 26];
-                let (price, quantity, client_order_id, side, order_type) =
-                    array_refs![data_arr, 8, 8, 8, 1, 1];
-                MangoInstruction::PlacePerpOrder {
-                    price: i64::from_le_bytes(*price),
-                    quantity: i64::from_le_bytes(*quantity),
-                    client_order_id: u64::from_le_bytes(*client_order_id),
-                    side: Side::try_from_primitive(side[0]).ok()?,
-                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
-                }
-            }
-            13 => {
-                // ***
-                let data_arr = array_ref![data, 0, 9];
-                let (client_order_id, invalid_id_ok) = array_refs![data_arr, 8, 1];
+    /// Place a batch of limit orders on perp markets with a single health check at the end
+    /// If any leg fails or the account ends up below init health, the whole batch is reverted
+    ///
+    /// Only perp legs are supported: a spot leg would need a serum new order CPI and about a
+    /// dozen more accounts per market, so a few of them would overflow the transaction size.
+    /// Put PlaceSpotOrder instructions in the same transaction to rebalance spot; they are
+    /// reverted along with it but each checks health on its own.
+    /// Every leg is a plain Limit order with no client order id or tag, no expiry and a match
+    /// limit of DEFAULT_MATCH_LIMIT; use PlacePerpOrder2 for anything else
+    ///
+    /// Accounts expected by this instruction (4 + MAX_PAIRS + 4 * legs.len()):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4..4+MAX_PAIRS `[]` open_orders_ais - open orders accs of the MangoAccount
+    /// then for each leg, in the same order as `legs`:
+    ///     `[writable]` perp_market_ai
+    ///     `[writable]` bids_ai - bids account for this PerpMarket
+    ///     `[writable]` asks_ai - asks account for this PerpMarket
+    ///     `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    ExecuteRebalanceLeg {
+        legs: Vec<PerpRebalanceLeg>,
+    },
 
-                MangoInstruction::CancelPerpOrderByClientId {
-                    client_order_id: u64::from_le_bytes(*client_order_id),
-                    invalid_id_ok: invalid_id_ok[0] != 0,
-                }
-            }
-            14 => {
-                // ***
-                let data_arr = array_ref![data, 0, 17];
-                let (order_id, invalid_id_ok) = array_refs![data_arr, 16, 1];
-                MangoInstruction::CancelPerpOrder {
-                    order_id: i128::from_le_bytes(*order_id),
-                    invalid_id_ok: invalid_id_ok[0] != 0,
-                }
-            }
-            15 => {
-                let data_arr = array_ref![data, 0, 8];
-                MangoInstruction::ConsumeEvents { limit: usize::from_le_bytes(*data_arr) }
-            }
-            16 => MangoInstruction::CachePerpMarkets,
-            17 => MangoInstruction::UpdateFunding,
-            18 => {
-                let data_arr = array_ref![data, 0, 16];
-                MangoInstruction::SetOracle { price: I80F48::from_le_bytes(*data_arr) }
-            }
-            19 => MangoInstruction::SettleFunds,
-            20 => {
-                let data_array = array_ref![data, 0, 20];
-                let fields = array_refs![data_array, 4, 16];
-                let side = match u32::from_le_bytes(*fields.0) {
-                    0 => serum_dex::matching::Side::Bid,
-                    1 => serum_dex::matching::Side::Ask,
-                    _ => return None,
-                };
-                let order_id = u128::from_le_bytes(*fields.1);
-                let order = serum_dex::instruction::CancelOrderInstructionV2 { side, order_id };
-                MangoInstruction::CancelSpotOrder { order }
-            }
-            21 => MangoInstruction::UpdateRootBank,
+    /// Set the init health liquidations try to bring a liqee to. Liquidations transfer no more
+    /// than needed to reach it
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[signer]` admin_ai - MangoGroup admin
+    SetLiquidationTargetHealth {
+        liquidation_target_health: I80F48,
+    },
 
-            22 => {
-                let data_arr = array_ref![data, 0, 8];
+    /// Set the maker and taker fees of a PerpMarket without touching its other params.
+    /// maker_fee may be negative to pay a rebate, but never by more than taker_fee
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[]` perp_market_ai - PerpMarket
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    SetPerpMarketFees {
+        maker_fee: I80F48,
+        taker_fee: I80F48,
+    },
 
-                MangoInstruction::SettlePnl { market_index: usize::from_le_bytes(*data_arr) }
-            }
-            23 => {
-                let data = array_ref![data, 0, 16];
-                let (token_index, quantity) = array_refs![data, 8, 8];
+    /// Log the owner and delegate of a MangoAccount. Changes no state
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_account_ai - MangoAccount
+    GetAccountAuthority,
 
-                MangoInstruction::SettleBorrow {
-                    token_index: usize::from_le_bytes(*token_index),
-                    quantity: u64::from_le_bytes(*quantity),
-                }
-            }
-            24 => {
-                let data_arr = array_ref![data, 0, 1];
+    /// Allow an owner to deposit while the deposit whitelist is enabled.
+    /// Creates the group's DepositWhitelist on first use, paid for by the admin
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable, signer]` admin_ai - MangoGroup admin
+    /// 2. `[writable]` deposit_whitelist_ai - DepositWhitelist PDA of the MangoGroup
+    /// 3. `[]` system_prog_ai - System program
+    AddToDepositWhitelist {
+        owner: Pubkey,
+    },
 
-                MangoInstruction::ForceCancelSpotOrders { limit: u8::from_le_bytes(*data_arr) }
-            }
-            25 => {
-                let data_arr = array_ref![data, 0, 1];
+    /// Stop an owner from depositing while the deposit whitelist is enabled
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[signer]` admin_ai - MangoGroup admin
+    /// 2. `[writable]` deposit_whitelist_ai - DepositWhitelist PDA of the MangoGroup
+    RemoveFromDepositWhitelist {
+        owner: Pubkey,
+    },
 
-                MangoInstruction::ForceCancelPerpOrders { limit: u8::from_le_bytes(*data_arr) }
-            }
-            26 => {
-                let data_arr = array_ref![data, 0, 16];
+    /// Turn the deposit whitelist on or off. Deposits are open to everyone while it is off
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[signer]` admin_ai - MangoGroup admin
+    SetDepositWhitelistEnabled {
+        enabled: bool,
+    },
 
-                MangoInstruction::LiquidateTokenAndToken {
-                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
-                }
-            }
-            27 => {
-                let data = array_ref![data, 0, 34];
-                let (asset_type, asset_index, liab_type, liab_index, max_liab_transfer) =
-                    array_refs![data, 1, 8, 1, 8, 16];
+    /// Log the insurance fund balance divided by the perp losses at risk on the given markets.
+    /// Changes no state
+    ///
+    /// Accounts expected by this instruction (3 + number of perp markets):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2. `[]` insurance_vault_ai - insurance vault of the MangoGroup
+    /// 3+... `[]` perp_market_ais - PerpMarkets of the MangoGroup, each at most once
+    GetInsuranceCoverage,
 
-                MangoInstruction::LiquidateTokenAndPerp {
-                    asset_type: AssetType::try_from(u8::from_le_bytes(*asset_type)).unwrap(),
-                    asset_index: usize::from_le_bytes(*asset_index),
-                    liab_type: AssetType::try_from(u8::from_le_bytes(*liab_type)).unwrap(),
-                    liab_index: usize::from_le_bytes(*liab_index),
-                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
-                }
-            }
-            28 => {
-                let data_arr = array_ref![data, 0, 8];
+    /// Log the realized pnl of a MangoAccount on one perp market. Changes no state
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_account_ai - MangoAccount
+    /// 2. `[]` perp_market_ai - PerpMarket
+    /// 3. `[]` pnl_tracker_ai - PnlTracker of the MangoAccount
+    GetRealizedPnl,
 
-                MangoInstruction::LiquidatePerpMarket {
-                    base_transfer_request: i64::from_le_bytes(*data_arr),
-                }
-            }
-            29 => MangoInstruction::SettleFees,
-            30 => {
-                let data = array_ref![data, 0, 24];
-                let (liab_index, max_liab_transfer) = array_refs![data, 8, 16];
+    /// Set the max quantity of a token a single Withdraw may take out of this MangoAccount.
+    /// `max_withdraw` of 0 removes the cap. The caps are kept in a WithdrawCaps account which
+    /// the first call creates, paid for by the owner
+    ///
+    /// Accounts expected by this instruction (5):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[writable, signer]` owner_ai - owner of the MangoAccount
+    /// 3. `[writable]` withdraw_caps_ai - WithdrawCaps of the MangoAccount, see
+    ///     `WithdrawCaps::find_address`
+    /// 4. `[]` system_prog_ai - System program
+    SetMaxWithdrawPerTx {
+        token_index: usize,
+        max_withdraw: u64,
+    },
 
-                MangoInstruction::ResolvePerpBankruptcy {
-                    liab_index: usize::from_le_bytes(*liab_index),
-                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
-                }
-            }
-            31 => {
-                let data_arr = array_ref![data, 0, 16];
+    /// Set the native MNGO deposit an account needs to place perp and spot orders.
+    /// `min_mngo_to_trade` of 0 disables the requirement
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[signer]` admin_ai - MangoGroup admin
+    SetMinMngoToTrade {
+        min_mngo_to_trade: u64,
+    },
 
-                MangoInstruction::ResolveTokenBankruptcy {
-                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
-                }
-            }
-            32 => MangoInstruction::InitSpotOpenOrders,
-            33 => MangoInstruction::RedeemMngo,
-            34 => {
-                let info = array_ref![data, 0, INFO_LEN];
-                MangoInstruction::AddMangoAccountInfo { info: *info }
-            }
-            35 => {
-                let quantity = array_ref![data, 0, 8];
-                MangoInstruction::DepositMsrm { quantity: u64::from_le_bytes(*quantity) }
-            }
-            36 => {
-                let quantity = array_ref![data, 0, 8];
-                MangoInstruction::WithdrawMsrm { quantity: u64::from_le_bytes(*quantity) }
+    /// Move `quantity` native tokens and the matching deposits between two node banks of the same
+    /// root bank, e.g. to keep any single vault under a size limit
+    ///
+    /// Accounts expected by this instruction (9):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` root_bank_ai - RootBank of both node banks
+    /// 2. `[writable]` src_node_bank_ai - NodeBank to take the deposits from
+    /// 3. `[writable]` src_vault_ai - vault of src_node_bank
+    /// 4. `[writable]` dst_node_bank_ai - NodeBank to move the deposits to
+    /// 5. `[writable]` dst_vault_ai - vault of dst_node_bank
+    /// 6. `[signer]` admin_ai - MangoGroup admin
+    /// 7. `[]` signer_ai - MangoGroup signer key
+    /// 8. `[]` token_prog_ai - SPL token program
+    RebalanceNodeBanks {
+        quantity: u64,
+    },
+
+    /// Log the init and maint health the MangoAccount would have after a Withdraw with the same
+    /// parameters. Changes no state
+    ///
+    /// Accounts expected by this instruction: the same as Withdraw, none of them need to be
+    /// writable or signers
+    PreviewWithdraw {
+        quantity: u64,
+        allow_borrow: bool,
+    },
+
+    /// Set the fee, in basis points, charged on top of quote withdrawals and sent to the
+    /// insurance fund. 0 disables it
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[signer]` admin_ai - MangoGroup admin
+    SetWithdrawFee {
+        withdraw_fee_bps: u16,
+    },
+
+    /// Cancel the perp open orders on one market that were placed before cutoff_ts, a unix
+    /// timestamp. limit is the maximum number of orders canceled
+    ///
+    /// Accounts expected: 6
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - Owner of Mango Account
+    /// 3. `[writable]` perp_market_ai - PerpMarket
+    /// 4. `[writable]` bids_ai - Bids acc
+    /// 5. `[writable]` asks_ai - Asks acc
+    CancelPerpOrdersOlderThan {
+        cutoff_ts: u64,
+        limit: u8,
+    },
+
+    /// Set the native quote notional the impact bid and ask must fill when UpdateFunding
+    /// prices the book. 0 uses the best bid and ask. The depth is kept in the market's
+    /// PerpMarketConfig, created on first use and paid for by the admin
+    ///
+    /// Accounts expected by this instruction (5):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` perp_market_ai - PerpMarket
+    /// 2. `[writable, signer]` admin_ai - MangoGroup admin
+    /// 3. `[writable]` perp_market_config_ai - PerpMarketConfig PDA of the PerpMarket
+    /// 4. `[]` system_prog_ai - System program
+    SetPerpImpactDepth {
+        quote_notional: i64,
+    },
+
+    /// Update the indexes of several root banks in one instruction, as UpdateRootBank does for one
+    ///
+    /// Accounts expected: 2 + (1 + node banks) per root bank
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_cache_ai - MangoCache
+    /// Then for each root bank, in any order of root banks:
+    /// `[writable]` root_bank_ai - RootBank
+    /// `[]` node_bank_ais - exactly num_node_banks NodeBanks of that RootBank
+    UpdateRootBanks,
+
+    /// Log whether a MangoAccount has perp pnl that SettlePnl has not yet moved into its quote
+    /// deposits. Changes no state
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_account_ai - MangoAccount
+    /// 2. `[]` mango_cache_ai - MangoCache
+    HasUnsettledPnl,
+
+    /// Cancel a spot order and settle the funds it frees back into the node banks
+    ///
+    /// Accounts expected by this instruction (21):
+    ///
+    /// 0. `[]` mango_group_ai - MangoGroup that this mango account is for
+    /// 1. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 2. `[signer]` owner_ai - MangoAccount owner
+    /// 3. `[writable]` mango_account_ai - MangoAccount
+    /// 4. `[]` dex_prog_ai - program id of serum dex
+    /// 5. `[writable]` spot_market_ai - dex MarketState account
+    /// 6. `[writable]` bids_ai - bids for this dex market
+    /// 7. `[writable]` asks_ai - asks for this dex market
+    /// 8. `[writable]` open_orders_ai - open orders for this market for this MangoAccount
+    /// 9. `[]` signer_ai - MangoGroup signer key
+    /// 10. `[writable]` dex_event_queue_ai - event queue for this dex market
+    /// 11. `[writable]` dex_base_ai - base vault for dex MarketState
+    /// 12. `[writable]` dex_quote_ai - quote vault for dex MarketState
+    /// 13. `[]` base_root_bank_ai - RootBank of the base token
+    /// 14. `[writable]` base_node_bank_ai - NodeBank of the base token
+    /// 15. `[]` quote_root_bank_ai - RootBank of the quote token
+    /// 16. `[writable]` quote_node_bank_ai - NodeBank of the quote token
+    /// 17. `[writable]` base_vault_ai - MangoGroup base vault acc
+    /// 18. `[writable]` quote_vault_ai - MangoGroup quote vault acc
+    /// 19. `[]` dex_signer_ai - dex Market signer account
+    /// 20. `[]` spl token program
+    CancelSpotOrderAndSettle {
+        order: serum_dex::instruction::CancelOrderInstructionV2,
+    },
+
+    /// Place an order on a perp market whose price is offset_ticks from the oracle price at the
+    /// time it matches. Bids don't trade above and asks don't trade below peg_limit; a resting
+    /// order past its limit stays on the book until the oracle moves back
+    ///
+    /// Accounts expected by this instruction (8 + MAX_PAIRS):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4. `[writable]` perp_market_ai
+    /// 5. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 6. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 8+... `[]` open_orders_ais - open orders accs of the MangoAccount
+    PlacePerpPeggedOrder {
+        /// Offset from the oracle price in quote lots per base lot
+        offset_ticks: i64,
+        /// Price limit in quote lots per base lot
+        peg_limit: i64,
+        quantity: i64,
+        client_order_id: u64,
+        side: Side,
+        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> PostOnlySlide
+        order_type: OrderType,
+    },
+
+    /// Turn post-trade risk logging on or off. While on, PlacePerpOrder and PlacePerpOrder2 log
+    /// the account's leverage and health after each order, and the limits the order passed
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[signer]` admin_ai - MangoGroup admin
+    SetPostTradeRiskLogEnabled {
+        enabled: bool,
+    },
+
+    /// Set how old, in seconds, the cached price of an oracle may be for health checks, which
+    /// includes liquidations. This overrides the group's valid_interval for the spot and perp
+    /// markets using the oracle; 0 reverts to valid_interval
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[]` oracle_ai - oracle of the market
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    SetMaxOracleAge {
+        max_oracle_age: u32,
+    },
+
+    /// Update funding for up to MAX_UPDATE_ALL_FUNDING_MARKETS perp markets, like UpdateFunding
+    /// does for each of them
+    ///
+    /// Accounts expected by this instruction (2 + 4 * number of markets):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2+4*i. `[writable]` perp_market_ai - PerpMarket
+    /// 3+4*i. `[]` bids_ai - Bids acc
+    /// 4+4*i. `[]` asks_ai - Asks acc
+    /// 5+4*i. `[]` perp_market_config_ai - PerpMarketConfig PDA of the PerpMarket
+    UpdateAllFunding,
+
+    /// Close a mango account that holds nothing and return its rent lamports to the owner,
+    /// along with those of its WithdrawCaps and PnlTracker if it has them. Accounts that use it
+    /// as their referrer keep its key, and ConsumeEvents stops crediting it
+    ///
+    /// Accounts expected by this instruction (5):
+    ///
+    /// 0. `[]` mango_group_ai - MangoGroup that this mango account is for
+    /// 1. `[writable]` mango_account_ai - the mango account to close
+    /// 2. `[writable, signer]` owner_ai - Solana account of owner of the mango account
+    /// 3. `[writable]` withdraw_caps_ai - WithdrawCaps PDA of the mango account, even if it
+    ///     has none
+    /// 4. `[writable]` pnl_tracker_ai - PnlTracker PDA of the mango account, even if it has none
+    CloseMangoAccount,
+
+    /// Log the current slot with a MangoAccount's token balances and the cached indexes, and its
+    /// perp positions with the settled and cached funding, so off-chain pnl history can be
+    /// anchored to on-chain state. Changes no state
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_account_ai - MangoAccount
+    /// 2. `[]` mango_cache_ai - MangoCache
+    GetPositionHistoryAnchor,
+
+    /// Close an empty spot open orders account, removing it from the MangoAccount and sending its
+    /// rent lamports to the owner
+    ///
+    /// Accounts expected by this instruction (7):
+    ///
+    /// 0. `[]` mango_group_ai - MangoGroup that this mango account is for
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[writable, signer]` owner_ai - MangoAccount owner
+    /// 3. `[]` dex_prog_ai - program id of serum dex
+    /// 4. `[writable]` open_orders_ai - open orders for this market for this MangoAccount
+    /// 5. `[]` spot_market_ai - dex MarketState account
+    /// 6. `[]` signer_ai - Group Signer Account
+    CloseSpotOpenOrders,
+
+    /// Set the max fraction a RootBank's deposit index may grow by in one UpdateRootBank, e.g.
+    /// 0.01 for 1%. Larger jumps are rejected until the admin raises the cap; 0 disables it
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` root_bank_ai - RootBank of the token
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    SetMaxIndexGrowth {
+        max_index_growth: I80F48,
+    },
+
+    /// Let another key place and cancel orders and settle funds for a MangoAccount. Withdrawals
+    /// and other instructions moving funds out of the account still need the owner.
+    /// Passing Pubkey::default() as the delegate removes it
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - MangoAccount owner
+    /// 3. `[]` delegate_ai - delegate
+    SetDelegate,
+
+    /// Log the bid-ask spread of a perp market in basis points of the mid price, or null if
+    /// either side of the book is empty. Changes no state
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` perp_market_ai - PerpMarket
+    /// 2. `[]` bids_ai - bids account for this PerpMarket
+    /// 3. `[]` asks_ai - asks account for this PerpMarket
+    GetPerpSpread,
+
+    /// Place an order on a perp market
+    /// Same as PlacePerpOrder2 but with `expiry_timestamp`: once the clock reaches it the order
+    /// is removed from the book by the next order that meets it instead of being filled. An
+    /// order that is already expired when placed is ignored
+    ///
+    /// Accounts expected by this instruction (8 + MAX_PAIRS):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4. `[writable]` perp_market_ai
+    /// 5. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 6. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 8+... `[]` open_orders_ais - open orders accs of the MangoAccount
+    PlacePerpOrder3 {
+        price: i64,
+        quantity: i64,
+        client_order_id: u64,
+        side: Side,
+        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> PostOnlySlide
+        order_type: OrderType,
+        safe_mode: bool,
+        tag: [u8; 8],
+        /// Unix timestamp after which the order is no longer valid; 0 means it never expires
+        expiry_timestamp: u64,
+    },
+
+    /// Check that the accounts of a group add up and log whether they do. See
+    /// check_group_invariants for what is checked. Changes no state
+    ///
+    /// Accounts expected by this instruction (2 + ...):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2+... `[]` root banks, each followed by all its node banks, each followed by its vault,
+    ///       and then perp markets, in any order
+    CheckGroupInvariants,
+
+    /// Point a perp market's liquidity mining rewards at a new MNGO vault and move the balance
+    /// of the old vault into it. MNGO accrued on MangoAccounts but not yet redeemed is then
+    /// redeemed from the new vault
+    ///
+    /// Accounts expected by this instruction (7):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` perp_market_ai - PerpMarket
+    /// 2. `[writable]` old_mngo_vault_ai - the market's current MNGO vault
+    /// 3. `[writable]` new_mngo_vault_ai - MNGO token account owned by the group signer
+    /// 4. `[]` signer_ai - MangoGroup signer key
+    /// 5. `[signer]` admin_ai - MangoGroup admin
+    /// 6. `[]` token_prog_ai - SPL token program
+    SetPerpMngoVault,
+
+    /// Deposit several tokens into a mango account at once, one entry of `quantities` per token
+    ///
+    /// Accounts expected by this instruction (5 + 4 * quantities.len()):
+    ///
+    /// 0. `[]` mango_group_ai - MangoGroup that this mango account is for
+    /// 1. `[writable]` mango_account_ai - the mango account for this user
+    /// 2. `[signer]` owner_ai - Solana account of owner of the mango account
+    /// 3. `[]` mango_cache_ai - MangoCache
+    /// 4. `[]` token_prog_ai - acc pointed to by SPL token program id
+    /// 5+4i. `[]` root_bank_ai - RootBank of the i-th token
+    /// 6+4i. `[writable]` node_bank_ai - NodeBank owned by that RootBank
+    /// 7+4i. `[writable]` vault_ai - TokenAccount of that NodeBank
+    /// 8+4i. `[writable]` owner_token_account_ai - TokenAccount owned by user sending the i-th token
+    /// 5+4n. `[]` deposit_whitelist_ai - DepositWhitelist; only needed if the deposit whitelist is enabled
+    DepositBatch {
+        quantities: Vec<u64>,
+    },
+
+    /// Log the MNGO liquidity mining rate of a perp market and the share of it the resting
+    /// orders of a MangoAccount would currently earn. Changes no state
+    ///
+    /// Accounts expected by this instruction (5):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_account_ai - MangoAccount
+    /// 2. `[]` perp_market_ai - PerpMarket at `market_index`
+    /// 3. `[]` bids_ai - Bids account of the PerpMarket
+    /// 4. `[]` asks_ai - Asks account of the PerpMarket
+    GetMiningRate {
+        market_index: usize,
+    },
+
+    /// Hand a MangoAccount to a new owner. Any delegate is removed along with the old owner.
+    /// Passing Pubkey::default() as the new owner is rejected
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - current MangoAccount owner
+    /// 3. `[]` new_owner_ai - new MangoAccount owner
+    ChangeMangoAccountOwner,
+
+    /// Migrate a MangoAccount initialized by an older program to the current layout version,
+    /// zeroing the fields added since without touching positions or balances. Does nothing if
+    /// the account is already current
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - MangoAccount owner
+    UpgradeMangoAccount,
+
+    /// Make another MangoAccount of the group the referrer of this one. From then on the
+    /// referrer is credited REFERRER_FEE_SHARE of the taker fees of this account's perp fills,
+    /// provided ConsumeEvents is passed the referrer along with the taker and the referrer
+    /// still exists. The referrer must have a different owner
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - MangoAccount owner
+    /// 3. `[]` referrer_mango_account_ai - MangoAccount of the referrer
+    SetReferrerMemory {
+        /// Opaque to the program and not stored; the builder fills in the referrer's key
+        referrer_id: [u8; INFO_LEN],
+    },
+
+    /// Cancel a spot order by the client_order_id it was placed with, using the dex's
+    /// CancelOrderByClientIdV2, so clients don't need the dex order id
+    ///
+    /// Accounts expected by this instruction (10):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[signer]` owner_ai - MangoAccount owner or delegate
+    /// 2. `[]` mango_account_ai - MangoAccount
+    /// 3. `[]` dex_prog_ai - program id of serum dex
+    /// 4. `[writable]` spot_market_ai - serum dex MarketState account
+    /// 5. `[writable]` bids_ai - bids account for serum dex market
+    /// 6. `[writable]` asks_ai - asks account for serum dex market
+    /// 7. `[writable]` open_orders_ai - open orders for this market for this MangoAccount
+    /// 8. `[]` signer_ai - MangoGroup signer key
+    /// 9. `[writable]` dex_event_queue_ai - event queue for serum dex market
+    CancelSpotOrderByClientId {
+        client_order_id: u64,
+    },
+
+    /// Create the LossRecovery of a token that took socialized losses, paid for by the admin.
+    /// Depositors then register their shares with RegisterLossShare
+    ///
+    /// Accounts expected by this instruction (6):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable, signer]` admin_ai - MangoGroup admin
+    /// 2. `[]` root_bank_ai - RootBank of the token that took the loss
+    /// 3. `[writable]` loss_recovery_ai - LossRecovery PDA of the MangoGroup and token
+    /// 4. `[]` recovery_vault_ai - token account owned by the MangoGroup signer to hold the
+    ///     recovered funds
+    /// 5. `[]` system_prog_ai - System program
+    InitLossRecovery,
+
+    /// Deposit into a MangoAccount its share of the funds sent to the recovery vault so far,
+    /// less what it already claimed
+    ///
+    /// Accounts expected by this instruction (12):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - MangoAccount owner
+    /// 3. `[]` mango_cache_ai - MangoCache
+    /// 4. `[]` root_bank_ai - RootBank of the recovered token
+    /// 5. `[writable]` node_bank_ai - NodeBank
+    /// 6. `[writable]` vault_ai - TokenAccount owned by MangoGroup
+    /// 7. `[]` token_prog_ai - acc pointed to by SPL token program id
+    /// 8. `[writable]` loss_recovery_ai - LossRecovery of the token
+    /// 9. `[writable]` loss_share_ai - LossShare of the MangoAccount
+    /// 10. `[writable]` recovery_vault_ai - vault of the LossRecovery
+    /// 11. `[]` signer_ai - MangoGroup signer key
+    ClaimLossRecovery,
+
+    /// Set the MangoGroup's human readable name. `name` is UTF-8, zero padded to
+    /// GROUP_NAME_LEN bytes. Creates the group's GroupName on first use, paid for by the admin
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable, signer]` admin_ai - MangoGroup admin
+    /// 2. `[writable]` group_name_ai - GroupName PDA of the MangoGroup
+    /// 3. `[]` system_prog_ai - System program
+    SetGroupName {
+        name: [u8; GROUP_NAME_LEN],
+    },
+
+    /// Log the notional of the long open interest summed over the given perp markets, valued
+    /// at the cached oracle prices. Changes no state
+    ///
+    /// Accounts expected by this instruction (2 + number of perp markets):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2+... `[]` perp_market_ais - PerpMarkets of the MangoGroup, each at most once
+    GetOpenInterest,
+
+    /// Move deposited MSRM from one MangoAccount to another MangoAccount of the same owner.
+    /// The MSRM itself stays in the msrm vault; only the fee discount moves
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` src_mango_account_ai - MangoAccount to take the MSRM from
+    /// 2. `[writable]` dst_mango_account_ai - MangoAccount to credit the MSRM to
+    /// 3. `[signer]` owner_ai - owner of both MangoAccounts
+    TransferMsrm {
+        quantity: u64,
+    },
+
+    /// Log the utilization of a token and the annual rates its borrowers pay and its depositors
+    /// earn at that utilization. Changes no state
+    ///
+    /// Accounts expected: 2 + Node Banks
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` root_bank_ai - RootBank
+    /// 2+... `[]` node_bank_ais - all num_node_banks NodeBanks of the RootBank
+    GetInterestRates,
+
+    /// Cancel the perp open orders on one side of one market, e.g. only the bids.
+    /// limit is the maximum number of orders canceled
+    ///
+    /// Accounts expected: 6
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - Owner of Mango Account
+    /// 3. `[writable]` perp_market_ai - PerpMarket
+    /// 4. `[writable]` bids_ai - Bids acc
+    /// 5. `[writable]` asks_ai - Asks acc
+    CancelPerpOrdersSide {
+        side: Side,
+        limit: u8,
+    },
+
+    /// Create the PnlTracker of a MangoAccount, paid for by the owner, to track its realized
+    /// perp pnl from now on. The account can't have perp positions. ConsumeEvents and
+    /// LiquidatePerpMarket only update the tracker if it is passed along with the account; a
+    /// trade applied without it is missing from realized_pnl and entry_quote
+    ///
+    /// Accounts expected by this instruction (5):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[writable, signer]` owner_ai - MangoAccount owner
+    /// 3. `[writable]` pnl_tracker_ai - PnlTracker PDA of the MangoAccount
+    /// 4. `[]` system_prog_ai - System program
+    InitPnlTracker,
+
+    /// Add to the MangoAccount's share of a LossRecovery what its deposits of the token lost
+    /// to socialized losses since it last registered, going by the RootBank's
+    /// socialized_loss_index. Creates the LossShare on first use, paid for by the owner. The
+    /// share is measured on the deposits held now, so register before changing them after a
+    /// loss. Shares are fixed once the first claim is paid
+    ///
+    /// Accounts expected by this instruction (7):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_account_ai - MangoAccount
+    /// 2. `[writable, signer]` owner_ai - MangoAccount owner
+    /// 3. `[]` root_bank_ai - RootBank of the token
+    /// 4. `[writable]` loss_recovery_ai - LossRecovery of the token
+    /// 5. `[writable]` loss_share_ai - LossShare PDA of the LossRecovery and MangoAccount
+    /// 6. `[]` system_prog_ai - System program
+    RegisterLossShare,
+}
+
+pub const MAX_REBALANCE_LEGS: usize = 4;
+/// Markets one UpdateAllFunding can update while staying within the compute budget
+pub const MAX_UPDATE_ALL_FUNDING_MARKETS: usize = 4;
+
+/// One perp limit order of an ExecuteRebalanceLeg instruction. There are no spot legs and no
+/// order type; every leg is a Limit order
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PerpRebalanceLeg {
+    pub market_index: usize,
+    pub side: Side,
+    pub price: i64,
+    pub quantity: i64,
+}
+
+impl MangoInstruction {
+    pub fn unpack(input: &[u8]) -> Option<Self> {
+        Self::unpack_checked(input).ok()
+    }
+    /// Like unpack, but says why decoding failed. Data shorter than the instruction's layout
+    /// gives InstructionDataTooShort; an unknown discriminant or a bad value in the data gives
+    /// InvalidInstructionData
+    pub fn unpack_checked(input: &[u8]) -> Result<Self, ProgramError> {
+        let discrim = u32::from_le_bytes(*data_ref!(input, 4));
+        let data = &input[4..];
+        Ok(match discrim {
+            0 => {
+                let data = data_ref!(data, 64);
+                let (
+                    signer_nonce,
+                    valid_interval,
+                    quote_optimal_util,
+                    quote_optimal_rate,
+                    quote_max_rate,
+                ) = array_refs![data, 8, 8, 16, 16, 16];
+
+                MangoInstruction::InitMangoGroup {
+                    signer_nonce: u64::from_le_bytes(*signer_nonce),
+                    valid_interval: u64::from_le_bytes(*valid_interval),
+                    quote_optimal_util: I80F48::from_le_bytes(*quote_optimal_util),
+                    quote_optimal_rate: I80F48::from_le_bytes(*quote_optimal_rate),
+                    quote_max_rate: I80F48::from_le_bytes(*quote_max_rate),
+                }
+            }
+            1 => MangoInstruction::InitMangoAccount,
+            2 => {
+                let quantity = data_ref!(data, 8);
+                MangoInstruction::Deposit { quantity: u64::from_le_bytes(*quantity) }
+            }
+            3 => {
+                let data = data_ref!(data, 9);
+                let (quantity, allow_borrow) = array_refs![data, 8, 1];
+
+                let allow_borrow = match allow_borrow {
+                    [0] => false,
+                    [1] => true,
+                    _ => return Err(ProgramError::InvalidInstructionData),
+                };
+                MangoInstruction::Withdraw { quantity: u64::from_le_bytes(*quantity), allow_borrow }
+            }
+            4 => {
+                let data = data_ref!(data, 96);
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    optimal_util,
+                    optimal_rate,
+                    max_rate,
+                ) = array_refs![data, 16, 16, 16, 16, 16, 16];
+                MangoInstruction::AddSpotMarket {
+                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
+                    init_leverage: I80F48::from_le_bytes(*init_leverage),
+                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
+                    optimal_util: I80F48::from_le_bytes(*optimal_util),
+                    optimal_rate: I80F48::from_le_bytes(*optimal_rate),
+                    max_rate: I80F48::from_le_bytes(*max_rate),
+                }
+            }
+            5 => {
+                let market_index = data_ref!(data, 8);
+                MangoInstruction::AddToBasket { market_index: unpack_usize(market_index)? }
+            }
+            6 => {
+                let quantity = data_ref!(data, 8);
+                MangoInstruction::Borrow { quantity: u64::from_le_bytes(*quantity) }
+            }
+            7 => MangoInstruction::CachePrices,
+            8 => MangoInstruction::CacheRootBanks,
+            9 => {
+                let data_arr = data_ref!(data, 46);
+                let order = unpack_dex_new_order_v3(data_arr)
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                MangoInstruction::PlaceSpotOrder { order }
+            }
+            10 => MangoInstruction::AddOracle,
+            11 => {
+                let data_arr = data_ref!(data, 144);
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    base_lot_size,
+                    quote_lot_size,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    mngo_per_period,
+                ) = array_refs![data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8];
+                MangoInstruction::AddPerpMarket {
+                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
+                    init_leverage: I80F48::from_le_bytes(*init_leverage),
+                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
+                    maker_fee: I80F48::from_le_bytes(*maker_fee),
+                    taker_fee: I80F48::from_le_bytes(*taker_fee),
+                    base_lot_size: i64::from_le_bytes(*base_lot_size),
+                    quote_lot_size: i64::from_le_bytes(*quote_lot_size),
+                    rate: I80F48::from_le_bytes(*rate),
+                    max_depth_bps: I80F48::from_le_bytes(*max_depth_bps),
+                    target_period_length: u64::from_le_bytes(*target_period_length),
+                    mngo_per_period: u64::from_le_bytes(*mngo_per_period),
+                }
+            }
+            12 => {
+                let data_arr = data_ref!(data, 26);
+                let (price, quantity, client_order_id, side, order_type) =
+                    array_refs![data_arr, 8, 8, 8, 1, 1];
+                MangoInstruction::PlacePerpOrder {
+                    price: i64::from_le_bytes(*price),
+                    quantity: i64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    side: Side::try_from_primitive(side[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    order_type: OrderType::try_from_primitive(order_type[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                }
+            }
+            13 => {
+                // ***
+                let data_arr = data_ref!(data, 9);
+                let (client_order_id, invalid_id_ok) = array_refs![data_arr, 8, 1];
+
+                MangoInstruction::CancelPerpOrderByClientId {
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    invalid_id_ok: invalid_id_ok[0] != 0,
+                }
+            }
+            14 => {
+                // ***
+                let data_arr = data_ref!(data, 17);
+                let (order_id, invalid_id_ok) = array_refs![data_arr, 16, 1];
+                MangoInstruction::CancelPerpOrder {
+                    order_id: i128::from_le_bytes(*order_id),
+                    invalid_id_ok: invalid_id_ok[0] != 0,
+                }
+            }
+            15 => {
+                let data_arr = data_ref!(data, 8);
+                MangoInstruction::ConsumeEvents { limit: unpack_usize(data_arr)? }
+            }
+            16 => MangoInstruction::CachePerpMarkets,
+            17 => MangoInstruction::UpdateFunding,
+            18 => {
+                let data_arr = data_ref!(data, 16);
+                MangoInstruction::SetOracle { price: I80F48::from_le_bytes(*data_arr) }
+            }
+            19 => MangoInstruction::SettleFunds,
+            20 => {
+                let data_array = data_ref!(data, 20);
+                MangoInstruction::CancelSpotOrder {
+                    order: unpack_cancel_order_v2(data_array)
+                        .ok_or(ProgramError::InvalidInstructionData)?,
+                }
+            }
+            21 => MangoInstruction::UpdateRootBank,
+
+            22 => {
+                let data_arr = data_ref!(data, 8);
+
+                MangoInstruction::SettlePnl { market_index: unpack_usize(data_arr)? }
+            }
+            23 => {
+                let data = data_ref!(data, 16);
+                let (token_index, quantity) = array_refs![data, 8, 8];
+
+                MangoInstruction::SettleBorrow {
+                    token_index: unpack_usize(token_index)?,
+                    quantity: u64::from_le_bytes(*quantity),
+                }
+            }
+            24 => {
+                let data_arr = data_ref!(data, 1);
+
+                MangoInstruction::ForceCancelSpotOrders { limit: u8::from_le_bytes(*data_arr) }
+            }
+            25 => {
+                let data_arr = data_ref!(data, 1);
+
+                MangoInstruction::ForceCancelPerpOrders { limit: u8::from_le_bytes(*data_arr) }
+            }
+            26 => {
+                let data_arr = data_ref!(data, 16);
+
+                MangoInstruction::LiquidateTokenAndToken {
+                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
+                }
+            }
+            27 => {
+                let data = data_ref!(data, 34);
+                let (asset_type, asset_index, liab_type, liab_index, max_liab_transfer) =
+                    array_refs![data, 1, 8, 1, 8, 16];
+
+                MangoInstruction::LiquidateTokenAndPerp {
+                    asset_type: AssetType::try_from(asset_type[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    asset_index: unpack_usize(asset_index)?,
+                    liab_type: AssetType::try_from(liab_type[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    liab_index: unpack_usize(liab_index)?,
+                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
+                }
+            }
+            28 => {
+                let data_arr = data_ref!(data, 8);
+
+                MangoInstruction::LiquidatePerpMarket {
+                    base_transfer_request: i64::from_le_bytes(*data_arr),
+                }
+            }
+            29 => MangoInstruction::SettleFees,
+            30 => {
+                let data = data_ref!(data, 24);
+                let (liab_index, max_liab_transfer) = array_refs![data, 8, 16];
+
+                MangoInstruction::ResolvePerpBankruptcy {
+                    liab_index: unpack_usize(liab_index)?,
+                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
+                }
+            }
+            31 => {
+                let data_arr = data_ref!(data, 16);
+
+                MangoInstruction::ResolveTokenBankruptcy {
+                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
+                }
+            }
+            32 => MangoInstruction::InitSpotOpenOrders,
+            33 => MangoInstruction::RedeemMngo,
+            34 => {
+                let info = data_ref!(data, INFO_LEN);
+                MangoInstruction::AddMangoAccountInfo { info: *info }
+            }
+            35 => {
+                let quantity = data_ref!(data, 8);
+                MangoInstruction::DepositMsrm { quantity: u64::from_le_bytes(*quantity) }
+            }
+            36 => {
+                let quantity = data_ref!(data, 8);
+                MangoInstruction::WithdrawMsrm { quantity: u64::from_le_bytes(*quantity) }
+            }
+
+            37 => {
+                let data_arr = data_ref!(data, 137);
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    mngo_per_period,
+                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 17, 9, 9];
+
+                MangoInstruction::ChangePerpMarketParams {
+                    maint_leverage: unpack_i80f48_opt(maint_leverage),
+                    init_leverage: unpack_i80f48_opt(init_leverage),
+                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
+                    maker_fee: unpack_i80f48_opt(maker_fee),
+                    taker_fee: unpack_i80f48_opt(taker_fee),
+                    rate: unpack_i80f48_opt(rate),
+                    max_depth_bps: unpack_i80f48_opt(max_depth_bps),
+                    target_period_length: unpack_u64_opt(target_period_length),
+                    mngo_per_period: unpack_u64_opt(mngo_per_period),
+                }
+            }
+
+            38 => MangoInstruction::SetGroupAdmin,
+
+            39 => {
+                let data_arr = data_ref!(data, 1);
+                MangoInstruction::CancelAllPerpOrders { limit: u8::from_le_bytes(*data_arr) }
+            }
+
+            40 => MangoInstruction::ForceSettleQuotePositions,
+
+            41 => {
+                let data = data_ref!(data, 16);
+                let (token_index, quantity) = array_refs![data, 8, 8];
+
+                MangoInstruction::TransferCollateral {
+                    token_index: unpack_usize(token_index)?,
+                    quantity: u64::from_le_bytes(*quantity),
+                }
+            }
+            42 => {
+                let data_arr = data_ref!(data, 27);
+                let (price, quantity, client_order_id, side, order_type, safe_mode) =
+                    array_refs![data_arr, 8, 8, 8, 1, 1, 1];
+                MangoInstruction::PlacePerpOrder2 {
+                    price: i64::from_le_bytes(*price),
+                    quantity: i64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    side: Side::try_from_primitive(side[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    order_type: OrderType::try_from_primitive(order_type[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    safe_mode: safe_mode[0] != 0,
+                    tag: if data.len() >= 35 { *array_ref![data, 27, 8] } else { [0; 8] },
+                    match_limit: if data.len() >= 36 { data[35] } else { DEFAULT_MATCH_LIMIT },
+                }
+            }
+            43 => {
+                let num_legs = unpack_usize(data_ref!(data, 8))?;
+                let legs_data = &data[8..];
+                if num_legs > MAX_REBALANCE_LEGS {
+                    return Err(ProgramError::InvalidInstructionData);
+                }
+                if legs_data.len() < num_legs * 25 {
+                    return Err(ProgramError::Custom(
+                        MangoErrorCode::InstructionDataTooShort.into(),
+                    ));
+                }
+
+                let mut legs = Vec::with_capacity(num_legs);
+                for i in 0..num_legs {
+                    let leg_arr = array_ref![legs_data, i * 25, 25];
+                    let (market_index, side, price, quantity) = array_refs![leg_arr, 8, 1, 8, 8];
+                    legs.push(PerpRebalanceLeg {
+                        market_index: unpack_usize(market_index)?,
+                        side: Side::try_from_primitive(side[0])
+                            .map_err(|_| ProgramError::InvalidInstructionData)?,
+                        price: i64::from_le_bytes(*price),
+                        quantity: i64::from_le_bytes(*quantity),
+                    });
+                }
+                MangoInstruction::ExecuteRebalanceLeg { legs }
+            }
+            44 => {
+                let data_arr = data_ref!(data, 16);
+                MangoInstruction::SetLiquidationTargetHealth {
+                    liquidation_target_health: I80F48::from_le_bytes(*data_arr),
+                }
+            }
+            45 => {
+                let data_arr = data_ref!(data, 32);
+                let (maker_fee, taker_fee) = array_refs![data_arr, 16, 16];
+                MangoInstruction::SetPerpMarketFees {
+                    maker_fee: I80F48::from_le_bytes(*maker_fee),
+                    taker_fee: I80F48::from_le_bytes(*taker_fee),
+                }
+            }
+            46 => MangoInstruction::GetAccountAuthority,
+            47 => {
+                let owner = data_ref!(data, 32);
+                MangoInstruction::AddToDepositWhitelist { owner: Pubkey::new_from_array(*owner) }
+            }
+            48 => {
+                let owner = data_ref!(data, 32);
+                MangoInstruction::RemoveFromDepositWhitelist {
+                    owner: Pubkey::new_from_array(*owner),
+                }
+            }
+            49 => {
+                let enabled = data_ref!(data, 1);
+                MangoInstruction::SetDepositWhitelistEnabled { enabled: enabled[0] != 0 }
+            }
+            50 => MangoInstruction::GetInsuranceCoverage,
+            51 => MangoInstruction::GetRealizedPnl,
+            52 => {
+                let data = data_ref!(data, 16);
+                let (token_index, max_withdraw) = array_refs![data, 8, 8];
+
+                MangoInstruction::SetMaxWithdrawPerTx {
+                    token_index: unpack_usize(token_index)?,
+                    max_withdraw: u64::from_le_bytes(*max_withdraw),
+                }
+            }
+            53 => {
+                let min_mngo_to_trade = data_ref!(data, 8);
+                MangoInstruction::SetMinMngoToTrade {
+                    min_mngo_to_trade: u64::from_le_bytes(*min_mngo_to_trade),
+                }
+            }
+            54 => {
+                let quantity = data_ref!(data, 8);
+                MangoInstruction::RebalanceNodeBanks { quantity: u64::from_le_bytes(*quantity) }
+            }
+            55 => {
+                let data = data_ref!(data, 9);
+                let (quantity, allow_borrow) = array_refs![data, 8, 1];
+
+                MangoInstruction::PreviewWithdraw {
+                    quantity: u64::from_le_bytes(*quantity),
+                    allow_borrow: allow_borrow[0] != 0,
+                }
+            }
+            56 => {
+                let withdraw_fee_bps = data_ref!(data, 2);
+                MangoInstruction::SetWithdrawFee {
+                    withdraw_fee_bps: u16::from_le_bytes(*withdraw_fee_bps),
+                }
+            }
+            57 => {
+                let data_arr = data_ref!(data, 9);
+                let (cutoff_ts, limit) = array_refs![data_arr, 8, 1];
+                MangoInstruction::CancelPerpOrdersOlderThan {
+                    cutoff_ts: u64::from_le_bytes(*cutoff_ts),
+                    limit: u8::from_le_bytes(*limit),
+                }
+            }
+            58 => {
+                let quote_notional = data_ref!(data, 8);
+                MangoInstruction::SetPerpImpactDepth {
+                    quote_notional: i64::from_le_bytes(*quote_notional),
+                }
+            }
+            59 => MangoInstruction::UpdateRootBanks,
+            60 => MangoInstruction::HasUnsettledPnl,
+            61 => {
+                let data_array = data_ref!(data, 20);
+                MangoInstruction::CancelSpotOrderAndSettle {
+                    order: unpack_cancel_order_v2(data_array)
+                        .ok_or(ProgramError::InvalidInstructionData)?,
+                }
+            }
+            62 => {
+                let data_arr = data_ref!(data, 34);
+                let (offset_ticks, peg_limit, quantity, client_order_id, side, order_type) =
+                    array_refs![data_arr, 8, 8, 8, 8, 1, 1];
+                MangoInstruction::PlacePerpPeggedOrder {
+                    offset_ticks: i64::from_le_bytes(*offset_ticks),
+                    peg_limit: i64::from_le_bytes(*peg_limit),
+                    quantity: i64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    side: Side::try_from_primitive(side[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    order_type: OrderType::try_from_primitive(order_type[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                }
+            }
+            63 => {
+                let enabled = data_ref!(data, 1);
+                MangoInstruction::SetPostTradeRiskLogEnabled { enabled: enabled[0] != 0 }
+            }
+            64 => {
+                let max_oracle_age = data_ref!(data, 4);
+                MangoInstruction::SetMaxOracleAge {
+                    max_oracle_age: u32::from_le_bytes(*max_oracle_age),
+                }
+            }
+            65 => MangoInstruction::UpdateAllFunding,
+            66 => MangoInstruction::CloseMangoAccount,
+            67 => MangoInstruction::GetPositionHistoryAnchor,
+            68 => MangoInstruction::CloseSpotOpenOrders,
+            69 => {
+                let max_index_growth = data_ref!(data, 16);
+                MangoInstruction::SetMaxIndexGrowth {
+                    max_index_growth: I80F48::from_le_bytes(*max_index_growth),
+                }
+            }
+            70 => MangoInstruction::SetDelegate,
+            71 => MangoInstruction::GetPerpSpread,
+            72 => {
+                let data_arr = data_ref!(data, 43);
+                let (
+                    price,
+                    quantity,
+                    client_order_id,
+                    side,
+                    order_type,
+                    safe_mode,
+                    tag,
+                    expiry_timestamp,
+                ) = array_refs![data_arr, 8, 8, 8, 1, 1, 1, 8, 8];
+                MangoInstruction::PlacePerpOrder3 {
+                    price: i64::from_le_bytes(*price),
+                    quantity: i64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    side: Side::try_from_primitive(side[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    order_type: OrderType::try_from_primitive(order_type[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    safe_mode: safe_mode[0] != 0,
+                    tag: *tag,
+                    expiry_timestamp: u64::from_le_bytes(*expiry_timestamp),
+                }
+            }
+            73 => MangoInstruction::CheckGroupInvariants,
+            74 => MangoInstruction::SetPerpMngoVault,
+            75 => {
+                let num_entries = unpack_usize(data_ref!(data, 8))?;
+                let quantities_data = &data[8..];
+                if num_entries > MAX_TOKENS {
+                    return Err(ProgramError::InvalidInstructionData);
+                }
+                if quantities_data.len() < num_entries * 8 {
+                    return Err(ProgramError::Custom(
+                        MangoErrorCode::InstructionDataTooShort.into(),
+                    ));
+                }
+
+                let quantities = (0..num_entries)
+                    .map(|i| u64::from_le_bytes(*array_ref![quantities_data, i * 8, 8]))
+                    .collect();
+                MangoInstruction::DepositBatch { quantities }
+            }
+            76 => {
+                let market_index = data_ref!(data, 8);
+                MangoInstruction::GetMiningRate { market_index: unpack_usize(market_index)? }
+            }
+            77 => MangoInstruction::ChangeMangoAccountOwner,
+            78 => MangoInstruction::UpgradeMangoAccount,
+            79 => {
+                let referrer_id = data_ref!(data, INFO_LEN);
+                MangoInstruction::SetReferrerMemory { referrer_id: *referrer_id }
+            }
+            80 => {
+                let client_order_id = data_ref!(data, 8);
+                MangoInstruction::CancelSpotOrderByClientId {
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                }
+            }
+            81 => MangoInstruction::InitLossRecovery,
+            82 => MangoInstruction::ClaimLossRecovery,
+            83 => {
+                let name = data_ref!(data, GROUP_NAME_LEN);
+                MangoInstruction::SetGroupName { name: *name }
+            }
+            84 => MangoInstruction::GetOpenInterest,
+            85 => {
+                let quantity = data_ref!(data, 8);
+                MangoInstruction::TransferMsrm { quantity: u64::from_le_bytes(*quantity) }
+            }
+            86 => MangoInstruction::GetInterestRates,
+            87 => {
+                let data_arr = data_ref!(data, 2);
+                let (side, limit) = array_refs![data_arr, 1, 1];
+                MangoInstruction::CancelPerpOrdersSide {
+                    side: Side::try_from_primitive(side[0])
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                    limit: limit[0],
+                }
+            }
+            88 => MangoInstruction::InitPnlTracker,
+            89 => MangoInstruction::RegisterLossShare,
+
+            _ => {
+                msg!("Unknown MangoInstruction discriminant: {}", discrim);
+                return Err(ProgramError::InvalidInstructionData);
+            }
+        })
+    }
+    /// The u32 that leads the packed instruction and that unpack dispatches on, so instructions
+    /// can be labelled without decoding or re-encoding their payload
+    pub fn discriminant(&self) -> u32 {
+        match self {
+            MangoInstruction::InitMangoGroup { .. } => 0,
+            MangoInstruction::InitMangoAccount => 1,
+            MangoInstruction::Deposit { .. } => 2,
+            MangoInstruction::Withdraw { .. } => 3,
+            MangoInstruction::AddSpotMarket { .. } => 4,
+            MangoInstruction::AddToBasket { .. } => 5,
+            MangoInstruction::Borrow { .. } => 6,
+            MangoInstruction::CachePrices => 7,
+            MangoInstruction::CacheRootBanks => 8,
+            MangoInstruction::PlaceSpotOrder { .. } => 9,
+            MangoInstruction::AddOracle => 10,
+            MangoInstruction::AddPerpMarket { .. } => 11,
+            MangoInstruction::PlacePerpOrder { .. } => 12,
+            MangoInstruction::CancelPerpOrderByClientId { .. } => 13,
+            MangoInstruction::CancelPerpOrder { .. } => 14,
+            MangoInstruction::ConsumeEvents { .. } => 15,
+            MangoInstruction::CachePerpMarkets => 16,
+            MangoInstruction::UpdateFunding => 17,
+            MangoInstruction::SetOracle { .. } => 18,
+            MangoInstruction::SettleFunds => 19,
+            MangoInstruction::CancelSpotOrder { .. } => 20,
+            MangoInstruction::UpdateRootBank => 21,
+            MangoInstruction::SettlePnl { .. } => 22,
+            MangoInstruction::SettleBorrow { .. } => 23,
+            MangoInstruction::ForceCancelSpotOrders { .. } => 24,
+            MangoInstruction::ForceCancelPerpOrders { .. } => 25,
+            MangoInstruction::LiquidateTokenAndToken { .. } => 26,
+            MangoInstruction::LiquidateTokenAndPerp { .. } => 27,
+            MangoInstruction::LiquidatePerpMarket { .. } => 28,
+            MangoInstruction::SettleFees => 29,
+            MangoInstruction::ResolvePerpBankruptcy { .. } => 30,
+            MangoInstruction::ResolveTokenBankruptcy { .. } => 31,
+            MangoInstruction::InitSpotOpenOrders => 32,
+            MangoInstruction::RedeemMngo => 33,
+            MangoInstruction::AddMangoAccountInfo { .. } => 34,
+            MangoInstruction::DepositMsrm { .. } => 35,
+            MangoInstruction::WithdrawMsrm { .. } => 36,
+            MangoInstruction::ChangePerpMarketParams { .. } => 37,
+            MangoInstruction::SetGroupAdmin => 38,
+            MangoInstruction::CancelAllPerpOrders { .. } => 39,
+            MangoInstruction::ForceSettleQuotePositions => 40,
+            MangoInstruction::TransferCollateral { .. } => 41,
+            MangoInstruction::PlacePerpOrder2 { .. } => 42,
+            MangoInstruction::ExecuteRebalanceLeg { .. } => 43,
+            MangoInstruction::SetLiquidationTargetHealth { .. } => 44,
+            MangoInstruction::SetPerpMarketFees { .. } => 45,
+            MangoInstruction::GetAccountAuthority => 46,
+            MangoInstruction::AddToDepositWhitelist { .. } => 47,
+            MangoInstruction::RemoveFromDepositWhitelist { .. } => 48,
+            MangoInstruction::SetDepositWhitelistEnabled { .. } => 49,
+            MangoInstruction::GetInsuranceCoverage => 50,
+            MangoInstruction::GetRealizedPnl => 51,
+            MangoInstruction::SetMaxWithdrawPerTx { .. } => 52,
+            MangoInstruction::SetMinMngoToTrade { .. } => 53,
+            MangoInstruction::RebalanceNodeBanks { .. } => 54,
+            MangoInstruction::PreviewWithdraw { .. } => 55,
+            MangoInstruction::SetWithdrawFee { .. } => 56,
+            MangoInstruction::CancelPerpOrdersOlderThan { .. } => 57,
+            MangoInstruction::SetPerpImpactDepth { .. } => 58,
+            MangoInstruction::UpdateRootBanks => 59,
+            MangoInstruction::HasUnsettledPnl => 60,
+            MangoInstruction::CancelSpotOrderAndSettle { .. } => 61,
+            MangoInstruction::PlacePerpPeggedOrder { .. } => 62,
+            MangoInstruction::SetPostTradeRiskLogEnabled { .. } => 63,
+            MangoInstruction::SetMaxOracleAge { .. } => 64,
+            MangoInstruction::UpdateAllFunding => 65,
+            MangoInstruction::CloseMangoAccount => 66,
+            MangoInstruction::GetPositionHistoryAnchor => 67,
+            MangoInstruction::CloseSpotOpenOrders => 68,
+            MangoInstruction::SetMaxIndexGrowth { .. } => 69,
+            MangoInstruction::SetDelegate => 70,
+            MangoInstruction::GetPerpSpread => 71,
+            MangoInstruction::PlacePerpOrder3 { .. } => 72,
+            MangoInstruction::CheckGroupInvariants => 73,
+            MangoInstruction::SetPerpMngoVault => 74,
+            MangoInstruction::DepositBatch { .. } => 75,
+            MangoInstruction::GetMiningRate { .. } => 76,
+            MangoInstruction::ChangeMangoAccountOwner => 77,
+            MangoInstruction::UpgradeMangoAccount => 78,
+            MangoInstruction::SetReferrerMemory { .. } => 79,
+            MangoInstruction::CancelSpotOrderByClientId { .. } => 80,
+            MangoInstruction::InitLossRecovery => 81,
+            MangoInstruction::ClaimLossRecovery => 82,
+            MangoInstruction::SetGroupName { .. } => 83,
+            MangoInstruction::GetOpenInterest => 84,
+            MangoInstruction::TransferMsrm { .. } => 85,
+            MangoInstruction::GetInterestRates => 86,
+            MangoInstruction::CancelPerpOrdersSide { .. } => 87,
+            MangoInstruction::InitPnlTracker => 88,
+            MangoInstruction::RegisterLossShare => 89,
+        }
+    }
+    /// Length of the encoding pack produces, without building it. Indexers reading several
+    /// instructions out of one buffer can unpack one and skip this many bytes to the next
+    pub fn packed_len(&self) -> usize {
+        match self {
+            MangoInstruction::InitMangoGroup { .. } => 4 + 64,
+            MangoInstruction::InitMangoAccount => 4,
+            MangoInstruction::Deposit { .. } => 4 + 8,
+            MangoInstruction::Withdraw { .. } => 4 + 9,
+            MangoInstruction::AddSpotMarket { .. } => 4 + 96,
+            MangoInstruction::AddToBasket { .. } => 4 + 8,
+            MangoInstruction::Borrow { .. } => 4 + 8,
+            MangoInstruction::CachePrices => 4,
+            MangoInstruction::CacheRootBanks => 4,
+            MangoInstruction::PlaceSpotOrder { .. } => 4 + 46,
+            MangoInstruction::AddOracle => 4,
+            MangoInstruction::AddPerpMarket { .. } => 4 + 144,
+            MangoInstruction::PlacePerpOrder { .. } => 4 + 26,
+            MangoInstruction::CancelPerpOrderByClientId { .. } => 4 + 9,
+            MangoInstruction::CancelPerpOrder { .. } => 4 + 17,
+            MangoInstruction::ConsumeEvents { .. } => 4 + 8,
+            MangoInstruction::CachePerpMarkets => 4,
+            MangoInstruction::UpdateFunding => 4,
+            MangoInstruction::SetOracle { .. } => 4 + 16,
+            MangoInstruction::SettleFunds => 4,
+            MangoInstruction::CancelSpotOrder { .. } => 4 + 20,
+            MangoInstruction::UpdateRootBank => 4,
+            MangoInstruction::SettlePnl { .. } => 4 + 8,
+            MangoInstruction::SettleBorrow { .. } => 4 + 16,
+            MangoInstruction::ForceCancelSpotOrders { .. } => 4 + 1,
+            MangoInstruction::ForceCancelPerpOrders { .. } => 4 + 1,
+            MangoInstruction::LiquidateTokenAndToken { .. } => 4 + 16,
+            MangoInstruction::LiquidateTokenAndPerp { .. } => 4 + 34,
+            MangoInstruction::LiquidatePerpMarket { .. } => 4 + 8,
+            MangoInstruction::SettleFees => 4,
+            MangoInstruction::ResolvePerpBankruptcy { .. } => 4 + 24,
+            MangoInstruction::ResolveTokenBankruptcy { .. } => 4 + 16,
+            MangoInstruction::InitSpotOpenOrders => 4,
+            MangoInstruction::RedeemMngo => 4,
+            MangoInstruction::AddMangoAccountInfo { .. } => 4 + INFO_LEN,
+            MangoInstruction::DepositMsrm { .. } => 4 + 8,
+            MangoInstruction::WithdrawMsrm { .. } => 4 + 8,
+            MangoInstruction::ChangePerpMarketParams { .. } => 4 + 137,
+            MangoInstruction::SetGroupAdmin => 4,
+            MangoInstruction::CancelAllPerpOrders { .. } => 4 + 1,
+            MangoInstruction::ForceSettleQuotePositions => 4,
+            MangoInstruction::TransferCollateral { .. } => 4 + 16,
+            MangoInstruction::PlacePerpOrder2 { .. } => 4 + 36,
+            MangoInstruction::ExecuteRebalanceLeg { legs } => 4 + 8 + 25 * legs.len(),
+            MangoInstruction::SetLiquidationTargetHealth { .. } => 4 + 16,
+            MangoInstruction::SetPerpMarketFees { .. } => 4 + 32,
+            MangoInstruction::GetAccountAuthority => 4,
+            MangoInstruction::AddToDepositWhitelist { .. } => 4 + 32,
+            MangoInstruction::RemoveFromDepositWhitelist { .. } => 4 + 32,
+            MangoInstruction::SetDepositWhitelistEnabled { .. } => 4 + 1,
+            MangoInstruction::GetInsuranceCoverage => 4,
+            MangoInstruction::GetRealizedPnl => 4,
+            MangoInstruction::SetMaxWithdrawPerTx { .. } => 4 + 16,
+            MangoInstruction::SetMinMngoToTrade { .. } => 4 + 8,
+            MangoInstruction::RebalanceNodeBanks { .. } => 4 + 8,
+            MangoInstruction::PreviewWithdraw { .. } => 4 + 9,
+            MangoInstruction::SetWithdrawFee { .. } => 4 + 2,
+            MangoInstruction::CancelPerpOrdersOlderThan { .. } => 4 + 9,
+            MangoInstruction::SetPerpImpactDepth { .. } => 4 + 8,
+            MangoInstruction::UpdateRootBanks => 4,
+            MangoInstruction::HasUnsettledPnl => 4,
+            MangoInstruction::CancelSpotOrderAndSettle { .. } => 4 + 20,
+            MangoInstruction::PlacePerpPeggedOrder { .. } => 4 + 34,
+            MangoInstruction::SetPostTradeRiskLogEnabled { .. } => 4 + 1,
+            MangoInstruction::SetMaxOracleAge { .. } => 4 + 4,
+            MangoInstruction::UpdateAllFunding => 4,
+            MangoInstruction::CloseMangoAccount => 4,
+            MangoInstruction::GetPositionHistoryAnchor => 4,
+            MangoInstruction::CloseSpotOpenOrders => 4,
+            MangoInstruction::SetMaxIndexGrowth { .. } => 4 + 16,
+            MangoInstruction::SetDelegate => 4,
+            MangoInstruction::GetPerpSpread => 4,
+            MangoInstruction::PlacePerpOrder3 { .. } => 4 + 43,
+            MangoInstruction::CheckGroupInvariants => 4,
+            MangoInstruction::SetPerpMngoVault => 4,
+            MangoInstruction::DepositBatch { quantities } => 4 + 8 + 8 * quantities.len(),
+            MangoInstruction::GetMiningRate { .. } => 4 + 8,
+            MangoInstruction::ChangeMangoAccountOwner => 4,
+            MangoInstruction::UpgradeMangoAccount => 4,
+            MangoInstruction::SetReferrerMemory { .. } => 4 + 32,
+            MangoInstruction::CancelSpotOrderByClientId { .. } => 4 + 8,
+            MangoInstruction::InitLossRecovery => 4,
+            MangoInstruction::ClaimLossRecovery => 4,
+            MangoInstruction::SetGroupName { .. } => 4 + GROUP_NAME_LEN,
+            MangoInstruction::GetOpenInterest => 4,
+            MangoInstruction::TransferMsrm { .. } => 4 + 8,
+            MangoInstruction::GetInterestRates => 4,
+            MangoInstruction::CancelPerpOrdersSide { .. } => 4 + 2,
+            MangoInstruction::InitPnlTracker => 4,
+            MangoInstruction::RegisterLossShare => 4,
+        }
+    }
+    /// Encode the instruction in the exact layout unpack reads: a u32 discriminant followed by
+    /// the fields in declaration order, little-endian, with usize as u64 and enums and bools as u8
+    pub fn pack(&self) -> Vec<u8> {
+        match self {
+            MangoInstruction::InitMangoGroup {
+                signer_nonce,
+                valid_interval,
+                quote_optimal_util,
+                quote_optimal_rate,
+                quote_max_rate,
+            } => {
+                let mut data = 0u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&signer_nonce.to_le_bytes());
+                data.extend_from_slice(&valid_interval.to_le_bytes());
+                data.extend_from_slice(&quote_optimal_util.to_le_bytes());
+                data.extend_from_slice(&quote_optimal_rate.to_le_bytes());
+                data.extend_from_slice(&quote_max_rate.to_le_bytes());
+                data
             }
+            MangoInstruction::InitMangoAccount => 1u32.to_le_bytes().to_vec(),
+            MangoInstruction::Deposit { quantity } => {
+                let mut data = 2u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::Withdraw { quantity, allow_borrow } => {
+                let mut data = 3u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data.push(*allow_borrow as u8);
+                data
+            }
+            MangoInstruction::AddSpotMarket {
+                maint_leverage,
+                init_leverage,
+                liquidation_fee,
+                optimal_util,
+                optimal_rate,
+                max_rate,
+            } => {
+                let mut data = 4u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&maint_leverage.to_le_bytes());
+                data.extend_from_slice(&init_leverage.to_le_bytes());
+                data.extend_from_slice(&liquidation_fee.to_le_bytes());
+                data.extend_from_slice(&optimal_util.to_le_bytes());
+                data.extend_from_slice(&optimal_rate.to_le_bytes());
+                data.extend_from_slice(&max_rate.to_le_bytes());
+                data
+            }
+            MangoInstruction::AddToBasket { market_index } => {
+                let mut data = 5u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*market_index as u64).to_le_bytes());
+                data
+            }
+            MangoInstruction::Borrow { quantity } => {
+                let mut data = 6u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::CachePrices => 7u32.to_le_bytes().to_vec(),
+            MangoInstruction::CacheRootBanks => 8u32.to_le_bytes().to_vec(),
+            MangoInstruction::PlaceSpotOrder { order } => {
+                let mut data = 9u32.to_le_bytes().to_vec();
+                pack_dex_new_order_v3(order, &mut data);
+                data
+            }
+            MangoInstruction::AddOracle => 10u32.to_le_bytes().to_vec(),
+            MangoInstruction::AddPerpMarket {
+                maint_leverage,
+                init_leverage,
+                liquidation_fee,
+                maker_fee,
+                taker_fee,
+                base_lot_size,
+                quote_lot_size,
+                rate,
+                max_depth_bps,
+                target_period_length,
+                mngo_per_period,
+            } => {
+                let mut data = 11u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&maint_leverage.to_le_bytes());
+                data.extend_from_slice(&init_leverage.to_le_bytes());
+                data.extend_from_slice(&liquidation_fee.to_le_bytes());
+                data.extend_from_slice(&maker_fee.to_le_bytes());
+                data.extend_from_slice(&taker_fee.to_le_bytes());
+                data.extend_from_slice(&base_lot_size.to_le_bytes());
+                data.extend_from_slice(&quote_lot_size.to_le_bytes());
+                data.extend_from_slice(&rate.to_le_bytes());
+                data.extend_from_slice(&max_depth_bps.to_le_bytes());
+                data.extend_from_slice(&target_period_length.to_le_bytes());
+                data.extend_from_slice(&mngo_per_period.to_le_bytes());
+                data
+            }
+            MangoInstruction::PlacePerpOrder {
+                price,
+                quantity,
+                client_order_id,
+                side,
+                order_type,
+            } => {
+                let mut data = 12u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&price.to_le_bytes());
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data.extend_from_slice(&client_order_id.to_le_bytes());
+                data.push(*side as u8);
+                data.push(*order_type as u8);
+                data
+            }
+            MangoInstruction::CancelPerpOrderByClientId { client_order_id, invalid_id_ok } => {
+                let mut data = 13u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&client_order_id.to_le_bytes());
+                data.push(*invalid_id_ok as u8);
+                data
+            }
+            MangoInstruction::CancelPerpOrder { order_id, invalid_id_ok } => {
+                let mut data = 14u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&order_id.to_le_bytes());
+                data.push(*invalid_id_ok as u8);
+                data
+            }
+            MangoInstruction::ConsumeEvents { limit } => {
+                let mut data = 15u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*limit as u64).to_le_bytes());
+                data
+            }
+            MangoInstruction::CachePerpMarkets => 16u32.to_le_bytes().to_vec(),
+            MangoInstruction::UpdateFunding => 17u32.to_le_bytes().to_vec(),
+            MangoInstruction::SetOracle { price } => {
+                let mut data = 18u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&price.to_le_bytes());
+                data
+            }
+            MangoInstruction::SettleFunds => 19u32.to_le_bytes().to_vec(),
+            MangoInstruction::CancelSpotOrder { order } => {
+                let mut data = 20u32.to_le_bytes().to_vec();
+                pack_cancel_order_v2(order, &mut data);
+                data
+            }
+            MangoInstruction::UpdateRootBank => 21u32.to_le_bytes().to_vec(),
+            MangoInstruction::SettlePnl { market_index } => {
+                let mut data = 22u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*market_index as u64).to_le_bytes());
+                data
+            }
+            MangoInstruction::SettleBorrow { token_index, quantity } => {
+                let mut data = 23u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*token_index as u64).to_le_bytes());
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::ForceCancelSpotOrders { limit } => {
+                let mut data = 24u32.to_le_bytes().to_vec();
+                data.push(*limit);
+                data
+            }
+            MangoInstruction::ForceCancelPerpOrders { limit } => {
+                let mut data = 25u32.to_le_bytes().to_vec();
+                data.push(*limit);
+                data
+            }
+            MangoInstruction::LiquidateTokenAndToken { max_liab_transfer } => {
+                let mut data = 26u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&max_liab_transfer.to_le_bytes());
+                data
+            }
+            MangoInstruction::LiquidateTokenAndPerp {
+                asset_type,
+                asset_index,
+                liab_type,
+                liab_index,
+                max_liab_transfer,
+            } => {
+                let mut data = 27u32.to_le_bytes().to_vec();
+                data.push(*asset_type as u8);
+                data.extend_from_slice(&(*asset_index as u64).to_le_bytes());
+                data.push(*liab_type as u8);
+                data.extend_from_slice(&(*liab_index as u64).to_le_bytes());
+                data.extend_from_slice(&max_liab_transfer.to_le_bytes());
+                data
+            }
+            MangoInstruction::LiquidatePerpMarket { base_transfer_request } => {
+                let mut data = 28u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&base_transfer_request.to_le_bytes());
+                data
+            }
+            MangoInstruction::SettleFees => 29u32.to_le_bytes().to_vec(),
+            MangoInstruction::ResolvePerpBankruptcy { liab_index, max_liab_transfer } => {
+                let mut data = 30u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*liab_index as u64).to_le_bytes());
+                data.extend_from_slice(&max_liab_transfer.to_le_bytes());
+                data
+            }
+            MangoInstruction::ResolveTokenBankruptcy { max_liab_transfer } => {
+                let mut data = 31u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&max_liab_transfer.to_le_bytes());
+                data
+            }
+            MangoInstruction::InitSpotOpenOrders => 32u32.to_le_bytes().to_vec(),
+            MangoInstruction::RedeemMngo => 33u32.to_le_bytes().to_vec(),
+            MangoInstruction::AddMangoAccountInfo { info } => {
+                let mut data = 34u32.to_le_bytes().to_vec();
+                data.extend_from_slice(info);
+                data
+            }
+            MangoInstruction::DepositMsrm { quantity } => {
+                let mut data = 35u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::WithdrawMsrm { quantity } => {
+                let mut data = 36u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::ChangePerpMarketParams {
+                maint_leverage,
+                init_leverage,
+                liquidation_fee,
+                maker_fee,
+                taker_fee,
+                rate,
+                max_depth_bps,
+                target_period_length,
+                mngo_per_period,
+            } => {
+                let mut data = 37u32.to_le_bytes().to_vec();
+                for opt in [
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    rate,
+                    max_depth_bps,
+                ]
+                .iter()
+                {
+                    pack_i80f48_opt(opt, &mut data);
+                }
+                pack_u64_opt(target_period_length, &mut data);
+                pack_u64_opt(mngo_per_period, &mut data);
+                data
+            }
+            MangoInstruction::SetGroupAdmin => 38u32.to_le_bytes().to_vec(),
+            MangoInstruction::CancelAllPerpOrders { limit } => {
+                let mut data = 39u32.to_le_bytes().to_vec();
+                data.push(*limit);
+                data
+            }
+            MangoInstruction::ForceSettleQuotePositions => 40u32.to_le_bytes().to_vec(),
+            MangoInstruction::TransferCollateral { token_index, quantity } => {
+                let mut data = 41u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*token_index as u64).to_le_bytes());
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::PlacePerpOrder2 {
+                price,
+                quantity,
+                client_order_id,
+                side,
+                order_type,
+                safe_mode,
+                tag,
+                match_limit,
+            } => {
+                let mut data = 42u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&price.to_le_bytes());
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data.extend_from_slice(&client_order_id.to_le_bytes());
+                data.push(*side as u8);
+                data.push(*order_type as u8);
+                data.push(*safe_mode as u8);
+                data.extend_from_slice(tag);
+                data.push(*match_limit);
+                data
+            }
+            MangoInstruction::ExecuteRebalanceLeg { legs } => {
+                let mut data = 43u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(legs.len() as u64).to_le_bytes());
+                for leg in legs.iter() {
+                    data.extend_from_slice(&(leg.market_index as u64).to_le_bytes());
+                    data.push(leg.side as u8);
+                    data.extend_from_slice(&leg.price.to_le_bytes());
+                    data.extend_from_slice(&leg.quantity.to_le_bytes());
+                }
+                data
+            }
+            MangoInstruction::SetLiquidationTargetHealth { liquidation_target_health } => {
+                let mut data = 44u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&liquidation_target_health.to_le_bytes());
+                data
+            }
+            MangoInstruction::SetPerpMarketFees { maker_fee, taker_fee } => {
+                let mut data = 45u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&maker_fee.to_le_bytes());
+                data.extend_from_slice(&taker_fee.to_le_bytes());
+                data
+            }
+            MangoInstruction::GetAccountAuthority => 46u32.to_le_bytes().to_vec(),
+            MangoInstruction::AddToDepositWhitelist { owner } => {
+                let mut data = 47u32.to_le_bytes().to_vec();
+                data.extend_from_slice(owner.as_ref());
+                data
+            }
+            MangoInstruction::RemoveFromDepositWhitelist { owner } => {
+                let mut data = 48u32.to_le_bytes().to_vec();
+                data.extend_from_slice(owner.as_ref());
+                data
+            }
+            MangoInstruction::SetDepositWhitelistEnabled { enabled } => {
+                let mut data = 49u32.to_le_bytes().to_vec();
+                data.push(*enabled as u8);
+                data
+            }
+            MangoInstruction::GetInsuranceCoverage => 50u32.to_le_bytes().to_vec(),
+            MangoInstruction::GetRealizedPnl => 51u32.to_le_bytes().to_vec(),
+            MangoInstruction::SetMaxWithdrawPerTx { token_index, max_withdraw } => {
+                let mut data = 52u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*token_index as u64).to_le_bytes());
+                data.extend_from_slice(&max_withdraw.to_le_bytes());
+                data
+            }
+            MangoInstruction::SetMinMngoToTrade { min_mngo_to_trade } => {
+                let mut data = 53u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&min_mngo_to_trade.to_le_bytes());
+                data
+            }
+            MangoInstruction::RebalanceNodeBanks { quantity } => {
+                let mut data = 54u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::PreviewWithdraw { quantity, allow_borrow } => {
+                let mut data = 55u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data.push(*allow_borrow as u8);
+                data
+            }
+            MangoInstruction::SetWithdrawFee { withdraw_fee_bps } => {
+                let mut data = 56u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&withdraw_fee_bps.to_le_bytes());
+                data
+            }
+            MangoInstruction::CancelPerpOrdersOlderThan { cutoff_ts, limit } => {
+                let mut data = 57u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&cutoff_ts.to_le_bytes());
+                data.push(*limit);
+                data
+            }
+            MangoInstruction::SetPerpImpactDepth { quote_notional } => {
+                let mut data = 58u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quote_notional.to_le_bytes());
+                data
+            }
+            MangoInstruction::UpdateRootBanks => 59u32.to_le_bytes().to_vec(),
+            MangoInstruction::HasUnsettledPnl => 60u32.to_le_bytes().to_vec(),
+            MangoInstruction::CancelSpotOrderAndSettle { order } => {
+                let mut data = 61u32.to_le_bytes().to_vec();
+                pack_cancel_order_v2(order, &mut data);
+                data
+            }
+            MangoInstruction::PlacePerpPeggedOrder {
+                offset_ticks,
+                peg_limit,
+                quantity,
+                client_order_id,
+                side,
+                order_type,
+            } => {
+                let mut data = 62u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&offset_ticks.to_le_bytes());
+                data.extend_from_slice(&peg_limit.to_le_bytes());
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data.extend_from_slice(&client_order_id.to_le_bytes());
+                data.push(*side as u8);
+                data.push(*order_type as u8);
+                data
+            }
+            MangoInstruction::SetPostTradeRiskLogEnabled { enabled } => {
+                let mut data = 63u32.to_le_bytes().to_vec();
+                data.push(*enabled as u8);
+                data
+            }
+            MangoInstruction::SetMaxOracleAge { max_oracle_age } => {
+                let mut data = 64u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&max_oracle_age.to_le_bytes());
+                data
+            }
+            MangoInstruction::UpdateAllFunding => 65u32.to_le_bytes().to_vec(),
+            MangoInstruction::CloseMangoAccount => 66u32.to_le_bytes().to_vec(),
+            MangoInstruction::GetPositionHistoryAnchor => 67u32.to_le_bytes().to_vec(),
+            MangoInstruction::CloseSpotOpenOrders => 68u32.to_le_bytes().to_vec(),
+            MangoInstruction::SetMaxIndexGrowth { max_index_growth } => {
+                let mut data = 69u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&max_index_growth.to_le_bytes());
+                data
+            }
+            MangoInstruction::SetDelegate => 70u32.to_le_bytes().to_vec(),
+            MangoInstruction::GetPerpSpread => 71u32.to_le_bytes().to_vec(),
+            MangoInstruction::PlacePerpOrder3 {
+                price,
+                quantity,
+                client_order_id,
+                side,
+                order_type,
+                safe_mode,
+                tag,
+                expiry_timestamp,
+            } => {
+                let mut data = 72u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&price.to_le_bytes());
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data.extend_from_slice(&client_order_id.to_le_bytes());
+                data.push(*side as u8);
+                data.push(*order_type as u8);
+                data.push(*safe_mode as u8);
+                data.extend_from_slice(tag);
+                data.extend_from_slice(&expiry_timestamp.to_le_bytes());
+                data
+            }
+            MangoInstruction::CheckGroupInvariants => 73u32.to_le_bytes().to_vec(),
+            MangoInstruction::SetPerpMngoVault => 74u32.to_le_bytes().to_vec(),
+            MangoInstruction::DepositBatch { quantities } => {
+                let mut data = 75u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(quantities.len() as u64).to_le_bytes());
+                for quantity in quantities.iter() {
+                    data.extend_from_slice(&quantity.to_le_bytes());
+                }
+                data
+            }
+            MangoInstruction::GetMiningRate { market_index } => {
+                let mut data = 76u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&(*market_index as u64).to_le_bytes());
+                data
+            }
+            MangoInstruction::ChangeMangoAccountOwner => 77u32.to_le_bytes().to_vec(),
+            MangoInstruction::UpgradeMangoAccount => 78u32.to_le_bytes().to_vec(),
+            MangoInstruction::SetReferrerMemory { referrer_id } => {
+                let mut data = 79u32.to_le_bytes().to_vec();
+                data.extend_from_slice(referrer_id);
+                data
+            }
+            MangoInstruction::CancelSpotOrderByClientId { client_order_id } => {
+                let mut data = 80u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&client_order_id.to_le_bytes());
+                data
+            }
+            MangoInstruction::InitLossRecovery => 81u32.to_le_bytes().to_vec(),
+            MangoInstruction::ClaimLossRecovery => 82u32.to_le_bytes().to_vec(),
+            MangoInstruction::SetGroupName { name } => {
+                let mut data = 83u32.to_le_bytes().to_vec();
+                data.extend_from_slice(name);
+                data
+            }
+            MangoInstruction::GetOpenInterest => 84u32.to_le_bytes().to_vec(),
+            MangoInstruction::TransferMsrm { quantity } => {
+                let mut data = 85u32.to_le_bytes().to_vec();
+                data.extend_from_slice(&quantity.to_le_bytes());
+                data
+            }
+            MangoInstruction::GetInterestRates => 86u32.to_le_bytes().to_vec(),
+            MangoInstruction::CancelPerpOrdersSide { side, limit } => {
+                let mut data = 87u32.to_le_bytes().to_vec();
+                data.push(*side as u8);
+                data.push(*limit);
+                data
+            }
+            MangoInstruction::InitPnlTracker => 88u32.to_le_bytes().to_vec(),
+            MangoInstruction::RegisterLossShare => 89u32.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for MangoInstruction {
+    type Error = ProgramError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        Self::unpack_checked(input)
+    }
+}
+
+/// Indexes and counts go over the wire as u64 so the encoding is the same on every target.
+/// Fails if the value doesn't fit in this target's usize
+fn unpack_usize(data: &[u8; 8]) -> Result<usize, ProgramError> {
+    usize::try_from(u64::from_le_bytes(*data)).map_err(|_| ProgramError::InvalidInstructionData)
+}
+
+fn unpack_cancel_order_v2(
+    data: &[u8; 20],
+) -> Option<serum_dex::instruction::CancelOrderInstructionV2> {
+    let (side, order_id) = array_refs![data, 4, 16];
+    let side = match u32::from_le_bytes(*side) {
+        0 => serum_dex::matching::Side::Bid,
+        1 => serum_dex::matching::Side::Ask,
+        _ => return None,
+    };
+    let order_id = u128::from_le_bytes(*order_id);
+    Some(serum_dex::instruction::CancelOrderInstructionV2 { side, order_id })
+}
+
+fn pack_cancel_order_v2(
+    order: &serum_dex::instruction::CancelOrderInstructionV2,
+    data: &mut Vec<u8>,
+) {
+    data.extend_from_slice(&(order.side as u32).to_le_bytes());
+    data.extend_from_slice(&order.order_id.to_le_bytes());
+}
+
+fn unpack_i80f48_opt(data: &[u8; 17]) -> Option<I80F48> {
+    let (opt, val) = array_refs![data, 1, 16];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(I80F48::from_le_bytes(*val))
+    }
+}
+fn unpack_u64_opt(data: &[u8; 9]) -> Option<u64> {
+    let (opt, val) = array_refs![data, 1, 8];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(u64::from_le_bytes(*val))
+    }
+}
+fn pack_i80f48_opt(value: &Option<I80F48>, data: &mut Vec<u8>) {
+    match value {
+        None => data.extend_from_slice(&[0; 17]),
+        Some(val) => {
+            data.push(1);
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+    }
+}
+fn pack_u64_opt(value: &Option<u64>, data: &mut Vec<u8>) {
+    match value {
+        None => data.extend_from_slice(&[0; 9]),
+        Some(val) => {
+            data.push(1);
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+    }
+}
+
+fn unpack_dex_new_order_v3(
+    data: &[u8; 46],
+) -> Option<serum_dex::instruction::NewOrderInstructionV3> {
+    let (
+        &side_arr,
+        &price_arr,
+        &max_coin_qty_arr,
+        &max_native_pc_qty_arr,
+        &self_trade_behavior_arr,
+        &otype_arr,
+        &client_order_id_bytes,
+        &limit_arr,
+    ) = array_refs![data, 4, 8, 8, 8, 4, 4, 8, 2];
+
+    let side = serum_dex::matching::Side::try_from_primitive(
+        u32::from_le_bytes(side_arr).try_into().ok()?,
+    )
+    .ok()?;
+    let limit_price = NonZeroU64::new(u64::from_le_bytes(price_arr))?;
+    let max_coin_qty = NonZeroU64::new(u64::from_le_bytes(max_coin_qty_arr))?;
+    let max_native_pc_qty_including_fees =
+        NonZeroU64::new(u64::from_le_bytes(max_native_pc_qty_arr))?;
+    let self_trade_behavior = serum_dex::instruction::SelfTradeBehavior::try_from_primitive(
+        u32::from_le_bytes(self_trade_behavior_arr).try_into().ok()?,
+    )
+    .ok()?;
+    let order_type = serum_dex::matching::OrderType::try_from_primitive(
+        u32::from_le_bytes(otype_arr).try_into().ok()?,
+    )
+    .ok()?;
+    let client_order_id = u64::from_le_bytes(client_order_id_bytes);
+    let limit = u16::from_le_bytes(limit_arr);
+
+    Some(serum_dex::instruction::NewOrderInstructionV3 {
+        side,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty_including_fees,
+        self_trade_behavior,
+        order_type,
+        client_order_id,
+        limit,
+    })
+}
+
+fn pack_dex_new_order_v3(
+    order: &serum_dex::instruction::NewOrderInstructionV3,
+    data: &mut Vec<u8>,
+) {
+    data.extend_from_slice(&(order.side as u32).to_le_bytes());
+    data.extend_from_slice(&order.limit_price.get().to_le_bytes());
+    data.extend_from_slice(&order.max_coin_qty.get().to_le_bytes());
+    data.extend_from_slice(&order.max_native_pc_qty_including_fees.get().to_le_bytes());
+    data.extend_from_slice(&(order.self_trade_behavior as u32).to_le_bytes());
+    data.extend_from_slice(&(order.order_type as u32).to_le_bytes());
+    data.extend_from_slice(&order.client_order_id.to_le_bytes());
+    data.extend_from_slice(&order.limit.to_le_bytes());
+}
+
+pub fn init_mango_group(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    quote_mint_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    insurance_vault_pk: &Pubkey,
+    msrm_vault_pk: &Pubkey, // send in Pubkey:default() if not using this feature
+    fees_vault_pk: &Pubkey,
+    mango_cache_ai: &Pubkey,
+    dex_program_pk: &Pubkey,
+
+    signer_nonce: u64,
+    valid_interval: u64,
+    quote_optimal_util: I80F48,
+    quote_optimal_rate: I80F48,
+    quote_max_rate: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new_readonly(*quote_mint_pk, false),
+        AccountMeta::new_readonly(*quote_vault_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*quote_root_bank_pk, false),
+        AccountMeta::new_readonly(*insurance_vault_pk, false),
+        AccountMeta::new_readonly(*msrm_vault_pk, false),
+        AccountMeta::new_readonly(*fees_vault_pk, false),
+        AccountMeta::new(*mango_cache_ai, false),
+        AccountMeta::new_readonly(*dex_program_pk, false),
+    ];
+
+    let instr = MangoInstruction::InitMangoGroup {
+        signer_nonce,
+        valid_interval,
+        quote_optimal_util,
+        quote_optimal_rate,
+        quote_max_rate,
+    };
+
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn init_mango_account(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+    ];
+
+    let instr = MangoInstruction::InitMangoAccount;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn close_mango_account(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (withdraw_caps_pk, _) = WithdrawCaps::find_address(program_id, mango_account_pk);
+    let (pnl_tracker_pk, _) = PnlTracker::find_address(program_id, mango_account_pk);
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new(withdraw_caps_pk, false),
+        AccountMeta::new(pnl_tracker_pk, false),
+    ];
+
+    let instr = MangoInstruction::CloseMangoAccount;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn deposit(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    owner_token_account_pk: &Pubkey,
+
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    // Checked against the owner if the group has its deposit whitelist enabled
+    let (deposit_whitelist_pk, _) = DepositWhitelist::find_address(program_id, mango_group_pk);
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*vault_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new(*owner_token_account_pk, false),
+        AccountMeta::new_readonly(deposit_whitelist_pk, false),
+    ];
+
+    let instr = MangoInstruction::Deposit { quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+/// `entries` holds, per token, its root bank, node bank, vault, the owner's token account and
+/// the quantity to deposit
+pub fn deposit_batch(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    entries: &[(Pubkey, Pubkey, Pubkey, Pubkey, u64)],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+    for (root_bank_pk, node_bank_pk, vault_pk, owner_token_account_pk, _) in entries.iter() {
+        accounts.push(AccountMeta::new_readonly(*root_bank_pk, false));
+        accounts.push(AccountMeta::new(*node_bank_pk, false));
+        accounts.push(AccountMeta::new(*vault_pk, false));
+        accounts.push(AccountMeta::new(*owner_token_account_pk, false));
+    }
+    // Checked against the owner if the group has its deposit whitelist enabled
+    let (deposit_whitelist_pk, _) = DepositWhitelist::find_address(program_id, mango_group_pk);
+    accounts.push(AccountMeta::new_readonly(deposit_whitelist_pk, false));
+
+    let instr = MangoInstruction::DepositBatch {
+        quantities: entries.iter().map(|entry| entry.4).collect(),
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn add_spot_market(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    dex_program_pk: &Pubkey,
+    token_mint_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    admin_pk: &Pubkey,
+
+    maint_leverage: I80F48,
+    init_leverage: I80F48,
+    liquidation_fee: I80F48,
+    optimal_util: I80F48,
+    optimal_rate: I80F48,
+    max_rate: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new_readonly(*oracle_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*dex_program_pk, false),
+        AccountMeta::new_readonly(*token_mint_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new_readonly(*vault_pk, false),
+        AccountMeta::new(*root_bank_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::AddSpotMarket {
+        maint_leverage,
+        init_leverage,
+        liquidation_fee,
+        optimal_util,
+        optimal_rate,
+        max_rate,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn add_perp_market(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    mngo_vault_pk: &Pubkey,
+    admin_pk: &Pubkey,
+
+    maint_leverage: I80F48,
+    init_leverage: I80F48,
+    liquidation_fee: I80F48,
+    maker_fee: I80F48,
+    taker_fee: I80F48,
+    base_lot_size: i64,
+    quote_lot_size: i64,
+    rate: I80F48,
+    max_depth_bps: I80F48,
+    target_period_length: u64,
+    mngo_per_period: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new_readonly(*mngo_vault_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::AddPerpMarket {
+        maint_leverage,
+        init_leverage,
+        liquidation_fee,
+        maker_fee,
+        taker_fee,
+        base_lot_size,
+        quote_lot_size,
+        rate,
+        max_depth_bps,
+        target_period_length,
+        mngo_per_period,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn place_perp_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
+    side: Side,
+    price: i64,
+    quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr =
+        MangoInstruction::PlacePerpOrder { side, price, quantity, client_order_id, order_type };
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn place_perp_pegged_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
+    side: Side,
+    offset_ticks: i64,
+    peg_limit: i64,
+    quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::PlacePerpPeggedOrder {
+        offset_ticks,
+        peg_limit,
+        quantity,
+        client_order_id,
+        side,
+        order_type,
+    };
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn cancel_perp_order_by_client_id(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,   // read
+    mango_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    client_order_id: u64,
+    invalid_id_ok: bool,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+    ];
+    let instr = MangoInstruction::CancelPerpOrderByClientId { client_order_id, invalid_id_ok };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn cancel_perp_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,   // read
+    mango_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    order_id: i128,
+    invalid_id_ok: bool,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+    ];
+    let instr = MangoInstruction::CancelPerpOrder { order_id, invalid_id_ok };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn cancel_all_perp_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,   // read
+    mango_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+    ];
+    let instr = MangoInstruction::CancelAllPerpOrders { limit };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn force_cancel_perp_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,         // read
+    mango_cache_pk: &Pubkey,         // read
+    perp_market_pk: &Pubkey,         // read
+    bids_pk: &Pubkey,                // write
+    asks_pk: &Pubkey,                // write
+    liqee_mango_account_pk: &Pubkey, // write
+    open_orders_pks: &[Pubkey],      // read
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+    ];
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    let instr = MangoInstruction::ForceCancelPerpOrders { limit };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn force_cancel_spot_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    base_root_bank_pk: &Pubkey,
+    base_node_bank_pk: &Pubkey,
+    base_vault_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_event_queue_pk: &Pubkey,
+    dex_base_pk: &Pubkey,
+    dex_quote_pk: &Pubkey,
+    dex_signer_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+    market_index: usize, // used to determine which of the open orders accounts should be passed in write
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new_readonly(*base_root_bank_pk, false),
+        AccountMeta::new(*base_node_bank_pk, false),
+        AccountMeta::new(*base_vault_pk, false),
+        AccountMeta::new_readonly(*quote_root_bank_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*quote_vault_pk, false),
+        AccountMeta::new(*spot_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new(*dex_event_queue_pk, false),
+        AccountMeta::new(*dex_base_pk, false),
+        AccountMeta::new(*dex_quote_pk, false),
+        AccountMeta::new_readonly(*dex_signer_pk, false),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+    accounts.extend(liqee_open_orders_pks.iter().enumerate().map(|(i, pk)| {
+        if i == market_index {
+            AccountMeta::new(*pk, false)
+        } else {
+            AccountMeta::new_readonly(*pk, false)
+        }
+    }));
+
+    let instr = MangoInstruction::ForceCancelSpotOrders { limit };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn consume_events(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,      // read
+    mango_cache_pk: &Pubkey,      // read
+    perp_market_pk: &Pubkey,      // read
+    event_queue_pk: &Pubkey,      // write
+    mango_acc_pks: &mut [Pubkey], // write
+    limit: usize,
+) -> Result<Instruction, ProgramError> {
+    let fixed_accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    mango_acc_pks.sort();
+    let mango_accounts = mango_acc_pks.into_iter().map(|pk| AccountMeta::new(*pk, false));
+    let accounts = fixed_accounts.into_iter().chain(mango_accounts).collect();
+    let instr = MangoInstruction::ConsumeEvents { limit };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn settle_pnl(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,     // read
+    mango_account_a_pk: &Pubkey, // write
+    mango_account_b_pk: &Pubkey, // write
+    mango_cache_pk: &Pubkey,     // read
+    root_bank_pk: &Pubkey,       // read
+    node_bank_pk: &Pubkey,       // write
+    market_index: usize,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_a_pk, false),
+        AccountMeta::new(*mango_account_b_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+    let instr = MangoInstruction::SettlePnl { market_index };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+/// SettleBorrow is deprecated: the program only logs it and returns, leaving the account as it
+/// was. The builder exists so older clients can still assemble it
+///
+/// Accounts, in order:
+/// 0. `[]` mango_group_pk - MangoGroup
+/// 1. `[]` mango_cache_pk - MangoCache
+/// 2. `[writable]` mango_account_pk - MangoAccount
+/// 3. `[signer]` owner_pk - MangoAccount owner
+/// 4. `[]` root_bank_pk - RootBank of `token_index`
+/// 5. `[writable]` node_bank_pk - NodeBank
+pub fn settle_borrow(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,   // read
+    mango_cache_pk: &Pubkey,   // read
+    mango_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    root_bank_pk: &Pubkey,     // read
+    node_bank_pk: &Pubkey,     // write
+    token_index: usize,
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+    let instr = MangoInstruction::SettleBorrow { token_index, quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn settle_fees(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,   // read
+    mango_cache_pk: &Pubkey,   // read
+    perp_market_pk: &Pubkey,   // write
+    mango_account_pk: &Pubkey, // write
+    root_bank_pk: &Pubkey,     // read
+    node_bank_pk: &Pubkey,     // write
+    bank_vault_pk: &Pubkey,    // write
+    fees_vault_pk: &Pubkey,    // write
+    signer_pk: &Pubkey,        // read
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*bank_vault_pk, false),
+        AccountMeta::new(*fees_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+    let instr = MangoInstruction::SettleFees;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn update_funding(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey, // read
+    mango_cache_pk: &Pubkey, // read
+    perp_market_pk: &Pubkey, // write
+    bids_pk: &Pubkey,        // read
+    asks_pk: &Pubkey,        // read
+) -> Result<Instruction, ProgramError> {
+    let (perp_market_config_pk, _) = PerpMarketConfig::find_address(program_id, perp_market_pk);
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(*bids_pk, false),
+        AccountMeta::new_readonly(*asks_pk, false),
+        AccountMeta::new_readonly(perp_market_config_pk, false),
+    ];
+    let instr = MangoInstruction::UpdateFunding {};
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+/// `perp_markets` holds the (perp_market, bids, asks) keys of each market to update
+pub fn update_all_funding(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_markets: &[(Pubkey, Pubkey, Pubkey)],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+    ];
+    for (perp_market_pk, bids_pk, asks_pk) in perp_markets.iter() {
+        accounts.push(AccountMeta::new(*perp_market_pk, false));
+        accounts.push(AccountMeta::new_readonly(*bids_pk, false));
+        accounts.push(AccountMeta::new_readonly(*asks_pk, false));
+        let (perp_market_config_pk, _) = PerpMarketConfig::find_address(program_id, perp_market_pk);
+        accounts.push(AccountMeta::new_readonly(perp_market_config_pk, false));
+    }
+
+    let instr = MangoInstruction::UpdateAllFunding;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn withdraw(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    token_account_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+    insurance_vault_pk: &Pubkey,
+
+    quantity: u64,
+    allow_borrow: bool,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*vault_pk, false),
+        AccountMeta::new(*token_account_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    // Receives the withdraw fee, if the group charges one
+    accounts.push(AccountMeta::new(*insurance_vault_pk, false));
+    // Checked against the caps if the account has any, ignored otherwise
+    let (withdraw_caps_pk, _) = WithdrawCaps::find_address(program_id, mango_account_pk);
+    accounts.push(AccountMeta::new_readonly(withdraw_caps_pk, false));
+
+    let instr = MangoInstruction::Withdraw { quantity, allow_borrow };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+/// Encode `s` as MangoAccount info: its UTF-8 bytes zero padded to INFO_LEN. Longer strings are
+/// cut at the last whole character that fits
+pub fn info_from_str(s: &str) -> [u8; INFO_LEN] {
+    let mut len = s.len().min(INFO_LEN);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    let mut info = [0u8; INFO_LEN];
+    info[..len].copy_from_slice(&s.as_bytes()[..len]);
+    info
+}
+
+pub fn add_mango_account_info(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    info: [u8; INFO_LEN],
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+    ];
+
+    let instr = MangoInstruction::AddMangoAccountInfo { info };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn deposit_msrm(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    msrm_account_pk: &Pubkey,
+    msrm_vault_pk: &Pubkey,
+
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*msrm_account_pk, false),
+        AccountMeta::new(*msrm_vault_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+
+    let instr = MangoInstruction::DepositMsrm { quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn withdraw_msrm(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    msrm_account_pk: &Pubkey,
+    msrm_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*msrm_account_pk, false),
+        AccountMeta::new(*msrm_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+
+    let instr = MangoInstruction::WithdrawMsrm { quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn transfer_msrm(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    src_mango_account_pk: &Pubkey,
+    dst_mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*src_mango_account_pk, false),
+        AccountMeta::new(*dst_mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+    ];
+
+    let instr = MangoInstruction::TransferMsrm { quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn borrow(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new(*pk, false)));
+
+    let instr = MangoInstruction::Borrow { quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn cache_prices(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    oracle_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+    ];
+    accounts.extend(oracle_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    let instr = MangoInstruction::CachePrices;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn cache_root_banks(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    root_bank_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+    ];
+    accounts.extend(root_bank_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    let instr = MangoInstruction::CacheRootBanks;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn cache_perp_markets(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+    ];
+    accounts.extend(perp_market_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    let instr = MangoInstruction::CachePerpMarkets;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn init_spot_open_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    signer_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+    ];
+
+    let instr = MangoInstruction::InitSpotOpenOrders;
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn close_spot_open_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    signer_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+    ];
+
+    let instr = MangoInstruction::CloseSpotOpenOrders;
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn place_spot_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    dex_request_queue_pk: &Pubkey,
+    dex_event_queue_pk: &Pubkey,
+    dex_base_pk: &Pubkey,
+    dex_quote_pk: &Pubkey,
+    base_root_bank_pk: &Pubkey,
+    base_node_bank_pk: &Pubkey,
+    base_vault_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_signer_pk: &Pubkey,
+    msrm_or_srm_vault_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+
+    market_index: usize, // used to determine which of the open orders accounts should be passed in write
+    order: serum_dex::instruction::NewOrderInstructionV3,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*spot_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*dex_request_queue_pk, false),
+        AccountMeta::new(*dex_event_queue_pk, false),
+        AccountMeta::new(*dex_base_pk, false),
+        AccountMeta::new(*dex_quote_pk, false),
+        AccountMeta::new_readonly(*base_root_bank_pk, false),
+        AccountMeta::new(*base_node_bank_pk, false),
+        AccountMeta::new(*base_vault_pk, false),
+        AccountMeta::new_readonly(*quote_root_bank_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*quote_vault_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+        AccountMeta::new_readonly(*dex_signer_pk, false),
+        AccountMeta::new_readonly(*msrm_or_srm_vault_pk, false),
+    ];
+
+    accounts.extend(open_orders_pks.iter().enumerate().map(|(i, pk)| {
+        if i == market_index {
+            AccountMeta::new(*pk, false)
+        } else {
+            AccountMeta::new_readonly(*pk, false)
+        }
+    }));
+
+    let instr = MangoInstruction::PlaceSpotOrder { order };
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+/// Place an immediate-or-cancel spot order that takes whatever is on the book, up to
+/// `max_base_qty` base lots and `max_quote_qty` native quote including fees. Buys are priced at
+/// u64::MAX and sells at 1, so the quantities are the only limit. Fails with InvalidParam if
+/// either quantity is zero
+pub fn place_spot_market_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    dex_request_queue_pk: &Pubkey,
+    dex_event_queue_pk: &Pubkey,
+    dex_base_pk: &Pubkey,
+    dex_quote_pk: &Pubkey,
+    base_root_bank_pk: &Pubkey,
+    base_node_bank_pk: &Pubkey,
+    base_vault_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_signer_pk: &Pubkey,
+    msrm_or_srm_vault_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+
+    market_index: usize,
+    side: serum_dex::matching::Side,
+    max_base_qty: u64,
+    max_quote_qty: u64,
+    client_order_id: u64,
+) -> Result<Instruction, ProgramError> {
+    let invalid_param = || ProgramError::Custom(MangoErrorCode::InvalidParam.into());
+    let limit_price = match side {
+        serum_dex::matching::Side::Bid => u64::MAX,
+        serum_dex::matching::Side::Ask => 1,
+    };
+    let order = serum_dex::instruction::NewOrderInstructionV3 {
+        side,
+        limit_price: NonZeroU64::new(limit_price).unwrap(),
+        max_coin_qty: NonZeroU64::new(max_base_qty).ok_or_else(invalid_param)?,
+        max_native_pc_qty_including_fees: NonZeroU64::new(max_quote_qty)
+            .ok_or_else(invalid_param)?,
+        self_trade_behavior: serum_dex::instruction::SelfTradeBehavior::DecrementTake,
+        order_type: serum_dex::matching::OrderType::ImmediateOrCancel,
+        client_order_id,
+        limit: u16::MAX,
+    };
+
+    place_spot_order(
+        program_id,
+        mango_group_pk,
+        mango_account_pk,
+        owner_pk,
+        mango_cache_pk,
+        dex_prog_pk,
+        spot_market_pk,
+        bids_pk,
+        asks_pk,
+        dex_request_queue_pk,
+        dex_event_queue_pk,
+        dex_base_pk,
+        dex_quote_pk,
+        base_root_bank_pk,
+        base_node_bank_pk,
+        base_vault_pk,
+        quote_root_bank_pk,
+        quote_node_bank_pk,
+        quote_vault_pk,
+        signer_pk,
+        dex_signer_pk,
+        msrm_or_srm_vault_pk,
+        open_orders_pks,
+        market_index,
+        order,
+    )
+}
+
+pub fn settle_funds(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_base_pk: &Pubkey,
+    dex_quote_pk: &Pubkey,
+    base_root_bank_pk: &Pubkey,
+    base_node_bank_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    base_vault_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    dex_signer_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*spot_market_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new(*dex_base_pk, false),
+        AccountMeta::new(*dex_quote_pk, false),
+        AccountMeta::new_readonly(*base_root_bank_pk, false),
+        AccountMeta::new(*base_node_bank_pk, false),
+        AccountMeta::new_readonly(*quote_root_bank_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*base_vault_pk, false),
+        AccountMeta::new(*quote_vault_pk, false),
+        AccountMeta::new_readonly(*dex_signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+
+    let instr = MangoInstruction::SettleFunds;
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn add_oracle(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::AddOracle;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn update_root_bank(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+        AccountMeta::new(*root_bank_pk, false),
+    ];
+
+    accounts.extend(node_bank_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::UpdateRootBank;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn get_interest_rates(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+    ];
+
+    accounts.extend(node_bank_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::GetInterestRates;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_oracle(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    price: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::SetOracle { price };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn liquidate_token_and_token(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    liqor_mango_account_pk: &Pubkey,
+    liqor_pk: &Pubkey,
+    asset_root_bank_pk: &Pubkey,
+    asset_node_bank_pk: &Pubkey,
+    liab_root_bank_pk: &Pubkey,
+    liab_node_bank_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+    liqor_open_orders_pks: &[Pubkey],
+    max_liab_transfer: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new(*liqor_mango_account_pk, false),
+        AccountMeta::new_readonly(*liqor_pk, true),
+        AccountMeta::new_readonly(*asset_root_bank_pk, false),
+        AccountMeta::new(*asset_node_bank_pk, false),
+        AccountMeta::new_readonly(*liab_root_bank_pk, false),
+        AccountMeta::new(*liab_node_bank_pk, false),
+    ];
+
+    accounts.extend(liqee_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    accounts.extend(liqor_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::LiquidateTokenAndToken { max_liab_transfer };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn liquidate_token_and_perp(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    liqor_mango_account_pk: &Pubkey,
+    liqor_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+    liqor_open_orders_pks: &[Pubkey],
+    asset_type: AssetType,
+    asset_index: usize,
+    liab_type: AssetType,
+    liab_index: usize,
+    max_liab_transfer: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new(*liqor_mango_account_pk, false),
+        AccountMeta::new_readonly(*liqor_pk, true),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+
+    accounts.extend(liqee_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    accounts.extend(liqor_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::LiquidateTokenAndPerp {
+        asset_type,
+        asset_index,
+        liab_type,
+        liab_index,
+        max_liab_transfer,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+/// ForceSettleQuotePositions is deprecated, but liquidators still call it until it's removed
+pub fn force_settle_quote_positions(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    liqor_mango_account_pk: &Pubkey,
+    liqor_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new(*liqor_mango_account_pk, false),
+        AccountMeta::new_readonly(*liqor_pk, true),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+
+    accounts.extend(liqee_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::ForceSettleQuotePositions;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn liquidate_perp_market(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    liqor_mango_account_pk: &Pubkey,
+    liqor_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+    liqor_open_orders_pks: &[Pubkey],
+    base_transfer_request: i64,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new(*liqor_mango_account_pk, false),
+        AccountMeta::new_readonly(*liqor_pk, true),
+    ];
 
-            37 => {
-                let data_arr = array_ref![data, 0, 137];
-                let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    mngo_per_period,
-                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 17, 9, 9];
+    accounts.extend(liqee_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    accounts.extend(liqor_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
 
-                MangoInstruction::ChangePerpMarketParams {
-                    maint_leverage: unpack_i80f48_opt(maint_leverage),
-                    init_leverage: unpack_i80f48_opt(init_leverage),
-                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
-                    maker_fee: unpack_i80f48_opt(maker_fee),
-                    taker_fee: unpack_i80f48_opt(taker_fee),
-                    rate: unpack_i80f48_opt(rate),
-                    max_depth_bps: unpack_i80f48_opt(max_depth_bps),
-                    target_period_length: unpack_u64_opt(target_period_length),
-                    mngo_per_period: unpack_u64_opt(mngo_per_period),
-                }
-            }
+    let instr = MangoInstruction::LiquidatePerpMarket { base_transfer_request };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
 
-            38 => MangoInstruction::SetGroupAdmin,
+pub fn resolve_perp_bankruptcy(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    liqor_mango_account_pk: &Pubkey,
+    liqor_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    dao_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    liqor_open_orders_pks: &[Pubkey],
+    liab_index: usize,
+    max_liab_transfer: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new(*liqor_mango_account_pk, false),
+        AccountMeta::new_readonly(*liqor_pk, true),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*vault_pk, false),
+        AccountMeta::new(*dao_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
 
-            39 => {
-                let data_arr = array_ref![data, 0, 1];
-                MangoInstruction::CancelAllPerpOrders { limit: u8::from_le_bytes(*data_arr) }
-            }
+    accounts.extend(liqor_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::ResolvePerpBankruptcy { liab_index, max_liab_transfer };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn resolve_token_bankruptcy(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    liqor_mango_account_pk: &Pubkey,
+    liqor_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    dao_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    liab_root_bank_pk: &Pubkey,
+    liab_node_bank_pk: &Pubkey,
+    liqor_open_orders_pks: &[Pubkey],
+    liab_node_bank_pks: &[Pubkey],
+    max_liab_transfer: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new(*liqor_mango_account_pk, false),
+        AccountMeta::new_readonly(*liqor_pk, true),
+        AccountMeta::new_readonly(*quote_root_bank_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*quote_vault_pk, false),
+        AccountMeta::new(*dao_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new(*liab_root_bank_pk, false),
+        AccountMeta::new(*liab_node_bank_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+
+    accounts.extend(liqor_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    accounts.extend(liab_node_bank_pks.iter().map(|pk| AccountMeta::new(*pk, false)));
+
+    let instr = MangoInstruction::ResolveTokenBankruptcy { max_liab_transfer };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn transfer_collateral(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    src_mango_account_pk: &Pubkey,
+    dst_mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    src_open_orders_pks: &[Pubkey],
+
+    token_index: usize,
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*src_mango_account_pk, false),
+        AccountMeta::new(*dst_mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+
+    accounts.extend(src_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::TransferCollateral { token_index, quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn place_perp_order2(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
+    side: Side,
+    price: i64,
+    quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
+    safe_mode: bool,
+    tag: [u8; 8],
+    match_limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::PlacePerpOrder2 {
+        side,
+        price,
+        quantity,
+        client_order_id,
+        order_type,
+        safe_mode,
+        tag,
+        match_limit,
+    };
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn execute_rebalance_leg(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
+    leg_pks: &[(Pubkey, Pubkey, Pubkey, Pubkey)], // (perp_market, bids, asks, event_queue)
+    legs: Vec<PerpRebalanceLeg>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+    ];
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    for (perp_market_pk, bids_pk, asks_pk, event_queue_pk) in leg_pks.iter() {
+        accounts.push(AccountMeta::new(*perp_market_pk, false));
+        accounts.push(AccountMeta::new(*bids_pk, false));
+        accounts.push(AccountMeta::new(*asks_pk, false));
+        accounts.push(AccountMeta::new(*event_queue_pk, false));
+    }
+
+    let instr = MangoInstruction::ExecuteRebalanceLeg { legs };
+    let data = instr.pack();
+
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_liquidation_target_health(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    liquidation_target_health: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts =
+        vec![AccountMeta::new(*mango_group_pk, false), AccountMeta::new_readonly(*admin_pk, true)];
+
+    let instr = MangoInstruction::SetLiquidationTargetHealth { liquidation_target_health };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn change_perp_market_params(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    maint_leverage: Option<I80F48>,
+    init_leverage: Option<I80F48>,
+    liquidation_fee: Option<I80F48>,
+    maker_fee: Option<I80F48>,
+    taker_fee: Option<I80F48>,
+    rate: Option<I80F48>,
+    max_depth_bps: Option<I80F48>,
+    target_period_length: Option<u64>,
+    mngo_per_period: Option<u64>,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::ChangePerpMarketParams {
+        maint_leverage,
+        init_leverage,
+        liquidation_fee,
+        maker_fee,
+        taker_fee,
+        rate,
+        max_depth_bps,
+        target_period_length,
+        mngo_per_period,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_group_admin(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    new_admin_pk: &Pubkey,
+    admin_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new_readonly(*new_admin_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::SetGroupAdmin;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_perp_market_fees(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    maker_fee: I80F48,
+    taker_fee: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::SetPerpMarketFees { maker_fee, taker_fee };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn get_account_authority(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+    ];
+
+    let instr = MangoInstruction::GetAccountAuthority;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
 
-            40 => MangoInstruction::ForceSettleQuotePositions,
+pub fn add_to_deposit_whitelist(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    deposit_whitelist_pk: &Pubkey,
+    owner: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*admin_pk, true),
+        AccountMeta::new(*deposit_whitelist_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
+    ];
 
-            _ => {
-                return None;
-            }
-        })
-    }
-    pub fn pack(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
-    }
+    let instr = MangoInstruction::AddToDepositWhitelist { owner: *owner };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-fn unpack_i80f48_opt(data: &[u8; 17]) -> Option<I80F48> {
-    let (opt, val) = array_refs![data, 1, 16];
-    if opt[0] == 0 {
-        None
-    } else {
-        Some(I80F48::from_le_bytes(*val))
-    }
-}
-fn unpack_u64_opt(data: &[u8; 9]) -> Option<u64> {
-    let (opt, val) = array_refs![data, 1, 8];
-    if opt[0] == 0 {
-        None
-    } else {
-        Some(u64::from_le_bytes(*val))
-    }
-}
+pub fn remove_from_deposit_whitelist(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    deposit_whitelist_pk: &Pubkey,
+    owner: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new(*deposit_whitelist_pk, false),
+    ];
 
-fn unpack_dex_new_order_v3(
-    data: &[u8; 46],
-) -> Option<serum_dex::instruction::NewOrderInstructionV3> {
-    let (
-        &side_arr,
-        &price_arr,
-        &max_coin_qty_arr,
-        &max_native_pc_qty_arr,
-        &self_trade_behavior_arr,
-        &otype_arr,
-        &client_order_id_bytes,
-        &limit_arr,
-    ) = array_refs![data, 4, 8, 8, 8, 4, 4, 8, 2];
+    let instr = MangoInstruction::RemoveFromDepositWhitelist { owner: *owner };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
 
-    let side = serum_dex::matching::Side::try_from_primitive(
-        u32::from_le_bytes(side_arr).try_into().ok()?,
-    )
-    .ok()?;
-    let limit_price = NonZeroU64::new(u64::from_le_bytes(price_arr))?;
-    let max_coin_qty = NonZeroU64::new(u64::from_le_bytes(max_coin_qty_arr))?;
-    let max_native_pc_qty_including_fees =
-        NonZeroU64::new(u64::from_le_bytes(max_native_pc_qty_arr))?;
-    let self_trade_behavior = serum_dex::instruction::SelfTradeBehavior::try_from_primitive(
-        u32::from_le_bytes(self_trade_behavior_arr).try_into().ok()?,
-    )
-    .ok()?;
-    let order_type = serum_dex::matching::OrderType::try_from_primitive(
-        u32::from_le_bytes(otype_arr).try_into().ok()?,
-    )
-    .ok()?;
-    let client_order_id = u64::from_le_bytes(client_order_id_bytes);
-    let limit = u16::from_le_bytes(limit_arr);
+pub fn set_deposit_whitelist_enabled(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    enabled: bool,
+) -> Result<Instruction, ProgramError> {
+    let accounts =
+        vec![AccountMeta::new(*mango_group_pk, false), AccountMeta::new_readonly(*admin_pk, true)];
 
-    Some(serum_dex::instruction::NewOrderInstructionV3 {
-        side,
-        limit_price,
-        max_coin_qty,
-        max_native_pc_qty_including_fees,
-        self_trade_behavior,
-        order_type,
-        client_order_id,
-        limit,
-    })
+    let instr = MangoInstruction::SetDepositWhitelistEnabled { enabled };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn init_mango_group(
+pub fn init_loss_recovery(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
-    signer_pk: &Pubkey,
     admin_pk: &Pubkey,
-    quote_mint_pk: &Pubkey,
-    quote_vault_pk: &Pubkey,
-    quote_node_bank_pk: &Pubkey,
-    quote_root_bank_pk: &Pubkey,
-    insurance_vault_pk: &Pubkey,
-    msrm_vault_pk: &Pubkey, // send in Pubkey:default() if not using this feature
-    fees_vault_pk: &Pubkey,
-    mango_cache_ai: &Pubkey,
-    dex_program_pk: &Pubkey,
-
-    signer_nonce: u64,
-    valid_interval: u64,
-    quote_optimal_util: I80F48,
-    quote_optimal_rate: I80F48,
-    quote_max_rate: I80F48,
+    root_bank_pk: &Pubkey,
+    loss_recovery_pk: &Pubkey,
+    recovery_vault_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
-        AccountMeta::new(*mango_group_pk, false),
-        AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
-        AccountMeta::new_readonly(*quote_mint_pk, false),
-        AccountMeta::new_readonly(*quote_vault_pk, false),
-        AccountMeta::new(*quote_node_bank_pk, false),
-        AccountMeta::new(*quote_root_bank_pk, false),
-        AccountMeta::new_readonly(*insurance_vault_pk, false),
-        AccountMeta::new_readonly(*msrm_vault_pk, false),
-        AccountMeta::new_readonly(*fees_vault_pk, false),
-        AccountMeta::new(*mango_cache_ai, false),
-        AccountMeta::new_readonly(*dex_program_pk, false),
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*admin_pk, true),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*loss_recovery_pk, false),
+        AccountMeta::new_readonly(*recovery_vault_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
     ];
 
-    let instr = MangoInstruction::InitMangoGroup {
-        signer_nonce,
-        valid_interval,
-        quote_optimal_util,
-        quote_optimal_rate,
-        quote_max_rate,
-    };
-
+    let instr = MangoInstruction::InitLossRecovery;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn init_mango_account(
+pub fn register_loss_share(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_account_pk: &Pubkey,
     owner_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    loss_recovery_pk: &Pubkey,
+    loss_share_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*loss_recovery_pk, false),
+        AccountMeta::new(*loss_share_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
     ];
 
-    let instr = MangoInstruction::InitMangoAccount;
+    let instr = MangoInstruction::RegisterLossShare;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn deposit(
+pub fn claim_loss_recovery(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_account_pk: &Pubkey,
@@ -1018,9 +4380,10 @@ pub fn deposit(
     root_bank_pk: &Pubkey,
     node_bank_pk: &Pubkey,
     vault_pk: &Pubkey,
-    owner_token_account_pk: &Pubkey,
-
-    quantity: u64,
+    loss_recovery_pk: &Pubkey,
+    loss_share_pk: &Pubkey,
+    recovery_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
@@ -1031,109 +4394,162 @@ pub fn deposit(
         AccountMeta::new(*node_bank_pk, false),
         AccountMeta::new(*vault_pk, false),
         AccountMeta::new_readonly(spl_token::ID, false),
-        AccountMeta::new(*owner_token_account_pk, false),
+        AccountMeta::new(*loss_recovery_pk, false),
+        AccountMeta::new(*loss_share_pk, false),
+        AccountMeta::new(*recovery_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
     ];
 
-    let instr = MangoInstruction::Deposit { quantity };
+    let instr = MangoInstruction::ClaimLossRecovery;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn add_spot_market(
+pub fn set_post_trade_risk_log_enabled(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    dex_program_pk: &Pubkey,
-    token_mint_pk: &Pubkey,
-    node_bank_pk: &Pubkey,
-    vault_pk: &Pubkey,
-    root_bank_pk: &Pubkey,
     admin_pk: &Pubkey,
+    enabled: bool,
+) -> Result<Instruction, ProgramError> {
+    let accounts =
+        vec![AccountMeta::new(*mango_group_pk, false), AccountMeta::new_readonly(*admin_pk, true)];
 
-    maint_leverage: I80F48,
-    init_leverage: I80F48,
-    liquidation_fee: I80F48,
-    optimal_util: I80F48,
-    optimal_rate: I80F48,
-    max_rate: I80F48,
+    let instr = MangoInstruction::SetPostTradeRiskLogEnabled { enabled };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_max_oracle_age(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    max_oracle_age: u32,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new(*mango_group_pk, false),
         AccountMeta::new_readonly(*oracle_pk, false),
-        AccountMeta::new_readonly(*spot_market_pk, false),
-        AccountMeta::new_readonly(*dex_program_pk, false),
-        AccountMeta::new_readonly(*token_mint_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
-        AccountMeta::new_readonly(*vault_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::SetMaxOracleAge { max_oracle_age };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_max_index_growth(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    max_index_growth: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
         AccountMeta::new(*root_bank_pk, false),
         AccountMeta::new_readonly(*admin_pk, true),
     ];
 
-    let instr = MangoInstruction::AddSpotMarket {
-        maint_leverage,
-        init_leverage,
-        liquidation_fee,
-        optimal_util,
-        optimal_rate,
-        max_rate,
-    };
+    let instr = MangoInstruction::SetMaxIndexGrowth { max_index_growth };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_delegate(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    delegate_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*delegate_pk, false),
+    ];
+
+    let instr = MangoInstruction::SetDelegate;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn change_mango_account_owner(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    new_owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*new_owner_pk, false),
+    ];
+
+    let instr = MangoInstruction::ChangeMangoAccountOwner;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn upgrade_mango_account(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+    ];
+
+    let instr = MangoInstruction::UpgradeMangoAccount;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_referrer(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    referrer_mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*referrer_mango_account_pk, false),
+    ];
+
+    let instr =
+        MangoInstruction::SetReferrerMemory { referrer_id: referrer_mango_account_pk.to_bytes() };
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn add_perp_market(
+pub fn get_perp_spread(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
     perp_market_pk: &Pubkey,
-    event_queue_pk: &Pubkey,
     bids_pk: &Pubkey,
     asks_pk: &Pubkey,
-    mngo_vault_pk: &Pubkey,
-    admin_pk: &Pubkey,
-
-    maint_leverage: I80F48,
-    init_leverage: I80F48,
-    liquidation_fee: I80F48,
-    maker_fee: I80F48,
-    taker_fee: I80F48,
-    base_lot_size: i64,
-    quote_lot_size: i64,
-    rate: I80F48,
-    max_depth_bps: I80F48,
-    target_period_length: u64,
-    mngo_per_period: u64,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
-        AccountMeta::new(*mango_group_pk, false),
-        AccountMeta::new(*oracle_pk, false),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*event_queue_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
-        AccountMeta::new_readonly(*mngo_vault_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new_readonly(*bids_pk, false),
+        AccountMeta::new_readonly(*asks_pk, false),
     ];
 
-    let instr = MangoInstruction::AddPerpMarket {
-        maint_leverage,
-        init_leverage,
-        liquidation_fee,
-        maker_fee,
-        taker_fee,
-        base_lot_size,
-        quote_lot_size,
-        rate,
-        max_depth_bps,
-        target_period_length,
-        mngo_per_period,
-    };
+    let instr = MangoInstruction::GetPerpSpread;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn place_perp_order(
+pub fn place_perp_order3(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_account_pk: &Pubkey,
@@ -1149,6 +4565,9 @@ pub fn place_perp_order(
     quantity: i64,
     client_order_id: u64,
     order_type: OrderType,
+    safe_mode: bool,
+    tag: [u8; 8],
+    expiry_timestamp: u64,
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
@@ -1162,176 +4581,231 @@ pub fn place_perp_order(
     ];
     accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
 
-    let instr =
-        MangoInstruction::PlacePerpOrder { side, price, quantity, client_order_id, order_type };
+    let instr = MangoInstruction::PlacePerpOrder3 {
+        side,
+        price,
+        quantity,
+        client_order_id,
+        order_type,
+        safe_mode,
+        tag,
+        expiry_timestamp,
+    };
     let data = instr.pack();
 
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn cancel_perp_order_by_client_id(
+/// `banks` pairs each root bank with its node banks, in the root bank's order, and their vaults
+pub fn check_group_invariants(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey,   // read
-    mango_account_pk: &Pubkey, // write
-    owner_pk: &Pubkey,         // read, signer
-    perp_market_pk: &Pubkey,   // write
-    bids_pk: &Pubkey,          // write
-    asks_pk: &Pubkey,          // write
-    client_order_id: u64,
-    invalid_id_ok: bool,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    banks: &[(Pubkey, Vec<(Pubkey, Pubkey)>)],
+    perp_market_pks: &[Pubkey],
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
     ];
-    let instr = MangoInstruction::CancelPerpOrderByClientId { client_order_id, invalid_id_ok };
+    for (root_bank_pk, node_banks) in banks.iter() {
+        accounts.push(AccountMeta::new_readonly(*root_bank_pk, false));
+        for (node_bank_pk, vault_pk) in node_banks.iter() {
+            accounts.push(AccountMeta::new_readonly(*node_bank_pk, false));
+            accounts.push(AccountMeta::new_readonly(*vault_pk, false));
+        }
+    }
+    accounts.extend(perp_market_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::CheckGroupInvariants;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn cancel_perp_order(
+pub fn set_perp_mngo_vault(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey,   // read
-    mango_account_pk: &Pubkey, // write
-    owner_pk: &Pubkey,         // read, signer
-    perp_market_pk: &Pubkey,   // write
-    bids_pk: &Pubkey,          // write
-    asks_pk: &Pubkey,          // write
-    order_id: i128,
-    invalid_id_ok: bool,
+    mango_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    old_mngo_vault_pk: &Pubkey,
+    new_mngo_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    admin_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
         AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*old_mngo_vault_pk, false),
+        AccountMeta::new(*new_mngo_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new_readonly(spl_token::ID, false),
     ];
-    let instr = MangoInstruction::CancelPerpOrder { order_id, invalid_id_ok };
+
+    let instr = MangoInstruction::SetPerpMngoVault;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn cancel_all_perp_orders(
+pub fn get_mining_rate(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey,   // read
-    mango_account_pk: &Pubkey, // write
-    owner_pk: &Pubkey,         // read, signer
-    perp_market_pk: &Pubkey,   // write
-    bids_pk: &Pubkey,          // write
-    asks_pk: &Pubkey,          // write
-    limit: u8,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    market_index: usize,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new_readonly(*bids_pk, false),
+        AccountMeta::new_readonly(*asks_pk, false),
     ];
-    let instr = MangoInstruction::CancelAllPerpOrders { limit };
+
+    let instr = MangoInstruction::GetMiningRate { market_index };
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn force_cancel_perp_orders(
+pub fn get_insurance_coverage(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey,         // read
-    mango_cache_pk: &Pubkey,         // read
-    perp_market_pk: &Pubkey,         // read
-    bids_pk: &Pubkey,                // write
-    asks_pk: &Pubkey,                // write
-    liqee_mango_account_pk: &Pubkey, // write
-    open_orders_pks: &[Pubkey],      // read
-    limit: u8,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    insurance_vault_pk: &Pubkey,
+    perp_market_pks: &[Pubkey],
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
         AccountMeta::new_readonly(*mango_cache_pk, false),
-        AccountMeta::new_readonly(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
-        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new_readonly(*insurance_vault_pk, false),
     ];
-    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
-    let instr = MangoInstruction::ForceCancelPerpOrders { limit };
+    accounts.extend(perp_market_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::GetInsuranceCoverage;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn consume_events(
+pub fn get_open_interest(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey,      // read
-    mango_cache_pk: &Pubkey,      // read
-    perp_market_pk: &Pubkey,      // read
-    event_queue_pk: &Pubkey,      // write
-    mango_acc_pks: &mut [Pubkey], // write
-    limit: usize,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pks: &[Pubkey],
 ) -> Result<Instruction, ProgramError> {
-    let fixed_accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
         AccountMeta::new_readonly(*mango_cache_pk, false),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*event_queue_pk, false),
     ];
-    mango_acc_pks.sort();
-    let mango_accounts = mango_acc_pks.into_iter().map(|pk| AccountMeta::new(*pk, false));
-    let accounts = fixed_accounts.into_iter().chain(mango_accounts).collect();
-    let instr = MangoInstruction::ConsumeEvents { limit };
+    accounts.extend(perp_market_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::GetOpenInterest;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn settle_pnl(
+pub fn get_realized_pnl(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey,     // read
-    mango_account_a_pk: &Pubkey, // write
-    mango_account_b_pk: &Pubkey, // write
-    mango_cache_pk: &Pubkey,     // read
-    root_bank_pk: &Pubkey,       // read
-    node_bank_pk: &Pubkey,       // write
-    market_index: usize,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    pnl_tracker_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_a_pk, false),
-        AccountMeta::new(*mango_account_b_pk, false),
-        AccountMeta::new_readonly(*mango_cache_pk, false),
-        AccountMeta::new_readonly(*root_bank_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new_readonly(*pnl_tracker_pk, false),
     ];
-    let instr = MangoInstruction::SettlePnl { market_index };
+
+    let instr = MangoInstruction::GetRealizedPnl;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn update_funding(
+pub fn get_position_history_anchor(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey, // read
-    mango_cache_pk: &Pubkey, // read
-    perp_market_pk: &Pubkey, // write
-    bids_pk: &Pubkey,        // read
-    asks_pk: &Pubkey,        // read
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
         AccountMeta::new_readonly(*mango_cache_pk, false),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new_readonly(*bids_pk, false),
-        AccountMeta::new_readonly(*asks_pk, false),
     ];
-    let instr = MangoInstruction::UpdateFunding {};
+
+    let instr = MangoInstruction::GetPositionHistoryAnchor;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn withdraw(
+pub fn set_max_withdraw_per_tx(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    withdraw_caps_pk: &Pubkey,
+    token_index: usize,
+    max_withdraw: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new(*withdraw_caps_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
+    ];
+
+    let instr = MangoInstruction::SetMaxWithdrawPerTx { token_index, max_withdraw };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_min_mngo_to_trade(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    min_mngo_to_trade: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts =
+        vec![AccountMeta::new(*mango_group_pk, false), AccountMeta::new_readonly(*admin_pk, true)];
+
+    let instr = MangoInstruction::SetMinMngoToTrade { min_mngo_to_trade };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn rebalance_node_banks(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    src_node_bank_pk: &Pubkey,
+    src_vault_pk: &Pubkey,
+    dst_node_bank_pk: &Pubkey,
+    dst_vault_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*src_node_bank_pk, false),
+        AccountMeta::new(*src_vault_pk, false),
+        AccountMeta::new(*dst_node_bank_pk, false),
+        AccountMeta::new(*dst_vault_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+
+    let instr = MangoInstruction::RebalanceNodeBanks { quantity };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn preview_withdraw(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_account_pk: &Pubkey,
@@ -1348,197 +4822,280 @@ pub fn withdraw(
     allow_borrow: bool,
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
-        AccountMeta::new(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, false),
         AccountMeta::new_readonly(*mango_cache_pk, false),
         AccountMeta::new_readonly(*root_bank_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
-        AccountMeta::new(*vault_pk, false),
-        AccountMeta::new(*token_account_pk, false),
+        AccountMeta::new_readonly(*node_bank_pk, false),
+        AccountMeta::new_readonly(*vault_pk, false),
+        AccountMeta::new_readonly(*token_account_pk, false),
         AccountMeta::new_readonly(*signer_pk, false),
         AccountMeta::new_readonly(spl_token::ID, false),
     ];
 
-    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::PreviewWithdraw { quantity, allow_borrow };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_withdraw_fee(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    withdraw_fee_bps: u16,
+) -> Result<Instruction, ProgramError> {
+    let accounts =
+        vec![AccountMeta::new(*mango_group_pk, false), AccountMeta::new_readonly(*admin_pk, true)];
+
+    let instr = MangoInstruction::SetWithdrawFee { withdraw_fee_bps };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
 
-    let instr = MangoInstruction::Withdraw { quantity, allow_borrow };
+/// Fails with InvalidParam if `name` is longer than GROUP_NAME_LEN bytes
+pub fn set_group_name(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    name: &str,
+) -> Result<Instruction, ProgramError> {
+    if name.len() > GROUP_NAME_LEN {
+        return Err(ProgramError::Custom(MangoErrorCode::InvalidParam.into()));
+    }
+    let mut name_bytes = [0u8; GROUP_NAME_LEN];
+    name_bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+    let (group_name_pk, _) = GroupName::find_address(program_id, mango_group_pk);
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*admin_pk, true),
+        AccountMeta::new(group_name_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
+    ];
+
+    let instr = MangoInstruction::SetGroupName { name: name_bytes };
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn borrow(
+/// Build every instruction a new user needs, in order, to end up with an initialized
+/// MangoAccount, open orders accounts for `spot_markets` and a first deposit. The MangoAccount
+/// and each open orders account are created and funded by `owner_pk`, so both the owner and
+/// the keypairs of the new accounts must sign the transaction
+///
+/// `spot_markets` pairs each new open orders account with the spot market it is for
+pub fn new_user_instructions(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_account_pk: &Pubkey,
-    mango_cache_pk: &Pubkey,
     owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    spot_markets: &[(Pubkey, Pubkey)],
     root_bank_pk: &Pubkey,
     node_bank_pk: &Pubkey,
-    open_orders_pks: &[Pubkey],
+    vault_pk: &Pubkey,
+    owner_token_account_pk: &Pubkey,
+    rent: &solana_program::rent::Rent,
 
     quantity: u64,
+) -> Result<Vec<Instruction>, ProgramError> {
+    let mut instructions = vec![];
+
+    // The MangoAccount must exist and be owned by the program before InitMangoAccount
+    let mango_account_len = std::mem::size_of::<MangoAccount>();
+    instructions.push(solana_program::system_instruction::create_account(
+        owner_pk,
+        mango_account_pk,
+        rent.minimum_balance(mango_account_len),
+        mango_account_len as u64,
+        program_id,
+    ));
+    instructions.push(init_mango_account(program_id, mango_group_pk, mango_account_pk, owner_pk)?);
+
+    // serum dex accounts carry 12 bytes of padding around the OpenOrders struct
+    let open_orders_len = std::mem::size_of::<serum_dex::state::OpenOrders>() + 12;
+    for (open_orders_pk, spot_market_pk) in spot_markets.iter() {
+        instructions.push(solana_program::system_instruction::create_account(
+            owner_pk,
+            open_orders_pk,
+            rent.minimum_balance(open_orders_len),
+            open_orders_len as u64,
+            dex_prog_pk,
+        ));
+        instructions.push(init_spot_open_orders(
+            program_id,
+            mango_group_pk,
+            mango_account_pk,
+            owner_pk,
+            dex_prog_pk,
+            open_orders_pk,
+            spot_market_pk,
+            signer_pk,
+        )?);
+    }
+
+    instructions.push(deposit(
+        program_id,
+        mango_group_pk,
+        mango_account_pk,
+        owner_pk,
+        mango_cache_pk,
+        root_bank_pk,
+        node_bank_pk,
+        vault_pk,
+        owner_token_account_pk,
+        quantity,
+    )?);
+
+    Ok(instructions)
+}
+
+pub fn cancel_perp_orders_older_than(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,   // read
+    mango_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    cutoff_ts: u64,
+    limit: u8,
 ) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
-        AccountMeta::new(*mango_group_pk, false),
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
         AccountMeta::new(*mango_account_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*mango_cache_pk, false),
-        AccountMeta::new_readonly(*root_bank_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
     ];
-
-    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new(*pk, false)));
-
-    let instr = MangoInstruction::Borrow { quantity };
+    let instr = MangoInstruction::CancelPerpOrdersOlderThan { cutoff_ts, limit };
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn cache_prices(
+pub fn cancel_perp_orders_side(
     program_id: &Pubkey,
-    mango_group_pk: &Pubkey,
-    mango_cache_pk: &Pubkey,
-    oracle_pks: &[Pubkey],
+    mango_group_pk: &Pubkey,   // read
+    mango_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    side: Side,
+    limit: u8,
 ) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
+    let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_cache_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
     ];
-    accounts.extend(oracle_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
-    let instr = MangoInstruction::CachePrices;
+    let instr = MangoInstruction::CancelPerpOrdersSide { side, limit };
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn cache_root_banks(
+pub fn set_perp_impact_depth(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
-    mango_cache_pk: &Pubkey,
-    root_bank_pks: &[Pubkey],
+    perp_market_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    quote_notional: i64,
 ) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
+    let (perp_market_config_pk, _) = PerpMarketConfig::find_address(program_id, perp_market_pk);
+    let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new(*admin_pk, true),
+        AccountMeta::new(perp_market_config_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
     ];
-    accounts.extend(root_bank_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
-    let instr = MangoInstruction::CacheRootBanks;
+
+    let instr = MangoInstruction::SetPerpImpactDepth { quote_notional };
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn cache_perp_markets(
+/// `root_banks` pairs each root bank with all of its node banks
+pub fn update_root_banks(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_cache_pk: &Pubkey,
-    perp_market_pks: &[Pubkey],
+    root_banks: &[(Pubkey, Vec<Pubkey>)],
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
         AccountMeta::new(*mango_cache_pk, false),
     ];
-    accounts.extend(perp_market_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
-    let instr = MangoInstruction::CachePerpMarkets;
+
+    for (root_bank_pk, node_bank_pks) in root_banks.iter() {
+        accounts.push(AccountMeta::new(*root_bank_pk, false));
+        accounts.extend(node_bank_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    }
+
+    let instr = MangoInstruction::UpdateRootBanks;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn init_spot_open_orders(
+pub fn has_unsettled_pnl(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
-    dex_prog_pk: &Pubkey,
-    open_orders_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    signer_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*dex_prog_pk, false),
-        AccountMeta::new(*open_orders_pk, false),
-        AccountMeta::new_readonly(*spot_market_pk, false),
-        AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
     ];
 
-    let instr = MangoInstruction::InitSpotOpenOrders;
+    let instr = MangoInstruction::HasUnsettledPnl;
     let data = instr.pack();
-
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn place_spot_order(
+pub fn cancel_spot_order_by_client_id(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
-    mango_account_pk: &Pubkey,
     owner_pk: &Pubkey,
-    mango_cache_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
     dex_prog_pk: &Pubkey,
     spot_market_pk: &Pubkey,
     bids_pk: &Pubkey,
     asks_pk: &Pubkey,
-    dex_request_queue_pk: &Pubkey,
-    dex_event_queue_pk: &Pubkey,
-    dex_base_pk: &Pubkey,
-    dex_quote_pk: &Pubkey,
-    base_root_bank_pk: &Pubkey,
-    base_node_bank_pk: &Pubkey,
-    base_vault_pk: &Pubkey,
-    quote_root_bank_pk: &Pubkey,
-    quote_node_bank_pk: &Pubkey,
-    quote_vault_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
     signer_pk: &Pubkey,
-    dex_signer_pk: &Pubkey,
-    msrm_or_srm_vault_pk: &Pubkey,
-    open_orders_pks: &[Pubkey],
-
-    market_index: usize, // used to determine which of the open orders accounts should be passed in write
-    order: serum_dex::instruction::NewOrderInstructionV3,
+    dex_event_queue_pk: &Pubkey,
+    client_order_id: u64,
 ) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
+    let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_account_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
         AccountMeta::new_readonly(*dex_prog_pk, false),
         AccountMeta::new(*spot_market_pk, false),
         AccountMeta::new(*bids_pk, false),
         AccountMeta::new(*asks_pk, false),
-        AccountMeta::new(*dex_request_queue_pk, false),
-        AccountMeta::new(*dex_event_queue_pk, false),
-        AccountMeta::new(*dex_base_pk, false),
-        AccountMeta::new(*dex_quote_pk, false),
-        AccountMeta::new_readonly(*base_root_bank_pk, false),
-        AccountMeta::new(*base_node_bank_pk, false),
-        AccountMeta::new(*base_vault_pk, false),
-        AccountMeta::new_readonly(*quote_root_bank_pk, false),
-        AccountMeta::new(*quote_node_bank_pk, false),
-        AccountMeta::new(*quote_vault_pk, false),
-        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new(*open_orders_pk, false),
         AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
-        AccountMeta::new_readonly(*dex_signer_pk, false),
-        AccountMeta::new_readonly(*msrm_or_srm_vault_pk, false),
+        AccountMeta::new(*dex_event_queue_pk, false),
     ];
 
-    accounts.extend(open_orders_pks.iter().enumerate().map(|(i, pk)| {
-        if i == market_index {
-            AccountMeta::new(*pk, false)
-        } else {
-            AccountMeta::new_readonly(*pk, false)
-        }
-    }));
-
-    let instr = MangoInstruction::PlaceSpotOrder { order };
+    let instr = MangoInstruction::CancelSpotOrderByClientId { client_order_id };
     let data = instr.pack();
-
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn settle_funds(
+pub fn cancel_spot_order_and_settle(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_cache_pk: &Pubkey,
@@ -1546,8 +5103,11 @@ pub fn settle_funds(
     mango_account_pk: &Pubkey,
     dex_prog_pk: &Pubkey,
     spot_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
     open_orders_pk: &Pubkey,
     signer_pk: &Pubkey,
+    dex_event_queue_pk: &Pubkey,
     dex_base_pk: &Pubkey,
     dex_quote_pk: &Pubkey,
     base_root_bank_pk: &Pubkey,
@@ -1557,6 +5117,7 @@ pub fn settle_funds(
     base_vault_pk: &Pubkey,
     quote_vault_pk: &Pubkey,
     dex_signer_pk: &Pubkey,
+    order: serum_dex::instruction::CancelOrderInstructionV2,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
@@ -1565,8 +5126,11 @@ pub fn settle_funds(
         AccountMeta::new(*mango_account_pk, false),
         AccountMeta::new_readonly(*dex_prog_pk, false),
         AccountMeta::new(*spot_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
         AccountMeta::new(*open_orders_pk, false),
         AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new(*dex_event_queue_pk, false),
         AccountMeta::new(*dex_base_pk, false),
         AccountMeta::new(*dex_quote_pk, false),
         AccountMeta::new_readonly(*base_root_bank_pk, false),
@@ -1579,98 +5143,146 @@ pub fn settle_funds(
         AccountMeta::new_readonly(spl_token::ID, false),
     ];
 
-    let instr = MangoInstruction::SettleFunds;
+    let instr = MangoInstruction::CancelSpotOrderAndSettle { order };
     let data = instr.pack();
-
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
-pub fn add_oracle(
-    program_id: &Pubkey,
-    mango_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
-    admin_pk: &Pubkey,
-) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
-        AccountMeta::new(*mango_group_pk, false),
-        AccountMeta::new(*oracle_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
-    ];
+/// A perp market whose orders prepare_account_close should cancel
+pub struct PerpMarketCloseAccounts {
+    pub perp_market_pk: Pubkey,
+    pub bids_pk: Pubkey,
+    pub asks_pk: Pubkey,
+}
 
-    let instr = MangoInstruction::AddOracle;
-    let data = instr.pack();
-    Ok(Instruction { program_id: *program_id, accounts, data })
+/// A spot market whose open orders account prepare_account_close should empty and close, with
+/// the orders resting in it
+pub struct SpotMarketCloseAccounts {
+    pub spot_market_pk: Pubkey,
+    pub bids_pk: Pubkey,
+    pub asks_pk: Pubkey,
+    pub open_orders_pk: Pubkey,
+    pub dex_event_queue_pk: Pubkey,
+    pub dex_base_pk: Pubkey,
+    pub dex_quote_pk: Pubkey,
+    pub dex_signer_pk: Pubkey,
+    pub base_root_bank_pk: Pubkey,
+    pub base_node_bank_pk: Pubkey,
+    pub base_vault_pk: Pubkey,
+    pub orders: Vec<serum_dex::instruction::CancelOrderInstructionV2>,
 }
 
-pub fn update_root_bank(
+/// The instructions that wind a MangoAccount down so that CloseMangoAccount can follow, in the
+/// order they must run: CancelAllPerpOrders on each perp market, then for each spot market a
+/// CancelSpotOrderAndSettle per order, a SettleFunds for funds freed by earlier fills, and
+/// CloseSpotOpenOrders. There are more than fit in one transaction, so send them in order in as
+/// many transactions as needed
+pub fn prepare_account_close(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
     mango_cache_pk: &Pubkey,
-    root_bank_pk: &Pubkey,
-    node_bank_pks: &[Pubkey],
-) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
-        AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*mango_cache_pk, false),
-        AccountMeta::new(*root_bank_pk, false),
-    ];
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    perp_markets: &[PerpMarketCloseAccounts],
+    spot_markets: &[SpotMarketCloseAccounts],
+) -> Result<Vec<Instruction>, ProgramError> {
+    let mut instructions = vec![];
+    for perp_market in perp_markets.iter() {
+        instructions.push(cancel_all_perp_orders(
+            program_id,
+            mango_group_pk,
+            mango_account_pk,
+            owner_pk,
+            &perp_market.perp_market_pk,
+            &perp_market.bids_pk,
+            &perp_market.asks_pk,
+            MAX_PERP_OPEN_ORDERS as u8,
+        )?);
+    }
 
-    accounts.extend(node_bank_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    for spot_market in spot_markets.iter() {
+        for order in spot_market.orders.iter() {
+            instructions.push(cancel_spot_order_and_settle(
+                program_id,
+                mango_group_pk,
+                mango_cache_pk,
+                owner_pk,
+                mango_account_pk,
+                dex_prog_pk,
+                &spot_market.spot_market_pk,
+                &spot_market.bids_pk,
+                &spot_market.asks_pk,
+                &spot_market.open_orders_pk,
+                signer_pk,
+                &spot_market.dex_event_queue_pk,
+                &spot_market.dex_base_pk,
+                &spot_market.dex_quote_pk,
+                &spot_market.base_root_bank_pk,
+                &spot_market.base_node_bank_pk,
+                quote_root_bank_pk,
+                quote_node_bank_pk,
+                &spot_market.base_vault_pk,
+                quote_vault_pk,
+                &spot_market.dex_signer_pk,
+                order.clone(),
+            )?);
+        }
+        instructions.push(settle_funds(
+            program_id,
+            mango_group_pk,
+            mango_cache_pk,
+            owner_pk,
+            mango_account_pk,
+            dex_prog_pk,
+            &spot_market.spot_market_pk,
+            &spot_market.open_orders_pk,
+            signer_pk,
+            &spot_market.dex_base_pk,
+            &spot_market.dex_quote_pk,
+            &spot_market.base_root_bank_pk,
+            &spot_market.base_node_bank_pk,
+            quote_root_bank_pk,
+            quote_node_bank_pk,
+            &spot_market.base_vault_pk,
+            quote_vault_pk,
+            &spot_market.dex_signer_pk,
+        )?);
+        instructions.push(close_spot_open_orders(
+            program_id,
+            mango_group_pk,
+            mango_account_pk,
+            owner_pk,
+            dex_prog_pk,
+            &spot_market.open_orders_pk,
+            &spot_market.spot_market_pk,
+            signer_pk,
+        )?);
+    }
 
-    let instr = MangoInstruction::UpdateRootBank;
-    let data = instr.pack();
-    Ok(Instruction { program_id: *program_id, accounts, data })
+    Ok(instructions)
 }
 
-pub fn set_oracle(
+pub fn init_pnl_tracker(
     program_id: &Pubkey,
     mango_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
-    admin_pk: &Pubkey,
-    price: I80F48,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    pnl_tracker_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new(*oracle_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
-    ];
-
-    let instr = MangoInstruction::SetOracle { price };
-    let data = instr.pack();
-    Ok(Instruction { program_id: *program_id, accounts, data })
-}
-
-pub fn liquidate_token_and_token(
-    program_id: &Pubkey,
-    mango_group_pk: &Pubkey,
-    mango_cache_pk: &Pubkey,
-    liqee_mango_account_pk: &Pubkey,
-    liqor_mango_account_pk: &Pubkey,
-    liqor_pk: &Pubkey,
-    asset_root_bank_pk: &Pubkey,
-    asset_node_bank_pk: &Pubkey,
-    liab_root_bank_pk: &Pubkey,
-    liab_node_bank_pk: &Pubkey,
-    liqee_open_orders_pks: &[Pubkey],
-    liqor_open_orders_pks: &[Pubkey],
-    max_liab_transfer: I80F48,
-) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
-        AccountMeta::new_readonly(*mango_group_pk, false),
-        AccountMeta::new_readonly(*mango_cache_pk, false),
-        AccountMeta::new(*liqee_mango_account_pk, false),
-        AccountMeta::new(*liqor_mango_account_pk, false),
-        AccountMeta::new_readonly(*liqor_pk, true),
-        AccountMeta::new_readonly(*asset_root_bank_pk, false),
-        AccountMeta::new(*asset_node_bank_pk, false),
-        AccountMeta::new_readonly(*liab_root_bank_pk, false),
-        AccountMeta::new(*liab_node_bank_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new(*pnl_tracker_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
     ];
 
-    accounts.extend(liqee_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
-    accounts.extend(liqor_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
-
-    let instr = MangoInstruction::LiquidateTokenAndToken { max_liab_transfer };
+    let instr = MangoInstruction::InitPnlTracker;
     let data = instr.pack();
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
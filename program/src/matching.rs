@@ -1,9 +1,10 @@
 use crate::error::{check_assert, MangoError, MangoErrorCode, MangoResult, SourceFileId};
-use crate::queue::{EventQueue, FillEvent, OutEvent};
+use crate::queue::{maker_tag_details, EventQueue, FillEvent, OutEvent};
 use crate::state::{
-    DataType, MangoAccount, MetaData, PerpMarket, PerpMarketInfo, MAX_PERP_OPEN_ORDERS,
+    DataType, MangoAccount, MetaData, PerpMarket, PerpMarketInfo, MAX_PERP_OPEN_ORDERS, ZERO_I80F48,
 };
 use bytemuck::{cast, cast_mut, cast_ref};
+use fixed::types::I80F48;
 use mango_common::Loadable;
 use mango_macro::{Loadable, Pod};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
@@ -24,6 +25,16 @@ pub type NodeHandle = u32;
 
 const NODE_SIZE: usize = 88;
 
+/// Top bit of the upper 64 bits of an order key, set for orders pegged to the oracle. The rest of
+/// the upper 64 bits hold the biased peg offset instead of the price, so pegged orders sort by
+/// offset in their own half of the tree
+const PEGGED_FLAG: u64 = 1 << 63;
+const PEG_OFFSET_BIAS: i64 = 1 << 62;
+/// Largest peg offset, in either direction, that fits in an order key
+pub const MAX_PEG_OFFSET: i64 = PEG_OFFSET_BIAS - 1;
+/// Maker orders an order may cross when the instruction placing it doesn't set a match limit
+pub const DEFAULT_MATCH_LIMIT: u8 = 20;
+
 #[derive(IntoPrimitive, TryFromPrimitive)]
 #[repr(u32)]
 pub enum NodeTag {
@@ -32,6 +43,7 @@ pub enum NodeTag {
     LeafNode = 2,
     FreeNode = 3,
     LastFreeNode = 4,
+    LeafExtNode = 5,
 }
 
 #[derive(Copy, Clone, Pod)]
@@ -66,7 +78,10 @@ impl InnerNode {
 pub struct LeafNode {
     pub tag: u32,
     pub owner_slot: u8,
-    pub padding: [u8; 3],
+    /// Handle plus one of the LeafExtNode holding the order's LeafExt, little endian; 0 if the
+    /// order has none. Kept in what was padding so the node size doesn't change
+    pub ext_handle: [u8; 2],
+    pub padding: [u8; 1],
     pub key: i128,
     pub owner: Pubkey,
     pub quantity: i64,
@@ -81,10 +96,50 @@ pub struct LeafNode {
 }
 
 impl LeafNode {
+    /// Price in quote lots per base lot. Meaningless for pegged orders; see `effective_price`
     pub fn price(&self) -> i64 {
         (self.key >> 64) as i64
     }
 
+    /// Sequence number the order got when placed; lower means earlier
+    pub fn seq_num(&self, side: Side) -> u64 {
+        match side {
+            Side::Bid => !(self.key as u64),
+            Side::Ask => self.key as u64,
+        }
+    }
+
+    pub fn is_pegged(&self) -> bool {
+        self.key < 0
+    }
+
+    /// Offset from the oracle price in quote lots per base lot. Only meaningful for pegged orders
+    pub fn peg_offset(&self) -> i64 {
+        (((self.key >> 64) as u64 & !PEGGED_FLAG) as i64) - PEG_OFFSET_BIAS
+    }
+
+    /// The price this order trades at when the oracle is at `oracle_price_lots`. None for a
+    /// pegged order that is past `peg_limit`, which is taken from the order's LeafExt
+    pub fn effective_price(
+        &self,
+        side: Side,
+        oracle_price_lots: i64,
+        peg_limit: i64,
+    ) -> Option<i64> {
+        if self.is_pegged() {
+            pegged_price(side, oracle_price_lots, self.peg_offset(), peg_limit)
+        } else {
+            Some(self.price())
+        }
+    }
+
+    fn get_ext_handle(&self) -> Option<NodeHandle> {
+        match u16::from_le_bytes(self.ext_handle) {
+            0 => None,
+            h => Some(h as NodeHandle - 1),
+        }
+    }
+
     pub fn new(
         owner_slot: u8,
         key: i128,
@@ -97,7 +152,8 @@ impl LeafNode {
         Self {
             tag: NodeTag::LeafNode.into(),
             owner_slot,
-            padding: [0; 3],
+            ext_handle: [0; 2],
+            padding: [0; 1],
             key,
             owner,
             quantity,
@@ -108,6 +164,72 @@ impl LeafNode {
     }
 }
 
+/// Fields of an order that don't fit in its LeafNode. All zeros for a plain fixed price order,
+/// which then takes no extra node on the book
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LeafExt {
+    /// Only used by pegged orders: bids don't trade above and asks don't trade below this price
+    pub peg_limit: i64,
+    /// Opaque tag from the order's placer, logged with its fills; zeros if none was given
+    pub order_tag: [u8; 8],
+    /// Unix timestamp at which the order stops being valid and gets removed instead of filled;
+    /// 0 if it never expires
+    pub expiry_timestamp: u64,
+}
+
+impl LeafExt {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn is_expired(&self, now_ts: u64) -> bool {
+        self.expiry_timestamp != 0 && now_ts >= self.expiry_timestamp
+    }
+}
+
+/// Slab node holding the LeafExt of one LeafNode. It is not part of the tree; the leaf points
+/// at it through `ext_handle` and it is freed together with the leaf
+#[derive(Copy, Clone, Pod)]
+#[repr(C)]
+struct LeafExtNode {
+    tag: u32,
+    padding: [u8; 4],
+    peg_limit: i64,
+    order_tag: [u8; 8],
+    expiry_timestamp: u64,
+    reserved: [u8; NODE_SIZE - 32],
+}
+
+/// Price in quote lots per base lot of an order pegged `peg_offset` from the oracle price. None
+/// if that is not positive or is past `peg_limit`, in which case the order doesn't trade
+pub fn pegged_price(
+    side: Side,
+    oracle_price_lots: i64,
+    peg_offset: i64,
+    peg_limit: i64,
+) -> Option<i64> {
+    let price = oracle_price_lots.checked_add(peg_offset)?;
+    let within_limit = match side {
+        Side::Bid => price <= peg_limit,
+        Side::Ask => price >= peg_limit,
+    };
+    if price > 0 && within_limit {
+        Some(price)
+    } else {
+        None
+    }
+}
+
+/// Key of a pegged order. Like fixed price orders, bids with the same offset sort by descending
+/// and asks by ascending sequence number
+pub fn pegged_order_key(side: Side, peg_offset: i64, seq_num: u64) -> i128 {
+    let upper = ((PEGGED_FLAG | (peg_offset + PEG_OFFSET_BIAS) as u64) as i128) << 64;
+    match side {
+        Side::Bid => upper | (!seq_num as i128),
+        Side::Ask => upper | (seq_num as i128),
+    }
+}
+
 #[derive(Copy, Clone, Pod)]
 #[repr(C)]
 struct FreeNode {
@@ -126,6 +248,7 @@ pub struct AnyNode {
 const_assert_eq!(size_of::<AnyNode>(), size_of::<InnerNode>());
 const_assert_eq!(size_of::<AnyNode>(), size_of::<LeafNode>());
 const_assert_eq!(size_of::<AnyNode>(), size_of::<FreeNode>());
+const_assert_eq!(size_of::<AnyNode>(), size_of::<LeafExtNode>());
 
 enum NodeRef<'a> {
     Inner(&'a InnerNode),
@@ -202,16 +325,30 @@ impl AsRef<AnyNode> for LeafNode {
     Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug, Serialize, Deserialize,
 )]
 #[repr(u8)]
+#[serde(into = "u8", try_from = "u8")] // packed as a single byte to match MangoInstruction::unpack
 pub enum OrderType {
     Limit = 0,
     ImmediateOrCancel = 1,
     PostOnly = 2,
+    /// Like PostOnly, but a fixed price order that would cross the book is repriced to one tick
+    /// inside the best opposing order instead of being dropped
+    PostOnlySlide = 3,
+}
+
+/// Price of a new order on the book
+#[derive(Copy, Clone)]
+enum OrderPrice {
+    /// Price in quote lots per base lot
+    Fixed(i64),
+    /// Offset from the oracle price in quote lots per base lot, bounded by peg_limit
+    Pegged { offset: i64, peg_limit: i64 },
 }
 
 #[derive(
     Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug, Serialize, Deserialize,
 )]
 #[repr(u8)]
+#[serde(into = "u8", try_from = "u8")] // packed as a single byte to match MangoInstruction::unpack
 pub enum Side {
     Bid = 0,
     Ask = 1,
@@ -296,11 +433,37 @@ impl BookSide {
             Some(self.root_node)
         }
     }
+    /// Roots of the subtrees of fixed price orders and of pegged orders. Pegged keys have the
+    /// top bit set, so when the side holds both kinds the root splits on that bit
+    fn fixed_and_pegged_roots(&self) -> (Option<NodeHandle>, Option<NodeHandle>) {
+        let root = match self.root() {
+            None => return (None, None),
+            Some(root) => root,
+        };
+        match self.get(root).unwrap().case().unwrap() {
+            NodeRef::Inner(inner) if inner.prefix_len == 0 => {
+                (Some(inner.children[0]), Some(inner.children[1]))
+            }
+            NodeRef::Inner(InnerNode { key, .. }) | NodeRef::Leaf(LeafNode { key, .. }) => {
+                if *key < 0 {
+                    (None, Some(root))
+                } else {
+                    (Some(root), None)
+                }
+            }
+        }
+    }
+    fn fixed_root(&self) -> Option<NodeHandle> {
+        self.fixed_and_pegged_roots().0
+    }
+    /// Min and max only look at fixed price orders, since pricing pegged orders needs an oracle
+    /// price. Eviction and the incentives of cancels use them; find_best and walk_orders
+    /// include pegged orders
     pub fn find_min(&self) -> Option<NodeHandle> {
         self.find_min_max(false)
     }
     fn find_min_max(&self, find_max: bool) -> Option<NodeHandle> {
-        let mut root: NodeHandle = self.root()?;
+        let mut root: NodeHandle = self.fixed_root()?;
 
         let i = if find_max { 1 } else { 0 };
         loop {
@@ -322,7 +485,7 @@ impl BookSide {
         self.get_min_max(true)
     }
     fn get_min_max(&self, find_max: bool) -> Option<&LeafNode> {
-        let mut root: NodeHandle = self.root()?;
+        let mut root: NodeHandle = self.fixed_root()?;
 
         let i = if find_max { 1 } else { 0 };
         loop {
@@ -338,6 +501,75 @@ impl BookSide {
         }
     }
 
+    /// Handle and price of the order a taker on the other side matches first, out of the best
+    /// fixed price order and the best pegged order that is within its peg limit. On equal prices
+    /// the earlier order goes first, as between two fixed price orders
+    pub fn find_best(&self, side: Side, oracle_price_lots: i64) -> Option<(NodeHandle, i64)> {
+        let (fixed_root, pegged_root) = self.fixed_and_pegged_roots();
+        let fixed = fixed_root.map(|_| {
+            let handle = self.find_min_max(side == Side::Bid).unwrap();
+            (handle, self.get(handle).unwrap().as_leaf().unwrap().price())
+        });
+        let pegged =
+            pegged_root.and_then(|root| self.find_best_pegged(root, side, oracle_price_lots));
+        match (fixed, pegged) {
+            (Some(fixed), Some(pegged)) => {
+                let seq_num = |handle| self.get(handle).unwrap().as_leaf().unwrap().seq_num(side);
+                let pegged_is_better = match side {
+                    Side::Bid => pegged.1 > fixed.1,
+                    Side::Ask => pegged.1 < fixed.1,
+                } || (pegged.1 == fixed.1
+                    && seq_num(pegged.0) < seq_num(fixed.0));
+                Some(if pegged_is_better { pegged } else { fixed })
+            }
+            (fixed, pegged) => fixed.or(pegged),
+        }
+    }
+
+    /// Walk the pegged orders under `root` from the best offset outwards and return the first one
+    /// that is within its peg limit, with its price
+    fn find_best_pegged(
+        &self,
+        root: NodeHandle,
+        side: Side,
+        oracle_price_lots: i64,
+    ) -> Option<(NodeHandle, i64)> {
+        let (best_child, other_child) = match side {
+            Side::Bid => (1, 0),
+            Side::Ask => (0, 1),
+        };
+        let mut stack = vec![root];
+        while let Some(handle) = stack.pop() {
+            match self.get(handle)?.case()? {
+                NodeRef::Inner(inner) => {
+                    stack.push(inner.children[other_child]);
+                    stack.push(inner.children[best_child]);
+                }
+                NodeRef::Leaf(leaf) => {
+                    let peg_limit = self.leaf_ext(leaf).peg_limit;
+                    if let Some(price) = leaf.effective_price(side, oracle_price_lots, peg_limit) {
+                        return Some((handle, price));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn find_by_key(&self, search_key: i128) -> Option<&LeafNode> {
+        let mut node_h = self.root()?;
+        loop {
+            match self.get(node_h)?.case()? {
+                NodeRef::Inner(inner) => {
+                    node_h = inner.walk_down(search_key).0;
+                }
+                NodeRef::Leaf(leaf) => {
+                    return if leaf.key == search_key { Some(leaf) } else { None };
+                }
+            }
+        }
+    }
+
     fn remove_by_key(&mut self, search_key: i128) -> Option<LeafNode> {
         let mut parent_h = self.root()?;
         let mut child_h;
@@ -348,7 +580,7 @@ impl BookSide {
                 self.root_node = 0;
                 self.leaf_count = 0;
                 let _old_root = self.remove(parent_h).unwrap();
-                return Some(leaf);
+                return Some(self.free_leaf_ext(leaf));
             }
             NodeRef::Leaf(_) => return None,
             NodeRef::Inner(inner) => {
@@ -381,12 +613,18 @@ impl BookSide {
         let other_child_node_contents = self.remove(other_child_h).unwrap();
         *self.get_mut(parent_h).unwrap() = other_child_node_contents;
         self.leaf_count -= 1;
-        Some(cast(self.remove(child_h).unwrap()))
+        let leaf: LeafNode = cast(self.remove(child_h).unwrap());
+        Some(self.free_leaf_ext(leaf))
     }
 
     fn remove(&mut self, key: u32) -> Option<AnyNode> {
         let val = *self.get(key)?;
+        self.free(key);
+        Some(val)
+    }
 
+    /// Put the node at `key` on the free list, whatever it holds
+    fn free(&mut self, key: u32) {
         self.nodes[key as usize] = cast(FreeNode {
             tag: if self.free_list_len == 0 {
                 NodeTag::LastFreeNode.into()
@@ -394,17 +632,40 @@ impl BookSide {
                 NodeTag::FreeNode.into()
             },
             next: self.free_list_head,
-            padding: [0; 80],
+            padding: [0; NODE_SIZE - 8],
         });
 
         self.free_list_len += 1;
         self.free_list_head = key;
-        Some(val)
+    }
+
+    /// The LeafExt of `leaf`; all zeros if it has none
+    pub fn leaf_ext(&self, leaf: &LeafNode) -> LeafExt {
+        match leaf.get_ext_handle() {
+            None => LeafExt::default(),
+            Some(ext_handle) => {
+                let ext_node: &LeafExtNode = cast_ref(&self.nodes[ext_handle as usize]);
+                LeafExt {
+                    peg_limit: ext_node.peg_limit,
+                    order_tag: ext_node.order_tag,
+                    expiry_timestamp: ext_node.expiry_timestamp,
+                }
+            }
+        }
+    }
+
+    /// Free the LeafExtNode of a leaf that was taken out of the tree, and unlink it
+    fn free_leaf_ext(&mut self, mut leaf: LeafNode) -> LeafNode {
+        if let Some(ext_handle) = leaf.get_ext_handle() {
+            self.free(ext_handle);
+            leaf.ext_handle = [0; 2];
+        }
+        leaf
     }
 
     fn insert(&mut self, val: &AnyNode) -> MangoResult<u32> {
         match NodeTag::try_from(val.tag) {
-            Ok(NodeTag::InnerNode) | Ok(NodeTag::LeafNode) => (),
+            Ok(NodeTag::InnerNode) | Ok(NodeTag::LeafNode) | Ok(NodeTag::LeafExtNode) => (),
             _ => unreachable!(),
         };
 
@@ -463,7 +724,7 @@ impl BookSide {
                 if let Some(NodeRef::Leaf(&old_root_as_leaf)) = root_contents.case() {
                     // clobber the existing leaf
                     *self.get_mut(root).unwrap() = *new_leaf.as_ref();
-                    return Ok((root, Some(old_root_as_leaf)));
+                    return Ok((root, Some(self.free_leaf_ext(old_root_as_leaf))));
                 }
             }
             let shared_prefix_len: u32 = (root_key ^ new_leaf.key).leading_zeros();
@@ -504,10 +765,83 @@ impl BookSide {
         }
     }
 
+    /// Insert an order along with its LeafExt, which takes a node of its own unless it is empty
+    pub fn insert_leaf_with_ext(
+        &mut self,
+        new_leaf: &LeafNode,
+        ext: &LeafExt,
+    ) -> MangoResult<NodeHandle> {
+        if ext.is_empty() {
+            return Ok(self.insert_leaf(new_leaf)?.0);
+        }
+
+        let ext_node: AnyNode = cast(LeafExtNode {
+            tag: NodeTag::LeafExtNode.into(),
+            padding: [0; 4],
+            peg_limit: ext.peg_limit,
+            order_tag: ext.order_tag,
+            expiry_timestamp: ext.expiry_timestamp,
+            reserved: [0; NODE_SIZE - 32],
+        });
+        let ext_handle = self.insert(&ext_node)?;
+        let mut new_leaf = *new_leaf;
+        new_leaf.ext_handle = ((ext_handle + 1) as u16).to_le_bytes();
+        match self.insert_leaf(&new_leaf) {
+            Ok((handle, _)) => Ok(handle),
+            Err(e) => {
+                self.free(ext_handle);
+                Err(e)
+            }
+        }
+    }
+
     pub fn is_full(&self) -> bool {
         self.free_list_len == 0 && self.bump_index == self.nodes.len()
     }
 
+    /// Nodes left for new orders
+    pub fn free_node_count(&self) -> usize {
+        self.free_list_len + self.nodes.len() - self.bump_index
+    }
+
+    /// Remove the worst fixed price orders until an order at `limit_price` with `ext` fits, and
+    /// return them. Every removed order must be worse than `limit_price`, so a pegged order past
+    /// its peg limit can't evict anything. Pegged orders are never evicted since their price
+    /// moves with the oracle; a side full of them fails with OutOfSpace
+    pub fn evict_for(
+        &mut self,
+        limit_price: Option<i64>,
+        ext: &LeafExt,
+    ) -> MangoResult<Vec<LeafNode>> {
+        let is_bids = match DataType::try_from(self.meta_data.data_type) {
+            Ok(DataType::Bids) => true,
+            Ok(DataType::Asks) => false,
+            _ => return Err(throw!()),
+        };
+
+        let mut evicted = vec![];
+        loop {
+            // A leaf, the inner node above it unless the side is empty, and its LeafExtNode
+            let needed = if self.leaf_count == 0 { 1 } else { 2 } + !ext.is_empty() as usize;
+            if self.free_node_count() >= needed {
+                return Ok(evicted);
+            }
+
+            let worst_h =
+                self.find_min_max(!is_bids).ok_or(throw_err!(MangoErrorCode::OutOfSpace))?;
+            let worst = *self.get(worst_h).unwrap().as_leaf().unwrap();
+            check!(
+                limit_price.map_or(false, |p| if is_bids {
+                    p > worst.price()
+                } else {
+                    p < worst.price()
+                }),
+                MangoErrorCode::OutOfSpace
+            )?;
+            evicted.push(self.remove_by_key(worst.key).unwrap());
+        }
+    }
+
     #[allow(dead_code)]
     fn to_vec(&self, root: NodeHandle, v: &mut Vec<NodeHandle>) {
         match self.get(root).unwrap().case().unwrap() {
@@ -522,6 +856,216 @@ impl BookSide {
     }
 }
 
+/// Side whose orders `book_side` holds, or None if it is not a bids or asks side
+fn book_side_side(book_side: &BookSide) -> Option<Side> {
+    match DataType::try_from(book_side.meta_data.data_type) {
+        Ok(DataType::Bids) => Some(Side::Bid),
+        Ok(DataType::Asks) => Some(Side::Ask),
+        _ => None,
+    }
+}
+
+/// Visit the fixed price orders of `book_side` from the best price outwards, until `visit`
+/// returns true. Returns None if `book_side` is not a bids or asks side.
+///
+/// Pegged orders are left out: their price needs an oracle price, and the spread, mining and
+/// fill estimates built on this describe the book without one. Expired orders are visited until
+/// a taker or a cancel removes them
+fn walk_fixed_orders(book_side: &BookSide, mut visit: impl FnMut(&LeafNode) -> bool) -> Option<()> {
+    let side = book_side_side(book_side)?;
+    for leaf in LeafWalk::new(book_side, book_side.fixed_root(), side) {
+        if visit(leaf) {
+            break;
+        }
+    }
+    Some(())
+}
+
+/// Leaves of the subtree of `book_side` under `root`, from the best price for `side` outwards.
+/// Ends early if the tree is malformed
+struct LeafWalk<'a> {
+    book_side: &'a BookSide,
+    stack: Vec<NodeHandle>,
+    best_child: usize,
+}
+
+impl<'a> LeafWalk<'a> {
+    fn new(book_side: &'a BookSide, root: Option<NodeHandle>, side: Side) -> Self {
+        // Bids fill from the highest price down, asks from the lowest price up
+        let best_child = match side {
+            Side::Bid => 1,
+            Side::Ask => 0,
+        };
+        Self { book_side, stack: root.into_iter().collect(), best_child }
+    }
+}
+
+impl<'a> Iterator for LeafWalk<'a> {
+    type Item = &'a LeafNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(handle) = self.stack.pop() {
+            match self.book_side.get(handle)?.case()? {
+                NodeRef::Inner(inner) => {
+                    self.stack.push(inner.children[1 - self.best_child]);
+                    self.stack.push(inner.children[self.best_child]);
+                }
+                NodeRef::Leaf(leaf) => return Some(leaf),
+            }
+        }
+        None
+    }
+}
+
+/// Visit the fixed price and pegged orders of `book_side` from the best price outwards, with
+/// their price when the oracle is at `oracle_price_lots`, until `visit` returns true. Like
+/// find_best, pegged orders past their peg limit are skipped and on equal prices the earlier
+/// order comes first. Returns None if `book_side` is not a bids or asks side
+fn walk_orders(
+    book_side: &BookSide,
+    oracle_price_lots: i64,
+    mut visit: impl FnMut(&LeafNode, i64) -> bool,
+) -> Option<()> {
+    let side = book_side_side(book_side)?;
+    let (fixed_root, pegged_root) = book_side.fixed_and_pegged_roots();
+    let mut fixed =
+        LeafWalk::new(book_side, fixed_root, side).map(|leaf| (leaf, leaf.price())).peekable();
+    // Pegged orders sort by offset, so their prices come out in order too
+    let mut pegged = LeafWalk::new(book_side, pegged_root, side)
+        .filter_map(|leaf| {
+            let peg_limit = book_side.leaf_ext(leaf).peg_limit;
+            Some((leaf, leaf.effective_price(side, oracle_price_lots, peg_limit)?))
+        })
+        .peekable();
+    loop {
+        let pegged_is_better = match (fixed.peek(), pegged.peek()) {
+            (None, None) => break,
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (Some((fixed_leaf, fixed_price)), Some((pegged_leaf, pegged_price))) => {
+                let is_better = match side {
+                    Side::Bid => pegged_price > fixed_price,
+                    Side::Ask => pegged_price < fixed_price,
+                };
+                is_better
+                    || (pegged_price == fixed_price
+                        && pegged_leaf.seq_num(side) < fixed_leaf.seq_num(side))
+            }
+        };
+        let (leaf, price) = if pegged_is_better { pegged.next() } else { fixed.next() }?;
+        if visit(leaf, price) {
+            break;
+        }
+    }
+    Some(())
+}
+
+/// Average price, in quote lots per base lot, paid to fill `impact_notional` quote lots against
+/// `book_side` when the oracle is at `oracle_price_lots`, walking it from the best order
+/// outwards. Pegged orders count like fixed price ones, see walk_orders. A non-positive
+/// `impact_notional` gives the best price. Returns None if the side is empty or too thin to fill
+/// the impact notional
+pub fn impact_price(
+    book_side: &BookSide,
+    impact_notional: i64,
+    oracle_price_lots: i64,
+) -> Option<I80F48> {
+    let impact_notional = I80F48::from_num(impact_notional);
+    let mut remaining = impact_notional;
+    let mut base_filled = ZERO_I80F48;
+    let mut impact_price = None;
+    walk_orders(book_side, oracle_price_lots, |leaf, price| {
+        let price = I80F48::from_num(price);
+        if !impact_notional.is_positive() {
+            impact_price = Some(price);
+            return true;
+        }
+
+        let notional = match price.checked_mul(I80F48::from_num(leaf.quantity)) {
+            Some(notional) => notional,
+            None => return true,
+        };
+        if notional >= remaining {
+            base_filled += remaining / price;
+            impact_price = Some(impact_notional / base_filled);
+            return true;
+        }
+        base_filled += I80F48::from_num(leaf.quantity);
+        remaining -= notional;
+        false
+    })?;
+    impact_price
+}
+
+/// Simulate a market buy spending at most `quote_amount` quote lots against the fixed price
+/// orders of `book_asks`. Like the matching engine, only whole base lots are bought. Returns the
+/// average price in quote lots per base lot and the base lots bought, or None if `book_asks`
+/// isn't an asks side or the budget can't buy a single base lot
+pub fn perp_fill_for_quote(book_asks: &BookSide, quote_amount: i64) -> Option<(I80F48, i64)> {
+    if book_asks.meta_data.data_type != DataType::Asks as u8 {
+        return None;
+    }
+
+    let mut remaining = quote_amount;
+    let mut base_filled: i64 = 0;
+    walk_fixed_orders(book_asks, |leaf| {
+        let price = leaf.price();
+        let base = leaf.quantity.min(remaining / price);
+        base_filled += base;
+        remaining -= base * price;
+        // A partly taken order means the budget can't reach the next, pricier, order
+        base < leaf.quantity
+    })?;
+
+    if base_filled <= 0 {
+        return None;
+    }
+    let quote_spent = I80F48::from_num(quote_amount - remaining);
+    Some((quote_spent / I80F48::from_num(base_filled), base_filled))
+}
+
+/// Bid-ask spread of the fixed price orders in basis points of the mid price, or None if either
+/// side is empty
+pub fn perp_spread_bps(bids: &BookSide, asks: &BookSide) -> Option<I80F48> {
+    let best_bid = I80F48::from_num(bids.get_max()?.price());
+    let best_ask = I80F48::from_num(asks.get_min()?.price());
+    let mid = (best_bid + best_ask) / 2;
+    Some((best_ask - best_bid) / mid * I80F48::from_num(10_000))
+}
+
+/// Liquidity mining points per second earned by the fixed price orders of `book_side` if they
+/// stayed where they are, as (points of `owner`'s orders, points of all orders). Like
+/// apply_incentives, an order earns the square of how far inside `max_depth_bps` of the best
+/// price it is, per base lot. Returns None if `book_side` is not a bids or asks side
+pub fn mining_points_rate(
+    book_side: &BookSide,
+    max_depth_bps: I80F48,
+    owner: &Pubkey,
+) -> Option<(I80F48, I80F48)> {
+    let mut best_price = None;
+    let mut owner_points = ZERO_I80F48;
+    let mut total_points = ZERO_I80F48;
+    walk_fixed_orders(book_side, |leaf| {
+        let price = leaf.price();
+        let best = *best_price.get_or_insert(price);
+        let dist_bps = I80F48::from_num((best - price).abs() * 10_000) / I80F48::from_num(best);
+        let dist_factor = max_depth_bps - dist_bps;
+        // Orders are visited from the best price outwards, so the rest are deeper still
+        if !dist_factor.is_positive() {
+            return true;
+        }
+
+        let points =
+            dist_factor.saturating_mul(dist_factor).saturating_mul(I80F48::from_num(leaf.quantity));
+        total_points = total_points.saturating_add(points);
+        if &leaf.owner == owner {
+            owner_points = owner_points.saturating_add(points);
+        }
+        false
+    })?;
+    Some((owner_points, total_points))
+}
+
 pub struct Book<'a> {
     bids: RefMut<'a, BookSide>,
     asks: RefMut<'a, BookSide>,
@@ -540,18 +1084,22 @@ impl<'a> Book<'a> {
         })
     }
 
-    fn get_best_bid_handle(&self) -> Option<NodeHandle> {
-        self.bids.find_max()
+    pub fn bids(&self) -> &BookSide {
+        &self.bids
     }
 
-    pub fn get_best_bid_price(&self) -> Option<i64> {
-        Some(self.bids.get_max()?.price())
+    pub fn asks(&self) -> &BookSide {
+        &self.asks
     }
 
-    fn get_best_ask_handle(&self) -> Option<NodeHandle> {
-        self.asks.find_min()
+    /// Best fixed price bid. Pegged bids are left out since pricing them needs an oracle price,
+    /// which cancels don't have; with one, use BookSide::find_best
+    pub fn get_best_bid_price(&self) -> Option<i64> {
+        Some(self.bids.get_max()?.price())
     }
 
+    /// Best fixed price ask. Pegged asks are left out since pricing them needs an oracle price,
+    /// which cancels don't have; with one, use BookSide::find_best
     pub fn get_best_ask_price(&self) -> Option<i64> {
         Some(self.asks.get_min()?.price())
     }
@@ -570,8 +1118,74 @@ impl<'a> Book<'a> {
         quantity: i64, // quantity is guaranteed to be greater than zero due to initial check --
         order_type: OrderType,
         client_order_id: u64,
+        order_tag: [u8; 8],
+        expiry_timestamp: u64,
+        match_limit: u8,
         now_ts: u64,
+        oracle_price: I80F48,
     ) -> MangoResult<()> {
+        match side {
+            Side::Bid => self.new_bid(
+                event_queue,
+                market,
+                info,
+                mango_account,
+                mango_account_pk,
+                market_index,
+                OrderPrice::Fixed(price),
+                quantity,
+                order_type,
+                client_order_id,
+                order_tag,
+                expiry_timestamp,
+                match_limit,
+                now_ts,
+                oracle_price,
+            ),
+            Side::Ask => self.new_ask(
+                event_queue,
+                market,
+                info,
+                mango_account,
+                mango_account_pk,
+                market_index,
+                OrderPrice::Fixed(price),
+                quantity,
+                order_type,
+                client_order_id,
+                order_tag,
+                expiry_timestamp,
+                match_limit,
+                now_ts,
+                oracle_price,
+            ),
+        }
+    }
+
+    /// Place an order whose price is `peg_offset` from the oracle price at the time it matches.
+    /// Bids never trade above and asks never trade below `peg_limit`
+    #[inline(never)]
+    pub fn new_pegged_order(
+        &mut self,
+        event_queue: &mut EventQueue,
+        market: &mut PerpMarket,
+        info: &PerpMarketInfo,
+        mango_account: &mut MangoAccount,
+        mango_account_pk: &Pubkey,
+        market_index: usize,
+        side: Side,
+        peg_offset: i64,
+        peg_limit: i64,
+        quantity: i64, // quantity is guaranteed to be greater than zero due to initial check --
+        order_type: OrderType,
+        client_order_id: u64,
+        order_tag: [u8; 8],
+        expiry_timestamp: u64,
+        match_limit: u8,
+        now_ts: u64,
+        oracle_price: I80F48,
+    ) -> MangoResult<()> {
+        let price = OrderPrice::Pegged { offset: peg_offset, peg_limit };
         match side {
             Side::Bid => self.new_bid(
                 event_queue,
@@ -584,7 +1198,11 @@ impl<'a> Book<'a> {
                 quantity,
                 order_type,
                 client_order_id,
+                order_tag,
+                expiry_timestamp,
+                match_limit,
                 now_ts,
+                oracle_price,
             ),
             Side::Ask => self.new_ask(
                 event_queue,
@@ -597,7 +1215,11 @@ impl<'a> Book<'a> {
                 quantity,
                 order_type,
                 client_order_id,
+                order_tag,
+                expiry_timestamp,
+                match_limit,
                 now_ts,
+                oracle_price,
             ),
         }
     }
@@ -611,39 +1233,98 @@ impl<'a> Book<'a> {
         mango_account: &mut MangoAccount,
         mango_account_pk: &Pubkey,
         market_index: usize,
-        price: i64,
+        price: OrderPrice,
         quantity: i64, // quantity is guaranteed to be greater than zero due to initial check --
         order_type: OrderType,
         client_order_id: u64,
+        order_tag: [u8; 8],
+        expiry_timestamp: u64,
+        mut match_limit: u8,
         now_ts: u64,
+        oracle_price: I80F48,
     ) -> MangoResult<()> {
         // TODO proper error handling
         // TODO handle the case where we run out of compute (right now just fails)
-        let (post_only, post_allowed) = match order_type {
+        let (post_only, mut post_allowed) = match order_type {
             OrderType::Limit => (false, true),
             OrderType::ImmediateOrCancel => (false, false),
-            OrderType::PostOnly => (true, true),
+            OrderType::PostOnly | OrderType::PostOnlySlide => (true, true),
+        };
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
+        // A post-only-slide bid that would cross the book moves to one tick below the best ask
+        let price = match (order_type, price) {
+            (OrderType::PostOnlySlide, OrderPrice::Fixed(price)) => {
+                match self.asks.find_best(Side::Ask, oracle_price_lots) {
+                    Some((_, best_ask_price)) if price >= best_ask_price => {
+                        if best_ask_price <= 1 {
+                            msg!("Order could not be placed due to PostOnlySlide");
+                            return Ok(());
+                        }
+                        OrderPrice::Fixed(best_ask_price - 1)
+                    }
+                    _ => OrderPrice::Fixed(price),
+                }
+            }
+            (_, price) => price,
+        };
+        // A pegged order past its peg limit has no price; it doesn't match but may still rest
+        let (order_id, limit_price, peg_limit) = match price {
+            OrderPrice::Fixed(price) => (market.gen_order_id(Side::Bid, price), Some(price), 0),
+            OrderPrice::Pegged { offset, peg_limit } => (
+                market.gen_pegged_order_id(Side::Bid, offset),
+                pegged_price(Side::Bid, oracle_price_lots, offset, peg_limit),
+                peg_limit,
+            ),
         };
-        let order_id = market.gen_order_id(Side::Bid, price);
 
-        let best_initial = match self.get_best_bid_price() {
-            None => price,
-            Some(p) => p,
+        let best_initial = match self.bids.find_best(Side::Bid, oracle_price_lots) {
+            None => limit_price.unwrap_or(oracle_price_lots),
+            Some((_, p)) => p,
         };
 
         // if post only and price >= best_ask, return
         // Iterate through book and match against this new bid
         let mut rem_quantity = quantity; // base lots (aka contracts)
         while rem_quantity > 0 {
-            let best_ask_h = match self.get_best_ask_handle() {
+            let limit_price = match limit_price {
                 None => break,
-                Some(h) => h,
+                Some(p) => p,
             };
-
+            let (best_ask_h, best_ask_price) =
+                match self.asks.find_best(Side::Ask, oracle_price_lots) {
+                    None => break,
+                    Some(best) => best,
+                };
+
+            let best_ask_ext =
+                self.asks.leaf_ext(self.asks.get(best_ask_h).unwrap().as_leaf().unwrap());
             let best_ask = self.asks.get_mut(best_ask_h).unwrap().as_leaf_mut().unwrap();
-            let best_ask_price = best_ask.price();
 
-            if price < best_ask_price {
+            // Past the match limit the rest of the order only rests if it doesn't cross the book
+            if match_limit == 0 {
+                post_allowed &= limit_price < best_ask_price;
+                break;
+            }
+            match_limit -= 1;
+
+            // An expired order is taken off the book rather than filled; the OutEvent frees its
+            // slot on the maker's MangoAccount
+            if best_ask_ext.is_expired(now_ts) {
+                let event = OutEvent::new(
+                    Side::Ask,
+                    best_ask.owner_slot,
+                    now_ts,
+                    event_queue.header.seq_num,
+                    best_ask.owner,
+                    best_ask.quantity,
+                );
+                event_queue.push_back(cast(event)).unwrap();
+                let key = best_ask.key;
+                let _removed_node = self.asks.remove_by_key(key).unwrap();
+                continue;
+            }
+
+            if limit_price < best_ask_price {
                 break;
             } else if post_only {
                 msg!("Order could not be placed due to PostOnly");
@@ -677,6 +1358,9 @@ impl<'a> Book<'a> {
                 best_ask_price,
                 match_quantity,
             );
+            if best_ask_ext.order_tag != [0; 8] {
+                msg!("{}", maker_tag_details(&fill, &best_ask_ext.order_tag));
+            }
             event_queue.push_back(cast(fill)).unwrap();
 
             // now either best_ask.quantity == 0 or rem_quantity == 0 or both
@@ -689,11 +1373,9 @@ impl<'a> Book<'a> {
 
         // If there are still quantity unmatched, place on the book
         if rem_quantity > 0 && post_allowed {
-            if self.bids.is_full() {
-                // If this bid is higher than lowest bid, boot that bid and insert this one
-                let min_bid_handle = self.bids.find_min().unwrap();
-                let min_bid = self.bids.get(min_bid_handle).unwrap().as_leaf().unwrap();
-                check!(price > min_bid.price(), MangoErrorCode::OutOfSpace)?;
+            // If the bids are full, boot the lowest fixed price bids if this bid is higher
+            let ext = LeafExt { peg_limit, order_tag, expiry_timestamp };
+            for min_bid in self.bids.evict_for(limit_price, &ext)? {
                 let event = OutEvent::new(
                     Side::Bid,
                     min_bid.owner_slot,
@@ -703,8 +1385,6 @@ impl<'a> Book<'a> {
                     min_bid.quantity,
                 );
                 event_queue.push_back(cast(event)).unwrap();
-
-                let _removed_node = self.bids.remove(min_bid_handle).unwrap();
             }
 
             let owner_slot = mango_account
@@ -719,14 +1399,23 @@ impl<'a> Book<'a> {
                 now_ts,
                 best_initial,
             );
-            let _result = self.bids.insert_leaf(&new_bid)?;
-
-            msg!(
-                "bid on book client_id={} quantity={} price={}",
-                client_order_id,
-                rem_quantity,
-                price
-            );
+            let _result = self.bids.insert_leaf_with_ext(&new_bid, &ext)?;
+
+            match price {
+                OrderPrice::Fixed(price) => msg!(
+                    "bid on book client_id={} quantity={} price={}",
+                    client_order_id,
+                    rem_quantity,
+                    price
+                ),
+                OrderPrice::Pegged { offset, peg_limit } => msg!(
+                    "pegged bid on book client_id={} quantity={} offset={} peg_limit={}",
+                    client_order_id,
+                    rem_quantity,
+                    offset,
+                    peg_limit
+                ),
+            }
 
             mango_account.add_order(market_index, Side::Bid, &new_bid)?;
         }
@@ -735,7 +1424,7 @@ impl<'a> Book<'a> {
     }
 
     #[inline(never)]
-    pub fn new_ask(
+    fn new_ask(
         &mut self,
         event_queue: &mut EventQueue,
         market: &mut PerpMarket,
@@ -743,38 +1432,93 @@ impl<'a> Book<'a> {
         mango_account: &mut MangoAccount,
         mango_account_pk: &Pubkey,
         market_index: usize,
-        price: i64,
+        price: OrderPrice,
         quantity: i64, // quantity is guaranteed to be greater than zero due to initial check --
         order_type: OrderType,
         client_order_id: u64,
+        order_tag: [u8; 8],
+        expiry_timestamp: u64,
+        mut match_limit: u8,
         now_ts: u64,
+        oracle_price: I80F48,
     ) -> MangoResult<()> {
         // TODO proper error handling
-        let (post_only, post_allowed) = match order_type {
+        let (post_only, mut post_allowed) = match order_type {
             OrderType::Limit => (false, true),
             OrderType::ImmediateOrCancel => (false, false),
-            OrderType::PostOnly => (true, true),
+            OrderType::PostOnly | OrderType::PostOnlySlide => (true, true),
+        };
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
+        // A post-only-slide ask that would cross the book moves to one tick above the best bid
+        let price = match (order_type, price) {
+            (OrderType::PostOnlySlide, OrderPrice::Fixed(price)) => {
+                match self.bids.find_best(Side::Bid, oracle_price_lots) {
+                    Some((_, best_bid_price)) if price <= best_bid_price => {
+                        OrderPrice::Fixed(best_bid_price + 1)
+                    }
+                    _ => OrderPrice::Fixed(price),
+                }
+            }
+            (_, price) => price,
+        };
+        // A pegged order past its peg limit has no price; it doesn't match but may still rest
+        let (order_id, limit_price, peg_limit) = match price {
+            OrderPrice::Fixed(price) => (market.gen_order_id(Side::Ask, price), Some(price), 0),
+            OrderPrice::Pegged { offset, peg_limit } => (
+                market.gen_pegged_order_id(Side::Ask, offset),
+                pegged_price(Side::Ask, oracle_price_lots, offset, peg_limit),
+                peg_limit,
+            ),
         };
-        let order_id = market.gen_order_id(Side::Ask, price);
 
-        let best_initial = match self.get_best_ask_price() {
-            None => price,
-            Some(p) => p,
+        let best_initial = match self.asks.find_best(Side::Ask, oracle_price_lots) {
+            None => limit_price.unwrap_or(oracle_price_lots),
+            Some((_, p)) => p,
         };
 
         // if post only and price >= best_ask, return
         // Iterate through book and match against this new bid
         let mut rem_quantity = quantity; // base lots (aka contracts)
         while rem_quantity > 0 {
-            let best_bid_h = match self.get_best_bid_handle() {
+            let limit_price = match limit_price {
                 None => break,
-                Some(h) => h,
+                Some(p) => p,
             };
-
+            let (best_bid_h, best_bid_price) =
+                match self.bids.find_best(Side::Bid, oracle_price_lots) {
+                    None => break,
+                    Some(best) => best,
+                };
+
+            let best_bid_ext =
+                self.bids.leaf_ext(self.bids.get(best_bid_h).unwrap().as_leaf().unwrap());
             let best_bid = self.bids.get_mut(best_bid_h).unwrap().as_leaf_mut().unwrap();
-            let best_bid_price = best_bid.price();
 
-            if price > best_bid_price {
+            // Past the match limit the rest of the order only rests if it doesn't cross the book
+            if match_limit == 0 {
+                post_allowed &= limit_price > best_bid_price;
+                break;
+            }
+            match_limit -= 1;
+
+            // An expired order is taken off the book rather than filled; the OutEvent frees its
+            // slot on the maker's MangoAccount
+            if best_bid_ext.is_expired(now_ts) {
+                let event = OutEvent::new(
+                    Side::Bid,
+                    best_bid.owner_slot,
+                    now_ts,
+                    event_queue.header.seq_num,
+                    best_bid.owner,
+                    best_bid.quantity,
+                );
+                event_queue.push_back(cast(event)).unwrap();
+                let key = best_bid.key;
+                let _removed_node = self.bids.remove_by_key(key).unwrap();
+                continue;
+            }
+
+            if limit_price > best_bid_price {
                 break;
             } else if post_only {
                 msg!("Order could not be placed due to PostOnly");
@@ -807,6 +1551,9 @@ impl<'a> Book<'a> {
                 best_bid_price,
                 match_quantity,
             );
+            if best_bid_ext.order_tag != [0; 8] {
+                msg!("{}", maker_tag_details(&fill, &best_bid_ext.order_tag));
+            }
 
             event_queue.push_back(cast(fill)).unwrap();
 
@@ -820,11 +1567,9 @@ impl<'a> Book<'a> {
 
         // If there are still quantity unmatched, place on the book
         if rem_quantity > 0 && post_allowed {
-            if self.bids.is_full() {
-                // If this asks is lower than highest ask, boot that ask and insert this one
-                let max_ask_handle = self.asks.find_min().unwrap();
-                let max_ask = self.asks.get(max_ask_handle).unwrap().as_leaf().unwrap();
-                check!(price < max_ask.price(), MangoErrorCode::OutOfSpace)?;
+            // If the asks are full, boot the highest fixed price asks if this ask is lower
+            let ext = LeafExt { peg_limit, order_tag, expiry_timestamp };
+            for max_ask in self.asks.evict_for(limit_price, &ext)? {
                 let event = OutEvent::new(
                     Side::Ask,
                     max_ask.owner_slot,
@@ -834,7 +1579,6 @@ impl<'a> Book<'a> {
                     max_ask.quantity,
                 );
                 event_queue.push_back(cast(event)).unwrap();
-                let _removed_node = self.asks.remove(max_ask_handle).unwrap();
             }
 
             let owner_slot = mango_account
@@ -850,14 +1594,23 @@ impl<'a> Book<'a> {
                 best_initial,
             );
 
-            msg!(
-                "ask on book client_id={} quantity={} price={}",
-                client_order_id,
-                rem_quantity,
-                price
-            );
+            match price {
+                OrderPrice::Fixed(price) => msg!(
+                    "ask on book client_id={} quantity={} price={}",
+                    client_order_id,
+                    rem_quantity,
+                    price
+                ),
+                OrderPrice::Pegged { offset, peg_limit } => msg!(
+                    "pegged ask on book client_id={} quantity={} offset={} peg_limit={}",
+                    client_order_id,
+                    rem_quantity,
+                    offset,
+                    peg_limit
+                ),
+            }
 
-            let _result = self.asks.insert_leaf(&new_ask)?;
+            let _result = self.asks.insert_leaf_with_ext(&new_ask, &ext)?;
             mango_account.add_order(market_index, Side::Ask, &new_ask)?;
         }
 
@@ -924,8 +1677,8 @@ impl<'a> Book<'a> {
             let order_side = mango_account.order_side[i];
 
             let best_final = match order_side {
-                Side::Bid => self.get_best_bid_price().unwrap(),
-                Side::Ask => self.get_best_ask_price().unwrap(),
+                Side::Bid => self.get_best_bid_price(),
+                Side::Ask => self.get_best_ask_price(),
             };
 
             match self.cancel_order(order_id, order_side) {
@@ -933,16 +1686,19 @@ impl<'a> Book<'a> {
                     // technically these should be the same. Can enable this check to be extra sure
                     // check!(i == order.owner_slot as usize, MathError)?;
                     mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
-                    mango_account.perp_accounts[market_index].apply_incentives(
-                        perp_market,
-                        order_side,
-                        order.price(),
-                        order.best_initial,
-                        best_final,
-                        order.timestamp,
-                        now_ts,
-                        order.quantity,
-                    )?;
+                    // Pegged orders have no fixed price to measure against the best price
+                    if !order.is_pegged() {
+                        mango_account.perp_accounts[market_index].apply_incentives(
+                            perp_market,
+                            order_side,
+                            order.price(),
+                            order.best_initial,
+                            best_final.unwrap(),
+                            order.timestamp,
+                            now_ts,
+                            order.quantity,
+                        )?;
+                    }
                 }
                 Err(_) => {
                     // If it's not on the book, then it has been matched and only Keeper can remove
@@ -956,4 +1712,115 @@ impl<'a> Book<'a> {
         }
         Ok(())
     }
+
+    /// Cancel the account's orders on this market that were placed before `cutoff_ts`, with
+    /// liquidity incentives. `limit` bounds the number of orders canceled
+    pub fn cancel_older_than_with_incentives(
+        &mut self,
+        mango_account: &mut MangoAccount,
+        perp_market: &mut PerpMarket,
+        market_index: usize,
+        cutoff_ts: u64,
+        mut limit: u8,
+    ) -> MangoResult<()> {
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        for i in 0..MAX_PERP_OPEN_ORDERS {
+            if limit == 0 {
+                break;
+            }
+            if mango_account.order_market[i] != market_index as u8 {
+                // means slot is free or belongs to different perp market
+                continue;
+            }
+            let order_id = mango_account.orders[i];
+            let order_side = mango_account.order_side[i];
+
+            let book_side = match order_side {
+                Side::Bid => &self.bids,
+                Side::Ask => &self.asks,
+            };
+            match book_side.find_by_key(order_id) {
+                Some(order) if order.timestamp < cutoff_ts => {}
+                // Too recent, or not on the book because it has been matched
+                _ => continue,
+            }
+
+            let best_final = match order_side {
+                Side::Bid => self.get_best_bid_price(),
+                Side::Ask => self.get_best_ask_price(),
+            };
+
+            let order = self.cancel_order(order_id, order_side)?;
+            mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+            if !order.is_pegged() {
+                mango_account.perp_accounts[market_index].apply_incentives(
+                    perp_market,
+                    order_side,
+                    order.price(),
+                    order.best_initial,
+                    best_final.unwrap(),
+                    order.timestamp,
+                    now_ts,
+                    order.quantity,
+                )?;
+            }
+
+            limit -= 1;
+        }
+        Ok(())
+    }
+
+    /// Cancel the account's orders on one side of this market, with liquidity incentives.
+    /// `limit` bounds the number of orders canceled
+    pub fn cancel_side_with_incentives(
+        &mut self,
+        mango_account: &mut MangoAccount,
+        perp_market: &mut PerpMarket,
+        market_index: usize,
+        side: Side,
+        mut limit: u8,
+    ) -> MangoResult<()> {
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        for i in 0..MAX_PERP_OPEN_ORDERS {
+            if limit == 0 {
+                break;
+            }
+            if mango_account.order_market[i] != market_index as u8
+                || mango_account.order_side[i] != side
+            {
+                // means slot is free, belongs to different perp market or is on the other side
+                continue;
+            }
+            let order_id = mango_account.orders[i];
+
+            let best_final = match side {
+                Side::Bid => self.get_best_bid_price(),
+                Side::Ask => self.get_best_ask_price(),
+            };
+
+            let order = match self.cancel_order(order_id, side) {
+                Ok(order) => order,
+                // If it's not on the book, then it has been matched and only Keeper can remove
+                Err(_) => continue,
+            };
+            mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+            if !order.is_pegged() {
+                mango_account.perp_accounts[market_index].apply_incentives(
+                    perp_market,
+                    side,
+                    order.price(),
+                    order.best_initial,
+                    best_final.unwrap(),
+                    order.timestamp,
+                    now_ts,
+                    order.quantity,
+                )?;
+            }
+
+            limit -= 1;
+        }
+        Ok(())
+    }
 }
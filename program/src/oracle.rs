@@ -4,7 +4,7 @@ use mango_macro::{Loadable, Pod};
 use solana_program::{account_info::AccountInfo, pubkey::Pubkey, rent::Rent};
 use std::{cell::RefMut, mem::size_of};
 
-use crate::error::{check_assert, MangoErrorCode, MangoResult, SourceFileId};
+use crate::error::{check_assert, MangoError, MangoErrorCode, MangoResult, SourceFileId};
 
 declare_check_assert_macros!(SourceFileId::Oracle);
 
@@ -221,6 +221,31 @@ impl AccKey {
     }
 }
 
+/// Convert a price published as `value * 10^expo` UI quote per UI base into native quote per
+/// native base so cached prices are comparable regardless of the oracle's exponent
+pub fn normalize_oracle_price(
+    value: I80F48,
+    expo: i32,
+    base_decimals: u8,
+    quote_decimals: u8,
+) -> MangoResult<I80F48> {
+    let decimals = (quote_decimals as i32)
+        .checked_add(expo)
+        .and_then(|d| d.checked_sub(base_decimals as i32))
+        .ok_or(math_err!())?;
+    if decimals == 0 {
+        return Ok(value);
+    }
+
+    let decimal_adj =
+        I80F48::from_num(10u64.checked_pow(decimals.abs() as u32).ok_or(math_err!())?);
+    if decimals < 0 {
+        value.checked_div(decimal_adj).ok_or(math_err!())
+    } else {
+        value.checked_mul(decimal_adj).ok_or(math_err!())
+    }
+}
+
 pub fn determine_oracle_type<'a>(account: &'a AccountInfo) -> OracleType {
     let borrowed = &account.data.borrow();
     if borrowed[0] == 212 && borrowed[1] == 195 && borrowed[2] == 178 && borrowed[3] == 161 {
@@ -15,7 +15,6 @@ use solana_program::clock::Clock;
 use solana_program::entrypoint::ProgramResult;
 use solana_program::instruction::{AccountMeta, Instruction};
 use solana_program::msg;
-use solana_program::program_error::ProgramError;
 use solana_program::program_pack::{IsInitialized, Pack};
 use solana_program::pubkey::Pubkey;
 use solana_program::rent::Rent;
@@ -23,20 +22,33 @@ use solana_program::sysvar::Sysvar;
 use spl_token::state::{Account, Mint};
 
 use crate::error::{check_assert, MangoError, MangoErrorCode, MangoResult, SourceFileId};
+use crate::ids::mngo_token;
 use crate::ids::msrm_token;
 use crate::ids::srm_token;
-use crate::instruction::MangoInstruction;
-use crate::matching::{Book, BookSide, OrderType, Side};
-use crate::oracle::{determine_oracle_type, OracleType, Price, StubOracle};
-use crate::queue::{EventQueue, EventType, FillEvent, LiquidateEvent, OutEvent};
+use crate::instruction::{MangoInstruction, PerpRebalanceLeg, MAX_UPDATE_ALL_FUNDING_MARKETS};
+use crate::matching::{
+    mining_points_rate, perp_spread_bps, Book, BookSide, OrderType, Side, DEFAULT_MATCH_LIMIT,
+    MAX_PEG_OFFSET,
+};
+use crate::oracle::{determine_oracle_type, normalize_oracle_price, OracleType, Price, StubOracle};
+use crate::queue::{
+    fill_event_details, EventQueue, EventType, FillEvent, LiquidateEvent, OutEvent,
+};
 use crate::state::{
-    load_asks_mut, load_bids_mut, load_market_state, load_open_orders, AssetType, DataType,
-    HealthCache, HealthType, MangoAccount, MangoCache, MangoGroup, MetaData, NodeBank, PerpMarket,
-    PerpMarketCache, PerpMarketInfo, PriceCache, RootBank, RootBankCache, SpotMarketInfo,
-    TokenInfo, UserActiveAssets, FREE_ORDER_SLOT, INFO_LEN, MAX_NODE_BANKS, MAX_PAIRS,
-    MAX_PERP_OPEN_ORDERS, ONE_I80F48, QUOTE_INDEX, ZERO_I80F48,
+    account_leverage, borrow_apr, check_group_invariants, deposit_apr, has_unsettled_pnl,
+    load_asks_mut, load_bids_mut, load_market_state, load_open_orders, parse_group_name,
+    perp_losses_at_risk, position_history_anchor, post_trade_risk_details, root_bank_utilization,
+    token_and_token_liquidation_amounts, total_open_interest, AssetType, DataType,
+    DepositWhitelist, GroupName, HealthCache, HealthType, LossRecovery, LossShare, MangoAccount,
+    MangoCache, MangoGroup, MetaData, NodeBank, PerpMarket, PerpMarketCache, PerpMarketConfig,
+    PerpMarketInfo, PnlTracker, PriceCache, RootBank, RootBankCache, RootBankSnapshot,
+    SpotMarketInfo, TokenInfo, UserActiveAssets, WithdrawCaps, DEPOSIT_WHITELIST_SEED,
+    FREE_ORDER_SLOT, GROUP_NAME_LEN, GROUP_NAME_SEED, INFO_LEN, LOSS_RECOVERY_SEED,
+    LOSS_SHARE_SEED, MANGO_ACCOUNT_VERSION, MAX_NODE_BANKS, MAX_PAIRS, MAX_PERP_OPEN_ORDERS,
+    MAX_TOKENS, ONE_I80F48, PERP_MARKET_CONFIG_SEED, PNL_TRACKER_SEED, QUOTE_INDEX,
+    WITHDRAW_CAPS_SEED, ZERO_I80F48,
 };
-use crate::utils::{gen_signer_key, gen_signer_seeds};
+use crate::utils::{gen_signer_key, gen_signer_seeds, invert_side, open_orders_settleable};
 use switchboard_program::FastRoundResultAccountData;
 
 declare_check_assert_macros!(SourceFileId::Processor);
@@ -80,10 +92,10 @@ impl Processor {
         let mut mango_group: RefMut<MangoGroup> = MangoGroup::load_mut(mango_group_ai)?;
         check!(!mango_group.meta_data.is_initialized, MangoErrorCode::Default)?;
 
-        check!(
-            gen_signer_key(signer_nonce, mango_group_ai.key, program_id)? == *signer_ai.key,
-            MangoErrorCode::InvalidSignerKey
-        )?;
+        // The nonce must derive exactly the signer key; a nonce with no valid address fails too
+        let derived_signer_key = gen_signer_key(signer_nonce, mango_group_ai.key, program_id)
+            .map_err(|_| throw_err!(MangoErrorCode::InvalidSignerNonce))?;
+        check!(derived_signer_key == *signer_ai.key, MangoErrorCode::InvalidSignerNonce)?;
         mango_group.signer_nonce = signer_nonce;
         mango_group.signer_key = *signer_ai.key;
         mango_group.valid_interval = valid_interval;
@@ -136,7 +148,8 @@ impl Processor {
             mint: *quote_mint_ai.key,
             root_bank: *quote_root_bank_ai.key,
             decimals: mint.decimals,
-            padding: [0u8; 7],
+            padding: [0u8; 3],
+            max_oracle_age: 0,
         };
 
         check!(admin_ai.is_signer, MangoErrorCode::Default)?;
@@ -191,11 +204,56 @@ impl Processor {
         mango_account.mango_group = *mango_group_ai.key;
         mango_account.owner = *owner_ai.key;
         mango_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
-        mango_account.meta_data = MetaData::new(DataType::MangoAccount, 0, true);
+        mango_account.meta_data =
+            MetaData::new(DataType::MangoAccount, MANGO_ACCOUNT_VERSION, true);
 
         Ok(())
     }
 
+    #[inline(never)]
+    /// Close an empty mango account, sending its lamports to the owner and zeroing its data
+    fn close_mango_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // write, signer
+            withdraw_caps_ai,   // write
+            pnl_tracker_ai,     // write
+        ] = accounts;
+
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        let _mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        {
+            let mut mango_account =
+                MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+            check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+            check!(mango_account.can_close(), MangoErrorCode::AccountNotEmpty)?;
+
+            // Close the PDAs too, so a new account at the same address doesn't inherit them
+            let (withdraw_caps_pk, _) =
+                WithdrawCaps::find_address(program_id, mango_account_ai.key);
+            check_eq!(withdraw_caps_ai.key, &withdraw_caps_pk, MangoErrorCode::InvalidAccount)?;
+            if mango_account.has_withdraw_caps {
+                WithdrawCaps::load_checked(withdraw_caps_ai, program_id, mango_account_ai.key)?;
+                close_program_account(withdraw_caps_ai, owner_ai)?;
+                mango_account.has_withdraw_caps = false;
+            }
+            let (pnl_tracker_pk, _) = PnlTracker::find_address(program_id, mango_account_ai.key);
+            check_eq!(pnl_tracker_ai.key, &pnl_tracker_pk, MangoErrorCode::InvalidAccount)?;
+            if mango_account.has_pnl_tracker {
+                PnlTracker::load_checked(pnl_tracker_ai, program_id, mango_account_ai.key)?;
+                close_program_account(pnl_tracker_ai, owner_ai)?;
+                mango_account.has_pnl_tracker = false;
+            }
+        }
+
+        close_program_account(mango_account_ai, owner_ai)
+    }
+
     #[inline(never)]
     /// Add asset and spot market to mango group
     /// Initialize a root bank and add it to the mango group
@@ -263,7 +321,9 @@ impl Processor {
             mint: *mint_ai.key,
             root_bank: *root_bank_ai.key,
             decimals: mint.decimals,
-            padding: [0u8; 7],
+            padding: [0u8; 3],
+            // Keep an override set before the spot market was added
+            max_oracle_age: mango_group.tokens[market_index].max_oracle_age,
         };
 
         let (maint_asset_weight, maint_liab_weight) = get_leverage_weights(maint_leverage);
@@ -474,7 +534,7 @@ impl Processor {
     /// Deposit instruction
     fn deposit(program_id: &Pubkey, accounts: &[AccountInfo], quantity: u64) -> MangoResult<()> {
         const NUM_FIXED: usize = 9;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let (fixed_ais, whitelist_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
             mango_group_ai,         // read
             mango_account_ai,       // write
@@ -485,8 +545,18 @@ impl Processor {
             vault_ai,               // write
             token_prog_ai,          // read
             owner_token_account_ai, // write
-        ] = accounts;
+        ] = fixed_ais;
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        if mango_group.deposit_whitelist_enabled {
+            let deposit_whitelist_ai =
+                whitelist_ais.first().ok_or(throw_err!(MangoErrorCode::NotWhitelisted))?;
+            let deposit_whitelist = DepositWhitelist::load_checked(
+                deposit_whitelist_ai,
+                program_id,
+                mango_group_ai.key,
+            )?;
+            check!(deposit_whitelist.contains(owner_ai.key), MangoErrorCode::NotWhitelisted)?;
+        }
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
 
@@ -527,6 +597,93 @@ impl Processor {
         )
     }
 
+    #[inline(never)]
+    /// Deposit several tokens, each into its own node bank, in one instruction
+    fn deposit_batch(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantities: Vec<u64>,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 5;
+        const NUM_PER_ENTRY: usize = 4;
+        let (fixed_ais, entry_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,         // read
+            mango_account_ai,       // write
+            owner_ai,               // read
+            mango_cache_ai,         // read
+            token_prog_ai,          // read
+        ] = fixed_ais;
+        check!(
+            entry_ais.len() >= quantities.len() * NUM_PER_ENTRY,
+            MangoErrorCode::InvalidAccount
+        )?;
+        let (entry_ais, whitelist_ais) = entry_ais.split_at(quantities.len() * NUM_PER_ENTRY);
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        if mango_group.deposit_whitelist_enabled {
+            let deposit_whitelist_ai =
+                whitelist_ais.first().ok_or(throw_err!(MangoErrorCode::NotWhitelisted))?;
+            let deposit_whitelist = DepositWhitelist::load_checked(
+                deposit_whitelist_ai,
+                program_id,
+                mango_group_ai.key,
+            )?;
+            check!(deposit_whitelist.contains(owner_ai.key), MangoErrorCode::NotWhitelisted)?;
+        }
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::Default)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        for (quantity, ais) in quantities.iter().zip(entry_ais.chunks_exact(NUM_PER_ENTRY)) {
+            let [
+                root_bank_ai,           // read
+                node_bank_ai,           // write
+                vault_ai,               // write
+                owner_token_account_ai, // write
+            ] = array_ref![ais, 0, NUM_PER_ENTRY];
+
+            let token_index = mango_group
+                .find_root_bank_index(root_bank_ai.key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidToken))?;
+
+            let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+            let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+            check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::Default)?;
+            check_eq!(&node_bank.vault, vault_ai.key, MangoErrorCode::InvalidVault)?;
+
+            invoke_transfer(
+                token_prog_ai,
+                owner_token_account_ai,
+                vault_ai,
+                owner_ai,
+                &[],
+                *quantity,
+            )?;
+
+            let root_bank_cache = &mango_cache.root_bank_cache[token_index];
+            check!(
+                now_ts <= root_bank_cache.last_update + mango_group.valid_interval,
+                MangoErrorCode::InvalidCache
+            )?;
+
+            checked_change_net(
+                root_bank_cache,
+                &mut node_bank,
+                &mut mango_account,
+                mango_account_ai.key,
+                token_index,
+                I80F48::from_num(*quantity),
+            )?;
+        }
+
+        Ok(())
+    }
+
     // TODO create client functions and instruction.rs
     #[inline(never)]
     #[allow(unused)]
@@ -782,8 +939,8 @@ impl Processor {
         allow_borrow: bool,
     ) -> MangoResult<()> {
         const NUM_FIXED: usize = 10;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let (fixed_ais, open_orders_ais, extra_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
         let [
             mango_group_ai,     // read
             mango_account_ai,   // write
@@ -811,6 +968,21 @@ impl Processor {
         let token_index = mango_group
             .find_root_bank_index(root_bank_ai.key)
             .ok_or(throw_err!(MangoErrorCode::InvalidToken))?;
+        if mango_account.has_withdraw_caps {
+            let (withdraw_caps_pk, _) =
+                WithdrawCaps::find_address(program_id, mango_account_ai.key);
+            let withdraw_caps_ai = extra_ais
+                .iter()
+                .find(|ai| ai.key == &withdraw_caps_pk)
+                .ok_or(throw_err!(MangoErrorCode::MissingWithdrawCaps))?;
+            let withdraw_caps =
+                WithdrawCaps::load_checked(withdraw_caps_ai, program_id, mango_account_ai.key)?;
+            let max_withdraw = withdraw_caps.max_withdraw_per_tx[token_index];
+            check!(
+                max_withdraw == 0 || quantity <= max_withdraw,
+                MangoErrorCode::WithdrawCapExceeded
+            )?;
+        }
 
         let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
         check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::InvalidNodeBank)?;
@@ -830,9 +1002,12 @@ impl Processor {
 
         let root_bank_cache = &mango_cache.root_bank_cache[token_index];
 
+        // The fee is charged on top of quantity
+        let fee = mango_group.get_withdraw_fee(token_index, quantity);
+
         // Borrow if withdrawing more than deposits
         let native_deposit = mango_account.get_native_deposit(root_bank_cache, token_index)?;
-        let withdraw = I80F48::from_num(quantity);
+        let withdraw = I80F48::from_num(quantity) + I80F48::from_num(fee);
         check!(native_deposit >= withdraw || allow_borrow, MangoErrorCode::InsufficientFunds)?;
         checked_change_net(
             root_bank_cache,
@@ -852,6 +1027,20 @@ impl Processor {
             &[&signers_seeds],
             quantity,
         )?;
+        if fee > 0 {
+            let insurance_vault_ai = extra_ais
+                .iter()
+                .find(|ai| ai.key == &mango_group.insurance_vault)
+                .ok_or(throw_err!(MangoErrorCode::InvalidVault))?;
+            invoke_transfer(
+                token_prog_ai,
+                vault_ai,
+                insurance_vault_ai,
+                signer_ai,
+                &[&signers_seeds],
+                fee,
+            )?;
+        }
 
         let mut health_cache = HealthCache::new(active_assets);
         health_cache.init_vals(&mango_group, &mango_cache, &mango_account, open_orders_ais)?;
@@ -862,6 +1051,83 @@ impl Processor {
         Ok(())
     }
 
+    #[inline(never)]
+    /// Log the health the MangoAccount would have after the equivalent Withdraw
+    fn preview_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+        allow_borrow: bool,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 10;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            _owner_ai,          // read
+            mango_cache_ai,     // read
+            root_bank_ai,       // read
+            node_bank_ai,       // read
+            _vault_ai,          // read
+            _token_account_ai,  // read
+            _signer_ai,         // read
+            _token_prog_ai,     // read
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        mango_account.check_open_orders(&mango_group, open_orders_ais)?;
+
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        let token_index = mango_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidToken))?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::InvalidNodeBank)?;
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+        let active_assets = UserActiveAssets::new(
+            &mango_group,
+            &mango_account,
+            vec![(AssetType::Token, token_index)],
+        );
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+
+        let root_bank_cache = &mango_cache.root_bank_cache[token_index];
+        let fee = mango_group.get_withdraw_fee(token_index, quantity);
+        let native_deposit = mango_account.get_native_deposit(root_bank_cache, token_index)?;
+        let withdraw = I80F48::from_num(quantity) + I80F48::from_num(fee);
+        check!(native_deposit >= withdraw || allow_borrow, MangoErrorCode::InsufficientFunds)?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&mango_group, &mango_cache, &mango_account, open_orders_ais)?;
+        health_cache.simulate_token_change(
+            &mango_cache,
+            &mango_account,
+            open_orders_ais,
+            token_index,
+            -withdraw,
+        )?;
+        let init_health = health_cache.get_health(&mango_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
+
+        msg!(
+            "PreviewWithdraw details: {{ \
+                \"mango_account\": {}, \
+                \"init_health\": {}, \
+                \"maint_health\": {} \
+                }}",
+            mango_account_ai.key.to_string(),
+            init_health.to_num::<f64>(),
+            maint_health.to_num::<f64>()
+        );
+
+        Ok(())
+    }
+
     #[inline(never)]
     /// Call the init_open_orders instruction in serum dex and add this OpenOrders account to margin account
     fn init_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
@@ -887,7 +1153,7 @@ impl Processor {
 
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
         check!(owner_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
 
@@ -920,6 +1186,65 @@ impl Processor {
         Ok(())
     }
 
+    #[inline(never)]
+    fn close_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // read
+            mango_account_ai,       // write
+            owner_ai,               // write, signer
+            dex_prog_ai,            // read
+            open_orders_ai,         // write
+            spot_market_ai,         // read
+            signer_ai,              // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check_eq!(dex_prog_ai.key, &mango_group.dex_program_id, MangoErrorCode::InvalidProgramId)?;
+        check_eq!(signer_ai.key, &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
+
+        let market_index = mango_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
+        check_eq!(
+            &mango_account.spot_open_orders[market_index],
+            open_orders_ai.key,
+            MangoErrorCode::InvalidOpenOrdersAccount
+        )?;
+
+        {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            check!(
+                open_orders.native_coin_total == 0 && open_orders.native_pc_total == 0,
+                MangoErrorCode::AccountNotEmpty
+            )?;
+        }
+
+        if mango_account.in_margin_basket[market_index] {
+            mango_account.in_margin_basket[market_index] = false;
+            mango_account.num_in_margin_basket -= 1;
+        }
+        mango_account.spot_open_orders[market_index] = Pubkey::default();
+
+        let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+        invoke_close_open_orders(
+            dex_prog_ai,
+            open_orders_ai,
+            signer_ai,
+            owner_ai,
+            spot_market_ai,
+            &[&signers_seeds],
+        )?;
+
+        Ok(())
+    }
+
     #[inline(never)]
     fn place_spot_order(
         program_id: &Pubkey,
@@ -969,7 +1294,7 @@ impl Processor {
 
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
         check!(owner_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
 
@@ -1016,6 +1341,7 @@ impl Processor {
         let active_assets = UserActiveAssets::new(&mango_group, &mango_account, vec![]);
         let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
         mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        mango_account.check_mngo_stake(&mango_group, &mango_cache)?;
 
         let mut health_cache = HealthCache::new(active_assets);
         health_cache.init_vals(&mango_group, &mango_cache, &mango_account, open_orders_ais)?;
@@ -1166,7 +1492,7 @@ impl Processor {
             MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
         check!(owner_ai.is_signer, MangoErrorCode::Default)?;
-        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::Default)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::Default)?;
 
         let market_i = mango_group.find_spot_market_index(spot_market_ai.key).unwrap();
         check_eq!(
@@ -1221,7 +1547,7 @@ impl Processor {
 
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(owner_ai.key == &mango_account.owner, MangoErrorCode::InvalidOwner)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
         check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
 
@@ -1267,10 +1593,7 @@ impl Processor {
 
         let (pre_base, pre_quote) = {
             let open_orders = load_open_orders(open_orders_ai)?;
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
+            open_orders_settleable(&open_orders)
         };
 
         let signer_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
@@ -1293,10 +1616,7 @@ impl Processor {
             // remove from margin basket if it's empty
             mango_account.update_basket(spot_market_index, &open_orders)?;
 
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
+            open_orders_settleable(&open_orders)
         };
 
         // TODO OPT - remove sanity check if confident
@@ -1334,6 +1654,82 @@ impl Processor {
         Ok(())
     }
 
+    #[inline(never)]
+    fn cancel_spot_order_and_settle(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        data: Vec<u8>,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 21;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            owner_ai,               // signer
+            mango_account_ai,       // write
+            dex_prog_ai,            // read
+            spot_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
+            open_orders_ai,         // write
+            signer_ai,              // read
+            dex_event_queue_ai,     // write
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
+            base_root_bank_ai,      // read
+            base_node_bank_ai,      // write
+            quote_root_bank_ai,     // read
+            quote_node_bank_ai,     // write
+            base_vault_ai,          // write
+            quote_vault_ai,         // write
+            dex_signer_ai,          // read
+            token_prog_ai,          // read
+        ] = accounts;
+
+        // Both handlers do their own checks; the cancel releases its borrow of the
+        // MangoAccount before settle_funds loads it mutably
+        Self::cancel_spot_order(
+            program_id,
+            &[
+                mango_group_ai.clone(),
+                owner_ai.clone(),
+                mango_account_ai.clone(),
+                dex_prog_ai.clone(),
+                spot_market_ai.clone(),
+                bids_ai.clone(),
+                asks_ai.clone(),
+                open_orders_ai.clone(),
+                signer_ai.clone(),
+                dex_event_queue_ai.clone(),
+            ],
+            data,
+        )?;
+
+        Self::settle_funds(
+            program_id,
+            &[
+                mango_group_ai.clone(),
+                mango_cache_ai.clone(),
+                owner_ai.clone(),
+                mango_account_ai.clone(),
+                dex_prog_ai.clone(),
+                spot_market_ai.clone(),
+                open_orders_ai.clone(),
+                signer_ai.clone(),
+                dex_base_ai.clone(),
+                dex_quote_ai.clone(),
+                base_root_bank_ai.clone(),
+                base_node_bank_ai.clone(),
+                quote_root_bank_ai.clone(),
+                quote_node_bank_ai.clone(),
+                base_vault_ai.clone(),
+                quote_vault_ai.clone(),
+                dex_signer_ai.clone(),
+                token_prog_ai.clone(),
+            ],
+        )
+    }
+
     #[inline(never)]
     fn place_perp_order(
         program_id: &Pubkey,
@@ -1343,6 +1739,10 @@ impl Processor {
         quantity: i64,
         client_order_id: u64,
         order_type: OrderType,
+        safe_mode: bool,
+        order_tag: [u8; 8],
+        expiry_timestamp: u64,
+        match_limit: u8,
     ) -> MangoResult<()> {
         check!(price > 0, MangoErrorCode::InvalidParam)?;
         check!(quantity > 0, MangoErrorCode::InvalidParam)?;
@@ -1364,10 +1764,6 @@ impl Processor {
 
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
-        mango_account.check_open_orders(&mango_group, open_orders_ais)?;
 
         let clock = Clock::get()?;
         let now_ts = clock.unix_timestamp as u64;
@@ -1380,31 +1776,35 @@ impl Processor {
             .find_perp_market_index(perp_market_ai.key)
             .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        let active_assets = UserActiveAssets::new(
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let (mut health_cache, pre_health) = init_perp_order_health(
             &mango_group,
-            &mango_account,
-            vec![(AssetType::Perp, market_index)],
-        );
+            &mango_cache,
+            &mut mango_account,
+            owner_ai,
+            open_orders_ais,
+            &[market_index],
+            now_ts,
+        )?;
 
-        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
-        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        if expiry_timestamp != 0 && expiry_timestamp <= now_ts {
+            msg!("Order is already expired");
+            return Ok(()); // return silently to not fail other instructions in tx
+        }
 
-        let mut health_cache = HealthCache::new(active_assets);
-        health_cache.init_vals(&mango_group, &mango_cache, &mango_account, open_orders_ais)?;
-        let pre_health = health_cache.get_health(&mango_group, HealthType::Init);
-
-        // update the being_liquidated flag
-        if mango_account.being_liquidated {
-            if pre_health >= ZERO_I80F48 {
-                mango_account.being_liquidated = false;
-            } else {
-                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
-            }
+        // In safe mode, reject orders that would leave the account liquidatable if fully filled
+        if safe_mode {
+            let pmi = &mango_group.perp_markets[market_index];
+            let pmc = &mango_cache.perp_market_cache[market_index];
+            let oracle_price = mango_cache.price_cache[market_index].price;
+            let pa = &mango_account.perp_accounts[market_index];
+            let filled = pa.simulate_taker_fill(pmi, side, price, quantity)?;
+            let filled_maint_health = health_cache.get_health(&mango_group, HealthType::Maint)
+                - pa.get_health(pmi, pmc, oracle_price, HealthType::Maint)?
+                + filled.get_health(pmi, pmc, oracle_price, HealthType::Maint)?;
+            check!(filled_maint_health >= ZERO_I80F48, MangoErrorCode::InsufficientHealth)?;
         }
 
-        // This means health must only go up
-        let reduce_only = pre_health < ZERO_I80F48;
-
         let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
         let mut event_queue =
             EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
@@ -1421,15 +1821,220 @@ impl Processor {
             quantity,
             order_type,
             client_order_id,
+            order_tag,
+            expiry_timestamp,
+            match_limit,
             now_ts,
+            mango_cache.price_cache[market_index].price,
         )?;
 
         health_cache.update_perp_val(&mango_group, &mango_cache, &mango_account, market_index)?;
-        let post_health = health_cache.get_health(&mango_group, HealthType::Init);
-        // If an account is in reduce_only mode, health must only go up
-        check!(
-            post_health >= ZERO_I80F48 || (reduce_only && post_health >= pre_health),
-            MangoErrorCode::InsufficientFunds
+        check_post_trade_health(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            mango_account_ai.key,
+            open_orders_ais,
+            &health_cache,
+            pre_health,
+            &[market_index],
+            safe_mode,
+        )
+    }
+
+    #[inline(never)]
+    /// Place an order on a perp market whose price follows the oracle price at offset_ticks
+    fn place_perp_pegged_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        side: Side,
+        offset_ticks: i64,
+        peg_limit: i64,
+        quantity: i64,
+        client_order_id: u64,
+        order_type: OrderType,
+    ) -> MangoResult<()> {
+        check!(peg_limit > 0, MangoErrorCode::InvalidParam)?;
+        check!(offset_ticks.abs() <= MAX_PEG_OFFSET, MangoErrorCode::InvalidParam)?;
+        check!(quantity > 0, MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 8;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            mango_cache_ai,     // read
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+            event_queue_ai,     // write
+        ] = fixed_ais;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let (mut health_cache, pre_health) = init_perp_order_health(
+            &mango_group,
+            &mango_cache,
+            &mut mango_account,
+            owner_ai,
+            open_orders_ais,
+            &[market_index],
+            now_ts,
+        )?;
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+
+        book.new_pegged_order(
+            &mut event_queue,
+            &mut perp_market,
+            &mango_group.perp_markets[market_index],
+            &mut mango_account,
+            mango_account_ai.key,
+            market_index,
+            side,
+            offset_ticks,
+            peg_limit,
+            quantity,
+            order_type,
+            client_order_id,
+            [0; 8],
+            0,
+            DEFAULT_MATCH_LIMIT,
+            now_ts,
+            mango_cache.price_cache[market_index].price,
+        )?;
+
+        health_cache.update_perp_val(&mango_group, &mango_cache, &mango_account, market_index)?;
+        check_post_trade_health(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            mango_account_ai.key,
+            open_orders_ais,
+            &health_cache,
+            pre_health,
+            &[market_index],
+            false,
+        )
+    }
+
+    #[inline(never)]
+    /// Place a limit order on a perp market for each leg and only check health once at the end.
+    /// Perp only: see ExecuteRebalanceLeg for why there are no spot legs
+    fn execute_rebalance_leg(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        legs: Vec<PerpRebalanceLeg>,
+    ) -> MangoResult<()> {
+        check!(!legs.is_empty(), MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 4;
+        let (fixed_ais, open_orders_ais, leg_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            mango_cache_ai,     // read
+        ] = fixed_ais;
+        check_eq!(leg_ais.len(), 4 * legs.len(), MangoErrorCode::InvalidAccount)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        for (leg, leg_ais) in legs.iter().zip(leg_ais.chunks_exact(4)) {
+            check!(leg.price > 0, MangoErrorCode::InvalidParam)?;
+            check!(leg.quantity > 0, MangoErrorCode::InvalidParam)?;
+            let market_index = mango_group
+                .find_perp_market_index(leg_ais[0].key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+            check_eq!(market_index, leg.market_index, MangoErrorCode::InvalidMarket)?;
+        }
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+
+        let market_indexes: Vec<usize> = legs.iter().map(|leg| leg.market_index).collect();
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let (mut health_cache, pre_health) = init_perp_order_health(
+            &mango_group,
+            &mango_cache,
+            &mut mango_account,
+            owner_ai,
+            open_orders_ais,
+            &market_indexes,
+            now_ts,
+        )?;
+
+        for (leg, leg_ais) in legs.iter().zip(leg_ais.chunks_exact(4)) {
+            let [
+                perp_market_ai,     // write
+                bids_ai,            // write
+                asks_ai,            // write
+                event_queue_ai,     // write
+            ] = array_ref![leg_ais, 0, 4];
+
+            let mut perp_market =
+                PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+            let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+            let mut event_queue =
+                EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+
+            book.new_order(
+                &mut event_queue,
+                &mut perp_market,
+                &mango_group.perp_markets[leg.market_index],
+                &mut mango_account,
+                mango_account_ai.key,
+                leg.market_index,
+                leg.side,
+                leg.price,
+                leg.quantity,
+                OrderType::Limit,
+                0,
+                [0; 8],
+                0,
+                DEFAULT_MATCH_LIMIT,
+                now_ts,
+                mango_cache.price_cache[leg.market_index].price,
+            )?;
+
+            health_cache.update_perp_val(
+                &mango_group,
+                &mango_cache,
+                &mango_account,
+                leg.market_index,
+            )?;
+        }
+
+        check_post_trade_health(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            mango_account_ai.key,
+            open_orders_ais,
+            &health_cache,
+            pre_health,
+            &market_indexes,
+            false,
         )
     }
 
@@ -1456,7 +2061,7 @@ impl Processor {
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
         check!(owner_ai.is_signer, MangoErrorCode::Default)?;
-        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
 
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
@@ -1470,25 +2075,28 @@ impl Processor {
         let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
 
         let best_final = match side {
-            Side::Bid => book.get_best_bid_price().unwrap(),
-            Side::Ask => book.get_best_ask_price().unwrap(),
+            Side::Bid => book.get_best_bid_price(),
+            Side::Ask => book.get_best_ask_price(),
         };
 
         let order = book.cancel_order(order_id, side)?;
         check_eq!(&order.owner, mango_account_ai.key, MangoErrorCode::InvalidOrderId)?;
         mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
 
-        let perp_account = &mut mango_account.perp_accounts[market_index];
-        perp_account.apply_incentives(
-            &mut perp_market,
-            side,
-            order.price(),
-            order.best_initial,
-            best_final,
-            order.timestamp,
-            Clock::get()?.unix_timestamp as u64,
-            order.quantity,
-        )?;
+        // Pegged orders have no fixed price to measure against the best price
+        if !order.is_pegged() {
+            let perp_account = &mut mango_account.perp_accounts[market_index];
+            perp_account.apply_incentives(
+                &mut perp_market,
+                side,
+                order.price(),
+                order.best_initial,
+                best_final.unwrap(),
+                order.timestamp,
+                Clock::get()?.unix_timestamp as u64,
+                order.quantity,
+            )?;
+        }
 
         Ok(())
     }
@@ -1519,7 +2127,7 @@ impl Processor {
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
         check!(owner_ai.is_signer, MangoErrorCode::Default)?;
-        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
 
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
@@ -1531,23 +2139,25 @@ impl Processor {
         let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
 
         let best_final = match side {
-            Side::Bid => book.get_best_bid_price().unwrap(),
-            Side::Ask => book.get_best_ask_price().unwrap(),
+            Side::Bid => book.get_best_bid_price(),
+            Side::Ask => book.get_best_ask_price(),
         };
 
         let order = book.cancel_order(order_id, side)?;
         check_eq!(&order.owner, mango_account_ai.key, MangoErrorCode::InvalidOrderId)?;
         mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
-        mango_account.perp_accounts[market_index].apply_incentives(
-            &mut perp_market,
-            side,
-            order.price(),
-            order.best_initial,
-            best_final,
-            order.timestamp,
-            Clock::get()?.unix_timestamp as u64,
-            order.quantity,
-        )?;
+        if !order.is_pegged() {
+            mango_account.perp_accounts[market_index].apply_incentives(
+                &mut perp_market,
+                side,
+                order.price(),
+                order.best_initial,
+                best_final.unwrap(),
+                order.timestamp,
+                Clock::get()?.unix_timestamp as u64,
+                order.quantity,
+            )?;
+        }
 
         Ok(())
     }
@@ -1575,7 +2185,7 @@ impl Processor {
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
         check!(owner_ai.is_signer, MangoErrorCode::Default)?;
-        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
 
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
@@ -1586,6 +2196,92 @@ impl Processor {
         book.cancel_all_with_incentives(&mut mango_account, &mut perp_market, market_index, limit)
     }
 
+    #[inline(never)]
+    fn cancel_perp_orders_older_than(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        cutoff_ts: u64,
+        limit: u8,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        book.cancel_older_than_with_incentives(
+            &mut mango_account,
+            &mut perp_market,
+            market_index,
+            cutoff_ts,
+            limit,
+        )
+    }
+
+    #[inline(never)]
+    fn cancel_perp_orders_side(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        side: Side,
+        limit: u8,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        book.cancel_side_with_incentives(
+            &mut mango_account,
+            &mut perp_market,
+            market_index,
+            side,
+            limit,
+        )
+    }
+
     #[inline(never)]
     /// Take two MangoAccount and settle quote currency pnl between them
     fn settle_pnl(
@@ -1597,7 +2293,6 @@ impl Processor {
         //  maybe you don't allow people to withdraw if they don't have enough
         //  when liquidating, make sure you settle their pnl first?
         // TODO consider doing this in batches of 32 accounts that are close to zero sum
-        // TODO write unit tests for this function
 
         const NUM_FIXED: usize = 6;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
@@ -1658,11 +2353,14 @@ impl Processor {
         let a_pnl: I80F48 = a.quote_position - new_quote_pos_a;
         let b_pnl: I80F48 = b.quote_position - new_quote_pos_b;
 
-        // pnl must be opposite signs for there to be a settlement
-        if a_pnl * b_pnl > 0 {
+        // nothing to settle if either account is flat
+        if a_pnl.is_zero() || b_pnl.is_zero() {
             return Ok(());
         }
 
+        // pnl must be opposite signs for there to be a settlement
+        check!(a_pnl.is_negative() != b_pnl.is_negative(), MangoErrorCode::NoPnlToSettle)?;
+
         let settlement = a_pnl.abs().min(b_pnl.abs());
         let a_settle = if a_pnl > 0 { settlement } else { -settlement };
         a.transfer_quote_position(b, a_settle);
@@ -1917,10 +2615,7 @@ impl Processor {
 
         let (pre_base, pre_quote) = {
             let open_orders = load_open_orders(open_orders_ai)?;
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
+            open_orders_settleable(&open_orders)
         };
 
         if pre_base == 0 && pre_quote == 0 {
@@ -1946,10 +2641,7 @@ impl Processor {
         let (post_base, post_quote) = {
             let open_orders = load_open_orders(open_orders_ai)?;
             liqee_ma.update_basket(market_index, &open_orders)?;
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
+            open_orders_settleable(&open_orders)
         };
 
         check!(post_base <= pre_base, MangoErrorCode::Default)?;
@@ -2142,39 +2834,15 @@ impl Processor {
         let asset_bank = &mango_cache.root_bank_cache[asset_index];
         let liab_bank = &mango_cache.root_bank_cache[liab_index];
 
-        let asset_price = mango_cache.get_price(asset_index);
-        let liab_price = mango_cache.get_price(liab_index);
-
-        let (asset_fee, init_asset_weight) = if asset_index == QUOTE_INDEX {
-            (ONE_I80F48, ONE_I80F48)
-        } else {
-            let asset_info = &mango_group.spot_markets[asset_index];
-            check!(!asset_info.is_empty(), MangoErrorCode::InvalidMarket)?;
-            (ONE_I80F48 + asset_info.liquidation_fee, asset_info.init_asset_weight)
-        };
-
-        let (liab_fee, init_liab_weight) = if liab_index == QUOTE_INDEX {
-            (ONE_I80F48, ONE_I80F48)
-        } else {
-            let liab_info = &mango_group.spot_markets[liab_index];
-            check!(!liab_info.is_empty(), MangoErrorCode::InvalidMarket)?;
-            (ONE_I80F48 - liab_info.liquidation_fee, liab_info.init_liab_weight)
-        };
-
-        // Max liab transferred to reach init_health == 0
-        let deficit_max_liab: I80F48 = -init_health
-            / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee));
-
-        let native_deposits = liqee_ma.get_native_deposit(asset_bank, asset_index)?;
-        let native_borrows = liqee_ma.get_native_borrow(liab_bank, liab_index)?;
-
-        // Max liab transferred to reach asset_i == 0
-        let asset_implied_liab_transfer =
-            native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
-        let actual_liab_transfer = min(
-            min(min(deficit_max_liab, native_borrows), max_liab_transfer),
-            asset_implied_liab_transfer,
-        );
+        let (actual_liab_transfer, asset_transfer) = token_and_token_liquidation_amounts(
+            &mango_group,
+            &mango_cache,
+            &liqee_ma,
+            init_health,
+            asset_index,
+            liab_index,
+            max_liab_transfer,
+        )?;
 
         // Transfer into liqee to reduce liabilities
         checked_change_net(
@@ -2196,9 +2864,6 @@ impl Processor {
             -actual_liab_transfer,
         )?;
 
-        let asset_transfer =
-            actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
-
         // Transfer collater into liqor
         checked_change_net(
             &asset_bank,
@@ -2250,6 +2915,8 @@ impl Processor {
             }
         }
 
+        let asset_price = mango_cache.get_price(asset_index);
+        let liab_price = mango_cache.get_price(liab_index);
         msg!(
             "liquidate_token_and_token details: {{ \
             \"asset_index\": {}, \
@@ -2515,11 +3182,11 @@ impl Processor {
 
             let native_deposits = liqee_ma.get_native_deposit(bank_cache, asset_index)?;
 
-            // Max liab transferred to reach init_health == 0
+            // Max liab transferred to reach init_health == liquidation_target_health
             let deficit_max_liab = if asset_index == QUOTE_INDEX {
                 native_deposits
             } else {
-                -init_health
+                (mango_group.liquidation_target_health - init_health)
                     / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee))
             };
 
@@ -2598,7 +3265,7 @@ impl Processor {
 
             let native_borrows = liqee_ma.get_native_borrow(bank_cache, liab_index)?;
 
-            let deficit_max_liab: I80F48 = -init_health
+            let deficit_max_liab: I80F48 = (mango_group.liquidation_target_health - init_health)
                 / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee));
 
             // Max liab transferred to reach asset_i == 0
@@ -2719,7 +3386,7 @@ impl Processor {
         // position is transfered to the liqor at favorable rate
         check!(base_transfer_request != 0, MangoErrorCode::InvalidParam)?;
         const NUM_FIXED: usize = 7;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
+        let (accounts, pnl_tracker_ais) = array_refs![accounts, NUM_FIXED + 2 * MAX_PAIRS; ..;];
         let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
             array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
 
@@ -2750,9 +3417,16 @@ impl Processor {
 
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
-        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::PerpMarketMismatch))?;
         let pmi = &mango_group.perp_markets[market_index];
         check!(!pmi.is_empty(), MangoErrorCode::InvalidMarket)?;
+        check_eq!(
+            event_queue_ai.key,
+            &perp_market.event_queue,
+            MangoErrorCode::PerpMarketMismatch
+        )?;
         let mut event_queue: EventQueue =
             EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
@@ -2809,7 +3483,8 @@ impl Processor {
 
             let health_per_lot =
                 lot_price * (ONE_I80F48 - pmi.init_asset_weight - pmi.liquidation_fee);
-            let max_transfer = -init_health / health_per_lot;
+            let max_transfer =
+                (mango_group.liquidation_target_health - init_health) / health_per_lot;
             let max_transfer: i64 = max_transfer.checked_ceil().unwrap().to_num();
 
             let base_transfer =
@@ -2826,7 +3501,8 @@ impl Processor {
 
             let health_per_lot =
                 lot_price * (ONE_I80F48 - pmi.init_liab_weight + pmi.liquidation_fee);
-            let max_transfer = -init_health / health_per_lot;
+            let max_transfer =
+                (mango_group.liquidation_target_health - init_health) / health_per_lot;
             let max_transfer: i64 = max_transfer.checked_floor().unwrap().to_num();
 
             let base_transfer =
@@ -2838,6 +3514,29 @@ impl Processor {
             (base_transfer, quote_transfer)
         };
 
+        if let Some(mut tracker) =
+            load_pnl_tracker(pnl_tracker_ais, program_id, liqee_mango_account_ai.key, &liqee_ma)
+        {
+            tracker.record_trade(
+                market_index,
+                liqee_ma.perp_accounts[market_index].base_position,
+                -base_transfer,
+                -quote_transfer,
+            );
+        }
+        if let Some(mut tracker) =
+            load_pnl_tracker(pnl_tracker_ais, program_id, liqor_mango_account_ai.key, &liqor_ma)
+        {
+            tracker.record_trade(
+                market_index,
+                liqor_ma.perp_accounts[market_index].base_position,
+                base_transfer,
+                quote_transfer,
+            );
+        }
+
+        let liqee_perp_account = &mut liqee_ma.perp_accounts[market_index];
+        let liqor_perp_account = &mut liqor_ma.perp_accounts[market_index];
         liqee_perp_account.change_base_position(&mut perp_market, -base_transfer);
         liqor_perp_account.change_base_position(&mut perp_market, base_transfer);
 
@@ -3119,6 +3818,8 @@ impl Processor {
 
         // Make sure there actually exist liabs here
         check!(liqee_ma.borrows[liab_index].is_positive(), MangoErrorCode::Default)?;
+        // Collateral that reappeared since the account went bankrupt must be liquidated first
+        liqee_ma.check_insolvent(&mango_group, &mango_cache)?;
         let liab_price = mango_cache.get_price(liab_index);
         let liab_fee = if liab_index == QUOTE_INDEX {
             ONE_I80F48
@@ -3238,6 +3939,228 @@ impl Processor {
         Ok(())
     }
 
+    #[inline(never)]
+    fn init_loss_recovery(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            admin_ai,           // write, signer
+            root_bank_ai,       // read
+            loss_recovery_ai,   // write
+            recovery_vault_ai,  // read
+            system_prog_ai,     // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check_eq!(
+            system_prog_ai.key,
+            &solana_program::system_program::ID,
+            MangoErrorCode::InvalidProgramId
+        )?;
+
+        let token_index = mango_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
+        let (loss_recovery_pk, bump) =
+            LossRecovery::find_address(program_id, mango_group_ai.key, token_index);
+        check_eq!(loss_recovery_ai.key, &loss_recovery_pk, MangoErrorCode::InvalidAccount)?;
+        check!(loss_recovery_ai.data_is_empty(), MangoErrorCode::InvalidAccount)?;
+
+        let recovery_vault = Account::unpack(&recovery_vault_ai.try_borrow_data()?)?;
+        check!(recovery_vault.is_initialized(), MangoErrorCode::InvalidVault)?;
+        check!(recovery_vault.delegate.is_none(), MangoErrorCode::InvalidVault)?;
+        check!(recovery_vault.close_authority.is_none(), MangoErrorCode::InvalidVault)?;
+        check_eq!(recovery_vault.owner, mango_group.signer_key, MangoErrorCode::InvalidVault)?;
+        check_eq!(
+            recovery_vault.mint,
+            mango_group.tokens[token_index].mint,
+            MangoErrorCode::InvalidVault
+        )?;
+        check_eq!(recovery_vault_ai.owner, &spl_token::ID, MangoErrorCode::InvalidVault)?;
+
+        let token_index_bytes = (token_index as u64).to_le_bytes();
+        let seeds: &[&[u8]] =
+            &[mango_group_ai.key.as_ref(), LOSS_RECOVERY_SEED, &token_index_bytes, &[bump]];
+        invoke_create_account(
+            system_prog_ai,
+            admin_ai,
+            loss_recovery_ai,
+            program_id,
+            size_of::<LossRecovery>(),
+            &[seeds],
+        )?;
+
+        let mut loss_recovery = LossRecovery::load_mut(loss_recovery_ai)?;
+        loss_recovery.meta_data = MetaData::new(DataType::LossRecovery, 0, true);
+        loss_recovery.mango_group = *mango_group_ai.key;
+        loss_recovery.token_index = token_index;
+        loss_recovery.vault = *recovery_vault_ai.key;
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn register_loss_share(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            owner_ai,           // write, signer
+            root_bank_ai,       // read
+            loss_recovery_ai,   // write
+            loss_share_ai,      // write
+            system_prog_ai,     // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check_eq!(
+            system_prog_ai.key,
+            &solana_program::system_program::ID,
+            MangoErrorCode::InvalidProgramId
+        )?;
+
+        let mut loss_recovery =
+            LossRecovery::load_mut_checked(loss_recovery_ai, program_id, mango_group_ai.key)?;
+        let token_index = loss_recovery.token_index;
+        check_eq!(
+            root_bank_ai.key,
+            &mango_group.tokens[token_index].root_bank,
+            MangoErrorCode::InvalidRootBank
+        )?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+
+        if loss_share_ai.data_is_empty() {
+            let (loss_share_pk, bump) =
+                LossShare::find_address(program_id, loss_recovery_ai.key, mango_account_ai.key);
+            check_eq!(loss_share_ai.key, &loss_share_pk, MangoErrorCode::InvalidAccount)?;
+
+            let seeds: &[&[u8]] = &[
+                loss_recovery_ai.key.as_ref(),
+                LOSS_SHARE_SEED,
+                mango_account_ai.key.as_ref(),
+                &[bump],
+            ];
+            invoke_create_account(
+                system_prog_ai,
+                owner_ai,
+                loss_share_ai,
+                program_id,
+                size_of::<LossShare>(),
+                &[seeds],
+            )?;
+
+            let mut loss_share = LossShare::load_mut(loss_share_ai)?;
+            loss_share.meta_data = MetaData::new(DataType::LossShare, 0, true);
+            loss_share.loss_recovery = *loss_recovery_ai.key;
+            loss_share.mango_account = *mango_account_ai.key;
+        }
+
+        let mut loss_share =
+            LossShare::load_mut_checked(loss_share_ai, program_id, loss_recovery_ai.key)?;
+        check_eq!(&loss_share.mango_account, mango_account_ai.key, MangoErrorCode::InvalidAccount)?;
+
+        loss_recovery.register(&mut loss_share, mango_account.deposits[token_index], &root_bank)?;
+
+        msg!(
+            "LossShareRegistered details: {{ \"mango_account\": {}, \"token_index\": {}, \"share\": {} }}",
+            mango_account_ai.key,
+            token_index,
+            loss_share.share.to_num::<f64>()
+        );
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn claim_loss_recovery(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 12;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            mango_cache_ai,     // read
+            root_bank_ai,       // read
+            node_bank_ai,       // write
+            vault_ai,           // write
+            token_prog_ai,      // read
+            loss_recovery_ai,   // write
+            loss_share_ai,      // write
+            recovery_vault_ai,  // write
+            signer_ai,          // read
+        ] = accounts;
+        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check_eq!(signer_ai.key, &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+
+        let mut loss_recovery =
+            LossRecovery::load_mut_checked(loss_recovery_ai, program_id, mango_group_ai.key)?;
+        let token_index = loss_recovery.token_index;
+        check_eq!(recovery_vault_ai.key, &loss_recovery.vault, MangoErrorCode::InvalidVault)?;
+        let mut loss_share =
+            LossShare::load_mut_checked(loss_share_ai, program_id, loss_recovery_ai.key)?;
+        check_eq!(&loss_share.mango_account, mango_account_ai.key, MangoErrorCode::InvalidAccount)?;
+
+        check_eq!(
+            root_bank_ai.key,
+            &mango_group.tokens[token_index].root_bank,
+            MangoErrorCode::InvalidRootBank
+        )?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::InvalidNodeBank)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+        check_eq!(&node_bank.vault, vault_ai.key, MangoErrorCode::InvalidVault)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let root_bank_cache = &mango_cache.root_bank_cache[token_index];
+        check!(
+            now_ts <= root_bank_cache.last_update + mango_group.valid_interval,
+            MangoErrorCode::InvalidCache
+        )?;
+
+        let recovery_vault = Account::unpack(&recovery_vault_ai.try_borrow_data()?)?;
+        let quantity = loss_recovery.claim(&mut loss_share, recovery_vault.amount)?;
+
+        let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+        invoke_transfer(
+            token_prog_ai,
+            recovery_vault_ai,
+            vault_ai,
+            signer_ai,
+            &[&signers_seeds],
+            quantity,
+        )?;
+
+        msg!(
+            "LossRecoveryClaim details: {{ \"mango_account\": {}, \"token_index\": {}, \"quantity\": {} }}",
+            mango_account_ai.key,
+            token_index,
+            quantity
+        );
+
+        checked_change_net(
+            root_bank_cache,
+            &mut node_bank,
+            &mut mango_account,
+            mango_account_ai.key,
+            token_index,
+            I80F48::from_num(quantity),
+        )
+    }
+
     #[inline(never)]
     /// *** Keeper Related Instructions ***
     /// Update the deposit and borrow index on a passed in RootBank
@@ -3254,6 +4177,64 @@ impl Processor {
         let mut mango_cache =
             MangoCache::load_mut_checked(mango_cache_ai, program_id, &mango_group)?;
 
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+        Self::refresh_root_bank(
+            program_id,
+            &mango_group,
+            &mut mango_cache,
+            root_bank_ai,
+            node_bank_ais,
+            now_ts,
+        )
+    }
+
+    #[inline(never)]
+    /// Update the indexes of several root banks at once. After the group and cache, the
+    /// accounts are one group per root bank: the root bank followed by all of its
+    /// num_node_banks node banks
+    fn update_root_banks(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_accounts, mut bank_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai, // read
+            mango_cache_ai, // write
+        ] = fixed_accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mut mango_cache =
+            MangoCache::load_mut_checked(mango_cache_ai, program_id, &mango_group)?;
+        check!(!bank_ais.is_empty(), MangoErrorCode::InvalidParam)?;
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+        while let Some((root_bank_ai, rest)) = bank_ais.split_first() {
+            let num_node_banks = RootBank::load_checked(root_bank_ai, program_id)?.num_node_banks;
+            check!(rest.len() >= num_node_banks, MangoErrorCode::InvalidAccount)?;
+            let (node_bank_ais, rest) = rest.split_at(num_node_banks);
+            Self::refresh_root_bank(
+                program_id,
+                &mango_group,
+                &mut mango_cache,
+                root_bank_ai,
+                node_bank_ais,
+                now_ts,
+            )?;
+            bank_ais = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Accrue interest on the root bank from its node banks and copy the new indexes to the cache
+    fn refresh_root_bank(
+        program_id: &Pubkey,
+        mango_group: &MangoGroup,
+        mango_cache: &mut MangoCache,
+        root_bank_ai: &AccountInfo,
+        node_bank_ais: &[AccountInfo],
+        now_ts: u64,
+    ) -> MangoResult<()> {
         let index = mango_group
             .find_root_bank_index(root_bank_ai.key)
             .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
@@ -3267,8 +4248,6 @@ impl Processor {
                 MangoErrorCode::InvalidNodeBank
             )?;
         }
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
         root_bank.update_index(node_bank_ais, program_id, now_ts)?;
 
         mango_cache.root_bank_cache[index] = RootBankCache {
@@ -3314,6 +4293,11 @@ impl Processor {
         let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        check_eq!(
+            event_queue_ai.key,
+            &perp_market.event_queue,
+            MangoErrorCode::EventQueueMismatch
+        )?;
         let mut event_queue: EventQueue =
             EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
@@ -3336,6 +4320,7 @@ impl Processor {
                     let fill: &FillEvent = cast_ref(event);
 
                     // handle self trade separately because of rust borrow checker
+                    let referrer_pk;
                     if fill.maker == fill.taker {
                         let mut ma = match mango_account_ais.iter().find(|ai| ai.key == &fill.maker)
                         {
@@ -3349,8 +4334,26 @@ impl Processor {
                                 mango_group_ai.key,
                             )?,
                         };
+                        let mut tracker =
+                            load_pnl_tracker(mango_account_ais, program_id, &fill.maker, &ma);
+                        let maker_side = invert_side(fill.taker_side);
+                        if let Some(tracker) = tracker.as_mut() {
+                            let pa = &ma.perp_accounts[market_index];
+                            tracker.record_fill(market_index, pa, &perp_market, fill, maker_side);
+                        }
                         ma.execute_maker(market_index, &mut perp_market, info, cache, fill)?;
+                        if let Some(tracker) = tracker.as_mut() {
+                            let pa = &ma.perp_accounts[market_index];
+                            tracker.record_fill(
+                                market_index,
+                                pa,
+                                &perp_market,
+                                fill,
+                                fill.taker_side,
+                            );
+                        }
                         ma.execute_taker(market_index, &mut perp_market, info, cache, fill)?;
+                        referrer_pk = ma.referrer;
                     } else {
                         let mut maker =
                             match mango_account_ais.iter().find(|ai| ai.key == &fill.maker) {
@@ -3377,37 +4380,43 @@ impl Processor {
                                 )?,
                             };
 
+                        if let Some(mut tracker) =
+                            load_pnl_tracker(mango_account_ais, program_id, &fill.maker, &maker)
+                        {
+                            let pa = &maker.perp_accounts[market_index];
+                            let maker_side = invert_side(fill.taker_side);
+                            tracker.record_fill(market_index, pa, &perp_market, fill, maker_side);
+                        }
+                        if let Some(mut tracker) =
+                            load_pnl_tracker(mango_account_ais, program_id, &fill.taker, &taker)
+                        {
+                            let pa = &taker.perp_accounts[market_index];
+                            tracker.record_fill(
+                                market_index,
+                                pa,
+                                &perp_market,
+                                fill,
+                                fill.taker_side,
+                            );
+                        }
+
                         maker.execute_maker(market_index, &mut perp_market, info, cache, fill)?;
                         taker.execute_taker(market_index, &mut perp_market, info, cache, fill)?;
+                        referrer_pk = taker.referrer;
+                    }
+
+                    // Loaded only now since the referrer may be the maker of the fill
+                    if let Some(mut referrer) = load_referrer(
+                        mango_account_ais,
+                        program_id,
+                        mango_group_ai.key,
+                        &referrer_pk,
+                    ) {
+                        referrer.execute_referrer(market_index, &mut perp_market, info, fill)?;
                     }
 
                     // TODO OPT remove this log if we start hitting compute limits
-                    msg!(
-                        "FillEvent details: {{ \
-                            \"timestamp\": {}, \
-                            \"seq_num\": {}, \
-                            \"maker\": {}, \
-                            \"taker\": {}, \
-                            \"taker_side\": {}, \
-                            \"maker_order_id\": {}, \
-                            \"taker_order_id\": {}, \
-                            \"maker_fee\": {}, \
-                            \"taker_fee\": {}, \
-                            \"price\": {}, \
-                            \"quantity\": {} \
-                            }}",
-                        fill.timestamp,
-                        fill.seq_num,
-                        fill.maker.to_string(),
-                        fill.taker.to_string(),
-                        if fill.taker_side == Side::Bid { "bid" } else { "sell" },
-                        fill.maker_order_id,
-                        fill.taker_order_id,
-                        fill.maker_fee.to_num::<f64>(),
-                        fill.taker_fee.to_num::<f64>(),
-                        fill.price,
-                        fill.quantity
-                    );
+                    msg!("{}", fill_event_details(fill));
                 }
                 EventType::Out => {
                     let out: &OutEvent = cast_ref(event);
@@ -3441,14 +4450,15 @@ impl Processor {
     /// Update the `funding_earned` of a `PerpMarket` using the current book price, spot index price
     /// and time since last update
     fn update_funding(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
-        const NUM_FIXED: usize = 5;
+        const NUM_FIXED: usize = 6;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            mango_group_ai,     // read
-            mango_cache_ai,     // read
-            perp_market_ai,     // write
-            bids_ai,            // read
-            asks_ai,            // read
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            perp_market_ai,         // write
+            bids_ai,                // read
+            asks_ai,                // read
+            perp_market_config_ai,  // read
         ] = accounts;
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
@@ -3459,13 +4469,25 @@ impl Processor {
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
 
         let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let impact_quote_notional = PerpMarketConfig::load_impact_quote_notional(
+            perp_market_config_ai,
+            program_id,
+            perp_market_ai.key,
+        )?;
 
         let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
         let clock = Clock::get()?;
         let now_ts = clock.unix_timestamp as u64;
 
-        perp_market.update_funding(&mango_group, &book, &mango_cache, market_index, now_ts)?;
+        perp_market.update_funding(
+            &mango_group,
+            &book,
+            &mango_cache,
+            market_index,
+            impact_quote_notional,
+            now_ts,
+        )?;
 
         msg!(
             "{{\"long_funding\":{}, \"short_funding\":{}}}",
@@ -3476,6 +4498,68 @@ impl Processor {
         Ok(())
     }
 
+    #[inline(never)]
+    /// Update funding for several perp markets in one instruction, so a keeper doesn't need an
+    /// UpdateFunding per market
+    fn update_all_funding(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_ais, perp_market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,     // read
+            mango_cache_ai,     // read
+        ] = fixed_ais;
+        check!(perp_market_ais.len() % 4 == 0, MangoErrorCode::InvalidAccount)?;
+        check!(
+            perp_market_ais.len() / 4 <= MAX_UPDATE_ALL_FUNDING_MARKETS,
+            MangoErrorCode::InvalidParam
+        )?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+
+        for market_ais in perp_market_ais.chunks_exact(4) {
+            let [
+                perp_market_ai,         // write
+                bids_ai,                // read
+                asks_ai,                // read
+                perp_market_config_ai,  // read
+            ] = array_ref![market_ais, 0, 4];
+
+            let mut perp_market =
+                PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+            let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+            let impact_quote_notional = PerpMarketConfig::load_impact_quote_notional(
+                perp_market_config_ai,
+                program_id,
+                perp_market_ai.key,
+            )?;
+            let market_index = mango_group
+                .find_perp_market_index(perp_market_ai.key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+            perp_market.update_funding(
+                &mango_group,
+                &book,
+                &mango_cache,
+                market_index,
+                impact_quote_notional,
+                now_ts,
+            )?;
+
+            msg!(
+                "{{\"market_index\":{}, \"long_funding\":{}, \"short_funding\":{}}}",
+                market_index,
+                perp_market.long_funding.to_num::<f64>(),
+                perp_market.short_funding.to_num::<f64>()
+            );
+        }
+
+        Ok(())
+    }
+
     #[inline(never)]
     /// Settle the mngo_accrued in a PerpAccount for MNGO tokens
     fn redeem_mngo(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
@@ -3548,6 +4632,8 @@ impl Processor {
             MangoErrorCode::InvalidCache
         )?;
 
+        // No health check is needed here since this only adds collateral. Health checks in later
+        // instructions build their HealthCache from deposits, so they see this MNGO right away
         checked_change_net(
             mngo_bank_cache,
             &mut mngo_node_bank,
@@ -3582,105 +4668,1396 @@ impl Processor {
     }
 
     #[inline(never)]
-    fn deposit_msrm(
+    fn set_referrer_memory(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        quantity: u64,
+        _referrer_id: [u8; INFO_LEN],
     ) -> MangoResult<()> {
-        const NUM_FIXED: usize = 6;
+        const NUM_FIXED: usize = 4;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
             mango_group_ai,     // read
             mango_account_ai,   // write
             owner_ai,           // read, signer
-            msrm_account_ai,    // write
-            msrm_vault_ai,      // write
-            token_prog_ai,      // read
+            referrer_ai,        // read
         ] = accounts;
-        check!(token_prog_ai.key == &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
-
-        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        check!(msrm_vault_ai.key == &mango_group.msrm_vault, MangoErrorCode::InvalidVault)?;
 
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
         check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(referrer_ai.key != mango_account_ai.key, MangoErrorCode::InvalidParam)?;
+
+        // An owner referring its own accounts would just get a discount on its taker fees
+        let referrer = MangoAccount::load_checked(referrer_ai, program_id, mango_group_ai.key)?;
+        check!(referrer.owner != mango_account.owner, MangoErrorCode::InvalidParam)?;
+
+        mango_account.referrer = *referrer_ai.key;
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn deposit_msrm(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            msrm_account_ai,    // write
+            msrm_vault_ai,      // write
+            token_prog_ai,      // read
+        ] = accounts;
+        check!(token_prog_ai.key == &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(msrm_vault_ai.key == &mango_group.msrm_vault, MangoErrorCode::InvalidVault)?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+
+        invoke_transfer(token_prog_ai, msrm_account_ai, msrm_vault_ai, owner_ai, &[], quantity)?;
+
+        mango_account.msrm_amount += quantity;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn withdraw_msrm(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            msrm_account_ai,    // write
+            msrm_vault_ai,      // write
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = accounts;
+        check!(token_prog_ai.key == &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(msrm_vault_ai.key == &mango_group.msrm_vault, MangoErrorCode::InvalidVault)?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+
+        check!(mango_account.msrm_amount >= quantity, MangoErrorCode::InsufficientFunds)?;
+
+        let signer_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+        invoke_transfer(
+            token_prog_ai,
+            msrm_vault_ai,
+            msrm_account_ai,
+            signer_ai,
+            &[&signer_seeds],
+            quantity,
+        )?;
+
+        mango_account.msrm_amount -= quantity;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn transfer_msrm(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // read
+            src_mango_account_ai,   // write
+            dst_mango_account_ai,   // write
+            owner_ai,               // read, signer
+        ] = accounts;
+        check!(
+            src_mango_account_ai.key != dst_mango_account_ai.key,
+            MangoErrorCode::InvalidAccount
+        )?;
+
+        MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut src_ma =
+            MangoAccount::load_mut_checked(src_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&src_ma.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+
+        let mut dst_ma =
+            MangoAccount::load_mut_checked(dst_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&dst_ma.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+
+        check!(src_ma.msrm_amount >= quantity, MangoErrorCode::InsufficientFunds)?;
+        dst_ma.msrm_amount = dst_ma.msrm_amount.checked_add(quantity).ok_or(math_err!())?;
+        src_ma.msrm_amount -= quantity;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_group_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            new_admin_ai,       // read
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        mango_group.admin = *new_admin_ai.key;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_liquidation_target_health(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        liquidation_target_health: I80F48,
+    ) -> MangoResult<()> {
+        check!(!liquidation_target_health.is_negative(), MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        mango_group.liquidation_target_health = liquidation_target_health;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set only the maker and taker fees of a perp market
+    fn set_perp_market_fees(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        maker_fee: I80F48,
+        taker_fee: I80F48,
+    ) -> MangoResult<()> {
+        // Fees are a fraction of the notional; a maker rebate may not exceed the taker fee
+        check!(!taker_fee.is_negative() && taker_fee < ONE_I80F48, MangoErrorCode::InvalidParam)?;
+        check!(maker_fee < ONE_I80F48, MangoErrorCode::InvalidParam)?;
+        check!(maker_fee + taker_fee >= ZERO_I80F48, MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            perp_market_ai,     // read
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        let info = &mut mango_group.perp_markets[market_index];
+        info.maker_fee = maker_fee;
+        info.taker_fee = taker_fee;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Log who controls a MangoAccount so integrators can verify it on chain
+    fn get_account_authority(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+        ] = accounts;
+
+        MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        msg!(
+            "AccountAuthority details: {{ \
+                \"mango_account\": {}, \
+                \"owner\": {}, \
+                \"delegate\": {} \
+                }}",
+            mango_account_ai.key.to_string(),
+            mango_account.owner.to_string(),
+            mango_account.delegate.to_string()
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Log the pnl a MangoAccount has realized on a perp market
+    fn get_realized_pnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            perp_market_ai,     // read
+            pnl_tracker_ai,     // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let _mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        let pnl_tracker =
+            PnlTracker::load_checked(pnl_tracker_ai, program_id, mango_account_ai.key)?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        msg!(
+            "RealizedPnl details: {{ \
+                \"mango_account\": {}, \
+                \"market_index\": {}, \
+                \"realized_pnl\": {} \
+                }}",
+            mango_account_ai.key.to_string(),
+            market_index,
+            pnl_tracker.realized_pnl[market_index].to_num::<f64>()
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Create the PnlTracker of a MangoAccount. The account can't have perp positions yet, so
+    /// the entries start out matching them
+    fn init_pnl_tracker(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // write, signer
+            pnl_tracker_ai,     // write
+            system_prog_ai,     // read
+        ] = accounts;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(owner_ai.key, &mango_account.owner, MangoErrorCode::InvalidOwner)?;
+        check!(!mango_account.has_pnl_tracker, MangoErrorCode::InvalidAccount)?;
+        check!(
+            mango_account
+                .perp_accounts
+                .iter()
+                .all(|pa| pa.base_position == 0 && pa.taker_base == 0),
+            MangoErrorCode::InvalidAccount
+        )?;
+
+        let (pnl_tracker_pk, bump) = PnlTracker::find_address(program_id, mango_account_ai.key);
+        check_eq!(pnl_tracker_ai.key, &pnl_tracker_pk, MangoErrorCode::InvalidAccount)?;
+        let seeds: &[&[u8]] = &[mango_account_ai.key.as_ref(), PNL_TRACKER_SEED, &[bump]];
+        invoke_create_account(
+            system_prog_ai,
+            owner_ai,
+            pnl_tracker_ai,
+            program_id,
+            size_of::<PnlTracker>(),
+            &[seeds],
+        )?;
+
+        let mut pnl_tracker = PnlTracker::load_mut(pnl_tracker_ai)?;
+        pnl_tracker.meta_data = MetaData::new(DataType::PnlTracker, 0, true);
+        pnl_tracker.mango_account = *mango_account_ai.key;
+        mango_account.has_pnl_tracker = true;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn get_position_history_anchor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            mango_cache_ai,     // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+
+        msg!(
+            "{}",
+            position_history_anchor(
+                mango_account_ai.key,
+                &mango_group,
+                &mango_cache,
+                &mango_account,
+                Clock::get()?.slot
+            )
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn has_unsettled_pnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            mango_cache_ai,     // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+        let active_assets = UserActiveAssets::new(&mango_group, &mango_account, vec![]);
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+
+        msg!(
+            "HasUnsettledPnl details: {{ \
+                \"mango_account\": {}, \
+                \"has_unsettled_pnl\": {} \
+                }}",
+            mango_account_ai.key.to_string(),
+            has_unsettled_pnl(&mango_group, &mango_cache, &mango_account)
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the per-tx withdrawal cap of a token, creating the MangoAccount's WithdrawCaps on
+    /// first use
+    fn set_max_withdraw_per_tx(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        token_index: usize,
+        max_withdraw: u64,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // write, signer
+            withdraw_caps_ai,   // write
+            system_prog_ai,     // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
+        check!(
+            token_index < MAX_TOKENS && !mango_group.tokens[token_index].is_empty(),
+            MangoErrorCode::InvalidToken
+        )?;
+
+        let (withdraw_caps_pk, bump) = WithdrawCaps::find_address(program_id, mango_account_ai.key);
+        check_eq!(withdraw_caps_ai.key, &withdraw_caps_pk, MangoErrorCode::InvalidAccount)?;
+        if withdraw_caps_ai.data_is_empty() {
+            let seeds: &[&[u8]] = &[mango_account_ai.key.as_ref(), WITHDRAW_CAPS_SEED, &[bump]];
+            invoke_create_account(
+                system_prog_ai,
+                owner_ai,
+                withdraw_caps_ai,
+                program_id,
+                size_of::<WithdrawCaps>(),
+                &[seeds],
+            )?;
+
+            let mut withdraw_caps = WithdrawCaps::load_mut(withdraw_caps_ai)?;
+            withdraw_caps.meta_data = MetaData::new(DataType::WithdrawCaps, 0, true);
+            withdraw_caps.mango_account = *mango_account_ai.key;
+            mango_account.has_withdraw_caps = true;
+        }
+
+        let mut withdraw_caps =
+            WithdrawCaps::load_mut_checked(withdraw_caps_ai, program_id, mango_account_ai.key)?;
+        withdraw_caps.max_withdraw_per_tx[token_index] = max_withdraw;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_min_mngo_to_trade(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_mngo_to_trade: u64,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        mango_group.min_mngo_to_trade = min_mngo_to_trade;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn rebalance_node_banks(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 9;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            root_bank_ai,       // read
+            src_node_bank_ai,   // write
+            src_vault_ai,       // write
+            dst_node_bank_ai,   // write
+            dst_vault_ai,       // write
+            admin_ai,           // read, signer
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = accounts;
+        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+        check!(quantity > 0, MangoErrorCode::InvalidParam)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        mango_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        check!(src_node_bank_ai.key != dst_node_bank_ai.key, MangoErrorCode::InvalidParam)?;
+        check!(
+            root_bank.node_banks.contains(src_node_bank_ai.key),
+            MangoErrorCode::InvalidNodeBank
+        )?;
+        check!(
+            root_bank.node_banks.contains(dst_node_bank_ai.key),
+            MangoErrorCode::InvalidNodeBank
+        )?;
+
+        let mut src_node_bank = NodeBank::load_mut_checked(src_node_bank_ai, program_id)?;
+        let mut dst_node_bank = NodeBank::load_mut_checked(dst_node_bank_ai, program_id)?;
+        check_eq!(&src_node_bank.vault, src_vault_ai.key, MangoErrorCode::InvalidVault)?;
+        check_eq!(&dst_node_bank.vault, dst_vault_ai.key, MangoErrorCode::InvalidVault)?;
+
+        src_node_bank.checked_move_deposits(
+            &mut dst_node_bank,
+            &root_bank,
+            I80F48::from_num(quantity),
+        )?;
+
+        let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+        invoke_transfer(
+            token_prog_ai,
+            src_vault_ai,
+            dst_vault_ai,
+            signer_ai,
+            &[&signers_seeds],
+            quantity,
+        )?;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_withdraw_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        withdraw_fee_bps: u16,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(withdraw_fee_bps <= 10_000, MangoErrorCode::InvalidParam)?;
+
+        mango_group.withdraw_fee_bps = withdraw_fee_bps;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_group_name(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        name: [u8; GROUP_NAME_LEN],
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            admin_ai,           // write, signer
+            group_name_ai,      // write
+            system_prog_ai,     // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        parse_group_name(&name)?;
+
+        if group_name_ai.data_is_empty() {
+            let (group_name_pk, bump) = GroupName::find_address(program_id, mango_group_ai.key);
+            check_eq!(group_name_ai.key, &group_name_pk, MangoErrorCode::InvalidAccount)?;
+            check_eq!(
+                system_prog_ai.key,
+                &solana_program::system_program::ID,
+                MangoErrorCode::InvalidProgramId
+            )?;
+
+            let seeds: &[&[u8]] = &[mango_group_ai.key.as_ref(), GROUP_NAME_SEED, &[bump]];
+            invoke_create_account(
+                system_prog_ai,
+                admin_ai,
+                group_name_ai,
+                program_id,
+                size_of::<GroupName>(),
+                &[seeds],
+            )?;
+
+            let mut group_name = GroupName::load_mut(group_name_ai)?;
+            group_name.meta_data = MetaData::new(DataType::GroupName, 0, true);
+            group_name.mango_group = *mango_group_ai.key;
+        }
+
+        let mut group_name =
+            GroupName::load_mut_checked(group_name_ai, program_id, mango_group_ai.key)?;
+        group_name.name = name;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_perp_impact_depth(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quote_notional: i64,
+    ) -> MangoResult<()> {
+        check!(quote_notional >= 0, MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // read
+            perp_market_ai,         // read
+            admin_ai,               // write, signer
+            perp_market_config_ai,  // write
+            system_prog_ai,         // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+
+        if perp_market_config_ai.data_is_empty() {
+            let (config_pk, bump) = PerpMarketConfig::find_address(program_id, perp_market_ai.key);
+            check_eq!(perp_market_config_ai.key, &config_pk, MangoErrorCode::InvalidAccount)?;
+            check_eq!(
+                system_prog_ai.key,
+                &solana_program::system_program::ID,
+                MangoErrorCode::InvalidProgramId
+            )?;
+
+            let seeds: &[&[u8]] = &[perp_market_ai.key.as_ref(), PERP_MARKET_CONFIG_SEED, &[bump]];
+            invoke_create_account(
+                system_prog_ai,
+                admin_ai,
+                perp_market_config_ai,
+                program_id,
+                size_of::<PerpMarketConfig>(),
+                &[seeds],
+            )?;
+
+            let mut config = PerpMarketConfig::load_mut(perp_market_config_ai)?;
+            config.meta_data = MetaData::new(DataType::PerpMarketConfig, 0, true);
+            config.perp_market = *perp_market_ai.key;
+        }
+
+        let mut config = PerpMarketConfig::load_mut_checked(
+            perp_market_config_ai,
+            program_id,
+            perp_market_ai.key,
+        )?;
+        config.impact_quote_notional = quote_notional;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn add_to_deposit_whitelist(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        owner: Pubkey,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // read
+            admin_ai,               // write, signer
+            deposit_whitelist_ai,   // write
+            system_prog_ai,         // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        if deposit_whitelist_ai.data_is_empty() {
+            let (deposit_whitelist_pk, bump) =
+                DepositWhitelist::find_address(program_id, mango_group_ai.key);
+            check_eq!(
+                deposit_whitelist_ai.key,
+                &deposit_whitelist_pk,
+                MangoErrorCode::InvalidAccount
+            )?;
+            check_eq!(
+                system_prog_ai.key,
+                &solana_program::system_program::ID,
+                MangoErrorCode::InvalidProgramId
+            )?;
+
+            let seeds: &[&[u8]] = &[mango_group_ai.key.as_ref(), DEPOSIT_WHITELIST_SEED, &[bump]];
+            invoke_create_account(
+                system_prog_ai,
+                admin_ai,
+                deposit_whitelist_ai,
+                program_id,
+                size_of::<DepositWhitelist>(),
+                &[seeds],
+            )?;
+
+            let mut deposit_whitelist = DepositWhitelist::load_mut(deposit_whitelist_ai)?;
+            deposit_whitelist.meta_data = MetaData::new(DataType::DepositWhitelist, 0, true);
+            deposit_whitelist.mango_group = *mango_group_ai.key;
+        }
+
+        let mut deposit_whitelist = DepositWhitelist::load_mut_checked(
+            deposit_whitelist_ai,
+            program_id,
+            mango_group_ai.key,
+        )?;
+        deposit_whitelist.add(&owner)
+    }
+
+    #[inline(never)]
+    fn remove_from_deposit_whitelist(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        owner: Pubkey,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // read
+            admin_ai,               // read, signer
+            deposit_whitelist_ai,   // write
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        let mut deposit_whitelist = DepositWhitelist::load_mut_checked(
+            deposit_whitelist_ai,
+            program_id,
+            mango_group_ai.key,
+        )?;
+        deposit_whitelist.remove(&owner)
+    }
+
+    #[inline(never)]
+    fn set_deposit_whitelist_enabled(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        enabled: bool,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        mango_group.deposit_whitelist_enabled = enabled;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_post_trade_risk_log_enabled(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        enabled: bool,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        mango_group.post_trade_risk_log_enabled = enabled;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_max_oracle_age(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_oracle_age: u32,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // write
+            oracle_ai,          // read
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        let oracle_index = mango_group
+            .find_oracle_index(oracle_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidParam))?;
+        mango_group.tokens[oracle_index].max_oracle_age = max_oracle_age;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Log the bid-ask spread of a perp market in basis points
+    fn get_perp_spread(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            perp_market_ai,     // read
+            bids_ai,            // read
+            asks_ai,            // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+
+        let spread_bps = match perp_spread_bps(book.bids(), book.asks()) {
+            Some(spread_bps) => spread_bps.to_num::<f64>().to_string(),
+            None => "null".to_string(),
+        };
+        msg!(
+            "PerpSpread details: {{ \
+                \"perp_market\": \"{}\", \
+                \"spread_bps\": {} \
+                }}",
+            perp_market_ai.key,
+            spread_bps
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Log the MNGO liquidity mining rate of a perp market and the share of it the resting orders
+    /// of a MangoAccount would earn if the book stayed as it is
+    fn get_mining_rate(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        market_index: usize,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            perp_market_ai,     // read
+            bids_ai,            // read
+            asks_ai,            // read
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(market_index < mango_group.num_oracles, MangoErrorCode::InvalidParam)?;
+        check_eq!(
+            perp_market_ai.key,
+            &mango_group.perp_markets[market_index].perp_market,
+            MangoErrorCode::InvalidMarket
+        )?;
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+
+        let lmi = &perp_market.liquidity_mining_info;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let period_progress = if lmi.target_period_length == 0 {
+            ZERO_I80F48
+        } else {
+            I80F48::from_num(now_ts.saturating_sub(lmi.period_start))
+                / I80F48::from_num(lmi.target_period_length)
+        };
+
+        // Both sides of the book draw on the same MNGO budget
+        let (bid_points, bid_total) =
+            mining_points_rate(book.bids(), lmi.max_depth_bps, mango_account_ai.key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidAccount))?;
+        let (ask_points, ask_total) =
+            mining_points_rate(book.asks(), lmi.max_depth_bps, mango_account_ai.key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidAccount))?;
+        let account_points = bid_points.saturating_add(ask_points);
+        let total_points = bid_total.saturating_add(ask_total);
+        let share =
+            if total_points.is_positive() { account_points / total_points } else { ZERO_I80F48 };
+
+        msg!(
+            "MiningRate details: {{ \
+                \"mango_account\": \"{}\", \
+                \"market_index\": {}, \
+                \"mngo_per_period\": {}, \
+                \"mngo_left\": {}, \
+                \"period_progress\": {}, \
+                \"share\": {}, \
+                \"mngo_per_second\": {} \
+                }}",
+            mango_account_ai.key,
+            market_index,
+            lmi.mngo_per_period,
+            lmi.mngo_left,
+            period_progress.to_num::<f64>(),
+            share.to_num::<f64>(),
+            account_points.saturating_mul(lmi.rate).to_num::<f64>()
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn check_group_invariants(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_ais, bank_and_market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,     // read
+            mango_cache_ai,     // read
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+
+        // Each root bank is followed by its node banks, each followed by its vault
+        let mut root_banks = vec![];
+        let mut perp_markets = vec![];
+        let mut i = 0;
+        while i < bank_and_market_ais.len() {
+            let ai = &bank_and_market_ais[i];
+            if let Some(token_index) = mango_group.find_root_bank_index(ai.key) {
+                let root_bank = RootBank::load_checked(ai, program_id)?;
+                let num_node_banks = root_bank.num_node_banks;
+                check!(
+                    bank_and_market_ais.len() > i + 2 * num_node_banks,
+                    MangoErrorCode::InvalidAccount
+                )?;
+                let mut node_banks = Vec::with_capacity(num_node_banks);
+                for j in 0..num_node_banks {
+                    let node_bank_ai = &bank_and_market_ais[i + 1 + 2 * j];
+                    let vault_ai = &bank_and_market_ais[i + 2 + 2 * j];
+                    check_eq!(
+                        node_bank_ai.key,
+                        &root_bank.node_banks[j],
+                        MangoErrorCode::InvalidNodeBank
+                    )?;
+                    let node_bank = NodeBank::load_checked(node_bank_ai, program_id)?;
+                    check_eq!(vault_ai.key, &node_bank.vault, MangoErrorCode::InvalidVault)?;
+                    let vault = Account::unpack(&vault_ai.try_borrow_data()?)?;
+                    node_banks.push((node_bank, vault.amount));
+                }
+                root_banks.push((token_index, root_bank, node_banks));
+                i += 1 + 2 * num_node_banks;
+            } else {
+                let market_index = mango_group
+                    .find_perp_market_index(ai.key)
+                    .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+                let perp_market = PerpMarket::load_checked(ai, program_id, mango_group_ai.key)?;
+                perp_markets.push((market_index, perp_market));
+                i += 1;
+            }
+        }
+
+        let snapshots: Vec<RootBankSnapshot> = root_banks
+            .iter()
+            .map(|(token_index, root_bank, node_banks)| RootBankSnapshot {
+                token_index: *token_index,
+                root_bank,
+                node_banks: node_banks.iter().map(|(nb, amount)| (&**nb, *amount)).collect(),
+            })
+            .collect();
+        let perp_markets: Vec<(usize, &PerpMarket)> =
+            perp_markets.iter().map(|(market_index, pm)| (*market_index, &**pm)).collect();
+
+        match check_group_invariants(&mango_group, &mango_cache, &snapshots, &perp_markets) {
+            Ok(()) => msg!("GroupInvariants details: {{ \"pass\": true }}"),
+            Err(invariant) => msg!(
+                "GroupInvariants details: {{ \"pass\": false, \"invariant\": \"{:?}\" }}",
+                invariant
+            ),
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_perp_mngo_vault(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            perp_market_ai,     // write
+            old_mngo_vault_ai,  // write
+            new_mngo_vault_ai,  // write
+            signer_ai,          // read
+            admin_ai,           // read, signer
+            token_prog_ai,      // read
+        ] = accounts;
+        check!(token_prog_ai.key == &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check_eq!(signer_ai.key, &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
+        mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        check_eq!(old_mngo_vault_ai.key, &perp_market.mngo_vault, MangoErrorCode::InvalidVault)?;
+        check!(new_mngo_vault_ai.key != old_mngo_vault_ai.key, MangoErrorCode::InvalidVault)?;
+
+        // Same requirements PerpMarket::load_and_init puts on the first vault
+        check!(new_mngo_vault_ai.owner == &spl_token::ID, MangoErrorCode::InvalidOwner)?;
+        let new_mngo_vault = Account::unpack(&new_mngo_vault_ai.try_borrow_data()?)?;
+        check!(new_mngo_vault.owner == mango_group.signer_key, MangoErrorCode::InvalidOwner)?;
+        check!(new_mngo_vault.delegate.is_none(), MangoErrorCode::InvalidVault)?;
+        check!(new_mngo_vault.close_authority.is_none(), MangoErrorCode::InvalidVault)?;
+        check!(new_mngo_vault.mint == mngo_token::ID, MangoErrorCode::InvalidVault)?;
+
+        let old_mngo_vault = Account::unpack(&old_mngo_vault_ai.try_borrow_data()?)?;
+        if old_mngo_vault.amount > 0 {
+            let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+            invoke_transfer(
+                token_prog_ai,
+                old_mngo_vault_ai,
+                new_mngo_vault_ai,
+                signer_ai,
+                &[&signers_seeds],
+                old_mngo_vault.amount,
+            )?;
+        }
+
+        perp_market.mngo_vault = *new_mngo_vault_ai.key;
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_delegate(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            delegate_ai,        // read
+        ] = accounts;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+
+        mango_account.delegate = *delegate_ai.key;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn change_mango_account_owner(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            new_owner_ai,       // read
+        ] = accounts;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(new_owner_ai.key != &Pubkey::default(), MangoErrorCode::InvalidParam)?;
+
+        mango_account.owner = *new_owner_ai.key;
+        // The delegate was picked by the old owner
+        mango_account.delegate = Pubkey::default();
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn upgrade_mango_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+        ] = accounts;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(&mango_account.owner, owner_ai.key, MangoErrorCode::InvalidOwner)?;
+
+        let old_version = mango_account.meta_data.version;
+        if mango_account.upgrade() {
+            msg!("MangoAccount upgraded from version {} to {}", old_version, MANGO_ACCOUNT_VERSION);
+        } else {
+            msg!("MangoAccount is already at version {}", old_version);
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_max_index_growth(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_index_growth: I80F48,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            root_bank_ai,       // write
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(!max_index_growth.is_negative(), MangoErrorCode::InvalidParam)?;
+
+        mango_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
+        let mut root_bank = RootBank::load_mut_checked(root_bank_ai, program_id)?;
+        root_bank.max_index_growth = max_index_growth;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Log how much of the perp losses at risk the insurance fund could cover
+    fn get_insurance_coverage(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 3;
+        let (fixed_ais, perp_market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,     // read
+            mango_cache_ai,     // read
+            insurance_vault_ai, // read
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        check_eq!(
+            insurance_vault_ai.key,
+            &mango_group.insurance_vault,
+            MangoErrorCode::InvalidVault
+        )?;
+        let insurance_vault = Account::unpack(&insurance_vault_ai.try_borrow_data()?)?;
+
+        let mut seen = [false; MAX_PAIRS];
+        let mut losses_at_risk = ZERO_I80F48;
+        for perp_market_ai in perp_market_ais.iter() {
+            let market_index = mango_group
+                .find_perp_market_index(perp_market_ai.key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+            check!(!seen[market_index], MangoErrorCode::InvalidParam)?;
+            seen[market_index] = true;
+
+            let perp_market =
+                PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+            losses_at_risk += perp_losses_at_risk(
+                &mango_group.perp_markets[market_index],
+                &perp_market,
+                mango_cache.price_cache[market_index].price,
+            );
+        }
+
+        let insurance_balance = I80F48::from_num(insurance_vault.amount);
+        let coverage_ratio = if losses_at_risk.is_positive() {
+            (insurance_balance / losses_at_risk).to_num::<f64>().to_string()
+        } else {
+            "null".to_string()
+        };
+
+        msg!(
+            "InsuranceCoverage details: {{ \
+                \"insurance_balance\": {}, \
+                \"perp_losses_at_risk\": {}, \
+                \"coverage_ratio\": {} \
+                }}",
+            insurance_vault.amount,
+            losses_at_risk.to_num::<f64>(),
+            coverage_ratio
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn get_open_interest(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_ais, perp_market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,     // read
+            mango_cache_ai,     // read
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+
+        let mut seen = [false; MAX_PAIRS];
+        let mut perp_markets = Vec::with_capacity(perp_market_ais.len());
+        for perp_market_ai in perp_market_ais.iter() {
+            let market_index = mango_group
+                .find_perp_market_index(perp_market_ai.key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+            check!(!seen[market_index], MangoErrorCode::InvalidParam)?;
+            seen[market_index] = true;
+
+            let perp_market =
+                PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+            perp_markets.push((market_index, perp_market));
+        }
+
+        let perp_markets: Vec<(usize, &PerpMarket)> =
+            perp_markets.iter().map(|(market_index, pm)| (*market_index, &**pm)).collect();
+        let open_interest = total_open_interest(&mango_group, &mango_cache, &perp_markets);
+
+        msg!(
+            "OpenInterest details: {{ \"num_perp_markets\": {}, \"total_open_interest\": {} }}",
+            perp_markets.len(),
+            open_interest.to_num::<f64>()
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn get_interest_rates(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_ais, node_bank_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,     // read
+            root_bank_ai,       // read
+        ] = fixed_ais;
 
-        invoke_transfer(token_prog_ai, msrm_account_ai, msrm_vault_ai, owner_ai, &[], quantity)?;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let token_index = mango_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        check_eq!(root_bank.num_node_banks, node_bank_ais.len(), MangoErrorCode::InvalidParam)?;
+        let mut node_banks = Vec::with_capacity(node_bank_ais.len());
+        for (i, node_bank_ai) in node_bank_ais.iter().enumerate() {
+            check_eq!(node_bank_ai.key, &root_bank.node_banks[i], MangoErrorCode::InvalidNodeBank)?;
+            node_banks.push(NodeBank::load_checked(node_bank_ai, program_id)?);
+        }
 
-        mango_account.msrm_amount += quantity;
+        let node_banks: Vec<&NodeBank> = node_banks.iter().map(|nb| &**nb).collect();
+        let utilization = root_bank_utilization(&root_bank, &node_banks);
+
+        msg!(
+            "InterestRates details: {{ \
+                \"token_index\": {}, \
+                \"utilization\": {}, \
+                \"borrow_apr\": {}, \
+                \"deposit_apr\": {} \
+                }}",
+            token_index,
+            utilization.to_num::<f64>(),
+            borrow_apr(&root_bank, &node_banks).to_num::<f64>(),
+            deposit_apr(&root_bank, &node_banks).to_num::<f64>()
+        );
 
         Ok(())
     }
 
     #[inline(never)]
-    fn withdraw_msrm(
+    /// Move deposits of a token between two MangoAccounts that have the same owner
+    fn transfer_collateral(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
+        token_index: usize,
         quantity: u64,
     ) -> MangoResult<()> {
         const NUM_FIXED: usize = 7;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, src_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
         let [
-            mango_group_ai,     // read
-            mango_account_ai,   // write
-            owner_ai,           // read, signer
-            msrm_account_ai,    // write
-            msrm_vault_ai,      // write
-            signer_ai,          // read
-            token_prog_ai,      // read
-        ] = accounts;
-        check!(token_prog_ai.key == &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            src_mango_account_ai,   // write
+            dst_mango_account_ai,   // write
+            owner_ai,               // read, signer
+            root_bank_ai,           // read
+            node_bank_ai,           // write
+        ] = fixed_ais;
+        check!(
+            src_mango_account_ai.key != dst_mango_account_ai.key,
+            MangoErrorCode::InvalidAccount
+        )?;
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        check!(msrm_vault_ai.key == &mango_group.msrm_vault, MangoErrorCode::InvalidVault)?;
-
-        let mut mango_account =
-            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
 
-        check!(mango_account.msrm_amount >= quantity, MangoErrorCode::InsufficientFunds)?;
+        let mut src_ma =
+            MangoAccount::load_mut_checked(src_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&src_ma.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
+        check!(!src_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        src_ma.check_open_orders(&mango_group, src_open_orders_ais)?;
 
-        let signer_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
-        invoke_transfer(
-            token_prog_ai,
-            msrm_vault_ai,
-            msrm_account_ai,
-            signer_ai,
-            &[&signer_seeds],
-            quantity,
-        )?;
+        let mut dst_ma =
+            MangoAccount::load_mut_checked(dst_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(&dst_ma.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(!dst_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
 
-        mango_account.msrm_amount -= quantity;
+        let root_bank_index = mango_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
+        check_eq!(root_bank_index, token_index, MangoErrorCode::InvalidRootBank)?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::InvalidNodeBank)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
 
-        Ok(())
-    }
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let active_assets =
+            UserActiveAssets::new(&mango_group, &src_ma, vec![(AssetType::Token, token_index)]);
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
 
-    #[inline(never)]
-    fn set_group_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
-        const NUM_FIXED: usize = 3;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            mango_group_ai,     // write
-            new_admin_ai,       // read
-            admin_ai,           // read, signer
-        ] = accounts;
+        // Only deposits can be moved; this instruction may not be used to borrow
+        let root_bank_cache = &mango_cache.root_bank_cache[token_index];
+        let native_deposit = src_ma.get_native_deposit(root_bank_cache, token_index)?;
+        let transfer = I80F48::from_num(quantity);
+        check!(native_deposit >= transfer, MangoErrorCode::InsufficientFunds)?;
 
-        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        transfer_token_internal(
+            root_bank_cache,
+            &mut node_bank,
+            &mut src_ma,
+            &mut dst_ma,
+            src_mango_account_ai.key,
+            dst_mango_account_ai.key,
+            token_index,
+            transfer,
+        )?;
 
-        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&mango_group, &mango_cache, &src_ma, src_open_orders_ais)?;
+        let health = health_cache.get_health(&mango_group, HealthType::Init);
+        check!(health >= ZERO_I80F48, MangoErrorCode::InsufficientFunds)?;
 
-        mango_group.admin = *new_admin_ai.key;
+        msg!(
+            "transfer_collateral details: {{ \
+                \"owner\": {}, \
+                \"src_mango_account\": {}, \
+                \"dst_mango_account\": {}, \
+                \"token_index\": {}, \
+                \"quantity\": {} \
+                }}",
+            owner_ai.key,
+            src_mango_account_ai.key,
+            dst_mango_account_ai.key,
+            token_index,
+            quantity
+        );
 
         Ok(())
     }
 
     pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> MangoResult<()> {
-        let instruction =
-            MangoInstruction::unpack(data).ok_or(ProgramError::InvalidInstructionData)?;
+        let instruction = MangoInstruction::unpack_checked(data)?;
         match instruction {
             MangoInstruction::InitMangoGroup {
                 signer_nonce,
@@ -3738,7 +6115,7 @@ impl Processor {
             }
             MangoInstruction::Borrow { .. } => {
                 msg!("Mango: Borrow DEPRECATED");
-                Ok(())
+                Err(throw_err!(MangoErrorCode::UseWithdrawWithBorrow))
             }
             MangoInstruction::CachePrices => {
                 msg!("Mango: CachePrices");
@@ -3816,6 +6193,10 @@ impl Processor {
                     quantity,
                     client_order_id,
                     order_type,
+                    false,
+                    [0; 8],
+                    0,
+                    DEFAULT_MATCH_LIMIT,
                 )
             }
             MangoInstruction::CancelPerpOrderByClientId { client_order_id, invalid_id_ok } => {
@@ -3975,6 +6356,265 @@ impl Processor {
                 msg!("Mango: ForceSettleQuotePositions");
                 Self::force_settle_quote_positions(program_id, accounts)
             }
+            MangoInstruction::TransferCollateral { token_index, quantity } => {
+                msg!("Mango: TransferCollateral");
+                Self::transfer_collateral(program_id, accounts, token_index, quantity)
+            }
+            MangoInstruction::PlacePerpOrder2 {
+                side,
+                price,
+                quantity,
+                client_order_id,
+                order_type,
+                safe_mode,
+                tag,
+                match_limit,
+            } => {
+                msg!("Mango: PlacePerpOrder2 client_order_id={}", client_order_id);
+                Self::place_perp_order(
+                    program_id,
+                    accounts,
+                    side,
+                    price,
+                    quantity,
+                    client_order_id,
+                    order_type,
+                    safe_mode,
+                    tag,
+                    0,
+                    match_limit,
+                )
+            }
+            MangoInstruction::ExecuteRebalanceLeg { legs } => {
+                msg!("Mango: ExecuteRebalanceLeg");
+                Self::execute_rebalance_leg(program_id, accounts, legs)
+            }
+            MangoInstruction::SetLiquidationTargetHealth { liquidation_target_health } => {
+                msg!("Mango: SetLiquidationTargetHealth");
+                Self::set_liquidation_target_health(program_id, accounts, liquidation_target_health)
+            }
+            MangoInstruction::SetPerpMarketFees { maker_fee, taker_fee } => {
+                msg!("Mango: SetPerpMarketFees");
+                Self::set_perp_market_fees(program_id, accounts, maker_fee, taker_fee)
+            }
+            MangoInstruction::GetAccountAuthority => {
+                msg!("Mango: GetAccountAuthority");
+                Self::get_account_authority(program_id, accounts)
+            }
+            MangoInstruction::AddToDepositWhitelist { owner } => {
+                msg!("Mango: AddToDepositWhitelist");
+                Self::add_to_deposit_whitelist(program_id, accounts, owner)
+            }
+            MangoInstruction::RemoveFromDepositWhitelist { owner } => {
+                msg!("Mango: RemoveFromDepositWhitelist");
+                Self::remove_from_deposit_whitelist(program_id, accounts, owner)
+            }
+            MangoInstruction::SetDepositWhitelistEnabled { enabled } => {
+                msg!("Mango: SetDepositWhitelistEnabled");
+                Self::set_deposit_whitelist_enabled(program_id, accounts, enabled)
+            }
+            MangoInstruction::SetPostTradeRiskLogEnabled { enabled } => {
+                msg!("Mango: SetPostTradeRiskLogEnabled");
+                Self::set_post_trade_risk_log_enabled(program_id, accounts, enabled)
+            }
+            MangoInstruction::SetMaxOracleAge { max_oracle_age } => {
+                msg!("Mango: SetMaxOracleAge");
+                Self::set_max_oracle_age(program_id, accounts, max_oracle_age)
+            }
+            MangoInstruction::UpdateAllFunding => {
+                msg!("Mango: UpdateAllFunding");
+                Self::update_all_funding(program_id, accounts)
+            }
+            MangoInstruction::CloseMangoAccount => {
+                msg!("Mango: CloseMangoAccount");
+                Self::close_mango_account(program_id, accounts)
+            }
+            MangoInstruction::GetPositionHistoryAnchor => {
+                msg!("Mango: GetPositionHistoryAnchor");
+                Self::get_position_history_anchor(program_id, accounts)
+            }
+            MangoInstruction::CloseSpotOpenOrders => {
+                msg!("Mango: CloseSpotOpenOrders");
+                Self::close_spot_open_orders(program_id, accounts)
+            }
+            MangoInstruction::SetMaxIndexGrowth { max_index_growth } => {
+                msg!("Mango: SetMaxIndexGrowth");
+                Self::set_max_index_growth(program_id, accounts, max_index_growth)
+            }
+            MangoInstruction::SetDelegate => {
+                msg!("Mango: SetDelegate");
+                Self::set_delegate(program_id, accounts)
+            }
+            MangoInstruction::GetPerpSpread => {
+                msg!("Mango: GetPerpSpread");
+                Self::get_perp_spread(program_id, accounts)
+            }
+            MangoInstruction::PlacePerpOrder3 {
+                side,
+                price,
+                quantity,
+                client_order_id,
+                order_type,
+                safe_mode,
+                tag,
+                expiry_timestamp,
+            } => {
+                msg!("Mango: PlacePerpOrder3 client_order_id={}", client_order_id);
+                Self::place_perp_order(
+                    program_id,
+                    accounts,
+                    side,
+                    price,
+                    quantity,
+                    client_order_id,
+                    order_type,
+                    safe_mode,
+                    tag,
+                    expiry_timestamp,
+                    DEFAULT_MATCH_LIMIT,
+                )
+            }
+            MangoInstruction::CheckGroupInvariants => {
+                msg!("Mango: CheckGroupInvariants");
+                Self::check_group_invariants(program_id, accounts)
+            }
+            MangoInstruction::SetPerpMngoVault => {
+                msg!("Mango: SetPerpMngoVault");
+                Self::set_perp_mngo_vault(program_id, accounts)
+            }
+            MangoInstruction::DepositBatch { quantities } => {
+                msg!("Mango: DepositBatch");
+                Self::deposit_batch(program_id, accounts, quantities)
+            }
+            MangoInstruction::GetMiningRate { market_index } => {
+                msg!("Mango: GetMiningRate");
+                Self::get_mining_rate(program_id, accounts, market_index)
+            }
+            MangoInstruction::ChangeMangoAccountOwner => {
+                msg!("Mango: ChangeMangoAccountOwner");
+                Self::change_mango_account_owner(program_id, accounts)
+            }
+            MangoInstruction::UpgradeMangoAccount => {
+                msg!("Mango: UpgradeMangoAccount");
+                Self::upgrade_mango_account(program_id, accounts)
+            }
+            MangoInstruction::SetReferrerMemory { referrer_id } => {
+                msg!("Mango: SetReferrerMemory");
+                Self::set_referrer_memory(program_id, accounts, referrer_id)
+            }
+            MangoInstruction::CancelSpotOrderByClientId { client_order_id } => {
+                msg!("Mango: CancelSpotOrderByClientId client_order_id={}", client_order_id);
+                let data = serum_dex::instruction::MarketInstruction::CancelOrderByClientIdV2(
+                    client_order_id,
+                )
+                .pack();
+                Self::cancel_spot_order(program_id, accounts, data)
+            }
+            MangoInstruction::InitLossRecovery => {
+                msg!("Mango: InitLossRecovery");
+                Self::init_loss_recovery(program_id, accounts)
+            }
+            MangoInstruction::ClaimLossRecovery => {
+                msg!("Mango: ClaimLossRecovery");
+                Self::claim_loss_recovery(program_id, accounts)
+            }
+            MangoInstruction::SetGroupName { name } => {
+                msg!("Mango: SetGroupName");
+                Self::set_group_name(program_id, accounts, name)
+            }
+            MangoInstruction::GetOpenInterest => {
+                msg!("Mango: GetOpenInterest");
+                Self::get_open_interest(program_id, accounts)
+            }
+            MangoInstruction::TransferMsrm { quantity } => {
+                msg!("Mango: TransferMsrm");
+                Self::transfer_msrm(program_id, accounts, quantity)
+            }
+            MangoInstruction::GetInterestRates => {
+                msg!("Mango: GetInterestRates");
+                Self::get_interest_rates(program_id, accounts)
+            }
+            MangoInstruction::CancelPerpOrdersSide { side, limit } => {
+                msg!("Mango: CancelPerpOrdersSide");
+                Self::cancel_perp_orders_side(program_id, accounts, side, limit)
+            }
+            MangoInstruction::GetInsuranceCoverage => {
+                msg!("Mango: GetInsuranceCoverage");
+                Self::get_insurance_coverage(program_id, accounts)
+            }
+            MangoInstruction::GetRealizedPnl => {
+                msg!("Mango: GetRealizedPnl");
+                Self::get_realized_pnl(program_id, accounts)
+            }
+            MangoInstruction::InitPnlTracker => {
+                msg!("Mango: InitPnlTracker");
+                Self::init_pnl_tracker(program_id, accounts)
+            }
+            MangoInstruction::RegisterLossShare => {
+                msg!("Mango: RegisterLossShare");
+                Self::register_loss_share(program_id, accounts)
+            }
+            MangoInstruction::SetMaxWithdrawPerTx { token_index, max_withdraw } => {
+                msg!("Mango: SetMaxWithdrawPerTx");
+                Self::set_max_withdraw_per_tx(program_id, accounts, token_index, max_withdraw)
+            }
+            MangoInstruction::SetMinMngoToTrade { min_mngo_to_trade } => {
+                msg!("Mango: SetMinMngoToTrade");
+                Self::set_min_mngo_to_trade(program_id, accounts, min_mngo_to_trade)
+            }
+            MangoInstruction::RebalanceNodeBanks { quantity } => {
+                msg!("Mango: RebalanceNodeBanks");
+                Self::rebalance_node_banks(program_id, accounts, quantity)
+            }
+            MangoInstruction::PreviewWithdraw { quantity, allow_borrow } => {
+                msg!("Mango: PreviewWithdraw");
+                Self::preview_withdraw(program_id, accounts, quantity, allow_borrow)
+            }
+            MangoInstruction::SetWithdrawFee { withdraw_fee_bps } => {
+                msg!("Mango: SetWithdrawFee");
+                Self::set_withdraw_fee(program_id, accounts, withdraw_fee_bps)
+            }
+            MangoInstruction::CancelPerpOrdersOlderThan { cutoff_ts, limit } => {
+                msg!("Mango: CancelPerpOrdersOlderThan");
+                Self::cancel_perp_orders_older_than(program_id, accounts, cutoff_ts, limit)
+            }
+            MangoInstruction::SetPerpImpactDepth { quote_notional } => {
+                msg!("Mango: SetPerpImpactDepth");
+                Self::set_perp_impact_depth(program_id, accounts, quote_notional)
+            }
+            MangoInstruction::UpdateRootBanks => {
+                msg!("Mango: UpdateRootBanks");
+                Self::update_root_banks(program_id, accounts)
+            }
+            MangoInstruction::HasUnsettledPnl => {
+                msg!("Mango: HasUnsettledPnl");
+                Self::has_unsettled_pnl(program_id, accounts)
+            }
+            MangoInstruction::CancelSpotOrderAndSettle { order } => {
+                msg!("Mango: CancelSpotOrderAndSettle");
+                let data = serum_dex::instruction::MarketInstruction::CancelOrderV2(order).pack();
+                Self::cancel_spot_order_and_settle(program_id, accounts, data)
+            }
+            MangoInstruction::PlacePerpPeggedOrder {
+                offset_ticks,
+                peg_limit,
+                quantity,
+                client_order_id,
+                side,
+                order_type,
+            } => {
+                msg!("Mango: PlacePerpPeggedOrder client_order_id={}", client_order_id);
+                Self::place_perp_pegged_order(
+                    program_id,
+                    accounts,
+                    side,
+                    offset_ticks,
+                    peg_limit,
+                    quantity,
+                    client_order_id,
+                    order_type,
+                )
+            }
         }
     }
 }
@@ -4123,6 +6763,168 @@ fn invoke_transfer<'a>(
     solana_program::program::invoke_signed(&transfer_instruction, &accs, signers_seeds)
 }
 
+fn invoke_create_account<'a>(
+    system_prog_ai: &AccountInfo<'a>,
+    payer_ai: &AccountInfo<'a>,
+    new_account_ai: &AccountInfo<'a>,
+    owner: &Pubkey,
+    space: usize,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let lamports = Rent::get()?.minimum_balance(space);
+    let instruction = solana_program::system_instruction::create_account(
+        payer_ai.key,
+        new_account_ai.key,
+        lamports,
+        space as u64,
+        owner,
+    );
+    let accs = [system_prog_ai.clone(), payer_ai.clone(), new_account_ai.clone()];
+
+    solana_program::program::invoke_signed(&instruction, &accs, signers_seeds)
+}
+
+/// Move all lamports of the program owned `account_ai` to `receiver_ai` and zero its data, so
+/// the runtime deletes it at the end of the transaction
+fn close_program_account(account_ai: &AccountInfo, receiver_ai: &AccountInfo) -> MangoResult<()> {
+    let receiver_lamports =
+        receiver_ai.lamports().checked_add(account_ai.lamports()).ok_or(math_err!())?;
+    **receiver_ai.lamports.borrow_mut() = receiver_lamports;
+    **account_ai.lamports.borrow_mut() = 0;
+    for byte in account_ai.try_borrow_mut_data()?.iter_mut() {
+        *byte = 0;
+    }
+    Ok(())
+}
+
+/// Load the PnlTracker of `mango_account` from `ais` if the account has one and it was passed.
+/// A tracker that was left out just misses the trade: that is a reporting loss, while failing
+/// would leave the fill stuck at the front of the event queue. The tracker is recognized by the
+/// MangoAccount it records, which saves a find_program_address per fill
+fn load_pnl_tracker<'a>(
+    ais: &'a [AccountInfo],
+    program_id: &Pubkey,
+    mango_account_pk: &Pubkey,
+    mango_account: &MangoAccount,
+) -> Option<RefMut<'a, PnlTracker>> {
+    if !mango_account.has_pnl_tracker {
+        return None;
+    }
+    // load_mut panics on data of the wrong size
+    ais.iter()
+        .filter(|ai| ai.owner == program_id && ai.data_len() == size_of::<PnlTracker>())
+        .find_map(|ai| PnlTracker::load_mut_checked(ai, program_id, mango_account_pk).ok())
+}
+
+/// Load the referrer `referrer_pk` from `ais` to credit it its share of a taker fee. Best effort:
+/// if the referrer wasn't passed or is no longer a MangoAccount of the group, e.g. because it was
+/// closed, None is returned and its share stays in the market's fees_accrued. Failing instead
+/// would leave the fill stuck at the front of the event queue
+fn load_referrer<'a>(
+    ais: &'a [AccountInfo],
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    referrer_pk: &Pubkey,
+) -> Option<RefMut<'a, MangoAccount>> {
+    if referrer_pk == &Pubkey::default() {
+        return None;
+    }
+    let referrer_ai = ais.iter().find(|ai| ai.key == referrer_pk)?;
+    // load_mut panics on data of the wrong size
+    if referrer_ai.data_len() != size_of::<MangoAccount>() {
+        return None;
+    }
+    MangoAccount::load_mut_checked(referrer_ai, program_id, mango_group_pk).ok()
+}
+
+/// Checks shared by every instruction that places perp orders for a MangoAccount: the signer
+/// must be the owner or delegate, the cache must be valid for the traded markets and the MNGO
+/// stake requirement met. Clears being_liquidated if the account is healthy again and returns
+/// the health cache along with the init health before the trade
+fn init_perp_order_health(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &mut MangoAccount,
+    owner_ai: &AccountInfo,
+    open_orders_ais: &[AccountInfo; MAX_PAIRS],
+    market_indexes: &[usize],
+    now_ts: u64,
+) -> MangoResult<(HealthCache, I80F48)> {
+    check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+    check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+    check!(mango_account.is_owner_or_delegate(owner_ai.key), MangoErrorCode::InvalidOwner)?;
+    mango_account.check_open_orders(mango_group, open_orders_ais)?;
+
+    let active_assets = UserActiveAssets::new(
+        mango_group,
+        mango_account,
+        market_indexes.iter().map(|&i| (AssetType::Perp, i)).collect(),
+    );
+    mango_cache.check_valid(mango_group, &active_assets, now_ts)?;
+    mango_account.check_mngo_stake(mango_group, mango_cache)?;
+
+    let mut health_cache = HealthCache::new(active_assets);
+    health_cache.init_vals(mango_group, mango_cache, mango_account, open_orders_ais)?;
+    let pre_health = health_cache.get_health(mango_group, HealthType::Init);
+
+    // update the being_liquidated flag
+    if mango_account.being_liquidated {
+        if pre_health >= ZERO_I80F48 {
+            mango_account.being_liquidated = false;
+        } else {
+            return Err(throw_err!(MangoErrorCode::BeingLiquidated));
+        }
+    }
+
+    Ok((health_cache, pre_health))
+}
+
+/// Reject the perp orders just placed unless init health is non-negative or, for an account
+/// that started below zero (reduce only), health didn't go down. Logs the post trade risk for
+/// each market traded if the group has that enabled
+fn check_post_trade_health(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    mango_account_pk: &Pubkey,
+    open_orders_ais: &[AccountInfo; MAX_PAIRS],
+    health_cache: &HealthCache,
+    pre_health: I80F48,
+    market_indexes: &[usize],
+    safe_mode: bool,
+) -> MangoResult<()> {
+    // This means health must only go up
+    let reduce_only = pre_health < ZERO_I80F48;
+    let post_health = health_cache.get_health(mango_group, HealthType::Init);
+    check!(
+        post_health >= ZERO_I80F48 || (reduce_only && post_health >= pre_health),
+        MangoErrorCode::InsufficientFunds
+    )?;
+
+    if mango_group.post_trade_risk_log_enabled {
+        let leverage = account_leverage(mango_group, mango_cache, mango_account, open_orders_ais)?;
+        for &market_index in market_indexes {
+            msg!(
+                "{}",
+                post_trade_risk_details(
+                    mango_account_pk,
+                    market_index,
+                    leverage,
+                    pre_health,
+                    post_health,
+                    reduce_only,
+                    safe_mode,
+                    mango_group.min_mngo_to_trade
+                )
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the oracle price in native quote per native base. Feeds are scaled by their own exponent
+/// and the base and quote decimals stored in the group's TokenInfo, so no per oracle scale is kept
 #[inline(never)]
 fn read_oracle(
     mango_group: &MangoGroup,
@@ -4130,46 +6932,24 @@ fn read_oracle(
     oracle_ai: &AccountInfo,
 ) -> MangoResult<I80F48> {
     let quote_decimals: u8 = mango_group.tokens[QUOTE_INDEX].decimals;
+    let base_decimals: u8 = mango_group.tokens[token_index].decimals;
     let oracle_type = determine_oracle_type(oracle_ai);
     let price = match oracle_type {
         OracleType::Pyth => {
             let price_account = Price::get_price(oracle_ai).unwrap();
             let value = I80F48::from_num(price_account.agg.price);
-
-            let decimals = (quote_decimals as i32)
-                .checked_add(price_account.expo)
-                .unwrap()
-                .checked_sub(mango_group.tokens[token_index].decimals as i32)
-                .unwrap();
-
-            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-            if decimals < 0 {
-                value.checked_div(decimal_adj).unwrap()
-            } else {
-                value.checked_mul(decimal_adj).unwrap()
-            }
+            normalize_oracle_price(value, price_account.expo, base_decimals, quote_decimals)?
         }
         OracleType::Stub => {
             let oracle = StubOracle::load(oracle_ai)?;
             I80F48::from_num(oracle.price)
         }
         OracleType::Switchboard => {
-            // TODO do decimal fixes for cases where base decimals != quote decimals
+            // switchboard publishes UI prices without an exponent
             let result =
                 FastRoundResultAccountData::deserialize(&oracle_ai.try_borrow_data()?).unwrap();
             let value = I80F48::from_num(result.result.result);
-            let decimals = (quote_decimals as i32)
-                .checked_sub(mango_group.tokens[token_index].decimals as i32)
-                .unwrap();
-            if decimals < 0 {
-                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-                value.checked_div(decimal_adj).unwrap()
-            } else if decimals > 0 {
-                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-                value.checked_mul(decimal_adj).unwrap()
-            } else {
-                value
-            }
+            normalize_oracle_price(value, 0, base_decimals, quote_decimals)?
         }
         OracleType::Unknown => {
             panic!("Unknown oracle");
@@ -4544,6 +7324,36 @@ fn invoke_init_open_orders<'a>(
     solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)
 }
 
+fn invoke_close_open_orders<'a>(
+    dex_prog_ai: &AccountInfo<'a>, // Have to add account of the program id
+    open_orders_ai: &AccountInfo<'a>,
+    signer_ai: &AccountInfo<'a>,
+    destination_ai: &AccountInfo<'a>,
+    spot_market_ai: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let data = serum_dex::instruction::MarketInstruction::CloseOpenOrders.pack();
+    let instruction = Instruction {
+        program_id: *dex_prog_ai.key,
+        data,
+        accounts: vec![
+            AccountMeta::new(*open_orders_ai.key, false),
+            AccountMeta::new_readonly(*signer_ai.key, true),
+            AccountMeta::new(*destination_ai.key, false),
+            AccountMeta::new_readonly(*spot_market_ai.key, false),
+        ],
+    };
+
+    let account_infos = [
+        dex_prog_ai.clone(),
+        open_orders_ai.clone(),
+        signer_ai.clone(),
+        destination_ai.clone(),
+        spot_market_ai.clone(),
+    ];
+    solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)
+}
+
 // Returns asset_weight and liab_weight
 fn get_leverage_weights(leverage: I80F48) -> (I80F48, I80F48) {
     (
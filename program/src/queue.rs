@@ -2,7 +2,7 @@ use crate::error::{check_assert, MangoErrorCode, MangoResult, SourceFileId};
 use crate::matching::Side;
 use crate::state::{DataType, MetaData, PerpMarket};
 use crate::utils::strip_header_mut;
-use bytemuck::Pod;
+use bytemuck::{bytes_of_mut, Pod, Zeroable};
 use fixed::types::I80F48;
 use mango_macro::Pod;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
@@ -203,6 +203,38 @@ impl<'a> EventQueue<'a> {
     }
 }
 
+/// Decode raw EventQueue account data and return `(fill_count, out_count, head, count)`
+/// Only the events currently in the queue are counted. Useful for sizing ConsumeEvents limits
+pub fn summarize_perp_event_queue(data: &[u8]) -> MangoResult<(usize, usize, usize, usize)> {
+    let header_size = size_of::<EventQueueHeader>();
+    check!(data.len() >= header_size, MangoErrorCode::InvalidAccount)?;
+
+    // copy out the header because account data may not be aligned
+    let mut header = EventQueueHeader::zeroed();
+    bytes_of_mut(&mut header).copy_from_slice(&data[..header_size]);
+    check_eq!(
+        header.meta_data.data_type,
+        DataType::EventQueue as u8,
+        MangoErrorCode::InvalidAccount
+    )?;
+
+    let capacity = (data.len() - header_size) / EVENT_SIZE;
+    check!(header.count <= capacity, MangoErrorCode::InvalidAccount)?;
+
+    let mut fill_count = 0;
+    let mut out_count = 0;
+    for i in 0..header.count {
+        let slot = (header.head + i) % capacity;
+        match EventType::try_from_primitive(data[header_size + slot * EVENT_SIZE]) {
+            Ok(EventType::Fill) => fill_count += 1,
+            Ok(EventType::Out) => out_count += 1,
+            _ => {}
+        }
+    }
+
+    Ok((fill_count, out_count, header.head, header.count))
+}
+
 #[derive(Copy, Clone, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum EventType {
@@ -304,6 +336,55 @@ impl FillEvent {
     }
 }
 
+/// Log line ConsumeEvents writes for each fill it processes
+pub fn fill_event_details(fill: &FillEvent) -> String {
+    format!(
+        "FillEvent details: {{ \
+            \"timestamp\": {}, \
+            \"seq_num\": {}, \
+            \"maker\": {}, \
+            \"taker\": {}, \
+            \"taker_side\": {}, \
+            \"maker_order_id\": {}, \
+            \"taker_order_id\": {}, \
+            \"maker_fee\": {}, \
+            \"taker_fee\": {}, \
+            \"price\": {}, \
+            \"quantity\": {} \
+            }}",
+        fill.timestamp,
+        fill.seq_num,
+        fill.maker.to_string(),
+        fill.taker.to_string(),
+        if fill.taker_side == Side::Bid { "bid" } else { "sell" },
+        fill.maker_order_id,
+        fill.taker_order_id,
+        fill.maker_fee.to_num::<f64>(),
+        fill.taker_fee.to_num::<f64>(),
+        fill.price,
+        fill.quantity
+    )
+}
+
+/// Log line the matching engine writes when `fill` takes a tagged maker order. The FillEvent
+/// has no room for the tag, so it is logged as the fill is made; join it with the FillEvent
+/// details on seq_num. The tag is written as 16 hex digits
+pub fn maker_tag_details(fill: &FillEvent, maker_tag: &[u8; 8]) -> String {
+    let maker_tag: String = maker_tag.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "FillEvent maker_tag: {{ \
+            \"seq_num\": {}, \
+            \"maker\": {}, \
+            \"maker_order_id\": {}, \
+            \"maker_tag\": \"{}\" \
+            }}",
+        fill.seq_num,
+        fill.maker.to_string(),
+        fill.maker_order_id,
+        maker_tag
+    )
+}
+
 #[derive(Copy, Clone, Debug, Pod)]
 #[repr(C)]
 pub struct OutEvent {
@@ -1,9 +1,13 @@
+use std::borrow::Cow;
 use std::cell::{Ref, RefMut};
 use std::cmp::{max, min};
 use std::convert::identity;
 use std::mem::size_of;
 
-use bytemuck::{from_bytes, from_bytes_mut, try_from_bytes_mut, Pod, Zeroable};
+use bytemuck::{
+    bytes_of, bytes_of_mut, from_bytes, from_bytes_mut, try_from_bytes, try_from_bytes_mut, Pod,
+    Zeroable,
+};
 use enumflags2::BitFlags;
 use fixed::types::I80F48;
 use fixed_macro::types::I80F48;
@@ -20,10 +24,11 @@ use solana_program::program_pack::Pack;
 use solana_program::pubkey::Pubkey;
 use solana_program::sysvar::{clock::Clock, rent::Rent, Sysvar};
 use spl_token::state::Account;
+use static_assertions::const_assert_eq;
 
 use crate::error::{check_assert, MangoError, MangoErrorCode, MangoResult, SourceFileId};
 use crate::ids::mngo_token;
-use crate::matching::{Book, LeafNode, Side};
+use crate::matching::{impact_price, pegged_order_key, Book, BookSide, LeafNode, Side};
 use crate::queue::FillEvent;
 use crate::utils::{invert_side, remove_slop_mut, split_open_orders};
 
@@ -43,7 +48,20 @@ pub const INFO_LEN: usize = 32;
 pub const MAX_PERP_OPEN_ORDERS: usize = 64;
 pub const FREE_ORDER_SLOT: u8 = u8::MAX; // TODO add check to prevent markets more than 255
 pub const MAX_NUM_IN_MARGIN_BASKET: u8 = 10;
+/// Layout version of MangoAccount; bump it whenever fields are carved out of the padding
+pub const MANGO_ACCOUNT_VERSION: u8 = 4;
+/// Share of the taker fees of a MangoAccount's fills credited to its referrer
+pub const REFERRER_FEE_SHARE: I80F48 = I80F48!(0.2);
 pub const INDEX_START: I80F48 = I80F48!(1_000_000);
+pub const MAX_DEPOSIT_WHITELIST: usize = 250;
+pub const DEPOSIT_WHITELIST_SEED: &[u8] = b"DepositWhitelist";
+pub const LOSS_RECOVERY_SEED: &[u8] = b"LossRecovery";
+pub const LOSS_SHARE_SEED: &[u8] = b"LossShare";
+pub const PNL_TRACKER_SEED: &[u8] = b"PnlTracker";
+pub const WITHDRAW_CAPS_SEED: &[u8] = b"WithdrawCaps";
+pub const GROUP_NAME_SEED: &[u8] = b"GroupName";
+pub const PERP_MARKET_CONFIG_SEED: &[u8] = b"PerpMarketConfig";
+pub const GROUP_NAME_LEN: usize = 32;
 
 declare_check_assert_macros!(SourceFileId::State);
 
@@ -73,6 +91,13 @@ pub enum DataType {
     Asks,
     MangoCache,
     EventQueue,
+    DepositWhitelist,
+    LossRecovery,
+    LossShare,
+    PnlTracker,
+    WithdrawCaps,
+    GroupName,
+    PerpMarketConfig,
 }
 
 const NUM_HEALTHS: usize = 2;
@@ -114,7 +139,10 @@ pub struct TokenInfo {
     pub mint: Pubkey,
     pub root_bank: Pubkey,
     pub decimals: u8,
-    pub padding: [u8; 7],
+    pub padding: [u8; 3],
+    /// Max age in seconds of the cached price of the oracle at this index for health checks;
+    /// 0 uses the group's valid_interval. Kept here so it also covers perp-only markets
+    pub max_oracle_age: u32,
 }
 
 impl TokenInfo {
@@ -181,13 +209,31 @@ pub struct MangoGroup {
     pub valid_interval: u64,
 
     // insurance vault is funded by the Mango DAO with USDC and can be withdrawn by the DAO
+    // It is one pool for all markets; no part of it is earmarked for a single perp market
     pub insurance_vault: Pubkey,
     pub srm_vault: Pubkey,
     pub msrm_vault: Pubkey,
     pub fees_vault: Pubkey,
 
-    pub padding: [u8; 32], // padding used for future expansions
+    /// Liquidations stop once the liqee's init health reaches this value; set by admin
+    pub liquidation_target_health: I80F48,
+
+    /// Native MNGO an account must have deposited to place orders; 0 disables the requirement
+    pub min_mngo_to_trade: u64,
+
+    /// Fee in basis points charged on top of quote withdrawals and sent to the insurance fund
+    pub withdraw_fee_bps: u16,
+
+    /// If set, Deposit only accepts owners on the group's DepositWhitelist
+    pub deposit_whitelist_enabled: bool,
+
+    /// If set, PlacePerpOrder logs the account's risk state after each order it accepts
+    pub post_trade_risk_log_enabled: bool,
+
+    pub padding: [u8; 4], // padding used for future expansions
 }
+// Deployed groups are this size; new fields must come out of the padding
+const_assert_eq!(size_of::<MangoGroup>(), 6032);
 
 impl MangoGroup {
     pub fn load_mut_checked<'a>(
@@ -223,9 +269,29 @@ impl MangoGroup {
         Ok(mango_group)
     }
 
+    /// Native fee owed on a withdrawal of `quantity`, rounded up. The insurance fund only holds
+    /// the quote token, so other tokens are never charged
+    pub fn get_withdraw_fee(&self, token_index: usize, quantity: u64) -> u64 {
+        if token_index != QUOTE_INDEX || self.withdraw_fee_bps == 0 {
+            return 0;
+        }
+        let fee = (quantity as u128 * self.withdraw_fee_bps as u128 + 9_999) / 10_000;
+        fee as u64
+    }
+
     pub fn find_oracle_index(&self, oracle_pk: &Pubkey) -> Option<usize> {
         self.oracles.iter().position(|pk| pk == oracle_pk) // TODO OPT profile
     }
+    /// Max age in seconds of the cached price at oracle index `i` for health checks
+    pub fn max_oracle_age(&self, i: usize) -> u64 {
+        match self.tokens[i].max_oracle_age {
+            0 => self.valid_interval,
+            max_oracle_age => max_oracle_age as u64,
+        }
+    }
+    pub fn find_token_index(&self, mint_pk: &Pubkey) -> Option<usize> {
+        self.tokens.iter().position(|token_info| &token_info.mint == mint_pk)
+    }
     pub fn find_root_bank_index(&self, root_bank_pk: &Pubkey) -> Option<usize> {
         // TODO profile and optimize
         self.tokens.iter().position(|token_info| &token_info.root_bank == root_bank_pk)
@@ -259,7 +325,15 @@ pub struct RootBank {
     pub borrow_index: I80F48,
     pub last_updated: u64,
 
-    padding: [u8; 64], // used for future expansions
+    /// Max fraction the deposit index may grow by in one update, e.g. 0.01 for 1%; 0 disables
+    /// the check. Set by the group admin
+    pub max_index_growth: I80F48,
+
+    /// Sum of the drops of deposit_index to socialized losses so far. Deposits held through all
+    /// of them lost deposits * socialized_loss_index native tokens; see LossShare
+    pub socialized_loss_index: I80F48,
+
+    padding: [u8; 32], // used for future expansions
 }
 
 impl RootBank {
@@ -370,15 +444,7 @@ impl RootBank {
         // TODO - is this a good assumption?
         let utilization = native_borrows.checked_div(native_deposits).unwrap_or(ZERO_I80F48);
 
-        // Calculate interest rate
-        let interest_rate = if utilization > self.optimal_util {
-            let extra_util = utilization - self.optimal_util;
-            let slope = (self.max_rate - self.optimal_rate) / (ONE_I80F48 - self.optimal_util);
-            self.optimal_rate + slope * extra_util
-        } else {
-            let slope = self.optimal_rate / self.optimal_util;
-            slope * utilization
-        };
+        let interest_rate = self.interest_rate(utilization);
 
         let borrow_interest: I80F48 =
             interest_rate.checked_mul(I80F48::from_num(now_ts - self.last_updated)).unwrap();
@@ -396,7 +462,7 @@ impl RootBank {
             .unwrap()
             .checked_add(self.borrow_index)
             .unwrap();
-        self.deposit_index = self
+        let deposit_index = self
             .deposit_index
             .checked_mul(deposit_interest)
             .unwrap()
@@ -404,7 +470,35 @@ impl RootBank {
             .unwrap()
             .checked_add(self.deposit_index)
             .unwrap();
+        self.check_index_growth(deposit_index)?;
+        self.deposit_index = deposit_index;
+
+        Ok(())
+    }
+
+    /// Annual borrow rate the rate model gives at `utilization`, e.g. 0.1 for 10%. Rises linearly
+    /// to optimal_rate at optimal_util, then linearly to max_rate at full utilization
+    pub fn interest_rate(&self, utilization: I80F48) -> I80F48 {
+        if utilization > self.optimal_util {
+            let extra_util = utilization - self.optimal_util;
+            let slope = (self.max_rate - self.optimal_rate) / (ONE_I80F48 - self.optimal_util);
+            self.optimal_rate + slope * extra_util
+        } else {
+            let slope = self.optimal_rate / self.optimal_util;
+            slope * utilization
+        }
+    }
 
+    /// Circuit breaker against a bug or manipulation inflating balances: fails if moving the
+    /// deposit index to `new_deposit_index` grows it by more than max_index_growth
+    pub fn check_index_growth(&self, new_deposit_index: I80F48) -> MangoResult<()> {
+        if self.max_index_growth.is_positive() {
+            let max_deposit_index = self
+                .deposit_index
+                .checked_mul(ONE_I80F48 + self.max_index_growth)
+                .ok_or(math_err!())?;
+            check!(new_deposit_index <= max_deposit_index, MangoErrorCode::IndexJumpTooLarge)?;
+        }
         Ok(())
     }
 
@@ -431,12 +525,12 @@ impl RootBank {
 
         // TODO what if loss is greater than entire native deposits
         let percentage_loss = native_loss.checked_div(native_deposits).unwrap();
-        self.deposit_index = self
-            .deposit_index
-            .checked_sub(percentage_loss.checked_mul(self.deposit_index).unwrap())
-            .unwrap();
+        let index_loss = percentage_loss.checked_mul(self.deposit_index).unwrap();
+        self.deposit_index = self.deposit_index.checked_sub(index_loss).unwrap();
 
         mango_cache.root_bank_cache[token_index].deposit_index = self.deposit_index;
+        self.socialized_loss_index =
+            self.socialized_loss_index.checked_add(index_loss).ok_or(math_err!())?;
 
         // Reduce borrows on the bankrupt_account; Spread out over node banks if necessary
         for i in 0..self.num_node_banks {
@@ -552,6 +646,22 @@ impl NodeBank {
     pub fn checked_sub_deposit(&mut self, v: I80F48) -> MangoResult<()> {
         Ok(self.deposits = self.deposits.checked_sub(v).ok_or(throw!())?)
     }
+    /// Move `native_quantity` of deposits to `dst`, another node bank of `root_bank`; the caller
+    /// moves the tokens between the vaults. Fails if this would leave more borrows than deposits
+    pub fn checked_move_deposits(
+        &mut self,
+        dst: &mut NodeBank,
+        root_bank: &RootBank,
+        native_quantity: I80F48,
+    ) -> MangoResult<()> {
+        let deposits = native_quantity.checked_div(root_bank.deposit_index).ok_or(math_err!())?;
+        self.checked_sub_deposit(deposits)?;
+        check!(
+            self.deposits * root_bank.deposit_index >= self.borrows * root_bank.borrow_index,
+            MangoErrorCode::InsufficientLiquidity
+        )?;
+        dst.checked_add_deposit(deposits)
+    }
     pub fn has_valid_deposits_borrows(&self, root_bank_cache: &RootBankCache) -> bool {
         self.get_total_native_deposit(root_bank_cache)
             >= self.get_total_native_borrow(root_bank_cache)
@@ -641,6 +751,24 @@ impl MangoCache {
         Ok(mango_cache)
     }
 
+    /// Decode a MangoCache from raw account data, e.g. as fetched by a client over RPC.
+    /// This gives the exact prices, bank indexes and funding the program uses for health, each
+    /// with the `last_update` at which it was cached
+    pub fn load_from_bytes(data: &[u8]) -> MangoResult<&Self> {
+        check_eq!(data.len(), size_of::<Self>(), MangoErrorCode::Default)?;
+        let mango_cache: &Self =
+            try_from_bytes(data).map_err(|_| throw_err!(MangoErrorCode::Default))?;
+
+        check_eq!(
+            mango_cache.meta_data.data_type,
+            DataType::MangoCache as u8,
+            MangoErrorCode::Default
+        )?;
+        check!(mango_cache.meta_data.is_initialized, MangoErrorCode::Default)?;
+
+        Ok(mango_cache)
+    }
+
     pub fn check_valid(
         &self,
         mango_group: &MangoGroup,
@@ -656,7 +784,7 @@ impl MangoCache {
         for i in 0..mango_group.num_oracles {
             if active_assets.spot[i] || active_assets.perps[i] {
                 check!(
-                    self.price_cache[i].last_update >= valid_start,
+                    now_ts <= self.price_cache[i].last_update + mango_group.max_oracle_age(i),
                     MangoErrorCode::InvalidPriceCache
                 )?;
             }
@@ -847,6 +975,32 @@ impl HealthCache {
         self.quote = quote;
     }
 
+    /// Recompute the cached values as if `native_change` of the token had been deposited (positive)
+    /// or withdrawn (negative), without changing the MangoAccount. Resets the computed health
+    pub fn simulate_token_change(
+        &mut self,
+        mango_cache: &MangoCache,
+        mango_account: &MangoAccount,
+        open_orders_ais: &[AccountInfo; MAX_PAIRS],
+        token_index: usize,
+        native_change: I80F48,
+    ) -> MangoResult<()> {
+        let bank_cache = &mango_cache.root_bank_cache[token_index];
+        let net = mango_account.get_net(bank_cache, token_index) + native_change;
+        if token_index == QUOTE_INDEX {
+            self.quote = net;
+        } else {
+            self.spot[token_index] = mango_account.get_spot_val_with_net(
+                net,
+                mango_cache.price_cache[token_index].price,
+                token_index,
+                &open_orders_ais[token_index],
+            )?;
+        }
+        self.health = [None; NUM_HEALTHS];
+        Ok(())
+    }
+
     /// Note market_index < QUOTE_INDEX
     pub fn update_spot_val(
         &mut self,
@@ -968,6 +1122,470 @@ impl HealthCache {
     }
 }
 
+/// Return the additional init health a perp order would use if it were placed now, i.e. the
+/// negative of the health delta. `price` is in quote lots per base lot and `quantity` in base lots.
+/// The order is valued both as resting on the book and as fully filled at `price` (incl. taker fee)
+/// and the worse of the two is returned.
+pub fn order_init_margin(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    market_index: usize,
+    side: Side,
+    price: i64,
+    quantity: i64,
+) -> MangoResult<I80F48> {
+    let pmi = &mango_group.perp_markets[market_index];
+    let pmc = &mango_cache.perp_market_cache[market_index];
+    let oracle_price = mango_cache.price_cache[market_index].price;
+    let pa = &mango_account.perp_accounts[market_index];
+
+    let health_before = pa.get_health(pmi, pmc, oracle_price, HealthType::Init)?;
+
+    let mut resting = *pa;
+    match side {
+        Side::Bid => resting.bids_quantity += quantity,
+        Side::Ask => resting.asks_quantity += quantity,
+    }
+
+    let filled = pa.simulate_taker_fill(pmi, side, price, quantity)?;
+
+    let health_after = min(
+        resting.get_health(pmi, pmc, oracle_price, HealthType::Init)?,
+        filled.get_health(pmi, pmc, oracle_price, HealthType::Init)?,
+    );
+
+    Ok(health_before - health_after)
+}
+
+/// Largest quantity in base lots of an order on `side` in the perp market that keeps init health
+/// non-negative, valued like order_init_margin at the oracle price: both resting on the book and
+/// fully filled as taker. The account's collateral, positions and resting orders all count.
+/// 0 if not a single lot fits
+pub fn max_perp_base(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    open_orders_ais: &[AccountInfo; MAX_PAIRS],
+    market_index: usize,
+    side: Side,
+) -> MangoResult<i64> {
+    let pmi = &mango_group.perp_markets[market_index];
+    let active_assets =
+        UserActiveAssets::new(mango_group, mango_account, vec![(AssetType::Perp, market_index)]);
+    let mut health_cache = HealthCache::new(active_assets);
+    health_cache.init_vals(mango_group, mango_cache, mango_account, open_orders_ais)?;
+    let health = health_cache.get_health(mango_group, HealthType::Init);
+
+    // Oracle price in quote lots per base lot
+    let price: i64 = (mango_cache.price_cache[market_index].price
+        * I80F48::from_num(pmi.base_lot_size)
+        / I80F48::from_num(pmi.quote_lot_size))
+    .checked_floor()
+    .ok_or(math_err!())?
+    .checked_to_num()
+    .ok_or(math_err!())?;
+    let price = max(price, 1);
+
+    // Keep quantities far enough from i64::MAX that valuing them in native units can't overflow
+    let max_quantity = i64::MAX / 4 / max(price, pmi.base_lot_size);
+    let fits = |quantity: i64| match order_init_margin(
+        mango_group,
+        mango_cache,
+        mango_account,
+        market_index,
+        side,
+        price,
+        quantity,
+    ) {
+        Ok(margin) => !(health - margin).is_negative(),
+        Err(_) => false,
+    };
+
+    // Health is concave in the quantity, so the quantities that fit are a range starting at 0
+    if !fits(0) {
+        return Ok(0);
+    }
+
+    let mut lo = 0;
+    let mut hi = 1;
+    while fits(hi) {
+        if hi >= max_quantity {
+            return Ok(hi);
+        }
+        lo = hi;
+        hi = min(hi * 2, max_quantity);
+    }
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if fits(mid) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(lo)
+}
+
+/// Liab repaid by the liqor and asset taken from the liqee, both native, when LiquidateTokenAndToken
+/// runs on an account with `init_health`
+pub fn token_and_token_liquidation_amounts(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    liqee: &MangoAccount,
+    init_health: I80F48,
+    asset_index: usize,
+    liab_index: usize,
+    max_liab_transfer: I80F48,
+) -> MangoResult<(I80F48, I80F48)> {
+    let asset_price = mango_cache.get_price(asset_index);
+    let liab_price = mango_cache.get_price(liab_index);
+
+    let (asset_fee, init_asset_weight) = if asset_index == QUOTE_INDEX {
+        (ONE_I80F48, ONE_I80F48)
+    } else {
+        let asset_info = &mango_group.spot_markets[asset_index];
+        check!(!asset_info.is_empty(), MangoErrorCode::InvalidMarket)?;
+        (ONE_I80F48 + asset_info.liquidation_fee, asset_info.init_asset_weight)
+    };
+
+    let (liab_fee, init_liab_weight) = if liab_index == QUOTE_INDEX {
+        (ONE_I80F48, ONE_I80F48)
+    } else {
+        let liab_info = &mango_group.spot_markets[liab_index];
+        check!(!liab_info.is_empty(), MangoErrorCode::InvalidMarket)?;
+        (ONE_I80F48 - liab_info.liquidation_fee, liab_info.init_liab_weight)
+    };
+
+    // Max liab transferred to reach init_health == liquidation_target_health
+    let deficit_max_liab: I80F48 = (mango_group.liquidation_target_health - init_health)
+        / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee));
+
+    let native_deposits =
+        liqee.get_native_deposit(&mango_cache.root_bank_cache[asset_index], asset_index)?;
+    let native_borrows =
+        liqee.get_native_borrow(&mango_cache.root_bank_cache[liab_index], liab_index)?;
+
+    // Max liab transferred to reach asset_i == 0
+    let asset_implied_liab_transfer =
+        native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
+    let actual_liab_transfer = min(
+        min(min(deficit_max_liab, native_borrows), max_liab_transfer),
+        asset_implied_liab_transfer,
+    );
+
+    let asset_transfer = actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
+    Ok((actual_liab_transfer, asset_transfer))
+}
+
+/// Expected profit of the liqor in native quote for a LiquidateTokenAndToken: the value of the
+/// asset taken minus the value of the liab repaid. The whole liquidation fee goes to the liqor,
+/// none of it is split off to the insurance fund. `liqee_init_health` is computed off chain,
+/// since it depends on the liqee's open orders accounts
+pub fn estimate_liquidation_reward(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    liqee: &MangoAccount,
+    liqee_init_health: I80F48,
+    asset_index: usize,
+    liab_index: usize,
+    max_liab_transfer: I80F48,
+) -> MangoResult<I80F48> {
+    let (liab_transfer, asset_transfer) = token_and_token_liquidation_amounts(
+        mango_group,
+        mango_cache,
+        liqee,
+        liqee_init_health,
+        asset_index,
+        liab_index,
+        max_liab_transfer,
+    )?;
+    if !liab_transfer.is_positive() {
+        return Ok(ZERO_I80F48);
+    }
+    Ok(asset_transfer * mango_cache.get_price(asset_index)
+        - liab_transfer * mango_cache.get_price(liab_index))
+}
+
+/// Total position notional over equity, both unweighted in native quote at cached prices.
+/// Notional sums the absolute value of each non-quote token balance, including base in its
+/// open orders account, and of each perp base position. Returns 0 if the account has no
+/// positions and I80F48::MAX if it has positions but no positive equity
+pub fn account_leverage(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    open_orders_ais: &[AccountInfo; MAX_PAIRS],
+) -> MangoResult<I80F48> {
+    let mut equity = mango_account.get_net(&mango_cache.root_bank_cache[QUOTE_INDEX], QUOTE_INDEX);
+    let mut notional = ZERO_I80F48;
+    for i in 0..mango_group.num_oracles {
+        let price = mango_cache.price_cache[i].price;
+        if !mango_group.spot_markets[i].is_empty() {
+            let mut base = mango_account.get_net(&mango_cache.root_bank_cache[i], i);
+            if mango_account.in_margin_basket[i] && *open_orders_ais[i].key != Pubkey::default() {
+                let open_orders = load_open_orders(&open_orders_ais[i])?;
+                let (quote_free, quote_locked, base_free, base_locked) =
+                    split_open_orders(&open_orders);
+                base += base_free + base_locked;
+                equity += quote_free + quote_locked;
+            }
+            equity += base * price;
+            notional += (base * price).abs();
+        }
+
+        let pmi = &mango_group.perp_markets[i];
+        if !pmi.is_empty() {
+            let pa = &mango_account.perp_accounts[i];
+            let base = I80F48::from_num(pa.base_position * pmi.base_lot_size) * price;
+            equity += base + pa.get_quote_position(&mango_cache.perp_market_cache[i]);
+            notional += base.abs();
+        }
+    }
+
+    if notional.is_zero() {
+        Ok(ZERO_I80F48)
+    } else if !equity.is_positive() {
+        Ok(I80F48::MAX)
+    } else {
+        Ok(notional / equity)
+    }
+}
+
+/// Log line recording that a perp order passed the risk limits, with the account's state after
+/// it. An order passes if init health is non-negative, or if the account was already below
+/// zero and the order didn't lower it (reduce only). Safe mode orders also had to keep maint
+/// health non-negative if fully filled, and the account needs min_mngo_to_trade deposited
+pub fn post_trade_risk_details(
+    mango_account_pk: &Pubkey,
+    market_index: usize,
+    leverage: I80F48,
+    pre_init_health: I80F48,
+    init_health: I80F48,
+    reduce_only: bool,
+    safe_mode: bool,
+    min_mngo_to_trade: u64,
+) -> String {
+    format!(
+        "PostTradeRisk details: {{ \
+            \"mango_account\": {}, \
+            \"market_index\": {}, \
+            \"leverage\": {}, \
+            \"pre_init_health\": {}, \
+            \"init_health\": {}, \
+            \"reduce_only\": {}, \
+            \"safe_mode\": {}, \
+            \"min_mngo_to_trade\": {} \
+            }}",
+        mango_account_pk.to_string(),
+        market_index,
+        leverage.to_num::<f64>(),
+        pre_init_health.to_num::<f64>(),
+        init_health.to_num::<f64>(),
+        reduce_only,
+        safe_mode,
+        min_mngo_to_trade
+    )
+}
+
+/// Log line anchoring off-chain position history to the on-chain state at `slot`: the account's
+/// token balances with the cached deposit and borrow indexes they convert with, and its perp
+/// positions with their settled funding next to the cached funding accumulators
+pub fn position_history_anchor(
+    mango_account_pk: &Pubkey,
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    slot: u64,
+) -> String {
+    let tokens: Vec<String> = (0..MAX_TOKENS)
+        .filter(|&i| {
+            !mango_group.tokens[i].is_empty()
+                && !(mango_account.deposits[i].is_zero() && mango_account.borrows[i].is_zero())
+        })
+        .map(|i| {
+            let bank_cache = &mango_cache.root_bank_cache[i];
+            format!(
+                "{{ \"token_index\": {}, \"deposits\": {}, \"borrows\": {}, \
+                 \"deposit_index\": {}, \"borrow_index\": {}, \"last_update\": {} }}",
+                i,
+                mango_account.deposits[i].to_num::<f64>(),
+                mango_account.borrows[i].to_num::<f64>(),
+                bank_cache.deposit_index.to_num::<f64>(),
+                bank_cache.borrow_index.to_num::<f64>(),
+                bank_cache.last_update
+            )
+        })
+        .collect();
+
+    let perps: Vec<String> = (0..mango_group.num_oracles)
+        .filter(|&i| {
+            let pa = &mango_account.perp_accounts[i];
+            !mango_group.perp_markets[i].is_empty()
+                && (pa.base_position != 0 || !pa.quote_position.is_zero())
+        })
+        .map(|i| {
+            let pa = &mango_account.perp_accounts[i];
+            let pmc = &mango_cache.perp_market_cache[i];
+            format!(
+                "{{ \"market_index\": {}, \"base_position\": {}, \"quote_position\": {}, \
+                 \"long_settled_funding\": {}, \"short_settled_funding\": {}, \
+                 \"long_funding\": {}, \"short_funding\": {}, \"last_update\": {} }}",
+                i,
+                pa.base_position,
+                pa.quote_position.to_num::<f64>(),
+                pa.long_settled_funding.to_num::<f64>(),
+                pa.short_settled_funding.to_num::<f64>(),
+                pmc.long_funding.to_num::<f64>(),
+                pmc.short_funding.to_num::<f64>(),
+                pmc.last_update
+            )
+        })
+        .collect();
+
+    format!(
+        "PositionHistoryAnchor details: {{ \
+            \"mango_account\": {}, \
+            \"slot\": {}, \
+            \"tokens\": [{}], \
+            \"perps\": [{}] \
+            }}",
+        mango_account_pk.to_string(),
+        slot,
+        tokens.join(", "),
+        perps.join(", ")
+    )
+}
+
+/// True if any perp position has pnl, including unsettled funding, at cached prices that
+/// SettlePnl has not yet moved into quote deposits
+pub fn has_unsettled_pnl(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+) -> bool {
+    (0..mango_group.num_oracles).any(|i| {
+        let pmi = &mango_group.perp_markets[i];
+        if pmi.is_empty() {
+            return false;
+        }
+        let pa = &mango_account.perp_accounts[i];
+        let base = I80F48::from_num(pa.base_position * pmi.base_lot_size)
+            * mango_cache.price_cache[i].price;
+        !(base + pa.get_quote_position(&mango_cache.perp_market_cache[i])).is_zero()
+    })
+}
+
+/// Return what the deposit of `token_index` adds to this account's health of `health_type`, in
+/// native quote: the native deposit times the cached price times the asset weight. Borrows and
+/// open orders are left out. Quote has a weight of one, and tokens without a spot market count
+/// for nothing
+pub fn weighted_deposit_value(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    token_index: usize,
+    health_type: HealthType,
+) -> MangoResult<I80F48> {
+    let asset_weight = if token_index == QUOTE_INDEX {
+        ONE_I80F48
+    } else {
+        let smi = &mango_group.spot_markets[token_index];
+        if smi.is_empty() {
+            return Ok(ZERO_I80F48);
+        }
+        match health_type {
+            HealthType::Maint => smi.maint_asset_weight,
+            HealthType::Init => smi.init_asset_weight,
+        }
+    };
+    let native_deposit =
+        mango_account.get_native_deposit(&mango_cache.root_bank_cache[token_index], token_index)?;
+    native_deposit
+        .checked_mul(mango_cache.get_price(token_index))
+        .and_then(|v| v.checked_mul(asset_weight))
+        .ok_or(math_err!())
+}
+
+/// List each token this account has deposited as (token_index, native deposit, value in native
+/// quote at the cached price, init asset weight), in token order. Borrows and open orders are
+/// left out, and the value is unweighted so the entries add up to the gross collateral. Quote
+/// has a weight of one and tokens without a spot market a weight of zero
+pub fn collateral_breakdown(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+) -> MangoResult<Vec<(usize, I80F48, I80F48, I80F48)>> {
+    let mut breakdown = vec![];
+    for token_index in 0..MAX_TOKENS {
+        if !mango_account.deposits[token_index].is_positive() {
+            continue;
+        }
+        let native_deposit = mango_account
+            .get_native_deposit(&mango_cache.root_bank_cache[token_index], token_index)?;
+        let quote_value =
+            native_deposit.checked_mul(mango_cache.get_price(token_index)).ok_or(math_err!())?;
+        let health_weight = if token_index == QUOTE_INDEX {
+            ONE_I80F48
+        } else if mango_group.spot_markets[token_index].is_empty() {
+            ZERO_I80F48
+        } else {
+            mango_group.spot_markets[token_index].init_asset_weight
+        };
+        breakdown.push((token_index, native_deposit, quote_value, health_weight));
+    }
+    Ok(breakdown)
+}
+
+/// Return the MangoGroup name stored in `name`: the UTF-8 text before the zero padding. Fails
+/// if the text isn't valid UTF-8 or anything but zeros follows the first zero
+pub fn parse_group_name(name: &[u8; GROUP_NAME_LEN]) -> MangoResult<&str> {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(GROUP_NAME_LEN);
+    check!(name[len..].iter().all(|&b| b == 0), MangoErrorCode::InvalidParam)?;
+    std::str::from_utf8(&name[..len]).map_err(|_| throw_err!(MangoErrorCode::InvalidParam))
+}
+
+/// Return (init health, maint health) of this account with the cached price of each
+/// (oracle_index, price) in `price_overrides` replaced, for scenario analysis like showing health
+/// after a 10% move. Spot and perp positions of an oracle both use its overridden price, and the
+/// cache itself is left unchanged
+pub fn health_at_prices(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    open_orders_ais: &[AccountInfo; MAX_PAIRS],
+    price_overrides: &[(usize, I80F48)],
+) -> MangoResult<(I80F48, I80F48)> {
+    let mut scenario_cache = *mango_cache;
+    for &(oracle_index, price) in price_overrides {
+        check!(oracle_index < mango_group.num_oracles, MangoErrorCode::InvalidParam)?;
+        check!(price.is_positive(), MangoErrorCode::InvalidParam)?;
+        scenario_cache.price_cache[oracle_index].price = price;
+    }
+
+    let active_assets = UserActiveAssets::new(mango_group, mango_account, vec![]);
+    let mut health_cache = HealthCache::new(active_assets);
+    health_cache.init_vals(mango_group, &scenario_cache, mango_account, open_orders_ais)?;
+    Ok((
+        health_cache.get_health(mango_group, HealthType::Init),
+        health_cache.get_health(mango_group, HealthType::Maint),
+    ))
+}
+
+/// Return the native amount of `token_index` that repays this account's borrow in full at the
+/// borrow index of `root_bank`. Rounds up, so depositing exactly this leaves no borrow behind
+pub fn borrow_native(
+    mango_account: &MangoAccount,
+    token_index: usize,
+    root_bank: &RootBank,
+) -> MangoResult<u64> {
+    mango_account.borrows[token_index]
+        .checked_mul(root_bank.borrow_index)
+        .and_then(|v| v.checked_ceil())
+        .and_then(|v| v.checked_to_num())
+        .ok_or(math_err!())
+}
+
 #[derive(Copy, Clone, Pod, Loadable)]
 #[repr(C)]
 pub struct MangoAccount {
@@ -1000,8 +1618,19 @@ pub struct MangoAccount {
     /// This account cannot do anything except go through `resolve_bankruptcy`
     pub is_bankrupt: bool,
     pub info: [u8; INFO_LEN],
+
+    /// Key allowed to trade on behalf of the owner; Pubkey::default() if there is none
+    pub delegate: Pubkey,
+    /// MangoAccount credited with REFERRER_FEE_SHARE of this account's perp taker fees;
+    /// Pubkey::default() if there is none
+    pub referrer: Pubkey,
+    /// If set, the account has a PnlTracker, updated wherever its perp positions change and it
+    /// is passed
+    pub has_pnl_tracker: bool,
+    /// If set, Withdraw must be passed the account's WithdrawCaps
+    pub has_withdraw_caps: bool,
     /// padding for expansions
-    pub padding: [u8; 70],
+    pub padding: [u8; 4],
 }
 
 impl MangoAccount {
@@ -1045,6 +1674,48 @@ impl MangoAccount {
 
         Ok(mango_account)
     }
+    /// Decode a MangoAccount from raw account data, e.g. as fetched by a client over RPC.
+    /// Fails if the data is not aligned for MangoAccount; see decode_mango_accounts
+    pub fn load_from_bytes(data: &[u8]) -> MangoResult<&Self> {
+        check_eq!(data.len(), size_of::<Self>(), MangoErrorCode::Default)?;
+        let mango_account: &Self =
+            try_from_bytes(data).map_err(|_| throw_err!(MangoErrorCode::Default))?;
+
+        check_eq!(
+            mango_account.meta_data.data_type,
+            DataType::MangoAccount as u8,
+            MangoErrorCode::Default
+        )?;
+        check!(mango_account.meta_data.is_initialized, MangoErrorCode::Default)?;
+
+        Ok(mango_account)
+    }
+    /// Bring an account initialized by an older program up to MANGO_ACCOUNT_VERSION by zeroing
+    /// the fields added since. Positions and balances are untouched. Returns false if the
+    /// account already was current
+    pub fn upgrade(&mut self) -> bool {
+        let version = self.meta_data.version;
+        if version >= MANGO_ACCOUNT_VERSION {
+            return false;
+        }
+
+        // Version 1 added the delegate, version 2 the referrer, version 3 has_pnl_tracker and
+        // version 4 has_withdraw_caps
+        if version < 1 {
+            self.delegate = Pubkey::default();
+        }
+        if version < 2 {
+            self.referrer = Pubkey::default();
+        }
+        if version < 3 {
+            self.has_pnl_tracker = false;
+        }
+        if version < 4 {
+            self.has_withdraw_caps = false;
+        }
+        self.meta_data.version = MANGO_ACCOUNT_VERSION;
+        true
+    }
     pub fn get_native_deposit(
         &self,
         root_bank_cache: &RootBankCache,
@@ -1052,6 +1723,27 @@ impl MangoAccount {
     ) -> MangoResult<I80F48> {
         self.deposits[token_i].checked_mul(root_bank_cache.deposit_index).ok_or(math_err!())
     }
+    /// Fails if the group requires a minimum MNGO deposit to place orders and this account has less.
+    /// If the group has no MNGO token, no account can meet a non-zero minimum
+    pub fn check_mngo_stake(
+        &self,
+        mango_group: &MangoGroup,
+        mango_cache: &MangoCache,
+    ) -> MangoResult<()> {
+        if mango_group.min_mngo_to_trade == 0 {
+            return Ok(());
+        }
+        let mngo_deposit = match mango_group.find_token_index(&mngo_token::ID) {
+            Some(mngo_index) => {
+                self.get_native_deposit(&mango_cache.root_bank_cache[mngo_index], mngo_index)?
+            }
+            None => ZERO_I80F48,
+        };
+        check!(
+            mngo_deposit >= I80F48::from_num(mango_group.min_mngo_to_trade),
+            MangoErrorCode::InsufficientMngoStake
+        )
+    }
     pub fn get_native_borrow(
         &self,
         root_bank_cache: &RootBankCache,
@@ -1119,6 +1811,17 @@ impl MangoAccount {
         open_orders_ai: &AccountInfo,
     ) -> MangoResult<(I80F48, I80F48)> {
         let base_net = self.get_net(bank_cache, market_index);
+        self.get_spot_val_with_net(base_net, price, market_index, open_orders_ai)
+    }
+
+    /// Same as get_spot_val but with `base_net` in place of the account's net token balance
+    fn get_spot_val_with_net(
+        &self,
+        base_net: I80F48,
+        price: I80F48,
+        market_index: usize,
+        open_orders_ai: &AccountInfo,
+    ) -> MangoResult<(I80F48, I80F48)> {
         if !self.in_margin_basket[market_index] || *open_orders_ai.key == Pubkey::default() {
             Ok((base_net * price, ZERO_I80F48))
         } else {
@@ -1177,6 +1880,15 @@ impl MangoAccount {
         Ok(())
     }
 
+    /// Return the margin basket as a bitmask, with bit i set if spot market i is in it
+    pub fn margin_basket_mask(&self) -> u64 {
+        self.in_margin_basket
+            .iter()
+            .enumerate()
+            .filter(|(_, in_basket)| **in_basket)
+            .fold(0, |mask, (i, _)| mask | (1 << i))
+    }
+
     /// Return true if account should enter bankruptcy.
     /// Note entering bankruptcy is calculated differently from exiting bankruptcy because of
     /// possible rounding issues and dust
@@ -1212,6 +1924,12 @@ impl MangoAccount {
         true
     }
 
+    /// Return true if `signer` may place and cancel orders for this account: the owner, or the
+    /// delegate if one is set. Anything that moves funds out of the account stays with the owner
+    pub fn is_owner_or_delegate(&self, signer: &Pubkey) -> bool {
+        &self.owner == signer || (self.delegate != Pubkey::default() && &self.delegate == signer)
+    }
+
     /// Return true if account should exit bankruptcy.
     /// An account can leave bankruptcy if all borrows are zero and all perp positions are non-negative
     /// Note entering bankruptcy is calculated differently from exiting bankruptcy because of
@@ -1232,6 +1950,73 @@ impl MangoAccount {
         true
     }
 
+    /// Return true if the account has nothing left for liquidators to seize and its liabilities
+    /// exceed its assets, so that resolving its bankruptcy can only be done by the insurance fund
+    /// or by socializing the loss. Funds in a spot open orders account keep it in the margin
+    /// basket and pending taker fills still move its perp positions, so either means there is
+    /// something left to liquidate or settle first
+    pub fn is_insolvent(
+        &self,
+        mango_group: &MangoGroup,
+        mango_cache: &MangoCache,
+    ) -> MangoResult<bool> {
+        let mut equity = ZERO_I80F48;
+        for i in (0..mango_group.num_oracles).chain(std::iter::once(QUOTE_INDEX)) {
+            if self.deposits[i] > DUST_THRESHOLD {
+                return Ok(false);
+            }
+            if i != QUOTE_INDEX {
+                if self.in_margin_basket[i] {
+                    return Ok(false);
+                }
+                let pa = &self.perp_accounts[i];
+                if pa.quote_position.is_positive()
+                    || pa.base_position != 0
+                    || pa.taker_base != 0
+                    || pa.taker_quote != 0
+                {
+                    return Ok(false);
+                }
+                equity += pa.quote_position;
+            }
+
+            let bank_cache = &mango_cache.root_bank_cache[i];
+            let price = mango_cache.get_price(i);
+            equity += (self.get_native_deposit(bank_cache, i)?
+                - self.get_native_borrow(bank_cache, i)?)
+                * price;
+        }
+        Ok(equity.is_negative())
+    }
+
+    /// Fail with NotBankrupt unless the account `is_insolvent`
+    pub fn check_insolvent(
+        &self,
+        mango_group: &MangoGroup,
+        mango_cache: &MangoCache,
+    ) -> MangoResult<()> {
+        check!(self.is_insolvent(mango_group, mango_cache)?, MangoErrorCode::NotBankrupt)
+    }
+
+    /// Return true if nothing would be lost by closing the account: no deposits above dust, no
+    /// borrows, MSRM or spot open orders accounts, and no perp positions, orders or unclaimed MNGO
+    pub fn can_close(&self) -> bool {
+        self.deposits.iter().all(|d| *d <= DUST_THRESHOLD)
+            && self.borrows.iter().all(|b| b.is_zero())
+            && self.msrm_amount == 0
+            && self.spot_open_orders.iter().all(|oo| oo == &Pubkey::default())
+            && self.order_market.iter().all(|m| *m == FREE_ORDER_SLOT)
+            && self.perp_accounts.iter().all(|pa| {
+                pa.base_position == 0
+                    && pa.quote_position.is_zero()
+                    && pa.bids_quantity == 0
+                    && pa.asks_quantity == 0
+                    && pa.taker_base == 0
+                    && pa.taker_quote == 0
+                    && pa.mngo_accrued == 0
+            })
+    }
+
     pub fn check_open_orders(
         &self,
         mango_group: &MangoGroup,
@@ -1324,6 +2109,28 @@ impl MangoAccount {
         Ok(())
     }
 
+    /// Credit this account, the referrer of the fill's taker, with REFERRER_FEE_SHARE of the
+    /// taker fee. The credit comes out of the market's fees_accrued. Returns the amount credited
+    pub fn execute_referrer(
+        &mut self,
+        market_index: usize,
+        perp_market: &mut PerpMarket,
+        info: &PerpMarketInfo,
+        fill: &FillEvent,
+    ) -> MangoResult<I80F48> {
+        let (_, quote_change) = fill.base_quote_change(fill.taker_side);
+        let fees =
+            I80F48::from_num(perp_market.quote_lot_size * quote_change).abs() * info.taker_fee;
+        if !fees.is_positive() {
+            return Ok(ZERO_I80F48);
+        }
+
+        let referral = fees * REFERRER_FEE_SHARE;
+        perp_market.fees_accrued -= referral;
+        self.perp_accounts[market_index].quote_position += referral;
+        Ok(referral)
+    }
+
     pub fn execute_maker(
         &mut self,
         market_index: usize,
@@ -1393,6 +2200,40 @@ impl MangoAccount {
     }
 }
 
+/// A MangoAccount decoded by decode_mango_accounts, with its position in the input batch
+pub struct MangoAccountView<'a> {
+    pub index: usize,
+    pub mango_account: Cow<'a, MangoAccount>,
+}
+
+/// Decode a batch of raw MangoAccount data, e.g. for a liquidator scanning all accounts.
+/// Aligned data is borrowed without copying; unaligned data, which RPC buffers may be, is
+/// copied. Malformed data is skipped and its index logged, so the returned views may have
+/// gaps in `index`
+pub fn decode_mango_accounts<'a>(datas: &[&'a [u8]]) -> Vec<MangoAccountView<'a>> {
+    let mut views = Vec::with_capacity(datas.len());
+    for (index, &data) in datas.iter().enumerate() {
+        let mango_account = match MangoAccount::load_from_bytes(data) {
+            Ok(mango_account) => Some(Cow::Borrowed(mango_account)),
+            Err(_) if data.len() == size_of::<MangoAccount>() => {
+                let mut mango_account = MangoAccount::zeroed();
+                bytes_of_mut(&mut mango_account).copy_from_slice(data);
+                MangoAccount::load_from_bytes(bytes_of(&mango_account))
+                    .ok()
+                    .map(|_| Cow::Owned(mango_account))
+            }
+            Err(_) => None,
+        };
+        match mango_account {
+            Some(mango_account) => views.push(MangoAccountView { index, mango_account }),
+            None => {
+                msg!("decode_mango_accounts: skipping malformed MangoAccount at index {}", index)
+            }
+        }
+    }
+    views
+}
+
 #[derive(Copy, Clone, Pod)]
 #[repr(C)]
 pub struct PerpAccount {
@@ -1553,6 +2394,48 @@ impl PerpAccount {
         }
     }
 
+    /// Return the health contribution of this PerpAccount with weights applied
+    pub fn get_health(
+        &self,
+        pmi: &PerpMarketInfo,
+        pmc: &PerpMarketCache,
+        price: I80F48,
+        health_type: HealthType,
+    ) -> MangoResult<I80F48> {
+        let (base, quote) = self.get_val(pmi, pmc, price)?;
+        let (asset_weight, liab_weight) = match health_type {
+            HealthType::Maint => (pmi.maint_asset_weight, pmi.maint_liab_weight),
+            HealthType::Init => (pmi.init_asset_weight, pmi.init_liab_weight),
+        };
+
+        if base.is_negative() {
+            Ok(base * liab_weight + quote)
+        } else {
+            Ok(base * asset_weight + quote)
+        }
+    }
+
+    /// Return a copy of this PerpAccount as it would be if an order of `quantity` base lots were
+    /// fully filled as taker at `price`, including the taker fee
+    pub fn simulate_taker_fill(
+        &self,
+        pmi: &PerpMarketInfo,
+        side: Side,
+        price: i64,
+        quantity: i64,
+    ) -> MangoResult<PerpAccount> {
+        let quote_change = price.checked_mul(quantity).ok_or(math_err!())?;
+        let (base_change, quote_change) = match side {
+            Side::Bid => (quantity, -quote_change),
+            Side::Ask => (-quantity, quote_change),
+        };
+        let mut filled = *self;
+        filled.add_taker_trade(base_change, quote_change);
+        filled.quote_position -=
+            I80F48::from_num(quote_change.abs() * pmi.quote_lot_size) * pmi.taker_fee;
+        Ok(filled)
+    }
+
     pub fn is_active(&self) -> bool {
         self.base_position != 0
             || !self.quote_position.is_zero()
@@ -1579,6 +2462,204 @@ impl PerpAccount {
     }
 }
 
+/// Return the absolute size of the perp position in native quote at `oracle_price`
+/// `oracle_price` is native quote per native base, as stored in the PriceCache
+pub fn perp_position_notional(
+    perp_account: &PerpAccount,
+    perp_market: &PerpMarket,
+    oracle_price: I80F48,
+) -> I80F48 {
+    I80F48::from_num(perp_account.base_position).abs()
+        * I80F48::from_num(perp_market.base_lot_size)
+        * oracle_price
+}
+
+/// Return the native quote value of the resting perp orders health assumes get filled, at `price`.
+/// Health takes whichever side moves the position furthest from zero: resting bids reserve this
+/// much quote to buy with, and resting asks add this much base exposure, valued in quote. Taker
+/// trades still on the EventQueue count towards the position
+pub fn perp_orders_locked_quote(
+    perp_account: &PerpAccount,
+    perp_market_info: &PerpMarketInfo,
+    price: I80F48,
+) -> I80F48 {
+    let base_position = perp_account.base_position + perp_account.taker_base;
+    let bids_base_net = base_position + perp_account.bids_quantity;
+    let asks_base_net = base_position - perp_account.asks_quantity;
+    let locked_lots = if bids_base_net.abs() > asks_base_net.abs() {
+        perp_account.bids_quantity
+    } else {
+        perp_account.asks_quantity
+    };
+    I80F48::from_num(locked_lots * perp_market_info.base_lot_size) * price
+}
+
+/// Return the perp losses, in native quote, the insurance fund may have to absorb on this market:
+/// the maint margin held against its open interest. Positions that lose more than their maint
+/// margin before they are liquidated go bankrupt and are covered by the insurance fund first
+pub fn perp_losses_at_risk(
+    perp_market_info: &PerpMarketInfo,
+    perp_market: &PerpMarket,
+    oracle_price: I80F48,
+) -> I80F48 {
+    // open_interest sums the longs and the shorts, so each side is half of it
+    let base_open = I80F48::from_num(perp_market.open_interest) / I80F48::from_num(2);
+    base_open
+        * I80F48::from_num(perp_market_info.base_lot_size)
+        * oracle_price
+        * (perp_market_info.maint_liab_weight - ONE_I80F48)
+}
+
+/// Return the notional, in native quote, of the long open interest summed over `perp_markets`,
+/// each paired with its market index and valued at the cached oracle price. Every long is
+/// matched by a short, so this is also the short side's notional
+pub fn total_open_interest(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    perp_markets: &[(usize, &PerpMarket)],
+) -> I80F48 {
+    perp_markets
+        .iter()
+        .map(|&(market_index, perp_market)| {
+            // open_interest sums the longs and the shorts, so each side is half of it
+            let long_lots = I80F48::from_num(perp_market.open_interest) / I80F48::from_num(2);
+            long_lots
+                * I80F48::from_num(mango_group.perp_markets[market_index].base_lot_size)
+                * mango_cache.price_cache[market_index].price
+        })
+        .fold(ZERO_I80F48, |total, notional| total + notional)
+}
+
+/// Seconds from `now_ts` until a perp market is next due a funding update, taking updates to be
+/// due every `target_period_length` seconds after `last_updated`. Funding accrues for however
+/// long has passed whenever UpdateFunding runs, so this is a countdown for display. Zero once the
+/// period has elapsed or if the market has no period length
+pub fn next_funding_time(perp_market: &PerpMarket, now_ts: u64) -> u64 {
+    let period = perp_market.liquidity_mining_info.target_period_length;
+    perp_market.last_updated.saturating_add(period).saturating_sub(now_ts)
+}
+
+/// Return the fraction of the token's native deposits that is borrowed, summed over
+/// `node_banks`, which should be all the node banks of `root_bank`. Zero if nothing is deposited
+pub fn root_bank_utilization(root_bank: &RootBank, node_banks: &[&NodeBank]) -> I80F48 {
+    let (deposits, borrows) =
+        node_banks.iter().fold((ZERO_I80F48, ZERO_I80F48), |(deposits, borrows), node_bank| {
+            (deposits + node_bank.deposits, borrows + node_bank.borrows)
+        });
+    let native_deposits = deposits * root_bank.deposit_index;
+    let native_borrows = borrows * root_bank.borrow_index;
+    native_borrows.checked_div(native_deposits).unwrap_or(ZERO_I80F48)
+}
+
+/// Return the annual rate borrowers of the token currently pay, as update_index would accrue it
+pub fn borrow_apr(root_bank: &RootBank, node_banks: &[&NodeBank]) -> I80F48 {
+    root_bank.interest_rate(root_bank_utilization(root_bank, node_banks))
+}
+
+/// Return the annual rate depositors of the token currently earn. Borrow interest is spread
+/// over all deposits, so this is the borrow rate scaled by utilization
+pub fn deposit_apr(root_bank: &RootBank, node_banks: &[&NodeBank]) -> I80F48 {
+    let utilization = root_bank_utilization(root_bank, node_banks);
+    root_bank.interest_rate(utilization) * utilization
+}
+
+/// Return how much of the liqee's `liab_index` borrows, in native liab tokens, would have to be
+/// socialized across depositors once resolve_token_bankruptcy has used up the `dao_vault_balance`
+/// native quote held in the insurance vault. Zero means the insurance fund covers the bankruptcy
+pub fn bankruptcy_shortfall(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    liqee: &MangoAccount,
+    liab_index: usize,
+    dao_vault_balance: u64,
+) -> MangoResult<I80F48> {
+    let native_borrows =
+        liqee.get_native_borrow(&mango_cache.root_bank_cache[liab_index], liab_index)?;
+    if !native_borrows.is_positive() {
+        return Ok(ZERO_I80F48);
+    }
+
+    let liab_price = mango_cache.get_price(liab_index);
+    let liab_fee = if liab_index == QUOTE_INDEX {
+        ONE_I80F48
+    } else {
+        ONE_I80F48 - mango_group.spot_markets[liab_index].liquidation_fee
+    };
+
+    // Mirror the rounding in resolve_token_bankruptcy so the estimate matches what it transfers
+    let insured_liabs = I80F48::from_num(dao_vault_balance) * liab_fee / liab_price;
+    let liab_transfer = native_borrows.min(insured_liabs);
+    let insurance_transfer = (liab_transfer * liab_price / liab_fee)
+        .checked_ceil()
+        .ok_or(math_err!())?
+        .to_num::<u64>()
+        .min(dao_vault_balance);
+    let covered = I80F48::from_num(insurance_transfer) * liab_fee / liab_price;
+
+    Ok((native_borrows - covered).max(ZERO_I80F48))
+}
+
+/// A group invariant that check_group_invariants found broken
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InvariantError {
+    /// Deposits minus borrows of a node bank, in native tokens, differ from its vault balance
+    VaultMismatch { token_index: usize, node_bank_index: usize },
+    /// Open interest of a perp market is negative or odd, so its longs can't equal its shorts
+    UnbalancedOpenInterest { market_index: usize },
+    /// Fees accrued or losses at risk of a perp market, which the insurance fund is settled
+    /// against, are negative
+    NegativeInsuranceAccounting { market_index: usize },
+}
+
+/// A root bank with its node banks, each paired with the balance of its vault
+pub struct RootBankSnapshot<'a> {
+    pub token_index: usize,
+    pub root_bank: &'a RootBank,
+    pub node_banks: Vec<(&'a NodeBank, u64)>,
+}
+
+/// Check that the accounts of a group add up: the deposits minus borrows of every node bank
+/// match its vault balance to within one native token, every perp market's open interest splits
+/// evenly into longs and shorts, and the perp fees and losses the insurance fund is settled
+/// against are not negative. Stops at the first broken invariant
+pub fn check_group_invariants(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    root_banks: &[RootBankSnapshot],
+    perp_markets: &[(usize, &PerpMarket)],
+) -> Result<(), InvariantError> {
+    for snapshot in root_banks.iter() {
+        let root_bank = snapshot.root_bank;
+        for (node_bank_index, (node_bank, vault_balance)) in snapshot.node_banks.iter().enumerate()
+        {
+            let net_deposits = node_bank.deposits * root_bank.deposit_index
+                - node_bank.borrows * root_bank.borrow_index;
+            if (net_deposits - I80F48::from_num(*vault_balance)).abs() > ONE_I80F48 {
+                return Err(InvariantError::VaultMismatch {
+                    token_index: snapshot.token_index,
+                    node_bank_index,
+                });
+            }
+        }
+    }
+
+    for &(market_index, perp_market) in perp_markets.iter() {
+        if perp_market.open_interest < 0 || perp_market.open_interest % 2 != 0 {
+            return Err(InvariantError::UnbalancedOpenInterest { market_index });
+        }
+        let losses_at_risk = perp_losses_at_risk(
+            &mango_group.perp_markets[market_index],
+            perp_market,
+            mango_cache.price_cache[market_index].price,
+        );
+        if perp_market.fees_accrued.is_negative() || losses_at_risk.is_negative() {
+            return Err(InvariantError::NegativeInsuranceAccounting { market_index });
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Copy, Clone, Pod)]
 #[repr(C)]
 /// Information regarding market maker incentives for a perp market
@@ -1725,13 +2806,20 @@ impl PerpMarket {
         }
     }
 
-    /// Use current order book price and index price to update the instantaneous funding
+    pub fn gen_pegged_order_id(&mut self, side: Side, peg_offset: i64) -> i128 {
+        self.seq_num += 1;
+        pegged_order_key(side, peg_offset, self.seq_num)
+    }
+
+    /// Use current order book price and index price to update the instantaneous funding.
+    /// `impact_quote_notional` comes from the market's PerpMarketConfig
     pub fn update_funding(
         &mut self,
         mango_group: &MangoGroup,
         book: &Book,
         mango_cache: &MangoCache,
         market_index: usize,
+        impact_quote_notional: i64,
         now_ts: u64,
     ) -> MangoResult<()> {
         // Get the index price from cache, ensure it's not outdated
@@ -1741,42 +2829,72 @@ impl PerpMarket {
             MangoErrorCode::InvalidCache
         )?;
         let index_price = price_cache.price;
-        // Get current book price & compare it to index price
 
-        // TODO get impact bid and impact ask if compute allows
         // TODO consider corner cases of funding being updated
-        let bid = book.get_best_bid_price();
-        let ask = book.get_best_ask_price();
+        let diff =
+            self.get_funding_rate(book.bids(), book.asks(), index_price, impact_quote_notional);
+
+        // TODO TEST consider what happens if time_factor is very small. Can funding_delta == 0 when diff != 0?
+        let time_factor = I80F48::from_num(now_ts - self.last_updated) / DAY;
+        let funding_delta: I80F48 = index_price
+            .checked_mul(diff)
+            .ok_or(math_err!())?
+            .checked_mul(I80F48::from_num(self.base_lot_size))
+            .ok_or(math_err!())?
+            .checked_mul(time_factor)
+            .ok_or(math_err!())?;
 
+        self.apply_funding_delta(funding_delta)?;
+        self.last_updated = now_ts;
+
+        // Check if liquidity incentives ought to be paid out and if so pay them out
+        Ok(())
+    }
+
+    /// Compare the mid of the impact bid and ask to the index price. `impact_quote_notional` is
+    /// the native quote the impact bid and ask must fill; 0 uses the best bid and ask. Pegged
+    /// orders are priced off the index price. A side that can't fill it counts as empty
+    pub fn get_funding_rate(
+        &self,
+        bids: &BookSide,
+        asks: &BookSide,
+        index_price: I80F48,
+        impact_quote_notional: i64,
+    ) -> I80F48 {
         const MAX_FUNDING: I80F48 = I80F48!(0.05);
         const MIN_FUNDING: I80F48 = I80F48!(-0.05);
 
-        let diff = match (bid, ask) {
+        let impact_notional = impact_quote_notional / self.quote_lot_size;
+        let oracle_price_lots = self.native_price_to_lot(index_price);
+        let bid = impact_price(bids, impact_notional, oracle_price_lots);
+        let ask = impact_price(asks, impact_notional, oracle_price_lots);
+
+        match (bid, ask) {
             (Some(bid), Some(ask)) => {
                 // calculate mid-market rate
-                let book_price = self.lot_to_native_price((bid + ask) / 2);
+                let book_price = (bid + ask) / 2 * I80F48::from_num(self.quote_lot_size)
+                    / I80F48::from_num(self.base_lot_size);
                 (book_price / index_price - ONE_I80F48).clamp(MIN_FUNDING, MAX_FUNDING)
             }
             (Some(_bid), None) => MAX_FUNDING,
             (None, Some(_ask)) => MIN_FUNDING,
             (None, None) => ZERO_I80F48,
-        };
-
-        // TODO TEST consider what happens if time_factor is very small. Can funding_delta == 0 when diff != 0?
-        let time_factor = I80F48::from_num(now_ts - self.last_updated) / DAY;
-        let funding_delta: I80F48 = index_price
-            .checked_mul(diff)
-            .unwrap()
-            .checked_mul(I80F48::from_num(self.base_lot_size))
-            .unwrap()
-            .checked_mul(time_factor)
-            .unwrap();
-
-        self.long_funding += funding_delta;
-        self.short_funding += funding_delta;
-        self.last_updated = now_ts;
+        }
+    }
 
-        // Check if liquidity incentives ought to be paid out and if so pay them out
+    /// Add funding_delta to both funding accumulators, or neither if either would overflow.
+    ///
+    /// The accumulators only grow by at most index_price * MAX_FUNDING * base_lot_size per day,
+    /// so reaching the I80F48 limit of about 6e23 takes centuries even for extreme markets.
+    /// There is no rollover: accounts realize funding as the difference to their settled
+    /// funding, which wrapping would corrupt. Instead UpdateFunding fails with MathError,
+    /// funding stops accruing and last_updated is left alone, so nothing is lost or double
+    /// counted while the market is wound down.
+    pub fn apply_funding_delta(&mut self, funding_delta: I80F48) -> MangoResult<()> {
+        let long_funding = self.long_funding.checked_add(funding_delta).ok_or(math_err!())?;
+        let short_funding = self.short_funding.checked_add(funding_delta).ok_or(math_err!())?;
+        self.long_funding = long_funding;
+        self.short_funding = short_funding;
         Ok(())
     }
 
@@ -1789,6 +2907,18 @@ impl PerpMarket {
             .unwrap()
     }
 
+    /// Convert from a native price to a price on the book, rounding down
+    pub fn native_price_to_lot(&self, price: I80F48) -> i64 {
+        price
+            .checked_mul(I80F48::from_num(self.base_lot_size))
+            .unwrap()
+            .checked_div(I80F48::from_num(self.quote_lot_size))
+            .unwrap()
+            .checked_floor()
+            .unwrap()
+            .to_num()
+    }
+
     /// Socialize the loss in this account across all longs and shorts
     pub fn socialize_loss(
         &mut self,
@@ -1809,6 +2939,488 @@ impl PerpMarket {
     }
 }
 
+/// Owners allowed to deposit while the MangoGroup has its deposit whitelist enabled.
+/// There is one per group, at the address given by `DepositWhitelist::find_address`
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct DepositWhitelist {
+    pub meta_data: MetaData,
+
+    pub mango_group: Pubkey,
+    pub num_owners: usize,
+    pub owners: [Pubkey; MAX_DEPOSIT_WHITELIST],
+}
+
+impl DepositWhitelist {
+    pub fn find_address(program_id: &Pubkey, mango_group_pk: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[mango_group_pk.as_ref(), DEPOSIT_WHITELIST_SEED], program_id)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_group_pk: &Pubkey,
+    ) -> MangoResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::DepositWhitelist as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_group_pk == &state.mango_group, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_group_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::DepositWhitelist as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_group_pk == &state.mango_group, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    pub fn contains(&self, owner: &Pubkey) -> bool {
+        self.owners[..self.num_owners].contains(owner)
+    }
+
+    /// Adding an owner that is already whitelisted is a no-op
+    pub fn add(&mut self, owner: &Pubkey) -> MangoResult<()> {
+        if self.contains(owner) {
+            return Ok(());
+        }
+        check!(self.num_owners < MAX_DEPOSIT_WHITELIST, MangoErrorCode::InvalidParam)?;
+        self.owners[self.num_owners] = *owner;
+        self.num_owners += 1;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, owner: &Pubkey) -> MangoResult<()> {
+        let i = self.owners[..self.num_owners]
+            .iter()
+            .position(|pk| pk == owner)
+            .ok_or(throw_err!(MangoErrorCode::NotWhitelisted))?;
+        let last = self.num_owners - 1;
+        self.owners[i] = self.owners[last];
+        self.owners[last] = Pubkey::default();
+        self.num_owners = last;
+        Ok(())
+    }
+}
+
+/// Funds recovered after socialized losses of a token, e.g. from a clawback or a donation, owed
+/// to the depositors who took the losses. Each depositor registers its own LossShare, derived
+/// from the RootBank's socialized_loss_index. Anyone can send recovered tokens to the vault, and
+/// they are paid out in proportion to the shares. There is one per group and token, at the
+/// address given by `LossRecovery::find_address`
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct LossRecovery {
+    pub meta_data: MetaData,
+
+    pub mango_group: Pubkey,
+    pub token_index: usize,
+    /// Token account owned by the MangoGroup signer that holds the recovered funds
+    pub vault: Pubkey,
+
+    /// Sum of the recorded shares, in native tokens lost
+    pub total_shares: I80F48,
+    /// Native tokens paid out so far; together with the vault balance, everything recovered
+    pub total_claimed: u64,
+    padding: [u8; 8],
+}
+
+impl LossRecovery {
+    pub fn find_address(
+        program_id: &Pubkey,
+        mango_group_pk: &Pubkey,
+        token_index: usize,
+    ) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[mango_group_pk.as_ref(), LOSS_RECOVERY_SEED, &(token_index as u64).to_le_bytes()],
+            program_id,
+        )
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_group_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::LossRecovery as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_group_pk == &state.mango_group, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    /// Add to `loss_share` what `deposits`, in the token's indexed units, lost to the socialized
+    /// losses since it was last registered. Shares are fixed once the first claim is paid, so
+    /// that claims stay proportional
+    pub fn register(
+        &mut self,
+        loss_share: &mut LossShare,
+        deposits: I80F48,
+        root_bank: &RootBank,
+    ) -> MangoResult<()> {
+        check!(self.total_claimed == 0, MangoErrorCode::InvalidParam)?;
+        let index_loss = root_bank
+            .socialized_loss_index
+            .checked_sub(loss_share.loss_index)
+            .ok_or(math_err!())?;
+        let share = deposits.checked_mul(index_loss).ok_or(math_err!())?;
+        self.total_shares = self.total_shares.checked_add(share).ok_or(math_err!())?;
+        loss_share.share = loss_share.share.checked_add(share).ok_or(math_err!())?;
+        loss_share.loss_index = root_bank.socialized_loss_index;
+        Ok(())
+    }
+
+    /// Native tokens `loss_share` can still claim while the vault holds `vault_balance`. Rounds
+    /// down, so the claims never add up to more than was recovered
+    pub fn claimable(&self, loss_share: &LossShare, vault_balance: u64) -> MangoResult<u64> {
+        if loss_share.share.is_zero() {
+            return Ok(0);
+        }
+        let fraction = loss_share.share.checked_div(self.total_shares).ok_or(math_err!())?;
+        let recovered = I80F48::from_num(vault_balance) + I80F48::from_num(self.total_claimed);
+        let owed = recovered.checked_mul(fraction).ok_or(math_err!())?.to_num::<u64>();
+        Ok(owed.saturating_sub(loss_share.claimed).min(vault_balance))
+    }
+
+    /// Mark everything `loss_share` can claim as paid and return it
+    pub fn claim(&mut self, loss_share: &mut LossShare, vault_balance: u64) -> MangoResult<u64> {
+        let quantity = self.claimable(loss_share, vault_balance)?;
+        check!(quantity > 0, MangoErrorCode::NothingToClaim)?;
+        loss_share.claimed += quantity;
+        self.total_claimed += quantity;
+        Ok(quantity)
+    }
+}
+
+/// The share of one MangoAccount in a LossRecovery, at the address given by
+/// `LossShare::find_address`. Accounts keep no history of their deposits, so the share is
+/// measured on the deposits the account holds when it registers: deposits withdrawn since a
+/// loss aren't covered, and deposits added since are
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct LossShare {
+    pub meta_data: MetaData,
+
+    pub loss_recovery: Pubkey,
+    pub mango_account: Pubkey,
+    /// Native tokens paid out to the MangoAccount so far
+    pub claimed: u64,
+    /// Native tokens the MangoAccount lost
+    pub share: I80F48,
+    /// The RootBank's socialized_loss_index when the share was last registered
+    pub loss_index: I80F48,
+}
+
+impl LossShare {
+    pub fn find_address(
+        program_id: &Pubkey,
+        loss_recovery_pk: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[loss_recovery_pk.as_ref(), LOSS_SHARE_SEED, mango_account_pk.as_ref()],
+            program_id,
+        )
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        loss_recovery_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::LossShare as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(loss_recovery_pk == &state.loss_recovery, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+}
+
+/// Realized pnl of a MangoAccount's perp positions, at the address given by
+/// `PnlTracker::find_address`. Opt-in, created by InitPnlTracker while the account has no perp
+/// positions, so that entry_quote always matches the open base_position
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct PnlTracker {
+    pub meta_data: MetaData,
+
+    pub mango_account: Pubkey,
+    /// Native quote paid (negative) or received (positive) to open the current base_position of
+    /// each perp market, before fees
+    pub entry_quote: [I80F48; MAX_PAIRS],
+    /// Cumulative native quote pnl realized by reducing or closing positions; excludes fees
+    /// and funding
+    pub realized_pnl: [I80F48; MAX_PAIRS],
+}
+
+impl PnlTracker {
+    pub fn find_address(program_id: &Pubkey, mango_account_pk: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[mango_account_pk.as_ref(), PNL_TRACKER_SEED], program_id)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> MangoResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::PnlTracker as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_account_pk == &state.mango_account, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::PnlTracker as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_account_pk == &state.mango_account, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    /// Realize pnl on the part of the position a trade reduces and track the entry of what
+    /// remains open. `base_position` is the position before the trade and `quote_change` is in
+    /// native quote before fees
+    pub fn record_trade(
+        &mut self,
+        market_index: usize,
+        base_position: i64,
+        base_change: i64,
+        quote_change: I80F48,
+    ) {
+        let entry_quote = &mut self.entry_quote[market_index];
+        let start = base_position;
+        if start == 0 || base_change == 0 || (start > 0) == (base_change > 0) {
+            *entry_quote += quote_change;
+            return;
+        }
+
+        // Part of the trade closes the position; the rest, if any, opens one on the other side
+        let closed = I80F48::from_num(base_change.abs().min(start.abs()));
+        let closed_entry = *entry_quote * closed / I80F48::from_num(start.abs());
+        let closed_quote = quote_change * closed / I80F48::from_num(base_change.abs());
+        self.realized_pnl[market_index] += closed_entry + closed_quote;
+        *entry_quote += quote_change - closed_quote - closed_entry;
+    }
+
+    /// Record the side of `fill` taken by `perp_account`, before the fill is applied to it
+    pub fn record_fill(
+        &mut self,
+        market_index: usize,
+        perp_account: &PerpAccount,
+        perp_market: &PerpMarket,
+        fill: &FillEvent,
+        side: Side,
+    ) {
+        let (base_change, quote_change) = fill.base_quote_change(side);
+        let quote = I80F48::from_num(perp_market.quote_lot_size * quote_change);
+        self.record_trade(market_index, perp_account.base_position, base_change, quote);
+    }
+}
+
+/// Per-tx withdrawal caps of a MangoAccount, at the address given by
+/// `WithdrawCaps::find_address`. Created by the first SetMaxWithdrawPerTx
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct WithdrawCaps {
+    pub meta_data: MetaData,
+
+    pub mango_account: Pubkey,
+    /// Max native amount of each token one Withdraw may take out; 0 means unlimited
+    pub max_withdraw_per_tx: [u64; MAX_TOKENS],
+}
+
+impl WithdrawCaps {
+    pub fn find_address(program_id: &Pubkey, mango_account_pk: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[mango_account_pk.as_ref(), WITHDRAW_CAPS_SEED], program_id)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> MangoResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::WithdrawCaps as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_account_pk == &state.mango_account, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::WithdrawCaps as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_account_pk == &state.mango_account, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+}
+
+/// Human readable label of a MangoGroup, at the address given by `GroupName::find_address`.
+/// Created by the first SetGroupName
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct GroupName {
+    pub meta_data: MetaData,
+
+    pub mango_group: Pubkey,
+    /// UTF-8, zero padded. See `parse_group_name`
+    pub name: [u8; GROUP_NAME_LEN],
+}
+
+impl GroupName {
+    pub fn find_address(program_id: &Pubkey, mango_group_pk: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[mango_group_pk.as_ref(), GROUP_NAME_SEED], program_id)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_group_pk: &Pubkey,
+    ) -> MangoResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::GroupName as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_group_pk == &state.mango_group, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_group_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::GroupName as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(mango_group_pk == &state.mango_group, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+}
+
+/// Settings of a PerpMarket added after its layout was deployed, at the address given by
+/// `PerpMarketConfig::find_address`. Created by the first SetPerpImpactDepth; a market without
+/// one uses the defaults
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct PerpMarketConfig {
+    pub meta_data: MetaData,
+
+    pub perp_market: Pubkey,
+    /// Native quote notional the impact bid and ask used for funding must fill; 0 uses the
+    /// best bid and ask
+    pub impact_quote_notional: i64,
+}
+
+impl PerpMarketConfig {
+    pub fn find_address(program_id: &Pubkey, perp_market_pk: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[perp_market_pk.as_ref(), PERP_MARKET_CONFIG_SEED],
+            program_id,
+        )
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        perp_market_pk: &Pubkey,
+    ) -> MangoResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::PerpMarketConfig as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(perp_market_pk == &state.perp_market, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        perp_market_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccount)?;
+        check!(
+            state.meta_data.data_type == DataType::PerpMarketConfig as u8,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check!(perp_market_pk == &state.perp_market, MangoErrorCode::InvalidAccount)?;
+        Ok(state)
+    }
+
+    /// The impact_quote_notional of the market, 0 if its config was never created. The address
+    /// of a missing config is checked, so the depth can't be dropped by leaving the config out
+    pub fn load_impact_quote_notional(
+        account: &AccountInfo,
+        program_id: &Pubkey,
+        perp_market_pk: &Pubkey,
+    ) -> MangoResult<i64> {
+        if account.data_is_empty() {
+            let (config_pk, _) = Self::find_address(program_id, perp_market_pk);
+            check_eq!(account.key, &config_pk, MangoErrorCode::InvalidAccount)?;
+            return Ok(0);
+        }
+        Ok(Self::load_checked(account, program_id, perp_market_pk)?.impact_quote_notional)
+    }
+}
+
 pub fn load_market_state<'a>(
     market_account: &'a AccountInfo,
     program_id: &Pubkey,
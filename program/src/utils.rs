@@ -67,3 +67,13 @@ pub fn split_open_orders(
         I80F48::from_num(open_orders.native_coin_total - open_orders.native_coin_free),
     )
 }
+
+/// Return the native (base_free, quote_free) that SettleFunds would move out of `open_orders`.
+/// Referrer rebates are paid out together with the free quote.
+#[inline(always)]
+pub fn open_orders_settleable(open_orders: &serum_dex::state::OpenOrders) -> (u64, u64) {
+    (
+        open_orders.native_coin_free,
+        open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
+    )
+}
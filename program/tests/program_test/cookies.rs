@@ -4,8 +4,9 @@ use std::num::NonZeroU64;
 
 use solana_program::pubkey::Pubkey;
 use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transport::TransportError;
 
-use mango::{ids::*, matching::*, queue::*, state::*, utils::*};
+use mango::{error::MangoErrorCode, ids::*, matching::*, queue::*, state::*, utils::*};
 
 use crate::*;
 
@@ -48,13 +49,23 @@ pub struct MangoGroupCookie {
 impl MangoGroupCookie {
     #[allow(dead_code)]
     pub async fn default(test: &mut MangoProgramTest) -> Self {
+        Self::init_with_signer_nonce(test, None).await.unwrap()
+    }
+
+    /// Init a group, passing `signer_nonce` instead of the nonce of the signer key if given
+    #[allow(dead_code)]
+    pub async fn init_with_signer_nonce(
+        test: &mut MangoProgramTest,
+        signer_nonce: Option<u64>,
+    ) -> Result<Self, TransportError> {
         let mango_program_id = test.mango_program_id;
         let serum_program_id = test.serum_program_id;
 
         let mango_group_pk = test.create_account(size_of::<MangoGroup>(), &mango_program_id).await;
         let mango_cache_pk = test.create_account(size_of::<MangoCache>(), &mango_program_id).await;
-        let (signer_pk, signer_nonce) =
+        let (signer_pk, derived_nonce) =
             create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+        let signer_nonce = signer_nonce.unwrap_or(derived_nonce);
         let admin_pk = test.get_payer_pk();
 
         let quote_mint_pk = test.mints[test.quote_index].pubkey.unwrap();
@@ -93,12 +104,12 @@ impl MangoGroupCookie {
         )
         .unwrap()];
 
-        test.process_transaction(&instructions, None).await.unwrap();
+        test.process_transaction(&instructions, None).await?;
 
         let mango_group = test.load_account::<MangoGroup>(mango_group_pk).await;
         let mango_cache = test.load_account::<MangoCache>(mango_group.mango_cache).await;
 
-        MangoGroupCookie {
+        Ok(MangoGroupCookie {
             address: mango_group_pk,
             mango_group: mango_group,
             mango_cache: mango_cache,
@@ -109,7 +120,7 @@ impl MangoGroupCookie {
             current_perp_order_id: STARTING_PERP_ORDER_ID,
             users_with_spot_event: vec![Vec::new(); test.num_mints - 1],
             users_with_perp_event: vec![Vec::new(); test.num_mints - 1],
-        }
+        })
     }
 
     #[allow(dead_code)]
@@ -190,6 +201,192 @@ impl MangoGroupCookie {
         test.process_transaction(&instructions, None).await.unwrap();
     }
 
+    #[allow(dead_code)]
+    pub async fn set_max_oracle_age(
+        &mut self,
+        test: &mut MangoProgramTest,
+        oracle_index: usize,
+        max_oracle_age: u32,
+    ) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let oracle_pk = self.mango_group.oracles[oracle_index];
+        let instructions = [mango::instruction::set_max_oracle_age(
+            &mango_program_id,
+            &self.address,
+            &oracle_pk,
+            &admin_pk,
+            max_oracle_age,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+        self.mango_group = test.load_account::<MangoGroup>(self.address).await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_liquidation_target_health(
+        &mut self,
+        test: &mut MangoProgramTest,
+        target_health: f64,
+    ) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let native_target_health = I80F48::from_num(target_health * test.quote_mint.unit);
+        let instructions = [mango::instruction::set_liquidation_target_health(
+            &mango_program_id,
+            &self.address,
+            &admin_pk,
+            native_target_health,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+        self.mango_group = test.load_account::<MangoGroup>(self.address).await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_perp_market_fees(
+        &mut self,
+        test: &mut MangoProgramTest,
+        mint_index: usize,
+        maker_fee: f64,
+        taker_fee: f64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let instructions = [mango::instruction::set_perp_market_fees(
+            &mango_program_id,
+            &self.address,
+            &self.perp_markets[mint_index].address,
+            &admin_pk,
+            I80F48::from_num(maker_fee),
+            I80F48::from_num(taker_fee),
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await?;
+        self.mango_group = test.load_account::<MangoGroup>(self.address).await;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub async fn add_to_deposit_whitelist(&mut self, test: &mut MangoProgramTest, owner: &Pubkey) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let (deposit_whitelist_pk, _) =
+            DepositWhitelist::find_address(&mango_program_id, &self.address);
+        let instructions = [mango::instruction::add_to_deposit_whitelist(
+            &mango_program_id,
+            &self.address,
+            &admin_pk,
+            &deposit_whitelist_pk,
+            owner,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+    }
+
+    #[allow(dead_code)]
+    pub async fn remove_from_deposit_whitelist(
+        &mut self,
+        test: &mut MangoProgramTest,
+        owner: &Pubkey,
+    ) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let (deposit_whitelist_pk, _) =
+            DepositWhitelist::find_address(&mango_program_id, &self.address);
+        let instructions = [mango::instruction::remove_from_deposit_whitelist(
+            &mango_program_id,
+            &self.address,
+            &admin_pk,
+            &deposit_whitelist_pk,
+            owner,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_deposit_whitelist_enabled(
+        &mut self,
+        test: &mut MangoProgramTest,
+        enabled: bool,
+    ) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let instructions = [mango::instruction::set_deposit_whitelist_enabled(
+            &mango_program_id,
+            &self.address,
+            &admin_pk,
+            enabled,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+        self.mango_group = test.load_account::<MangoGroup>(self.address).await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_post_trade_risk_log_enabled(
+        &mut self,
+        test: &mut MangoProgramTest,
+        enabled: bool,
+    ) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let instructions = [mango::instruction::set_post_trade_risk_log_enabled(
+            &mango_program_id,
+            &self.address,
+            &admin_pk,
+            enabled,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+        self.mango_group = test.load_account::<MangoGroup>(self.address).await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_min_mngo_to_trade(
+        &mut self,
+        test: &mut MangoProgramTest,
+        min_mngo_to_trade: u64,
+    ) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let instructions = [mango::instruction::set_min_mngo_to_trade(
+            &mango_program_id,
+            &self.address,
+            &admin_pk,
+            min_mngo_to_trade,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+        self.mango_group = test.load_account::<MangoGroup>(self.address).await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_withdraw_fee(&mut self, test: &mut MangoProgramTest, withdraw_fee_bps: u16) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let instructions = [mango::instruction::set_withdraw_fee(
+            &mango_program_id,
+            &self.address,
+            &admin_pk,
+            withdraw_fee_bps,
+        )
+        .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+        self.mango_group = test.load_account::<MangoGroup>(self.address).await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_group_name(&mut self, test: &mut MangoProgramTest, name: &str) {
+        let mango_program_id = test.mango_program_id;
+        let admin_pk = test.get_payer_pk();
+        let instructions =
+            [mango::instruction::set_group_name(&mango_program_id, &self.address, &admin_pk, name)
+                .unwrap()];
+        test.process_transaction(&instructions, None).await.unwrap();
+    }
+
     #[allow(dead_code)]
     pub async fn run_keeper(&mut self, test: &mut MangoProgramTest) {
         let mango_group = self.mango_group;
@@ -264,7 +461,15 @@ impl MangoGroupCookie {
                 let perp_market_cookie = self.perp_markets[perp_market_index];
                 let mut mango_account_pks = Vec::new();
                 for user_index in users_with_perp_event {
-                    mango_account_pks.push(self.mango_accounts[*user_index].address);
+                    let mango_account_cookie = &self.mango_accounts[*user_index];
+                    mango_account_pks.push(mango_account_cookie.address);
+                    if mango_account_cookie.mango_account.has_pnl_tracker {
+                        let (pnl_tracker_pk, _) = PnlTracker::find_address(
+                            &test.mango_program_id,
+                            &mango_account_cookie.address,
+                        );
+                        mango_account_pks.push(pnl_tracker_pk);
+                    }
                 }
                 test.consume_perp_events(&self, &perp_market_cookie, &mut mango_account_pks).await;
             }
@@ -297,8 +502,13 @@ impl MangoGroupCookie {
 
             for user_a_index in &bidders {
                 for user_b_index in &askers {
-                    test.settle_perp_funds(self, &perp_market_cookie, *user_a_index, *user_b_index)
+                    let result = test
+                        .settle_perp_funds(self, &perp_market_cookie, *user_a_index, *user_b_index)
                         .await;
+                    // Pairs whose pnl has the same sign have nothing to settle with each other
+                    if !is_mango_error(&result, MangoErrorCode::NoPnlToSettle) {
+                        result.unwrap();
+                    }
                     self.run_keeper(test).await;
                 }
             }
@@ -15,15 +15,18 @@ use solana_program::{
 };
 use solana_program_test::*;
 use solana_sdk::{
+    instruction::AccountMeta,
     instruction::Instruction,
+    instruction::InstructionError,
     signature::{Keypair, Signer},
-    transaction::Transaction,
+    transaction::{Transaction, TransactionError},
     transport::TransportError,
 };
 use spl_token::{state::*, *};
 
 use mango::{
     entrypoint::*,
+    error::MangoErrorCode,
     ids::*,
     instruction::*,
     matching::*,
@@ -64,6 +67,18 @@ impl AddPacked for ProgramTest {
     }
 }
 
+/// Returns true if the transaction failed with the given MangoErrorCode
+#[allow(dead_code)]
+pub fn is_mango_error(result: &Result<(), TransportError>, error_code: MangoErrorCode) -> bool {
+    match result {
+        Err(TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(code),
+        ))) => *code == u32::from(error_code),
+        _ => false,
+    }
+}
+
 pub struct ListingKeys {
     market_key: Keypair,
     req_q_key: Keypair,
@@ -111,11 +126,21 @@ pub struct MangoProgramTest {
     pub num_users: usize,
     pub users: Vec<Keypair>,
     pub token_accounts: Vec<Pubkey>, // user x mint
+    pub msrm_accounts: Vec<Pubkey>,  // user
 }
 
 impl MangoProgramTest {
     #[allow(dead_code)]
     pub async fn start_new(config: &MangoProgramTestConfig) -> Self {
+        Self::start_new_with_accounts(config, vec![]).await
+    }
+
+    /// Like start_new, but also preloads `accounts` (e.g. oracle feeds) into the bank
+    #[allow(dead_code)]
+    pub async fn start_new_with_accounts(
+        config: &MangoProgramTestConfig,
+        accounts: Vec<(Pubkey, solana_sdk::account::Account)>,
+    ) -> Self {
         let mango_program_id = Pubkey::new_unique();
         let serum_program_id = Pubkey::new_unique();
 
@@ -317,6 +342,7 @@ impl MangoProgramTest {
         // add users in loop
         let mut users = Vec::new();
         let mut token_accounts = Vec::new();
+        let mut msrm_accounts = Vec::new();
         for _ in 0..num_users {
             let user_key = Keypair::new();
             test.add_account(
@@ -343,11 +369,30 @@ impl MangoProgramTest {
 
                 token_accounts.push(token_key);
             }
+
+            let msrm_key = Pubkey::new_unique();
+            test.add_packable_account(
+                msrm_key,
+                u32::MAX as u64,
+                &spl_token::state::Account {
+                    mint: msrm_token::ID,
+                    owner: user_key.pubkey(),
+                    amount: 1_000_000,
+                    state: spl_token::state::AccountState::Initialized,
+                    ..spl_token::state::Account::default()
+                },
+                &spl_token::id(),
+            );
+            msrm_accounts.push(msrm_key);
             users.push(user_key);
         }
 
 
 
+        for (pubkey, account) in accounts {
+            test.add_account(pubkey, account);
+        }
+
         let mut context = test.start_with_context().await;
         let rent = context.banks_client.get_rent().await.unwrap();
         mints = mints[..num_mints].to_vec();
@@ -364,6 +409,7 @@ impl MangoProgramTest {
             num_users,
             users,
             token_accounts,
+            msrm_accounts,
         }
     }
 
@@ -387,9 +433,7 @@ impl MangoProgramTest {
 
         transaction.sign(&all_signers, self.context.last_blockhash);
 
-        self.context.banks_client.process_transaction(transaction).await.unwrap();
-
-        Ok(())
+        self.context.banks_client.process_transaction(transaction).await
     }
 
     #[allow(dead_code)]
@@ -727,6 +771,191 @@ impl MangoProgramTest {
         self.process_transaction(&instructions, Some(&[&user])).await.unwrap();
     }
 
+    #[allow(dead_code)]
+    pub async fn place_perp_order2(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookie: &PerpMarketCookie,
+        user_index: usize,
+        order_side: Side,
+        order_size: u64,
+        order_price: u64,
+        order_id: u64,
+        order_type: OrderType,
+        safe_mode: bool,
+        tag: [u8; 8],
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let perp_market = perp_market_cookie.perp_market;
+        let perp_market_pk = perp_market_cookie.address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+        let instructions = [place_perp_order2(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &mango_group.mango_cache,
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            &perp_market.event_queue,
+            &mango_account.spot_open_orders,
+            order_side,
+            order_price as i64,
+            order_size as i64,
+            order_id,
+            order_type,
+            safe_mode,
+            tag,
+            DEFAULT_MATCH_LIMIT,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn place_perp_order3(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookie: &PerpMarketCookie,
+        user_index: usize,
+        order_side: Side,
+        order_size: u64,
+        order_price: u64,
+        order_id: u64,
+        order_type: OrderType,
+        expiry_timestamp: u64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let perp_market = perp_market_cookie.perp_market;
+        let perp_market_pk = perp_market_cookie.address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+        let instructions = [place_perp_order3(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &mango_group.mango_cache,
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            &perp_market.event_queue,
+            &mango_account.spot_open_orders,
+            order_side,
+            order_price as i64,
+            order_size as i64,
+            order_id,
+            order_type,
+            false,
+            [0; 8],
+            expiry_timestamp,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn place_perp_pegged_order(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookie: &PerpMarketCookie,
+        user_index: usize,
+        order_side: Side,
+        order_size: u64,
+        offset_ticks: i64,
+        peg_limit: i64,
+        order_id: u64,
+        order_type: OrderType,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let perp_market = perp_market_cookie.perp_market;
+        let perp_market_pk = perp_market_cookie.address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+        let instructions = [place_perp_pegged_order(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &mango_group.mango_cache,
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            &perp_market.event_queue,
+            &mango_account.spot_open_orders,
+            order_side,
+            offset_ticks,
+            peg_limit,
+            order_size as i64,
+            order_id,
+            order_type,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn execute_rebalance_leg(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        user_legs: &[(usize, Side, f64, f64)], // (mint_index, side, price, size)
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+
+        let mut leg_pks = vec![];
+        let mut legs = vec![];
+        for (mint_index, side, price, size) in user_legs.iter() {
+            let mint = self.with_mint(*mint_index);
+            let perp_market_cookie = mango_group_cookie.perp_markets[*mint_index];
+            let perp_market = perp_market_cookie.perp_market;
+            leg_pks.push((
+                perp_market_cookie.address,
+                perp_market.bids,
+                perp_market.asks,
+                perp_market.event_queue,
+            ));
+            legs.push(PerpRebalanceLeg {
+                market_index: *mint_index,
+                side: *side,
+                price: self.price_number_to_lots(&mint, *price) as i64,
+                quantity: self.base_size_number_to_lots(&mint, *size) as i64,
+            });
+        }
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+        let instructions = [execute_rebalance_leg(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &mango_group.mango_cache,
+            &mango_account.spot_open_orders,
+            &leg_pks,
+            legs,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
     #[allow(dead_code)]
     pub async fn consume_perp_events(
         &mut self,
@@ -753,6 +982,68 @@ impl MangoProgramTest {
         self.process_transaction(&instructions, None).await.unwrap();
     }
 
+    #[allow(dead_code)]
+    pub async fn cancel_perp_orders_older_than(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookie: &PerpMarketCookie,
+        user_index: usize,
+        cutoff_ts: u64,
+        limit: u8,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let perp_market = perp_market_cookie.perp_market;
+        let perp_market_pk = perp_market_cookie.address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+        let instructions = [cancel_perp_orders_older_than(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            cutoff_ts,
+            limit,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn cancel_perp_orders_side(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookie: &PerpMarketCookie,
+        user_index: usize,
+        side: Side,
+        limit: u8,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let perp_market = perp_market_cookie.perp_market;
+        let perp_market_pk = perp_market_cookie.address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+        let instructions = [cancel_perp_orders_side(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            side,
+            limit,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
     #[allow(dead_code)]
     pub async fn force_cancel_perp_orders(
         &mut self,
@@ -783,6 +1074,63 @@ impl MangoProgramTest {
         self.process_transaction(&instructions, None).await.unwrap();
     }
 
+    #[allow(dead_code)]
+    pub async fn force_cancel_spot_orders(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        spot_market_cookie: &SpotMarketCookie,
+        user_index: usize,
+        limit: u8,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let serum_program_id = self.serum_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let mint_index = spot_market_cookie.mint.index;
+
+        let (signer_pk, _signer_nonce) =
+            create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+
+        let (base_root_bank_pk, base_root_bank) =
+            self.with_root_bank(&mango_group, mint_index).await;
+        let (base_node_bank_pk, base_node_bank) = self.with_node_bank(&base_root_bank, 0).await;
+        let (quote_root_bank_pk, quote_root_bank) =
+            self.with_root_bank(&mango_group, self.quote_index).await;
+        let (quote_node_bank_pk, quote_node_bank) = self.with_node_bank(&quote_root_bank, 0).await;
+
+        let (dex_signer_pk, _dex_signer_nonce) =
+            create_signer_key_and_nonce(&serum_program_id, &spot_market_cookie.market);
+
+        let instructions = [force_cancel_spot_orders(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &mango_account_pk,
+            &base_root_bank_pk,
+            &base_node_bank_pk,
+            &base_node_bank.vault,
+            &quote_root_bank_pk,
+            &quote_node_bank_pk,
+            &quote_node_bank.vault,
+            &spot_market_cookie.market,
+            &spot_market_cookie.bids,
+            &spot_market_cookie.asks,
+            &signer_pk,
+            &spot_market_cookie.event_q,
+            &spot_market_cookie.coin_vault,
+            &spot_market_cookie.pc_vault,
+            &dex_signer_pk,
+            &serum_program_id,
+            &mango_account.spot_open_orders,
+            mint_index,
+            limit,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
     // pub fn get_pnl(
     //     &mut self,
     //     mango_group_cookie: &MangoGroupCookie,
@@ -806,7 +1154,7 @@ impl MangoProgramTest {
         perp_market_cookie: &PerpMarketCookie,
         user_a_index: usize,
         user_b_index: usize,
-    ) {
+    ) -> Result<(), TransportError> {
         let mango_program_id = self.mango_program_id;
         let mango_group = mango_group_cookie.mango_group;
         let mango_group_pk = mango_group_cookie.address;
@@ -829,7 +1177,7 @@ impl MangoProgramTest {
         )
         .unwrap()];
 
-        self.process_transaction(&instructions, None).await.unwrap();
+        self.process_transaction(&instructions, None).await
     }
 
     #[allow(dead_code)]
@@ -1119,6 +1467,33 @@ impl MangoProgramTest {
         }
     }
 
+    #[allow(dead_code)]
+    pub async fn perform_update_root_banks(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        mint_indexes: &[usize],
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+
+        let mut root_banks = vec![];
+        for mint_index in mint_indexes {
+            let (root_bank_pk, root_bank) = self.with_root_bank(&mango_group, *mint_index).await;
+            let node_bank_pks = root_bank.node_banks[..root_bank.num_node_banks].to_vec();
+            root_banks.push((root_bank_pk, node_bank_pks));
+        }
+
+        let instructions = [update_root_banks(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &root_banks,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
     pub async fn update_funding(
         &mut self,
         mango_group_cookie: &MangoGroupCookie,
@@ -1142,6 +1517,30 @@ impl MangoProgramTest {
         self.process_transaction(&instructions, None).await.unwrap();
     }
 
+    #[allow(dead_code)]
+    pub async fn update_all_funding(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookies: &[PerpMarketCookie],
+    ) -> Result<(), TransportError> {
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_program_id = self.mango_program_id;
+        let perp_markets: Vec<(Pubkey, Pubkey, Pubkey)> = perp_market_cookies
+            .iter()
+            .map(|cookie| (cookie.address, cookie.perp_market.bids, cookie.perp_market.asks))
+            .collect();
+
+        let instructions = [update_all_funding(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &perp_markets,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
     #[allow(dead_code)]
     pub async fn place_spot_order(
         &mut self,
@@ -1285,6 +1684,68 @@ impl MangoProgramTest {
         self.process_transaction(&instructions, Some(&signers)).await.unwrap();
     }
 
+    #[allow(dead_code)]
+    pub async fn cancel_spot_order_and_settle(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        spot_market_cookie: &SpotMarketCookie,
+        user_index: usize,
+        order: serum_dex::instruction::CancelOrderInstructionV2,
+    ) {
+        let mango_program_id = self.mango_program_id;
+        let serum_program_id = self.serum_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let mint_index = spot_market_cookie.mint.index;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let (signer_pk, _signer_nonce) =
+            create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+
+        let (base_root_bank_pk, base_root_bank) =
+            self.with_root_bank(&mango_group, mint_index).await;
+        let (base_node_bank_pk, base_node_bank) = self.with_node_bank(&base_root_bank, 0).await;
+        let (quote_root_bank_pk, quote_root_bank) =
+            self.with_root_bank(&mango_group, self.quote_index).await;
+        let (quote_node_bank_pk, quote_node_bank) = self.with_node_bank(&quote_root_bank, 0).await;
+
+        let (dex_signer_pk, _dex_signer_nonce) =
+            create_signer_key_and_nonce(&serum_program_id, &spot_market_cookie.market);
+
+        let instructions = [mango::instruction::cancel_spot_order_and_settle(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &user.pubkey(),
+            &mango_account_pk,
+            &serum_program_id,
+            &spot_market_cookie.market,
+            &spot_market_cookie.bids,
+            &spot_market_cookie.asks,
+            &mango_account.spot_open_orders[mint_index],
+            &signer_pk,
+            &spot_market_cookie.event_q,
+            &spot_market_cookie.coin_vault,
+            &spot_market_cookie.pc_vault,
+            &base_root_bank_pk,
+            &base_node_bank_pk,
+            &quote_root_bank_pk,
+            &quote_node_bank_pk,
+            &base_node_bank.vault,
+            &quote_node_bank.vault,
+            &dex_signer_pk,
+            order,
+        )
+        .unwrap()];
+
+        let signers = vec![&user];
+
+        self.process_transaction(&instructions, Some(&signers)).await.unwrap();
+    }
+
     #[allow(dead_code)]
     pub async fn perform_deposit(
         &mut self,
@@ -1293,6 +1754,19 @@ impl MangoProgramTest {
         mint_index: usize,
         amount: u64,
     ) {
+        self.try_perform_deposit(mango_group_cookie, user_index, mint_index, amount)
+            .await
+            .unwrap();
+    }
+
+    #[allow(dead_code)]
+    pub async fn try_perform_deposit(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        mint_index: usize,
+        amount: u64,
+    ) -> Result<(), TransportError> {
         let mango_program_id = self.mango_program_id;
         let mango_group = mango_group_cookie.mango_group;
         let mango_group_pk = mango_group_cookie.address;
@@ -1317,7 +1791,7 @@ impl MangoProgramTest {
             amount,
         )
         .unwrap()];
-        self.process_transaction(&instructions, Some(&[&user])).await.unwrap();
+        self.process_transaction(&instructions, Some(&[&user])).await
     }
 
     #[allow(dead_code)]
@@ -1328,7 +1802,7 @@ impl MangoProgramTest {
         mint_index: usize,
         quantity: u64,
         allow_borrow: bool,
-    ) {
+    ) -> Result<(), TransportError> {
         let mango_program_id = self.mango_program_id;
         let mango_group = mango_group_cookie.mango_group;
         let mango_group_pk = mango_group_cookie.address;
@@ -1356,11 +1830,543 @@ impl MangoProgramTest {
             &user_token_account,
             &signer_pk,
             &mango_account.spot_open_orders,
+            &mango_group.insurance_vault,
             quantity,
             allow_borrow,
         )
         .unwrap()];
-        self.process_transaction(&instructions, Some(&[&user])).await.unwrap();
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_preview_withdraw(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        mint_index: usize,
+        quantity: u64,
+        allow_borrow: bool,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+
+        let user_pk = self.users[user_index].pubkey();
+        let user_token_account = self.with_user_token_account(user_index, mint_index);
+
+        let (signer_pk, _signer_nonce) =
+            create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+
+        let (root_bank_pk, root_bank) = self.with_root_bank(&mango_group, mint_index).await;
+        let (node_bank_pk, node_bank) = self.with_node_bank(&root_bank, 0).await;
+
+        let instructions = [preview_withdraw(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user_pk,
+            &mango_group.mango_cache,
+            &root_bank_pk,
+            &node_bank_pk,
+            &node_bank.vault,
+            &user_token_account,
+            &signer_pk,
+            &mango_account.spot_open_orders,
+            quantity,
+            allow_borrow,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_set_max_withdraw_per_tx(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        mint_index: usize,
+        max_withdraw: u64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+        // self.mints last token index will not always be QUOTE_INDEX hence the check
+        let token_index = if mint_index == self.quote_index { QUOTE_INDEX } else { mint_index };
+        let (withdraw_caps_pk, _) =
+            WithdrawCaps::find_address(&mango_program_id, &mango_account_pk);
+
+        let instructions = [set_max_withdraw_per_tx(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &withdraw_caps_pk,
+            token_index,
+            max_withdraw,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_set_perp_mngo_vault(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookie: &PerpMarketCookie,
+        new_mngo_vault_pk: &Pubkey,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let admin_pk = self.get_payer_pk();
+
+        let instructions = [set_perp_mngo_vault(
+            &mango_program_id,
+            &mango_group_cookie.address,
+            &perp_market_cookie.address,
+            &perp_market_cookie.perp_market.mngo_vault,
+            new_mngo_vault_pk,
+            &mango_group_cookie.mango_group.signer_key,
+            &admin_pk,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_close_mango_account(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [close_mango_account(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_set_delegate(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        delegate_pk: &Pubkey,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [set_delegate(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            delegate_pk,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_change_mango_account_owner(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        new_owner_pk: &Pubkey,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [change_mango_account_owner(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            new_owner_pk,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_upgrade_mango_account(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [upgrade_mango_account(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_set_referrer(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        referrer_index: usize,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let referrer_pk = mango_group_cookie.mango_accounts[referrer_index].address;
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [set_referrer(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &referrer_pk,
+            &user.pubkey(),
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_prepare_account_close(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let serum_program_id = self.serum_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let (signer_pk, _signer_nonce) =
+            create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+        let (quote_root_bank_pk, quote_root_bank) =
+            self.with_root_bank(&mango_group, self.quote_index).await;
+        let (quote_node_bank_pk, quote_node_bank) = self.with_node_bank(&quote_root_bank, 0).await;
+
+        let mut perp_markets = vec![];
+        for perp_market_cookie in mango_group_cookie.perp_markets.iter() {
+            let market_index = perp_market_cookie.mint.index;
+            if mango_account.order_market.iter().any(|m| *m as usize == market_index) {
+                perp_markets.push(PerpMarketCloseAccounts {
+                    perp_market_pk: perp_market_cookie.address,
+                    bids_pk: perp_market_cookie.perp_market.bids,
+                    asks_pk: perp_market_cookie.perp_market.asks,
+                });
+            }
+        }
+
+        let mut spot_markets = vec![];
+        for spot_market_cookie in mango_group_cookie.spot_markets.iter() {
+            let mint_index = spot_market_cookie.mint.index;
+            let open_orders_pk = mango_account.spot_open_orders[mint_index];
+            if open_orders_pk == Pubkey::default() {
+                continue;
+            }
+
+            let mut open_orders_acc = self.get_account(open_orders_pk).await;
+            let open_orders_ai = AccountInfo::new(
+                &open_orders_pk,
+                false,
+                false,
+                &mut open_orders_acc.lamports,
+                &mut open_orders_acc.data,
+                &serum_program_id,
+                false,
+                0,
+            );
+            let orders = {
+                let open_orders = load_open_orders(&open_orders_ai).unwrap();
+                (0..128)
+                    .filter(|slot| open_orders.free_slot_bits & (1u128 << slot) == 0)
+                    .map(|slot| serum_dex::instruction::CancelOrderInstructionV2 {
+                        side: if open_orders.is_bid_bits & (1u128 << slot) != 0 {
+                            serum_dex::matching::Side::Bid
+                        } else {
+                            serum_dex::matching::Side::Ask
+                        },
+                        order_id: open_orders.orders[slot],
+                    })
+                    .collect()
+            };
+
+            let (base_root_bank_pk, base_root_bank) =
+                self.with_root_bank(&mango_group, mint_index).await;
+            let (base_node_bank_pk, base_node_bank) = self.with_node_bank(&base_root_bank, 0).await;
+            let (dex_signer_pk, _dex_signer_nonce) =
+                create_signer_key_and_nonce(&serum_program_id, &spot_market_cookie.market);
+            spot_markets.push(SpotMarketCloseAccounts {
+                spot_market_pk: spot_market_cookie.market,
+                bids_pk: spot_market_cookie.bids,
+                asks_pk: spot_market_cookie.asks,
+                open_orders_pk,
+                dex_event_queue_pk: spot_market_cookie.event_q,
+                dex_base_pk: spot_market_cookie.coin_vault,
+                dex_quote_pk: spot_market_cookie.pc_vault,
+                dex_signer_pk,
+                base_root_bank_pk,
+                base_node_bank_pk,
+                base_vault_pk: base_node_bank.vault,
+                orders,
+            });
+        }
+
+        let instructions = prepare_account_close(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &mango_account_pk,
+            &user.pubkey(),
+            &serum_program_id,
+            &signer_pk,
+            &quote_root_bank_pk,
+            &quote_node_bank_pk,
+            &quote_node_bank.vault,
+            &perp_markets,
+            &spot_markets,
+        )
+        .unwrap();
+        // Too many accounts for a single transaction
+        for instruction in instructions {
+            self.process_transaction(&[instruction], Some(&[&user])).await?;
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_rebalance_node_banks(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        root_bank_pk: &Pubkey,
+        src_node_bank_pk: &Pubkey,
+        dst_node_bank_pk: &Pubkey,
+        quantity: u64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let admin_pk = self.get_payer_pk();
+        let (signer_pk, _signer_nonce) =
+            create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+        let src_node_bank = self.load_account::<NodeBank>(*src_node_bank_pk).await;
+        let dst_node_bank = self.load_account::<NodeBank>(*dst_node_bank_pk).await;
+
+        let instructions = [rebalance_node_banks(
+            &mango_program_id,
+            &mango_group_pk,
+            root_bank_pk,
+            src_node_bank_pk,
+            &src_node_bank.vault,
+            dst_node_bank_pk,
+            &dst_node_bank.vault,
+            &admin_pk,
+            &signer_pk,
+            quantity,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_transfer_collateral(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        src_account_index: usize,
+        dst_account_index: usize,
+        mint_index: usize,
+        quantity: u64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let src_mango_account = mango_group_cookie.mango_accounts[src_account_index].mango_account;
+        let src_mango_account_pk = mango_group_cookie.mango_accounts[src_account_index].address;
+        let dst_mango_account_pk = mango_group_cookie.mango_accounts[dst_account_index].address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let (root_bank_pk, root_bank) = self.with_root_bank(&mango_group, mint_index).await;
+        let (node_bank_pk, _node_bank) = self.with_node_bank(&root_bank, 0).await;
+        let token_index = if mint_index == self.quote_index { QUOTE_INDEX } else { mint_index };
+
+        let instructions = [transfer_collateral(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &src_mango_account_pk,
+            &dst_mango_account_pk,
+            &user.pubkey(),
+            &root_bank_pk,
+            &node_bank_pk,
+            &src_mango_account.spot_open_orders,
+            token_index,
+            quantity,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_deposit_msrm(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        account_index: usize,
+        quantity: u64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[account_index].address;
+        let msrm_account_pk = self.msrm_accounts[user_index];
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [deposit_msrm(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &msrm_account_pk,
+            &mango_group.msrm_vault,
+            quantity,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_transfer_msrm(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        src_account_index: usize,
+        dst_account_index: usize,
+        quantity: u64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let src_mango_account_pk = mango_group_cookie.mango_accounts[src_account_index].address;
+        let dst_mango_account_pk = mango_group_cookie.mango_accounts[dst_account_index].address;
+
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [transfer_msrm(
+            &mango_program_id,
+            &mango_group_pk,
+            &src_mango_account_pk,
+            &dst_mango_account_pk,
+            &user.pubkey(),
+            quantity,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_get_account_authority(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        mango_account_pk: &Pubkey,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+
+        let instructions =
+            [get_account_authority(&mango_program_id, &mango_group_pk, mango_account_pk).unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_get_insurance_coverage(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_pks: &[Pubkey],
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+
+        let instructions = [get_insurance_coverage(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &mango_group.insurance_vault,
+            perp_market_pks,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_get_realized_pnl(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+        mint_index: usize,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let perp_market_pk = mango_group_cookie.perp_markets[mint_index].address;
+        let (pnl_tracker_pk, _) = PnlTracker::find_address(&mango_program_id, &mango_account_pk);
+
+        let instructions = [get_realized_pnl(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &perp_market_pk,
+            &pnl_tracker_pk,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, None).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_init_pnl_tracker(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        user_index: usize,
+    ) -> Result<Pubkey, TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group_pk = mango_group_cookie.address;
+        let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+        let (pnl_tracker_pk, _) = PnlTracker::find_address(&mango_program_id, &mango_account_pk);
+        let user = Keypair::from_base58_string(&self.users[user_index].to_base58_string());
+
+        let instructions = [init_pnl_tracker(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &user.pubkey(),
+            &pnl_tracker_pk,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&user])).await?;
+        Ok(pnl_tracker_pk)
     }
 
     #[allow(dead_code)]
@@ -1371,6 +2377,28 @@ impl MangoProgramTest {
         liqor_index: usize,
         asset_index: usize,
         liab_index: usize,
+    ) {
+        let max_liab_transfer = I80F48::from_num(10_000); // TODO: This needs to adapt to the situation probably
+        self.perform_liquidate_token_and_token_with_max(
+            mango_group_cookie,
+            liqee_index,
+            liqor_index,
+            asset_index,
+            liab_index,
+            max_liab_transfer,
+        )
+        .await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn perform_liquidate_token_and_token_with_max(
+        &mut self,
+        mango_group_cookie: &mut MangoGroupCookie,
+        liqee_index: usize,
+        liqor_index: usize,
+        asset_index: usize,
+        liab_index: usize,
+        max_liab_transfer: I80F48,
     ) {
         let mango_program_id = self.mango_program_id;
         let mango_group = mango_group_cookie.mango_group;
@@ -1390,8 +2418,6 @@ impl MangoProgramTest {
             self.with_root_bank(&mango_group, liab_index).await;
         let (liab_node_bank_pk, _liab_node_bank) = self.with_node_bank(&liab_root_bank, 0).await;
 
-        let max_liab_transfer = I80F48::from_num(10_000); // TODO: This needs to adapt to the situation probably
-
         let instructions = vec![mango::instruction::liquidate_token_and_token(
             &mango_program_id,
             &mango_group_pk,
@@ -1417,6 +2443,42 @@ impl MangoProgramTest {
         mango_group_cookie.mango_accounts[liqor_index].mango_account =
             self.load_account::<MangoAccount>(liqor_mango_account_pk).await;
     }
+
+    #[allow(dead_code)]
+    pub async fn perform_liquidate_perp_market(
+        &mut self,
+        mango_group_cookie: &MangoGroupCookie,
+        perp_market_cookie: &PerpMarketCookie,
+        liqee_index: usize,
+        liqor_index: usize,
+        base_transfer_request: i64,
+    ) -> Result<(), TransportError> {
+        let mango_program_id = self.mango_program_id;
+        let mango_group = mango_group_cookie.mango_group;
+        let mango_group_pk = mango_group_cookie.address;
+        let liqee_mango_account = mango_group_cookie.mango_accounts[liqee_index].mango_account;
+        let liqee_mango_account_pk = mango_group_cookie.mango_accounts[liqee_index].address;
+        let liqor_mango_account = mango_group_cookie.mango_accounts[liqor_index].mango_account;
+        let liqor_mango_account_pk = mango_group_cookie.mango_accounts[liqor_index].address;
+
+        let liqor = Keypair::from_base58_string(&self.users[liqor_index].to_base58_string());
+
+        let instructions = [liquidate_perp_market(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &perp_market_cookie.address,
+            &perp_market_cookie.perp_market.event_queue,
+            &liqee_mango_account_pk,
+            &liqor_mango_account_pk,
+            &liqor.pubkey(),
+            &liqee_mango_account.spot_open_orders,
+            &liqor_mango_account.spot_open_orders,
+            base_transfer_request,
+        )
+        .unwrap()];
+        self.process_transaction(&instructions, Some(&[&liqor])).await
+    }
 }
 
 fn process_serum_instruction(
@@ -50,7 +50,8 @@ pub async fn withdraw_scenario(
             withdraw_amount,
             *allow_borrow,
         )
-        .await;
+        .await
+        .unwrap();
     }
 }
 
@@ -0,0 +1,240 @@
+mod program_test;
+use mango::error::MangoErrorCode;
+use mango::instruction::{place_perp_order, set_delegate, withdraw};
+use mango::matching::{OrderType, Side};
+use mango::state::{MangoAccount, MANGO_ACCOUNT_VERSION};
+use mango::utils::create_signer_key_and_nonce;
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program::pubkey::Pubkey;
+use solana_program_test::*;
+use solana_sdk::signature::{Keypair, Signer};
+
+#[tokio::test]
+async fn test_get_account_authority() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+
+    // === Act ===
+    // Step 1: Log the authority of a MangoAccount
+    test.perform_get_account_authority(&mango_group_cookie, &mango_account_pk).await.unwrap();
+
+    // Step 2: Anything that is not a MangoAccount of this group is rejected
+    let mango_group_pk = mango_group_cookie.address;
+    let result = test.perform_get_account_authority(&mango_group_cookie, &mango_group_pk).await;
+    assert!(result.is_err());
+
+    // === Assert ===
+    // The logged fields are read straight from the stored account
+    let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+    assert_eq!(mango_account.owner, test.users[user_index].pubkey());
+    assert_eq!(mango_account.delegate, Pubkey::default());
+}
+
+#[tokio::test]
+async fn test_delegate_can_trade_but_not_withdraw() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price = 10_000.0;
+    let user_deposits = vec![(user_index, test.quote_index, 100_000.0)];
+    let delegate = Keypair::new();
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let mango_program_id = test.mango_program_id;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let perp_market = perp_market_cookie.perp_market;
+    let place_order = place_perp_order(
+        &mango_program_id,
+        &mango_group_pk,
+        &mango_account_pk,
+        &delegate.pubkey(),
+        &mango_group.mango_cache,
+        &perp_market_cookie.address,
+        &perp_market.bids,
+        &perp_market.asks,
+        &perp_market.event_queue,
+        &mango_account.spot_open_orders,
+        Side::Bid,
+        test.price_number_to_lots(&perp_market_cookie.mint, base_price) as i64,
+        test.base_size_number_to_lots(&perp_market_cookie.mint, 1.0) as i64,
+        1,
+        OrderType::Limit,
+    )
+    .unwrap();
+
+    // === Act ===
+    // Step 1: Without a delegate set the key can't trade
+    let result = test.process_transaction(&[place_order.clone()], Some(&[&delegate])).await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidOwner));
+
+    // Step 2: Once set, the delegate can place perp orders
+    test.perform_set_delegate(&mango_group_cookie, user_index, &delegate.pubkey()).await.unwrap();
+    test.process_transaction(&[place_order.clone()], Some(&[&delegate])).await.unwrap();
+
+    // Step 3: But it can't withdraw
+    let (signer_pk, _signer_nonce) =
+        create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+    let (root_bank_pk, root_bank) = test.with_root_bank(&mango_group, test.quote_index).await;
+    let (node_bank_pk, node_bank) = test.with_node_bank(&root_bank, 0).await;
+    let user_token_account = test.with_user_token_account(user_index, test.quote_index);
+    let withdraw_instruction = withdraw(
+        &mango_program_id,
+        &mango_group_pk,
+        &mango_account_pk,
+        &delegate.pubkey(),
+        &mango_group.mango_cache,
+        &root_bank_pk,
+        &node_bank_pk,
+        &node_bank.vault,
+        &user_token_account,
+        &signer_pk,
+        &mango_account.spot_open_orders,
+        &mango_group.insurance_vault,
+        test.quote_mint.unit as u64,
+        false,
+    )
+    .unwrap();
+    let result = test.process_transaction(&[withdraw_instruction], Some(&[&delegate])).await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidOwner));
+
+    // Step 4: Passing the default key removes the delegate again
+    test.perform_set_delegate(&mango_group_cookie, user_index, &Pubkey::default()).await.unwrap();
+    let result = test.process_transaction(&[place_order], Some(&[&delegate])).await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidOwner));
+    let mango_account = test.load_account::<mango::state::MangoAccount>(mango_account_pk).await;
+    assert_eq!(mango_account.delegate, Pubkey::default());
+    assert_eq!(mango_account.perp_accounts[mint_index].bids_quantity, 1);
+}
+
+#[tokio::test]
+async fn test_change_mango_account_owner() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let delegate = Keypair::new();
+    let new_owner = Keypair::new();
+
+    // === Act ===
+    // Step 1: The default key can't own an account
+    let result = test
+        .perform_change_mango_account_owner(&mango_group_cookie, user_index, &Pubkey::default())
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidParam));
+
+    // Step 2: Hand the account, which has a delegate, to the new owner
+    test.perform_set_delegate(&mango_group_cookie, user_index, &delegate.pubkey()).await.unwrap();
+    test.perform_change_mango_account_owner(&mango_group_cookie, user_index, &new_owner.pubkey())
+        .await
+        .unwrap();
+
+    let mango_account = test.load_account::<mango::state::MangoAccount>(mango_account_pk).await;
+    assert_eq!(mango_account.owner, new_owner.pubkey());
+    assert_eq!(mango_account.delegate, Pubkey::default());
+
+    // Step 3: The old owner can no longer manage the account, the new one can
+    let result =
+        test.perform_set_delegate(&mango_group_cookie, user_index, &delegate.pubkey()).await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidOwner));
+    let instructions = [set_delegate(
+        &test.mango_program_id,
+        &mango_group_cookie.address,
+        &mango_account_pk,
+        &new_owner.pubkey(),
+        &delegate.pubkey(),
+    )
+    .unwrap()];
+    test.process_transaction(&instructions, Some(&[&new_owner])).await.unwrap();
+
+    // === Assert ===
+    let mango_account = test.load_account::<mango::state::MangoAccount>(mango_account_pk).await;
+    assert_eq!(mango_account.owner, new_owner.pubkey());
+    assert_eq!(mango_account.delegate, delegate.pubkey());
+}
+
+#[tokio::test]
+async fn test_upgrade_current_mango_account() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let delegate = Keypair::new();
+    test.perform_set_delegate(&mango_group_cookie, user_index, &delegate.pubkey()).await.unwrap();
+    let data_before = test.get_account(mango_account_pk).await.data;
+
+    // === Act ===
+    test.perform_upgrade_mango_account(&mango_group_cookie, user_index).await.unwrap();
+
+    // === Assert ===
+    // New accounts start at the current version, so the upgrade changes nothing
+    let mango_account = test.load_account::<MangoAccount>(mango_account_pk).await;
+    assert_eq!(mango_account.meta_data.version, MANGO_ACCOUNT_VERSION);
+    assert_eq!(mango_account.delegate, delegate.pubkey());
+    assert_eq!(test.get_account(mango_account_pk).await.data, data_before);
+}
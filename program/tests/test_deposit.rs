@@ -5,6 +5,11 @@ mod program_test;
 use solana_program_test::*;
 use program_test::*;
 use program_test::cookies::*;
+use program_test::scenarios::*;
+use mango::error::MangoErrorCode;
+use mango::instruction::deposit;
+use mango::state::{DepositWhitelist, WithdrawCaps};
+use solana_sdk::signature::{Keypair, Signer};
 
 
 
@@ -61,3 +66,171 @@ async fn test_deposit_succeeds() {
     assert_eq!(mango_account_deposit, deposit_amount);
 
 }
+
+#[tokio::test]
+async fn test_deposit_whitelist() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let whitelisted_user_index: usize = 0;
+    let other_user_index: usize = 1;
+    let deposit_amount = 1_000 * (test.quote_mint.unit as u64);
+    let (deposit_whitelist_pk, _) =
+        DepositWhitelist::find_address(&test.mango_program_id, &mango_group_cookie.address);
+    let whitelisted_owner = test.users[whitelisted_user_index].pubkey();
+
+    // === Act ===
+    // Step 1: Whitelist one owner and enable the whitelist
+    mango_group_cookie.add_to_deposit_whitelist(&mut test, &whitelisted_owner).await;
+    mango_group_cookie.set_deposit_whitelist_enabled(&mut test, true).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: Only the whitelisted owner can deposit
+    test.try_perform_deposit(
+        &mango_group_cookie,
+        whitelisted_user_index,
+        test.quote_index,
+        deposit_amount,
+    )
+    .await
+    .unwrap();
+
+    let result = test
+        .try_perform_deposit(
+            &mango_group_cookie,
+            other_user_index,
+            test.quote_index,
+            deposit_amount,
+        )
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::NotWhitelisted));
+
+    // Step 3: Leaving out the whitelist account the builder appends is rejected too
+    let mango_group = mango_group_cookie.mango_group;
+    let user = Keypair::from_base58_string(&test.users[whitelisted_user_index].to_base58_string());
+    let user_token_account = test.with_user_token_account(whitelisted_user_index, test.quote_index);
+    let (root_bank_pk, root_bank) = test.with_root_bank(&mango_group, test.quote_index).await;
+    let (node_bank_pk, node_bank) = test.with_node_bank(&root_bank, 0).await;
+    let mut instruction = deposit(
+        &test.mango_program_id,
+        &mango_group_cookie.address,
+        &mango_group_cookie.mango_accounts[whitelisted_user_index].address,
+        &user.pubkey(),
+        &mango_group.mango_cache,
+        &root_bank_pk,
+        &node_bank_pk,
+        &node_bank.vault,
+        &user_token_account,
+        deposit_amount,
+    )
+    .unwrap();
+    assert_eq!(instruction.accounts.pop().unwrap().pubkey, deposit_whitelist_pk);
+    let result = test.process_transaction(&[instruction], Some(&[&user])).await;
+    assert!(is_mango_error(&result, MangoErrorCode::NotWhitelisted));
+
+    // Step 4: A removed owner can no longer deposit
+    // (a different amount keeps the transaction distinct from the one in step 2)
+    mango_group_cookie.remove_from_deposit_whitelist(&mut test, &whitelisted_owner).await;
+    let result = test
+        .try_perform_deposit(
+            &mango_group_cookie,
+            whitelisted_user_index,
+            test.quote_index,
+            deposit_amount / 2,
+        )
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::NotWhitelisted));
+
+    // Step 5: Disabling the whitelist opens deposits to everyone again
+    mango_group_cookie.set_deposit_whitelist_enabled(&mut test, false).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.perform_deposit(&mango_group_cookie, other_user_index, test.quote_index, deposit_amount)
+        .await;
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    for &user_index in &[whitelisted_user_index, other_user_index] {
+        let mango_account_deposit = test
+            .with_mango_account_deposit(
+                &mango_group_cookie.mango_accounts[user_index].address,
+                test.quote_index,
+            )
+            .await;
+        assert_eq!(mango_account_deposit, deposit_amount);
+    }
+}
+
+#[tokio::test]
+async fn test_close_mango_account() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 3, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let depositor_index: usize = 0;
+    let empty_index: usize = 1;
+    let pda_index: usize = 2;
+    let user_deposits = vec![(depositor_index, test.quote_index, 1_000.0)];
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // === Act ===
+    // Step 1: An account holding deposits can't be closed
+    let result = test.perform_close_mango_account(&mango_group_cookie, depositor_index).await;
+    assert!(is_mango_error(&result, MangoErrorCode::AccountNotEmpty));
+
+    // Step 2: An empty account is closed and its rent goes back to the owner
+    let mango_account_pk = mango_group_cookie.mango_accounts[empty_index].address;
+    let owner_pk = test.users[empty_index].pubkey();
+    let account_lamports = test.get_account(mango_account_pk).await.lamports;
+    let owner_lamports = test.get_account(owner_pk).await.lamports;
+    test.perform_close_mango_account(&mango_group_cookie, empty_index).await.unwrap();
+
+    let closed = test.context.banks_client.get_account(mango_account_pk).await.unwrap();
+    assert!(closed.is_none());
+    assert_eq!(test.get_account(owner_pk).await.lamports, owner_lamports + account_lamports);
+
+    // Step 3: An account's WithdrawCaps and PnlTracker are closed along with it
+    test.perform_set_max_withdraw_per_tx(&mango_group_cookie, pda_index, test.quote_index, 100)
+        .await
+        .unwrap();
+    let pnl_tracker_pk =
+        test.perform_init_pnl_tracker(&mango_group_cookie, pda_index).await.unwrap();
+    let mango_account_pk = mango_group_cookie.mango_accounts[pda_index].address;
+    let (withdraw_caps_pk, _) =
+        WithdrawCaps::find_address(&test.mango_program_id, &mango_account_pk);
+    let owner_pk = test.users[pda_index].pubkey();
+    let account_lamports = test.get_account(mango_account_pk).await.lamports
+        + test.get_account(withdraw_caps_pk).await.lamports
+        + test.get_account(pnl_tracker_pk).await.lamports;
+    let owner_lamports = test.get_account(owner_pk).await.lamports;
+    test.perform_close_mango_account(&mango_group_cookie, pda_index).await.unwrap();
+
+    // === Assert ===
+    for pk in [mango_account_pk, withdraw_caps_pk, pnl_tracker_pk].iter() {
+        let closed = test.context.banks_client.get_account(*pk).await.unwrap();
+        assert!(closed.is_none());
+    }
+    assert_eq!(test.get_account(owner_pk).await.lamports, owner_lamports + account_lamports);
+}
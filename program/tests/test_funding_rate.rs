@@ -1,5 +1,5 @@
 mod program_test;
-use mango::{matching::*};
+use mango::{matching::*, state::PerpMarket};
 use program_test::*;
 use program_test::cookies::*;
 use program_test::scenarios::*;
@@ -109,3 +109,65 @@ async fn test_funding_rate() {
 // asker_quote_position after 22 hours: 9999422453.703668027245044
 // asker_quote_position after 24 hours: 10000000000
 // asker_quote_position after 48 hours: 10000000000
+
+#[tokio::test]
+async fn test_update_all_funding() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 3 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+
+    // Set oracles
+    for mint_index in 0..2 {
+        mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+    }
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 10_000.0)];
+
+    // Perp Orders, a lone bid pays the maximum funding rate
+    let user_perp_orders = vec![
+        (user_index, 0, Side::Bid, base_size, base_price),
+        (user_index, 1, Side::Bid, base_size, base_price),
+    ];
+
+    // === Act ===
+    // Step 1: Make deposits and place a bid on each market
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    place_perp_order_scenario(&mut test, &mut mango_group_cookie, &user_perp_orders).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    let perp_market_cookies = mango_group_cookie.perp_markets.clone();
+    let mut before = Vec::new();
+    for cookie in perp_market_cookies.iter() {
+        before.push(test.load_account::<PerpMarket>(cookie.address).await);
+    }
+
+    // Step 2: Let time pass, refresh prices and update both markets in one instruction
+    test.advance_clock_by_min_timespan(60).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let oracle_pks = mango_group.oracles[..mango_group.num_oracles].to_vec();
+    test.cache_all_prices(&mango_group, &mango_group_cookie.address, &oracle_pks).await;
+    test.update_all_funding(&mango_group_cookie, &perp_market_cookies).await.unwrap();
+
+    // === Assert ===
+    for (cookie, before) in perp_market_cookies.iter().zip(before.iter()) {
+        let after = test.load_account::<PerpMarket>(cookie.address).await;
+        assert!(after.last_updated > before.last_updated);
+        assert!(after.long_funding > before.long_funding);
+        assert!(after.short_funding > before.short_funding);
+    }
+}
@@ -1,5 +1,6 @@
 // Tests related to liquidations
 mod program_test;
+use mango::error::MangoErrorCode;
 use mango::state::*;
 use program_test::*;
 use program_test::cookies::*;
@@ -251,3 +252,207 @@ async fn test_token_and_token_liquidation_v2() {
     // TODO: Actually assert here
 
 }
+
+#[tokio::test]
+async fn test_token_and_token_liquidation_target_health() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let liqee_user_index: usize = 0;
+    let liqor_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let target_health: f64 = 100.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+    mango_group_cookie.set_liquidation_target_health(&mut test, target_health).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (liqee_user_index, test.quote_index, 10_000.0),
+        (liqor_user_index, mint_index, 10.0),
+        (liqor_user_index, test.quote_index, 10_000.0),
+    ];
+
+    // Withdraw amounts
+    let user_withdraws = vec![(liqee_user_index, mint_index, 0.8, true)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Borrow 0.8 BTC against the quote deposit
+    withdraw_scenario(&mut test, &mut mango_group_cookie, &user_withdraws).await;
+
+    // Step 3: Raise the price so the liqee drops below maint health
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price * 1.2).await;
+
+    // Step 4: Liquidate with a max_liab_transfer larger than the whole borrow
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.perform_liquidate_token_and_token_with_max(
+        &mut mango_group_cookie,
+        liqee_user_index,
+        liqor_user_index,
+        QUOTE_INDEX, // Asset index
+        mint_index,  // Liab index
+        I80F48::from_num(1_000_000_000),
+    )
+    .await;
+
+    // === Assert ===
+    let mango_cache = &mango_group_cookie.mango_cache;
+    let liqee = &mango_group_cookie.mango_accounts[liqee_user_index].mango_account;
+    let quote_deposit = liqee
+        .get_native_deposit(&mango_cache.root_bank_cache[QUOTE_INDEX], QUOTE_INDEX)
+        .unwrap();
+    let base_borrow =
+        liqee.get_native_borrow(&mango_cache.root_bank_cache[mint_index], mint_index).unwrap();
+    let init_health = quote_deposit
+        - base_borrow
+            * mango_cache.price_cache[mint_index].price
+            * mango_group_cookie.mango_group.spot_markets[mint_index].init_liab_weight;
+    let native_target_health = I80F48::from_num(target_health * test.quote_mint.unit);
+
+    println!("base_borrow: {}", base_borrow.to_string());
+    println!("init_health: {}", init_health.to_string());
+    // Liquidation stopped at the target instead of taking the full borrow
+    assert!(base_borrow.is_positive());
+    assert!((init_health - native_target_health).abs() < I80F48::from_num(test.quote_mint.unit));
+    assert!(!liqee.being_liquidated);
+}
+
+#[tokio::test]
+async fn test_force_cancel_spot_orders_settles_funds() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let liqee_user_index: usize = 0;
+    let lender_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let quote_deposit: f64 = 10_000.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (liqee_user_index, test.quote_index, quote_deposit),
+        (lender_user_index, mint_index, 10.0),
+    ];
+
+    // Withdraw amounts
+    let user_withdraws = vec![(liqee_user_index, mint_index, 0.5, true)];
+
+    // Resting spot bid far below the oracle price
+    let spot_orders =
+        vec![(liqee_user_index, mint_index, serum_dex::matching::Side::Bid, 0.4, 9_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Borrow 0.5 BTC against the quote deposit
+    withdraw_scenario(&mut test, &mut mango_group_cookie, &user_withdraws).await;
+
+    // Step 3: Lock part of the quote deposit in a spot bid
+    place_spot_order_scenario(&mut test, &mut mango_group_cookie, &spot_orders).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    let (_, quote_locked, _, _) =
+        test.get_oo_info(&mango_group_cookie, liqee_user_index, mint_index).await;
+    assert!(quote_locked.is_positive());
+
+    // Step 4: Raise the price so the liqee drops below maint health
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price * 2.0).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 5: Force cancel, which also settles the freed funds
+    let spot_market_cookie = mango_group_cookie.spot_markets[mint_index];
+    test.force_cancel_spot_orders(&mango_group_cookie, &spot_market_cookie, liqee_user_index, 20)
+        .await
+        .unwrap();
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let (quote_free, quote_locked, base_free, base_locked) =
+        test.get_oo_info(&mango_group_cookie, liqee_user_index, mint_index).await;
+    assert_eq!(quote_free, ZERO_I80F48);
+    assert_eq!(quote_locked, ZERO_I80F48);
+    assert_eq!(base_free, ZERO_I80F48);
+    assert_eq!(base_locked, ZERO_I80F48);
+
+    // The quote that was locked in the bid is back in the liqee's deposits, ready to be seized
+    let mango_cache = &mango_group_cookie.mango_cache;
+    let liqee = &mango_group_cookie.mango_accounts[liqee_user_index].mango_account;
+    let liqee_quote_deposit =
+        liqee.get_native_deposit(&mango_cache.root_bank_cache[QUOTE_INDEX], QUOTE_INDEX).unwrap();
+    let native_quote_deposit = I80F48::from_num(quote_deposit * test.quote_mint.unit);
+    assert!((liqee_quote_deposit - native_quote_deposit).abs() < ONE_I80F48);
+    assert!(liqee.being_liquidated);
+}
+
+#[tokio::test]
+async fn test_liquidate_perp_market_rejects_mismatched_accounts() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 3 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // General parameters
+    let liqee_user_index: usize = 0;
+    let liqor_user_index: usize = 1;
+
+    // Perp market 0 paired with the event queue of perp market 1
+    let mut mismatched_perp_market = mango_group_cookie.perp_markets[0];
+    mismatched_perp_market.perp_market.event_queue =
+        mango_group_cookie.perp_markets[1].perp_market.event_queue;
+
+    // === Act ===
+    let result = test
+        .perform_liquidate_perp_market(
+            &mango_group_cookie,
+            &mismatched_perp_market,
+            liqee_user_index,
+            liqor_user_index,
+            1,
+        )
+        .await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::PerpMarketMismatch));
+}
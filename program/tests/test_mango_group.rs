@@ -0,0 +1,88 @@
+#![cfg(feature = "test-bpf")]
+// Tests related to initializing and configuring a mango group
+mod program_test;
+
+use mango::error::MangoErrorCode;
+use mango::instruction::{set_group_name, MangoInstruction};
+use mango::state::{parse_group_name, GroupName, GROUP_NAME_LEN};
+use mango::utils::gen_signer_key;
+use program_test::cookies::*;
+use program_test::*;
+use solana_program_test::*;
+
+#[tokio::test]
+async fn test_init_mango_group_signer_nonce() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    // === Act ===
+    // Step 1: The nonce that derives the signer key is accepted
+    let mango_group_cookie =
+        MangoGroupCookie::init_with_signer_nonce(&mut test, None).await.unwrap();
+
+    // Step 2: Any other nonce is rejected. The harness uses the lowest valid nonce, so u64::MAX
+    // either derives a different key or no valid key at all
+    let result = MangoGroupCookie::init_with_signer_nonce(&mut test, Some(u64::MAX)).await;
+
+    // === Assert ===
+    let mango_group = mango_group_cookie.mango_group;
+    let signer_key = gen_signer_key(
+        mango_group.signer_nonce,
+        &mango_group_cookie.address,
+        &test.mango_program_id,
+    )
+    .unwrap();
+    assert_eq!(signer_key, mango_group.signer_key);
+    assert!(is_mango_error(&result.map(|_| ()), MangoErrorCode::InvalidSignerNonce));
+}
+
+#[tokio::test]
+async fn test_set_group_name() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    let (group_name_pk, _) =
+        GroupName::find_address(&test.mango_program_id, &mango_group_cookie.address);
+    assert!(test.context.banks_client.get_account(group_name_pk).await.unwrap().is_none());
+
+    // === Act ===
+    // Step 1: Set a name, which creates the GroupName
+    mango_group_cookie.set_group_name(&mut test, "Mango Group").await;
+
+    // Step 2: Rename the group
+    mango_group_cookie.set_group_name(&mut test, "Mango Main Group").await;
+
+    // Step 3: Try a name that is not valid UTF-8
+    let mut instruction = set_group_name(
+        &test.mango_program_id,
+        &mango_group_cookie.address,
+        &test.get_payer_pk(),
+        "",
+    )
+    .unwrap();
+    instruction.data = MangoInstruction::SetGroupName { name: [0xff; GROUP_NAME_LEN] }.pack();
+    let result = test.process_transaction(&[instruction], None).await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidParam));
+    let group_name = test.load_account::<GroupName>(group_name_pk).await;
+    assert_eq!(group_name.mango_group, mango_group_cookie.address);
+    assert_eq!(parse_group_name(&group_name.name).unwrap(), "Mango Main Group");
+}
@@ -1,10 +1,49 @@
 #![cfg(feature = "test-bpf")]
 
+use bytemuck::{bytes_of, Zeroable};
 use fixed::types::I80F48;
-use mango::matching::{AnyNode, InnerNode, LeafNode};
-use mango::state::{MangoAccount, MangoCache}; // ONE_I80F48
+use mango::error::{MangoError, MangoErrorCode};
+use mango::instruction::{
+    add_mango_account_info, cancel_perp_orders_side, cancel_spot_order_by_client_id,
+    change_mango_account_owner, change_perp_market_params, close_spot_open_orders, deposit_batch,
+    deposit_msrm, force_cancel_spot_orders, force_settle_quote_positions, get_interest_rates,
+    get_open_interest, get_position_history_anchor, info_from_str, liquidate_perp_market,
+    liquidate_token_and_perp, new_user_instructions, place_perp_pegged_order,
+    place_spot_market_order, resolve_perp_bankruptcy, resolve_token_bankruptcy, set_group_admin,
+    set_group_name, set_max_index_growth, set_max_oracle_age, set_perp_impact_depth,
+    set_post_trade_risk_log_enabled, set_referrer, settle_borrow, settle_fees, transfer_msrm,
+    update_all_funding, update_funding, withdraw, withdraw_msrm, MangoInstruction,
+    PerpRebalanceLeg,
+};
+use mango::matching::{
+    impact_price, mining_points_rate, pegged_order_key, pegged_price, perp_fill_for_quote,
+    perp_spread_bps, AnyNode, BookSide, InnerNode, LeafExt, LeafNode, OrderType, Side,
+    DEFAULT_MATCH_LIMIT,
+};
+use mango::oracle::normalize_oracle_price;
+use mango::queue::{maker_tag_details, summarize_perp_event_queue, FillEvent, OutEvent};
+use mango::state::{
+    account_leverage, bankruptcy_shortfall, borrow_apr, borrow_native, check_group_invariants,
+    collateral_breakdown, decode_mango_accounts, deposit_apr, estimate_liquidation_reward,
+    has_unsettled_pnl, health_at_prices, load_open_orders, max_perp_base, next_funding_time,
+    parse_group_name, perp_losses_at_risk, perp_orders_locked_quote, perp_position_notional,
+    position_history_anchor, post_trade_risk_details, root_bank_utilization, total_open_interest,
+    weighted_deposit_value, AssetType, DataType, DepositWhitelist, GroupName, HealthCache,
+    HealthType, InvariantError, LossRecovery, LossShare, MangoAccount, MangoCache, MangoGroup,
+    MetaData, NodeBank, PerpAccount, PerpMarket, PerpMarketCache, PerpMarketConfig, PerpMarketInfo,
+    PnlTracker, RootBank, RootBankSnapshot, UserActiveAssets, WithdrawCaps, GROUP_NAME_LEN,
+    INFO_LEN, MANGO_ACCOUNT_VERSION, MAX_PAIRS, ONE_I80F48, QUOTE_INDEX, ZERO_I80F48,
+};
+use mango::utils::open_orders_settleable;
+use serum_dex::state::{AccountFlag, OpenOrders};
+use solana_program::account_info::AccountInfo;
+use solana_program::program_error::ProgramError;
+use solana_program::pubkey::Pubkey;
+use solana_program::rent::Rent;
 use solana_program_test::tokio;
+use std::convert::{TryFrom, TryInto};
 use std::mem::{align_of, size_of};
+use std::num::NonZeroU64;
 
 #[tokio::test]
 async fn test_size() {
@@ -23,8 +62,3015 @@ async fn test_i80f48() {
     println!("{:#0128b}", r.to_bits())
 }
 
+#[tokio::test]
+async fn test_summarize_perp_event_queue() {
+    let fill = FillEvent::new(
+        Side::Bid,
+        0,
+        true,
+        0,
+        0,
+        Pubkey::default(),
+        0,
+        0,
+        I80F48::from_num(0),
+        0,
+        0,
+        [0; 8],
+        Pubkey::default(),
+        0,
+        0,
+        I80F48::from_num(0),
+        100,
+        1,
+    );
+    let out = OutEvent::new(Side::Ask, 0, 0, 0, Pubkey::default(), 1);
+
+    // Queue of capacity 8 with 4 live events wrapping around the end of the buffer
+    // slots 2..6 hold stale events that must not be counted
+    let capacity = 8;
+    let head: usize = 6;
+    let count: usize = 4;
+    let mut data = vec![];
+    data.extend_from_slice(bytemuck::bytes_of(&MetaData::new(DataType::EventQueue, 0, true)));
+    data.extend_from_slice(&head.to_le_bytes());
+    data.extend_from_slice(&count.to_le_bytes());
+    data.extend_from_slice(&10usize.to_le_bytes()); // seq_num
+    for slot in 0..capacity {
+        match slot {
+            0 | 1 | 6 => data.extend_from_slice(bytemuck::bytes_of(&fill)),
+            _ => data.extend_from_slice(bytemuck::bytes_of(&out)),
+        }
+    }
+
+    let (fill_count, out_count, queue_head, queue_count) =
+        summarize_perp_event_queue(&data).unwrap();
+    assert_eq!(fill_count, 3);
+    assert_eq!(out_count, 1);
+    assert_eq!(queue_head, head);
+    assert_eq!(queue_count, count);
+
+    // data that isn't an EventQueue is rejected
+    assert!(summarize_perp_event_queue(&data[..16]).is_err());
+}
+
+#[tokio::test]
+async fn test_normalize_oracle_price() {
+    let quote_decimals = 6;
+
+    // Two feeds for the same 6 decimal token publishing 40_000 at different exponents
+    let price_a =
+        normalize_oracle_price(I80F48::from_num(4_000_000_000_000i64), -8, 6, quote_decimals)
+            .unwrap();
+    let price_b =
+        normalize_oracle_price(I80F48::from_num(4_000_000i64), -2, 6, quote_decimals).unwrap();
+    assert_eq!(price_a, I80F48::from_num(40_000));
+    assert_eq!(price_a, price_b);
+
+    // Two feeds for a 9 decimal token publishing 150; native price is 0.15 quote per base
+    let price_c =
+        normalize_oracle_price(I80F48::from_num(15_000_000_000i64), -8, 9, quote_decimals).unwrap();
+    let price_d =
+        normalize_oracle_price(I80F48::from_num(150_000i64), -3, 9, quote_decimals).unwrap();
+    assert_eq!(price_c, price_d);
+    assert!((price_c - I80F48::from_num(0.15)).abs() < I80F48::from_num(0.000_001));
+
+    // Feeds without an exponent only adjust for decimals
+    let price_e = normalize_oracle_price(I80F48::from_num(150), 0, 9, quote_decimals).unwrap();
+    assert_eq!(price_e, price_c);
+}
+
+#[tokio::test]
+async fn test_perp_orders_locked_quote() {
+    let mut perp_market_info = PerpMarketInfo::zeroed();
+    perp_market_info.base_lot_size = 100;
+    let perp_market_cache = PerpMarketCache::zeroed();
+    let price = I80F48::from_num(40);
+
+    // Long 2 lots, bidding for 3 more and offering 4: the bids take the position further out
+    let mut perp_account = PerpAccount::zeroed();
+    perp_account.base_position = 2;
+    perp_account.bids_quantity = 3;
+    perp_account.asks_quantity = 4;
+    let locked = perp_orders_locked_quote(&perp_account, &perp_market_info, price);
+    assert_eq!(locked, I80F48::from_num(3 * 100 * 40));
+    let (_, quote) = perp_account.get_val(&perp_market_info, &perp_market_cache, price).unwrap();
+    assert_eq!(quote, -locked);
+
+    // Short 2 lots instead: the asks take it further out
+    perp_account.base_position = -2;
+    let locked = perp_orders_locked_quote(&perp_account, &perp_market_info, price);
+    assert_eq!(locked, I80F48::from_num(4 * 100 * 40));
+    let (_, quote) = perp_account.get_val(&perp_market_info, &perp_market_cache, price).unwrap();
+    assert_eq!(quote, locked);
+
+    // Unprocessed taker trades count towards the position
+    perp_account.taker_base = 5;
+    let locked = perp_orders_locked_quote(&perp_account, &perp_market_info, price);
+    assert_eq!(locked, I80F48::from_num(3 * 100 * 40));
+
+    // Nothing resting, nothing locked
+    let flat = PerpAccount::zeroed();
+    assert_eq!(perp_orders_locked_quote(&flat, &perp_market_info, price), ZERO_I80F48);
+}
+
+#[tokio::test]
+async fn test_interest_rates() {
+    let mut root_bank = RootBank::zeroed();
+    root_bank.optimal_util = I80F48::from_num(0.5);
+    root_bank.optimal_rate = I80F48::from_num(0.125);
+    root_bank.max_rate = I80F48::from_num(1.125);
+    root_bank.deposit_index = I80F48::from_num(2);
+    root_bank.borrow_index = I80F48::from_num(4);
+
+    // 50 deposited at an index of 2 makes 100 native; borrows are at an index of 4
+    let mut node_bank = NodeBank::zeroed();
+    node_bank.deposits = I80F48::from_num(50);
+    let mut rates_at = |borrows: f64| {
+        node_bank.borrows = I80F48::from_num(borrows);
+        let node_banks = [&node_bank];
+        (
+            root_bank_utilization(&root_bank, &node_banks),
+            borrow_apr(&root_bank, &node_banks),
+            deposit_apr(&root_bank, &node_banks),
+        )
+    };
+
+    // Below optimal utilization the rate rises to optimal_rate, then faster up to max_rate
+    let cases = [
+        (0.0, 0.0, 0.0, 0.0),
+        (6.25, 0.25, 0.0625, 0.015625),
+        (12.5, 0.5, 0.125, 0.0625),
+        (18.75, 0.75, 0.625, 0.46875),
+        (25.0, 1.0, 1.125, 1.125),
+    ];
+    for &(borrows, utilization, borrow_rate, deposit_rate) in cases.iter() {
+        assert_eq!(
+            rates_at(borrows),
+            (
+                I80F48::from_num(utilization),
+                I80F48::from_num(borrow_rate),
+                I80F48::from_num(deposit_rate)
+            )
+        );
+    }
+
+    // Nothing deposited, nothing borrowed
+    node_bank.deposits = ZERO_I80F48;
+    node_bank.borrows = ZERO_I80F48;
+    assert_eq!(borrow_apr(&root_bank, &[&node_bank]), ZERO_I80F48);
+    assert_eq!(deposit_apr(&root_bank, &[&node_bank]), ZERO_I80F48);
+
+    let keys: Vec<Pubkey> = (0..5).map(|_| Pubkey::new_unique()).collect();
+    let instruction = get_interest_rates(&keys[0], &keys[1], &keys[2], &keys[3..]).unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::GetInterestRates)
+    );
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys, keys[1..]);
+    assert!(instruction.accounts.iter().all(|m| !m.is_writable && !m.is_signer));
+}
+
+#[tokio::test]
+async fn test_total_open_interest() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.perp_markets[0].base_lot_size = 100;
+    mango_group.perp_markets[1].base_lot_size = 10;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(40_000);
+    mango_cache.price_cache[1].price = I80F48::from_num(2_000);
+
+    // 5 lots long against 5 lots short, and 30 lots long against 30 lots short
+    let mut first = PerpMarket::zeroed();
+    first.open_interest = 10;
+    let mut second = PerpMarket::zeroed();
+    second.open_interest = 60;
+
+    // 5 * 100 * 40_000 + 30 * 10 * 2_000 = 20_000_000 + 600_000
+    assert_eq!(
+        total_open_interest(&mango_group, &mango_cache, &[(0, &first), (1, &second)]),
+        I80F48::from_num(20_600_000)
+    );
+    assert_eq!(
+        total_open_interest(&mango_group, &mango_cache, &[(1, &second)]),
+        I80F48::from_num(600_000)
+    );
+    assert_eq!(total_open_interest(&mango_group, &mango_cache, &[]), ZERO_I80F48);
+
+    let keys: Vec<Pubkey> = (0..5).map(|_| Pubkey::new_unique()).collect();
+    let instruction = get_open_interest(&keys[0], &keys[1], &keys[2], &keys[3..]).unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::GetOpenInterest)
+    );
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys, keys[1..]);
+    assert!(instruction.accounts.iter().all(|m| !m.is_writable && !m.is_signer));
+}
+
+#[tokio::test]
+async fn test_perp_position_notional() {
+    // 6 decimal base and quote, 100 native base per lot, price of 40_000 UI
+    let mut perp_market = PerpMarket::zeroed();
+    perp_market.base_lot_size = 100;
+    let oracle_price = I80F48::from_num(40_000);
+
+    let mut long = PerpAccount::zeroed();
+    long.base_position = 5;
+    let mut short = PerpAccount::zeroed();
+    short.base_position = -5;
+
+    // 5 lots * 100 native base * 40_000 = 20_000_000 native quote, i.e. 20 UI quote
+    let expected = I80F48::from_num(20_000_000);
+    assert_eq!(perp_position_notional(&long, &perp_market, oracle_price), expected);
+    assert_eq!(perp_position_notional(&short, &perp_market, oracle_price), expected);
+    assert_eq!(
+        perp_position_notional(&PerpAccount::zeroed(), &perp_market, oracle_price),
+        I80F48::from_num(0)
+    );
+}
+
+#[tokio::test]
+async fn test_perp_losses_at_risk() {
+    // 5 lots long and 5 lots short, 100 native base per lot, price of 40_000 UI
+    let mut perp_market = PerpMarket::zeroed();
+    perp_market.open_interest = 10;
+    let mut perp_market_info = PerpMarketInfo::zeroed();
+    perp_market_info.base_lot_size = 100;
+    perp_market_info.maint_liab_weight = I80F48::from_num(1.25);
+    let oracle_price = I80F48::from_num(40_000);
+
+    // 5 lots * 100 native base * 40_000 = 20_000_000 notional, 25% of it held as maint margin
+    let losses_at_risk = perp_losses_at_risk(&perp_market_info, &perp_market, oracle_price);
+    assert_eq!(losses_at_risk, I80F48::from_num(5_000_000));
+
+    // An insurance fund of 12.5 UI quote covers that two and a half times
+    let insurance_balance = I80F48::from_num(12_500_000);
+    assert_eq!(insurance_balance / losses_at_risk, I80F48::from_num(2.5));
+
+    perp_market.open_interest = 0;
+    assert_eq!(
+        perp_losses_at_risk(&perp_market_info, &perp_market, oracle_price),
+        I80F48::from_num(0)
+    );
+}
+
+/// Group with one spot market at a price of 10 and a 5% liquidation fee, and a liqee with
+/// 1_000 native quote deposited and 100 native base borrowed
+fn liquidation_fixture() -> (MangoGroup, MangoCache, MangoAccount) {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    mango_group.spot_markets[0].spot_market = Pubkey::new_unique();
+    mango_group.spot_markets[0].init_asset_weight = I80F48::from_num(0.9);
+    mango_group.spot_markets[0].init_liab_weight = I80F48::from_num(1.1);
+    mango_group.spot_markets[0].liquidation_fee = I80F48::from_num(0.05);
+
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(10);
+    for root_bank_cache in mango_cache.root_bank_cache.iter_mut() {
+        root_bank_cache.deposit_index = ONE_I80F48;
+        root_bank_cache.borrow_index = ONE_I80F48;
+    }
+
+    let mut liqee = MangoAccount::zeroed();
+    liqee.deposits[QUOTE_INDEX] = I80F48::from_num(1_000);
+    liqee.borrows[0] = I80F48::from_num(100);
+
+    (mango_group, mango_cache, liqee)
+}
+
+#[tokio::test]
+async fn test_estimate_liquidation_reward() {
+    let (mango_group, mango_cache, liqee) = liquidation_fixture();
+    let init_health = I80F48::from_num(-10);
+    let tolerance = I80F48::from_num(0.0001);
+
+    // Capped by max_liab_transfer: repay 10 base worth 100 quote, receive 100 / 0.95 quote
+    let reward = estimate_liquidation_reward(
+        &mango_group,
+        &mango_cache,
+        &liqee,
+        init_health,
+        QUOTE_INDEX,
+        0,
+        I80F48::from_num(10),
+    )
+    .unwrap();
+    let expected = I80F48::from_num(100) / I80F48::from_num(0.95) - I80F48::from_num(100);
+    assert!((reward - expected).abs() < tolerance);
+
+    // Capped by the health deficit: liab = 10 / (10 * (1.1 - 1 / 0.95)) base
+    let reward = estimate_liquidation_reward(
+        &mango_group,
+        &mango_cache,
+        &liqee,
+        init_health,
+        QUOTE_INDEX,
+        0,
+        I80F48::from_num(1_000_000),
+    )
+    .unwrap();
+    let liab_price = I80F48::from_num(10);
+    let liab_fee = I80F48::from_num(0.95);
+    let deficit_liab =
+        I80F48::from_num(10) / (liab_price * (I80F48::from_num(1.1) - ONE_I80F48 / liab_fee));
+    let expected = deficit_liab * liab_price / liab_fee - deficit_liab * liab_price;
+    assert!((reward - expected).abs() < tolerance);
+
+    // Nothing to earn once the liqee is above the liquidation target
+    let reward = estimate_liquidation_reward(
+        &mango_group,
+        &mango_cache,
+        &liqee,
+        I80F48::from_num(50),
+        QUOTE_INDEX,
+        0,
+        I80F48::from_num(10),
+    )
+    .unwrap();
+    assert_eq!(reward, ZERO_I80F48);
+}
+
+#[tokio::test]
+async fn test_bankruptcy_shortfall() {
+    let (mango_group, mango_cache, liqee) = liquidation_fixture();
+    let tolerance = I80F48::from_num(0.0001);
+
+    // Fully covered: 100 base at price 10 with a 5% fee costs 1053 quote of insurance
+    let shortfall = bankruptcy_shortfall(&mango_group, &mango_cache, &liqee, 0, 1_053).unwrap();
+    assert_eq!(shortfall, ZERO_I80F48);
+    let shortfall = bankruptcy_shortfall(&mango_group, &mango_cache, &liqee, 0, 1_000_000).unwrap();
+    assert_eq!(shortfall, ZERO_I80F48);
+
+    // Under-covered: 500 quote of insurance repays 500 * 0.95 / 10 = 47.5 base
+    let shortfall = bankruptcy_shortfall(&mango_group, &mango_cache, &liqee, 0, 500).unwrap();
+    assert!((shortfall - I80F48::from_num(52.5)).abs() < tolerance);
+
+    // An empty insurance fund socializes all of the borrows
+    let shortfall = bankruptcy_shortfall(&mango_group, &mango_cache, &liqee, 0, 0).unwrap();
+    assert_eq!(shortfall, I80F48::from_num(100));
+
+    // No borrows, nothing to socialize
+    let shortfall =
+        bankruptcy_shortfall(&mango_group, &mango_cache, &liqee, QUOTE_INDEX, 0).unwrap();
+    assert_eq!(shortfall, ZERO_I80F48);
+}
+
+#[tokio::test]
+async fn test_is_insolvent() {
+    let (mango_group, mango_cache, mut liqee) = liquidation_fixture();
+
+    // Quote deposits can still be seized by liquidators
+    assert!(!liqee.is_insolvent(&mango_group, &mango_cache).unwrap());
+
+    // Only borrows left: the account is bankrupt
+    liqee.deposits[QUOTE_INDEX] = ZERO_I80F48;
+    assert!(liqee.is_insolvent(&mango_group, &mango_cache).unwrap());
+
+    // Dust does not count as collateral
+    liqee.deposits[0] = I80F48::from_num(0.0000001);
+    assert!(liqee.is_insolvent(&mango_group, &mango_cache).unwrap());
+
+    // Positive perp pnl or an open perp position can still be liquidated
+    liqee.perp_accounts[0].quote_position = I80F48::from_num(5);
+    assert!(!liqee.is_insolvent(&mango_group, &mango_cache).unwrap());
+    liqee.perp_accounts[0].quote_position = ZERO_I80F48;
+    liqee.perp_accounts[0].base_position = -1;
+    assert!(!liqee.is_insolvent(&mango_group, &mango_cache).unwrap());
+    liqee.perp_accounts[0].base_position = 0;
+
+    // So can taker fills that ConsumeEvents hasn't applied yet
+    liqee.perp_accounts[0].taker_base = 1;
+    liqee.perp_accounts[0].taker_quote = -10;
+    assert!(!liqee.is_insolvent(&mango_group, &mango_cache).unwrap());
+    liqee.perp_accounts[0].taker_base = 0;
+    liqee.perp_accounts[0].taker_quote = 0;
+    assert!(liqee.is_insolvent(&mango_group, &mango_cache).unwrap());
+
+    // Funds locked in a spot open orders account keep ResolveTokenBankruptcy from socializing
+    // the loss until they are settled and liquidated
+    let mut open_orders = OpenOrders::zeroed();
+    open_orders.native_coin_total = 5;
+    open_orders.native_pc_total = 100;
+    liqee.update_basket(0, &open_orders).unwrap();
+    assert!(matches!(
+        liqee.check_insolvent(&mango_group, &mango_cache),
+        Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::NotBankrupt, .. })
+    ));
+
+    // Once settled, the open orders account drops out of the basket
+    liqee.update_basket(0, &OpenOrders::zeroed()).unwrap();
+    liqee.check_insolvent(&mango_group, &mango_cache).unwrap();
+
+    // Nothing borrowed means nothing to resolve
+    let empty = MangoAccount::zeroed();
+    assert!(!empty.is_insolvent(&mango_group, &mango_cache).unwrap());
+}
+
+#[tokio::test]
+async fn test_redeemed_mngo_counts_toward_health() {
+    // Token 0 plays MNGO here
+    let (mango_group, mango_cache, mut mango_account) = liquidation_fixture();
+    mango_account.deposits[QUOTE_INDEX] = ZERO_I80F48;
+    mango_account.borrows[0] = ZERO_I80F48;
+    mango_account.borrows[QUOTE_INDEX] = I80F48::from_num(100);
+
+    let key = Pubkey::default();
+    let mut lamports = vec![0u64; MAX_PAIRS];
+    let mut data = vec![[0u8; 0]; MAX_PAIRS];
+    let ais: Vec<AccountInfo> = lamports
+        .iter_mut()
+        .zip(data.iter_mut())
+        .map(|(l, d)| AccountInfo::new(&key, false, false, l, &mut d[..], &key, false, 0))
+        .collect();
+    let open_orders_ais: &[AccountInfo; MAX_PAIRS] = ais.as_slice().try_into().unwrap();
+
+    let init_health = |mango_account: &MangoAccount| {
+        // Each instruction builds its own HealthCache, with no extra assets passed in
+        let active_assets = UserActiveAssets::new(&mango_group, mango_account, vec![]);
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&mango_group, &mango_cache, mango_account, open_orders_ais).unwrap();
+        health_cache.get_health(&mango_group, HealthType::Init)
+    };
+    assert_eq!(init_health(&mango_account), I80F48::from_num(-100));
+
+    // RedeemMngo credits 20 MNGO at a price of 10 and init asset weight of 0.9
+    mango_account.deposits[0] = I80F48::from_num(20);
+    assert_eq!(init_health(&mango_account), I80F48::from_num(80));
+}
+
+#[tokio::test]
+async fn test_check_group_invariants() {
+    let (mut mango_group, mango_cache, _) = liquidation_fixture();
+    mango_group.perp_markets[0].maint_liab_weight = I80F48::from_num(1.05);
+
+    let mut root_bank = RootBank::zeroed();
+    root_bank.deposit_index = I80F48::from_num(1.5);
+    root_bank.borrow_index = I80F48::from_num(2);
+    let mut node_bank = NodeBank::zeroed();
+    node_bank.deposits = I80F48::from_num(100);
+    node_bank.borrows = I80F48::from_num(40);
+    let mut perp_market = PerpMarket::zeroed();
+    perp_market.open_interest = 10;
+    perp_market.fees_accrued = I80F48::from_num(3);
+
+    let check = |vault_balance: u64, perp_market: &PerpMarket| {
+        let snapshot = RootBankSnapshot {
+            token_index: 0,
+            root_bank: &root_bank,
+            node_banks: vec![(&node_bank, vault_balance)],
+        };
+        check_group_invariants(&mango_group, &mango_cache, &[snapshot], &[(0, perp_market)])
+    };
+
+    // 100 * 1.5 deposited minus 40 * 2 borrowed leaves 70 in the vault
+    assert_eq!(check(70, &perp_market), Ok(()));
+    assert_eq!(check(71, &perp_market), Ok(()));
+    assert_eq!(
+        check(72, &perp_market),
+        Err(InvariantError::VaultMismatch { token_index: 0, node_bank_index: 0 })
+    );
+
+    let mut odd_open_interest = perp_market;
+    odd_open_interest.open_interest = 11;
+    assert_eq!(
+        check(70, &odd_open_interest),
+        Err(InvariantError::UnbalancedOpenInterest { market_index: 0 })
+    );
+
+    let mut negative_fees = perp_market;
+    negative_fees.fees_accrued = I80F48::from_num(-1);
+    assert_eq!(
+        check(70, &negative_fees),
+        Err(InvariantError::NegativeInsuranceAccounting { market_index: 0 })
+    );
+}
+
+#[tokio::test]
+async fn test_next_funding_time() {
+    let mut perp_market = PerpMarket::zeroed();
+    perp_market.last_updated = 1_000;
+    perp_market.liquidity_mining_info.target_period_length = 3_600;
+
+    // Mid period
+    assert_eq!(next_funding_time(&perp_market, 1_000), 3_600);
+    assert_eq!(next_funding_time(&perp_market, 2_800), 1_800);
+
+    // Just elapsed, and long overdue
+    assert_eq!(next_funding_time(&perp_market, 4_599), 1);
+    assert_eq!(next_funding_time(&perp_market, 4_600), 0);
+    assert_eq!(next_funding_time(&perp_market, 100_000), 0);
+
+    // Without a period length an update is always due
+    perp_market.liquidity_mining_info.target_period_length = 0;
+    assert_eq!(next_funding_time(&perp_market, 1_000), 0);
+}
+
+#[tokio::test]
+async fn test_max_perp_base() {
+    let (mut mango_group, mango_cache, mut mango_account) = liquidation_fixture();
+    mango_group.perp_markets[0].perp_market = Pubkey::new_unique();
+    mango_group.perp_markets[0].base_lot_size = 1;
+    mango_group.perp_markets[0].quote_lot_size = 1;
+    mango_group.perp_markets[0].init_asset_weight = I80F48::from_num(0.75);
+    mango_group.perp_markets[0].init_liab_weight = I80F48::from_num(1.25);
+    mango_account.borrows[0] = ZERO_I80F48;
+
+    let key = Pubkey::default();
+    let mut lamports = vec![0u64; MAX_PAIRS];
+    let mut data = vec![[0u8; 0]; MAX_PAIRS];
+    let ais: Vec<AccountInfo> = lamports
+        .iter_mut()
+        .zip(data.iter_mut())
+        .map(|(l, d)| AccountInfo::new(&key, false, false, l, &mut d[..], &key, false, 0))
+        .collect();
+    let open_orders_ais: &[AccountInfo; MAX_PAIRS] = ais.as_slice().try_into().unwrap();
+
+    let max_base = |mango_account: &MangoAccount, side| {
+        max_perp_base(&mango_group, &mango_cache, mango_account, open_orders_ais, 0, side).unwrap()
+    };
+
+    // 1_000 quote of collateral, and each lot at a price of 10 uses 2.5 of init health either way
+    assert_eq!(max_base(&mango_account, Side::Bid), 400);
+    assert_eq!(max_base(&mango_account, Side::Ask), 400);
+
+    // Already long 350 lots: only 50 more can be bought. A resting ask doesn't reduce the long, so
+    // selling is limited by the short of 400 it could leave once filled
+    mango_account.perp_accounts[0].base_position = 350;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-3_500);
+    assert_eq!(max_base(&mango_account, Side::Bid), 50);
+    assert_eq!(max_base(&mango_account, Side::Ask), 750);
+
+    // Past the limit nothing fits on either side
+    mango_account.perp_accounts[0].base_position = 500;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-5_000);
+    assert_eq!(max_base(&mango_account, Side::Bid), 0);
+    assert_eq!(max_base(&mango_account, Side::Ask), 0);
+}
+
+#[tokio::test]
+async fn test_mango_cache_load_from_bytes() {
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.meta_data = MetaData::new(DataType::MangoCache, 0, true);
+    mango_cache.price_cache[1].price = I80F48::from_num(42.5);
+    mango_cache.price_cache[1].last_update = 1_634_000_000;
+    mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index = ONE_I80F48;
+    mango_cache.root_bank_cache[QUOTE_INDEX].last_update = 1_634_000_001;
+    mango_cache.perp_market_cache[1].long_funding = I80F48::from_num(-3);
+    mango_cache.perp_market_cache[1].last_update = 1_634_000_002;
+
+    let data = bytemuck::bytes_of(&mango_cache).to_vec();
+    let decoded = MangoCache::load_from_bytes(&data).unwrap();
+    assert_eq!(decoded.price_cache[1].price, I80F48::from_num(42.5));
+    assert_eq!(decoded.price_cache[1].last_update, 1_634_000_000);
+    assert_eq!(decoded.root_bank_cache[QUOTE_INDEX].deposit_index, ONE_I80F48);
+    assert_eq!(decoded.root_bank_cache[QUOTE_INDEX].last_update, 1_634_000_001);
+    assert_eq!(decoded.perp_market_cache[1].long_funding, I80F48::from_num(-3));
+    assert_eq!(decoded.perp_market_cache[1].last_update, 1_634_000_002);
+
+    // Truncated data and other account types are rejected
+    assert!(MangoCache::load_from_bytes(&data[..data.len() - 1]).is_err());
+    mango_cache.meta_data = MetaData::new(DataType::MangoGroup, 0, true);
+    assert!(MangoCache::load_from_bytes(bytemuck::bytes_of(&mango_cache)).is_err());
+}
+
+#[tokio::test]
+async fn test_account_leverage() {
+    let (mango_group, mango_cache, _) = liquidation_fixture();
+
+    let key = Pubkey::default();
+    let mut lamports = vec![0u64; MAX_PAIRS];
+    let mut data = vec![[0u8; 0]; MAX_PAIRS];
+    let ais: Vec<AccountInfo> = lamports
+        .iter_mut()
+        .zip(data.iter_mut())
+        .map(|(l, d)| AccountInfo::new(&key, false, false, l, &mut d[..], &key, false, 0))
+        .collect();
+    let open_orders_ais: &[AccountInfo; MAX_PAIRS] = ais.as_slice().try_into().unwrap();
+
+    // A flat account has no leverage
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.deposits[QUOTE_INDEX] = I80F48::from_num(1_000);
+    let leverage =
+        account_leverage(&mango_group, &mango_cache, &mango_account, open_orders_ais).unwrap();
+    assert_eq!(leverage, ZERO_I80F48);
+
+    // 1000 of equity holding 3000 worth of token 0, bought with 2000 of borrowed quote
+    mango_account.deposits[QUOTE_INDEX] = ZERO_I80F48;
+    mango_account.borrows[QUOTE_INDEX] = I80F48::from_num(2_000);
+    mango_account.deposits[0] = I80F48::from_num(300);
+    let leverage =
+        account_leverage(&mango_group, &mango_cache, &mango_account, open_orders_ais).unwrap();
+    assert_eq!(leverage, I80F48::from_num(3));
+
+    // Positions without positive equity
+    mango_account.borrows[QUOTE_INDEX] = I80F48::from_num(3_000);
+    let leverage =
+        account_leverage(&mango_group, &mango_cache, &mango_account, open_orders_ais).unwrap();
+    assert_eq!(leverage, I80F48::MAX);
+}
+
+#[tokio::test]
+async fn test_place_spot_market_order() {
+    let pk = Pubkey::new_unique();
+    let open_orders_pks = [pk; MAX_PAIRS];
+    let market_order = |side, max_base_qty, max_quote_qty| {
+        place_spot_market_order(
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &pk,
+            &open_orders_pks,
+            0,
+            side,
+            max_base_qty,
+            max_quote_qty,
+            7,
+        )
+    };
+
+    // Buys are priced to take any ask and sells to take any bid, limited only by the quantities
+    for (side, limit_price) in
+        [(serum_dex::matching::Side::Bid, u64::MAX), (serum_dex::matching::Side::Ask, 1)].iter()
+    {
+        let instruction = market_order(*side, 10, 1_000).unwrap();
+        let order = match MangoInstruction::unpack(&instruction.data) {
+            Some(MangoInstruction::PlaceSpotOrder { order }) => order,
+            other => panic!("unexpected decode {:?}", other),
+        };
+        assert_eq!(order.side, *side);
+        assert_eq!(order.limit_price.get(), *limit_price);
+        assert_eq!(order.max_coin_qty.get(), 10);
+        assert_eq!(order.max_native_pc_qty_including_fees.get(), 1_000);
+        assert_eq!(order.order_type, serum_dex::matching::OrderType::ImmediateOrCancel);
+        assert_eq!(
+            order.self_trade_behavior,
+            serum_dex::instruction::SelfTradeBehavior::DecrementTake
+        );
+        assert_eq!(order.client_order_id, 7);
+    }
+
+    // Zero quantities are rejected rather than panicking
+    let invalid_param = ProgramError::Custom(MangoErrorCode::InvalidParam.into());
+    assert_eq!(market_order(serum_dex::matching::Side::Bid, 0, 1_000), Err(invalid_param.clone()));
+    assert_eq!(market_order(serum_dex::matching::Side::Ask, 10, 0), Err(invalid_param));
+}
+
+#[tokio::test]
+async fn test_checked_move_deposits() {
+    let mut root_bank = RootBank::zeroed();
+    root_bank.deposit_index = I80F48::from_num(1.5);
+    root_bank.borrow_index = ONE_I80F48;
+
+    let mut src = NodeBank::zeroed();
+    src.deposits = I80F48::from_num(1_000);
+    src.borrows = I80F48::from_num(100);
+    let mut dst = NodeBank::zeroed();
+    dst.deposits = I80F48::from_num(10);
+
+    // 300 native is 200 deposits at a deposit index of 1.5; the total is preserved
+    src.checked_move_deposits(&mut dst, &root_bank, I80F48::from_num(300)).unwrap();
+    assert_eq!(src.deposits, I80F48::from_num(800));
+    assert_eq!(dst.deposits, I80F48::from_num(210));
+    assert_eq!(src.borrows, I80F48::from_num(100));
+
+    // src has 1200 native deposits against 100 borrowed, so it can't give up more than 1100
+    let result = src.checked_move_deposits(&mut dst, &root_bank, I80F48::from_num(1_150));
+    assert!(matches!(
+        result,
+        Err(MangoError::MangoErrorCode {
+            mango_error_code: MangoErrorCode::InsufficientLiquidity,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_loss_recovery_claims() {
+    let mut root_bank = RootBank::zeroed();
+    let mut loss_recovery = LossRecovery::zeroed();
+    let mut alice = LossShare::zeroed();
+    let mut bob = LossShare::zeroed();
+    let mut carol = LossShare::zeroed();
+
+    // A first loss takes 0.25 off the deposit index; Alice held 1200 through it
+    root_bank.socialized_loss_index = I80F48::from_num(0.25);
+    loss_recovery.register(&mut alice, I80F48::from_num(1_200), &root_bank).unwrap();
+    assert_eq!(alice.share, I80F48::from_num(300));
+
+    // A second loss takes another 0.0625; Bob held 320 through both
+    root_bank.socialized_loss_index = I80F48::from_num(0.3125);
+    loss_recovery.register(&mut bob, I80F48::from_num(320), &root_bank).unwrap();
+    assert_eq!(bob.share, I80F48::from_num(100));
+
+    // Registering again only adds the losses since; Alice has withdrawn everything since
+    loss_recovery.register(&mut alice, ZERO_I80F48, &root_bank).unwrap();
+    loss_recovery.register(&mut bob, I80F48::from_num(320), &root_bank).unwrap();
+    assert_eq!(alice.share, I80F48::from_num(300));
+    assert_eq!(bob.share, I80F48::from_num(100));
+    assert_eq!(alice.loss_index, root_bank.socialized_loss_index);
+
+    // Carol had no deposits, so she has no share
+    loss_recovery.register(&mut carol, ZERO_I80F48, &root_bank).unwrap();
+    assert!(carol.share.is_zero());
+    assert_eq!(loss_recovery.total_shares, I80F48::from_num(400));
+
+    // 200 is recovered and split 3:1
+    let mut vault_balance = 200;
+    let quantity = loss_recovery.claim(&mut alice, vault_balance).unwrap();
+    assert_eq!(quantity, 150);
+    vault_balance -= quantity;
+    assert_eq!(loss_recovery.claimable(&bob, vault_balance).unwrap(), 50);
+    let result = loss_recovery.claim(&mut carol, vault_balance);
+    assert!(matches!(
+        result,
+        Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::NothingToClaim, .. })
+    ));
+
+    // Claiming again pays nothing more until more funds arrive
+    let result = loss_recovery.claim(&mut alice, vault_balance);
+    assert!(matches!(
+        result,
+        Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::NothingToClaim, .. })
+    ));
+
+    // Another 100 arrives; Bob, who hadn't claimed yet, gets his share of both
+    vault_balance += 100;
+    assert_eq!(loss_recovery.claim(&mut bob, vault_balance).unwrap(), 75);
+    vault_balance -= 75;
+    assert_eq!(loss_recovery.claim(&mut alice, vault_balance).unwrap(), 75);
+    vault_balance -= 75;
+    assert_eq!(vault_balance, 0);
+    assert_eq!(loss_recovery.total_claimed, 300);
+
+    // Shares are fixed once claims have started
+    root_bank.socialized_loss_index = I80F48::from_num(0.5);
+    let result = loss_recovery.register(&mut bob, I80F48::from_num(320), &root_bank);
+    assert!(matches!(
+        result,
+        Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::InvalidParam, .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_check_index_growth() {
+    let mut root_bank = RootBank::zeroed();
+    root_bank.deposit_index = I80F48::from_num(2);
+
+    // No cap by default
+    root_bank.check_index_growth(I80F48::from_num(200)).unwrap();
+
+    // With a 1% cap a normal interest update passes
+    root_bank.max_index_growth = I80F48::from_num(0.01);
+    root_bank.check_index_growth(I80F48::from_num(2.0001)).unwrap();
+    root_bank.check_index_growth(I80F48::from_num(2.019)).unwrap();
+
+    // while an anomalous jump is rejected
+    let result = root_bank.check_index_growth(I80F48::from_num(3));
+    assert!(matches!(
+        result,
+        Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::IndexJumpTooLarge, .. })
+    ));
+
+    let instruction = set_max_index_growth(
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        I80F48::from_num(0.01),
+    )
+    .unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::SetMaxIndexGrowth { max_index_growth: I80F48::from_num(0.01) })
+    );
+}
+
+#[tokio::test]
+async fn test_simulate_token_change() {
+    let (mango_group, mango_cache, mut mango_account) = liquidation_fixture();
+    mango_account.borrows[0] = ZERO_I80F48;
+    mango_account.deposits[0] = I80F48::from_num(100);
+
+    let key = Pubkey::default();
+    let mut lamports = vec![0u64; MAX_PAIRS];
+    let mut data = vec![[0u8; 0]; MAX_PAIRS];
+    let ais: Vec<AccountInfo> = lamports
+        .iter_mut()
+        .zip(data.iter_mut())
+        .map(|(l, d)| AccountInfo::new(&key, false, false, l, &mut d[..], &key, false, 0))
+        .collect();
+    let open_orders_ais: &[AccountInfo; MAX_PAIRS] = ais.as_slice().try_into().unwrap();
+
+    let health_cache = |mango_account: &MangoAccount| {
+        let active_assets =
+            UserActiveAssets::new(&mango_group, mango_account, vec![(AssetType::Token, 0)]);
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&mango_group, &mango_cache, mango_account, open_orders_ais).unwrap();
+        health_cache
+    };
+
+    // Withdrawing 150 of token 0 uses up the deposit of 100 and borrows 50
+    let mut simulated = health_cache(&mango_account);
+    simulated
+        .simulate_token_change(
+            &mango_cache,
+            &mango_account,
+            open_orders_ais,
+            0,
+            I80F48::from_num(-150),
+        )
+        .unwrap();
+    let mut withdrawn_account = mango_account;
+    withdrawn_account.deposits[0] = ZERO_I80F48;
+    withdrawn_account.borrows[0] = I80F48::from_num(50);
+    let mut actual = health_cache(&withdrawn_account);
+    for &health_type in &[HealthType::Init, HealthType::Maint] {
+        assert_eq!(
+            simulated.get_health(&mango_group, health_type),
+            actual.get_health(&mango_group, health_type)
+        );
+    }
+
+    // Withdrawing quote
+    let mut simulated = health_cache(&mango_account);
+    simulated
+        .simulate_token_change(
+            &mango_cache,
+            &mango_account,
+            open_orders_ais,
+            QUOTE_INDEX,
+            I80F48::from_num(-400),
+        )
+        .unwrap();
+    let mut withdrawn_account = mango_account;
+    withdrawn_account.deposits[QUOTE_INDEX] = I80F48::from_num(600);
+    let mut actual = health_cache(&withdrawn_account);
+    assert_eq!(
+        simulated.get_health(&mango_group, HealthType::Init),
+        actual.get_health(&mango_group, HealthType::Init)
+    );
+}
+
+#[tokio::test]
+async fn test_apply_funding_delta_extreme() {
+    let mut perp_market = PerpMarket::zeroed();
+    let funding_delta = I80F48::from_num(1e18);
+
+    let mut long = PerpAccount::zeroed();
+    long.base_position = 2;
+    let mut short = PerpAccount::zeroed();
+    short.base_position = -2;
+
+    // Long settles along the way, short only at the end; both must realize the same total
+    for i in 0..10_000 {
+        perp_market.apply_funding_delta(funding_delta).unwrap();
+        if i % 1_000 == 0 {
+            long.settle_funding(&perp_market_cache(&perp_market));
+        }
+    }
+    long.settle_funding(&perp_market_cache(&perp_market));
+    short.settle_funding(&perp_market_cache(&perp_market));
+
+    let total_funding = funding_delta * I80F48::from_num(10_000);
+    assert_eq!(perp_market.long_funding, total_funding);
+    assert_eq!(perp_market.short_funding, total_funding);
+    assert_eq!(long.quote_position, -total_funding * I80F48::from_num(2));
+    assert_eq!(short.quote_position, total_funding * I80F48::from_num(2));
+
+    // Overflow is rejected and leaves both accumulators untouched
+    perp_market.long_funding = I80F48::MAX - funding_delta / I80F48::from_num(2);
+    let short_funding = perp_market.short_funding;
+    let result = perp_market.apply_funding_delta(funding_delta);
+    assert!(matches!(
+        result,
+        Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::MathError, .. })
+    ));
+    assert_eq!(perp_market.long_funding, I80F48::MAX - funding_delta / I80F48::from_num(2));
+    assert_eq!(perp_market.short_funding, short_funding);
+}
+
+#[tokio::test]
+async fn test_record_trade_realized_pnl() {
+    let mut tracker = PnlTracker::zeroed();
+    let market_index = 1;
+
+    // Opening a long at 100 per lot realizes nothing
+    tracker.record_trade(market_index, 0, 10, I80F48::from_num(-1_000));
+    assert_eq!(tracker.realized_pnl[market_index], ZERO_I80F48);
+    assert_eq!(tracker.entry_quote[market_index], I80F48::from_num(-1_000));
+
+    // Selling half at 120 realizes 5 * 20
+    tracker.record_trade(market_index, 10, -5, I80F48::from_num(600));
+    assert_eq!(tracker.realized_pnl[market_index], I80F48::from_num(100));
+    assert_eq!(tracker.entry_quote[market_index], I80F48::from_num(-500));
+
+    // Selling 10 at 90 closes the remaining 5 at a loss of 5 * 10 and opens a short at 90
+    tracker.record_trade(market_index, 5, -10, I80F48::from_num(900));
+    assert_eq!(tracker.realized_pnl[market_index], I80F48::from_num(50));
+    assert_eq!(tracker.entry_quote[market_index], I80F48::from_num(450));
+
+    // Buying back the short at 80 realizes 5 * 10
+    tracker.record_trade(market_index, -5, 5, I80F48::from_num(-400));
+    assert_eq!(tracker.realized_pnl[market_index], I80F48::from_num(100));
+    assert_eq!(tracker.entry_quote[market_index], ZERO_I80F48);
+
+    // Other markets are untouched
+    assert_eq!(tracker.realized_pnl[0], ZERO_I80F48);
+    assert_eq!(tracker.entry_quote[0], ZERO_I80F48);
+}
+
+fn perp_market_cache(perp_market: &PerpMarket) -> PerpMarketCache {
+    PerpMarketCache {
+        long_funding: perp_market.long_funding,
+        short_funding: perp_market.short_funding,
+        last_update: 0,
+    }
+}
+
 // #[tokio::test]
 // async fn test_fixmul() {
 //     let y = I80F48::from_bits(fixmul(ONE_I80F48.to_bits(), ONE_I80F48.to_bits()));
 //     println!("{}", y);
 // }
+
+#[tokio::test]
+async fn test_new_user_instructions() {
+    let program_id = Pubkey::new_unique();
+    let mango_group_pk = Pubkey::new_unique();
+    let mango_account_pk = Pubkey::new_unique();
+    let owner_pk = Pubkey::new_unique();
+    let mango_cache_pk = Pubkey::new_unique();
+    let signer_pk = Pubkey::new_unique();
+    let dex_prog_pk = Pubkey::new_unique();
+    let spot_markets = [
+        (Pubkey::new_unique(), Pubkey::new_unique()),
+        (Pubkey::new_unique(), Pubkey::new_unique()),
+    ];
+    let root_bank_pk = Pubkey::new_unique();
+    let node_bank_pk = Pubkey::new_unique();
+    let vault_pk = Pubkey::new_unique();
+    let owner_token_account_pk = Pubkey::new_unique();
+
+    let instructions = new_user_instructions(
+        &program_id,
+        &mango_group_pk,
+        &mango_account_pk,
+        &owner_pk,
+        &mango_cache_pk,
+        &signer_pk,
+        &dex_prog_pk,
+        &spot_markets,
+        &root_bank_pk,
+        &node_bank_pk,
+        &vault_pk,
+        &owner_token_account_pk,
+        &Rent::default(),
+        1_000,
+    )
+    .unwrap();
+
+    // create + init for the MangoAccount and each open orders account, then the deposit
+    assert_eq!(instructions.len(), 2 + 2 * spot_markets.len() + 1);
+
+    // The MangoAccount is created for the program, then initialized
+    assert_eq!(instructions[0].program_id, solana_program::system_program::ID);
+    assert_eq!(instructions[0].accounts[0].pubkey, owner_pk);
+    assert_eq!(instructions[0].accounts[1].pubkey, mango_account_pk);
+    assert_eq!(instructions[1].program_id, program_id);
+    assert_eq!(
+        MangoInstruction::unpack(&instructions[1].data),
+        Some(MangoInstruction::InitMangoAccount)
+    );
+    assert_eq!(instructions[1].accounts[0].pubkey, mango_group_pk);
+    assert_eq!(instructions[1].accounts[1].pubkey, mango_account_pk);
+    assert_eq!(instructions[1].accounts[2].pubkey, owner_pk);
+
+    // Each open orders account is created for the dex, then initialized for its market
+    for (i, (open_orders_pk, spot_market_pk)) in spot_markets.iter().enumerate() {
+        let create = &instructions[2 + 2 * i];
+        assert_eq!(create.program_id, solana_program::system_program::ID);
+        assert_eq!(create.accounts[0].pubkey, owner_pk);
+        assert_eq!(create.accounts[1].pubkey, *open_orders_pk);
+
+        let init = &instructions[3 + 2 * i];
+        assert_eq!(init.program_id, program_id);
+        assert_eq!(
+            MangoInstruction::unpack(&init.data),
+            Some(MangoInstruction::InitSpotOpenOrders)
+        );
+        assert_eq!(init.accounts[0].pubkey, mango_group_pk);
+        assert_eq!(init.accounts[1].pubkey, mango_account_pk);
+        assert_eq!(init.accounts[2].pubkey, owner_pk);
+        assert_eq!(init.accounts[3].pubkey, dex_prog_pk);
+        assert_eq!(init.accounts[4].pubkey, *open_orders_pk);
+        assert_eq!(init.accounts[5].pubkey, *spot_market_pk);
+        assert_eq!(init.accounts[6].pubkey, signer_pk);
+    }
+
+    // The deposit comes last and goes into the new MangoAccount
+    let deposit = instructions.last().unwrap();
+    assert_eq!(deposit.program_id, program_id);
+    assert_eq!(
+        MangoInstruction::unpack(&deposit.data),
+        Some(MangoInstruction::Deposit { quantity: 1_000 })
+    );
+    assert_eq!(deposit.accounts[0].pubkey, mango_group_pk);
+    assert_eq!(deposit.accounts[1].pubkey, mango_account_pk);
+    assert_eq!(deposit.accounts[2].pubkey, owner_pk);
+    assert_eq!(deposit.accounts[3].pubkey, mango_cache_pk);
+    assert_eq!(deposit.accounts[4].pubkey, root_bank_pk);
+    assert_eq!(deposit.accounts[5].pubkey, node_bank_pk);
+    assert_eq!(deposit.accounts[6].pubkey, vault_pk);
+    assert_eq!(deposit.accounts[8].pubkey, owner_token_account_pk);
+    assert_eq!(
+        deposit.accounts[9].pubkey,
+        DepositWhitelist::find_address(&program_id, &mango_group_pk).0
+    );
+}
+
+fn book_side_with_orders(data_type: DataType, orders: &[(i64, i64)]) -> Box<BookSide> {
+    let mut book_side = Box::new(BookSide::zeroed());
+    book_side.meta_data = MetaData::new(data_type, 0, true);
+    for (i, (price, quantity)) in orders.iter().enumerate() {
+        let key = ((*price as i128) << 64) | i as i128;
+        let leaf = LeafNode::new(0, key, Pubkey::default(), *quantity, i as u64, 0, 0);
+        book_side.insert_leaf(&leaf).unwrap();
+    }
+    book_side
+}
+
+#[tokio::test]
+async fn test_impact_price() {
+    let close = |a: I80F48, b: f64| (a.to_num::<f64>() - b).abs() < 1e-6;
+
+    // Asks fill from the lowest price up
+    let asks = book_side_with_orders(DataType::Asks, &[(110, 10), (100, 10), (120, 10)]);
+    assert_eq!(impact_price(&asks, 0, 0), Some(I80F48::from_num(100)));
+    assert_eq!(impact_price(&asks, 500, 0), Some(I80F48::from_num(100)));
+    assert!(close(impact_price(&asks, 2_100, 0).unwrap(), 105.0));
+    // 1000 at 100 and 500 at 110
+    assert!(close(impact_price(&asks, 1_500, 0).unwrap(), 1_500.0 / (10.0 + 500.0 / 110.0)));
+    assert!(close(impact_price(&asks, 3_300, 0).unwrap(), 110.0));
+
+    // Bids fill from the highest price down
+    let bids = book_side_with_orders(DataType::Bids, &[(90, 10), (100, 10), (80, 10)]);
+    assert_eq!(impact_price(&bids, 0, 0), Some(I80F48::from_num(100)));
+    assert!(close(impact_price(&bids, 1_000, 0).unwrap(), 100.0));
+    assert!(close(impact_price(&bids, 1_900, 0).unwrap(), 95.0));
+
+    // A book too thin for the impact notional, or empty, has no impact price
+    assert_eq!(impact_price(&asks, 3_301, 0), None);
+    assert_eq!(impact_price(&bids, 10_000, 0), None);
+    let empty = book_side_with_orders(DataType::Asks, &[]);
+    assert_eq!(impact_price(&empty, 0, 0), None);
+}
+
+#[tokio::test]
+async fn test_perp_fill_for_quote() {
+    let asks = book_side_with_orders(DataType::Asks, &[(110, 10), (100, 10), (120, 10)]);
+
+    // 10 lots at 100, then the remaining 500 buys 4 whole lots at 110
+    let (avg_price, base_filled) = perp_fill_for_quote(&asks, 1_500).unwrap();
+    assert_eq!(base_filled, 14);
+    assert_eq!(avg_price, I80F48::from_num(1_440) / I80F48::from_num(14));
+
+    // A budget within the best order pays the best price
+    assert_eq!(perp_fill_for_quote(&asks, 750), Some((I80F48::from_num(100), 7)));
+
+    // A budget larger than the book buys all of it
+    assert_eq!(perp_fill_for_quote(&asks, 10_000), Some((I80F48::from_num(110), 30)));
+
+    // Nothing is bought with less than one lot's price, from an empty book, or from bids
+    assert_eq!(perp_fill_for_quote(&asks, 99), None);
+    let empty = book_side_with_orders(DataType::Asks, &[]);
+    assert_eq!(perp_fill_for_quote(&empty, 1_500), None);
+    let bids = book_side_with_orders(DataType::Bids, &[(100, 10)]);
+    assert_eq!(perp_fill_for_quote(&bids, 1_500), None);
+}
+
+#[tokio::test]
+async fn test_perp_spread_bps() {
+    let close = |a: I80F48, b: f64| (a.to_num::<f64>() - b).abs() < 1e-6;
+
+    // Best bid 99 and best ask 101: a spread of 2 around a mid of 100 is 200 bps
+    let bids = book_side_with_orders(DataType::Bids, &[(95, 10), (99, 10)]);
+    let asks = book_side_with_orders(DataType::Asks, &[(105, 10), (101, 10)]);
+    assert!(close(perp_spread_bps(&bids, &asks).unwrap(), 200.0));
+
+    // Without a bid or an ask there is no spread
+    let empty_bids = book_side_with_orders(DataType::Bids, &[]);
+    let empty_asks = book_side_with_orders(DataType::Asks, &[]);
+    assert_eq!(perp_spread_bps(&empty_bids, &asks), None);
+    assert_eq!(perp_spread_bps(&bids, &empty_asks), None);
+}
+
+#[tokio::test]
+async fn test_mining_points_rate() {
+    let inside_pk = Pubkey::new_unique();
+    let shallow_pk = Pubkey::new_unique();
+    let outside_pk = Pubkey::new_unique();
+    let mut asks = Box::new(BookSide::zeroed());
+    asks.meta_data = MetaData::new(DataType::Asks, 0, true);
+    let orders = [(1_000, 10, inside_pk), (1_005, 10, shallow_pk), (1_050, 10, outside_pk)];
+    for (i, (price, quantity, owner)) in orders.iter().enumerate() {
+        let key = ((*price as i128) << 64) | i as i128;
+        let leaf = LeafNode::new(0, key, *owner, *quantity, i as u64, 0, 0);
+        asks.insert_leaf(&leaf).unwrap();
+    }
+    let max_depth_bps = I80F48::from_num(100);
+
+    // The best order is 100 bps inside the depth, the next one 50 bps: 100^2 * 10 + 50^2 * 10
+    let total = I80F48::from_num(125_000);
+    assert_eq!(
+        mining_points_rate(&asks, max_depth_bps, &inside_pk),
+        Some((I80F48::from_num(100_000), total))
+    );
+    assert_eq!(
+        mining_points_rate(&asks, max_depth_bps, &shallow_pk),
+        Some((I80F48::from_num(25_000), total))
+    );
+
+    // An order 500 bps from the best price earns nothing
+    assert_eq!(mining_points_rate(&asks, max_depth_bps, &outside_pk), Some((ZERO_I80F48, total)));
+
+    // An empty book earns nothing, and only bids or asks can be walked
+    let empty = book_side_with_orders(DataType::Bids, &[]);
+    assert_eq!(
+        mining_points_rate(&empty, max_depth_bps, &inside_pk),
+        Some((ZERO_I80F48, ZERO_I80F48))
+    );
+    asks.meta_data.data_type = DataType::MangoAccount as u8;
+    assert_eq!(mining_points_rate(&asks, max_depth_bps, &inside_pk), None);
+}
+
+#[tokio::test]
+async fn test_funding_rate_impact_depth() {
+    let mut perp_market = PerpMarket::zeroed();
+    perp_market.quote_lot_size = 10;
+    perp_market.base_lot_size = 100;
+
+    let bids = book_side_with_orders(DataType::Bids, &[(100, 10), (80, 100)]);
+    let asks = book_side_with_orders(DataType::Asks, &[(102, 10), (120, 100)]);
+
+    // The best bid and ask have a mid of 101 quote lots per base lot, equal to the index
+    let index_price = I80F48::from_num(101 * 10) / I80F48::from_num(100);
+    assert_eq!(perp_market.get_funding_rate(&bids, &asks, index_price, 0), ZERO_I80F48);
+
+    // 1500 quote lots deep the impact bid drops further than the impact ask rises
+    let impact_bid = 1_500.0 / (10.0 + 500.0 / 80.0);
+    let impact_ask = 1_500.0 / (10.0 + 480.0 / 120.0);
+    let expected = (impact_bid + impact_ask) / 2.0 * 10.0 / 100.0 / 10.1 - 1.0;
+    let rate = perp_market.get_funding_rate(&bids, &asks, index_price, 15_000);
+    assert!(rate.is_negative());
+    assert!((rate.to_num::<f64>() - expected).abs() < 1e-6);
+
+    // Deeper than the bids can fill prices the market as having no bids
+    let rate = perp_market.get_funding_rate(&bids, &asks, index_price, 100_000);
+    assert!((rate.to_num::<f64>() + 0.05).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn test_funding_rate_pegged_only_side() {
+    let mut perp_market = PerpMarket::zeroed();
+    perp_market.quote_lot_size = 10;
+    perp_market.base_lot_size = 100;
+
+    // An index of 101 quote lots per base lot, with asks pegged 1 and 19 above it
+    let index_price = I80F48::from_num(101 * 10) / I80F48::from_num(100);
+    let bids = book_side_with_orders(DataType::Bids, &[(100, 10), (80, 100)]);
+    let mut asks = book_side_with_orders(DataType::Asks, &[]);
+    for (seq_num, (peg_offset, quantity)) in [(1i64, 10i64), (19, 100)].iter().enumerate() {
+        let key = pegged_order_key(Side::Ask, *peg_offset, seq_num as u64);
+        let leaf = LeafNode::new(0, key, Pubkey::default(), *quantity, 0, 0, 0);
+        asks.insert_leaf_with_ext(&leaf, &LeafExt::default()).unwrap();
+    }
+
+    // The pegged asks at 102 and 120 price funding like fixed price asks would
+    assert_eq!(perp_market.get_funding_rate(&bids, &asks, index_price, 0), ZERO_I80F48);
+    let impact_bid = 1_500.0 / (10.0 + 500.0 / 80.0);
+    let impact_ask = 1_500.0 / (10.0 + 480.0 / 120.0);
+    let expected = (impact_bid + impact_ask) / 2.0 * 10.0 / 100.0 / 10.1 - 1.0;
+    let rate = perp_market.get_funding_rate(&bids, &asks, index_price, 15_000);
+    assert!((rate.to_num::<f64>() - expected).abs() < 1e-6);
+
+    // Pegged asks past their peg limit don't trade, leaving the market without asks
+    let mut asks = book_side_with_orders(DataType::Asks, &[]);
+    let leaf = LeafNode::new(0, pegged_order_key(Side::Ask, 1, 0), Pubkey::default(), 10, 0, 0, 0);
+    let ext = LeafExt { peg_limit: 110, ..LeafExt::default() };
+    asks.insert_leaf_with_ext(&leaf, &ext).unwrap();
+    let rate = perp_market.get_funding_rate(&bids, &asks, index_price, 0);
+    assert!((rate.to_num::<f64>() - 0.05).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn test_perp_market_config_accounts() {
+    let keys: Vec<Pubkey> = (0..6).map(|_| Pubkey::new_unique()).collect();
+    let (perp_market_config_pk, _) = PerpMarketConfig::find_address(&keys[0], &keys[2]);
+
+    // SetPerpImpactDepth creates the config on first use, paid for by the admin
+    let instruction =
+        set_perp_impact_depth(&keys[0], &keys[1], &keys[2], &keys[3], 15_000).unwrap();
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, false, true, true, false]);
+    assert!(instruction.accounts[2].is_signer);
+    assert_eq!(instruction.accounts[3].pubkey, perp_market_config_pk);
+
+    // Funding is always passed the config, whether it was created or not
+    let instruction =
+        update_funding(&keys[0], &keys[1], &keys[3], &keys[2], &keys[4], &keys[5]).unwrap();
+    assert_eq!(instruction.accounts.len(), 6);
+    assert_eq!(instruction.accounts[5].pubkey, perp_market_config_pk);
+    assert!(!instruction.accounts[5].is_writable);
+
+    let perp_markets = [(keys[2], keys[4], keys[5])];
+    let instruction = update_all_funding(&keys[0], &keys[1], &keys[3], &perp_markets).unwrap();
+    assert_eq!(instruction.accounts.len(), 2 + 4);
+    assert_eq!(instruction.accounts[5].pubkey, perp_market_config_pk);
+}
+
+#[tokio::test]
+async fn test_unpack_usize_near_u32_max() {
+    // usize fields are encoded as u64 regardless of the target
+    let near_max = u32::MAX as u64 - 1;
+    let past_max = u32::MAX as u64 + 1;
+    let encode = |value: u64| {
+        let mut data = MangoInstruction::ConsumeEvents { limit: 0 }.pack();
+        data[4..12].copy_from_slice(&value.to_le_bytes());
+        data
+    };
+
+    assert_eq!(
+        MangoInstruction::unpack(&encode(near_max)),
+        Some(MangoInstruction::ConsumeEvents { limit: near_max as usize })
+    );
+
+    // Values that don't fit the target's usize are rejected instead of truncated
+    let decoded = MangoInstruction::unpack(&encode(past_max));
+    if cfg!(target_pointer_width = "64") {
+        assert_eq!(decoded, Some(MangoInstruction::ConsumeEvents { limit: past_max as usize }));
+    } else {
+        assert_eq!(decoded, None);
+    }
+}
+
+#[tokio::test]
+async fn test_has_unsettled_pnl() {
+    let (mut mango_group, mut mango_cache, _) = liquidation_fixture();
+    mango_group.perp_markets[0].perp_market = Pubkey::new_unique();
+    mango_group.perp_markets[0].base_lot_size = 100;
+    let mut mango_account = MangoAccount::zeroed();
+
+    // No perp positions
+    assert!(!has_unsettled_pnl(&mango_group, &mango_cache, &mango_account));
+
+    // A long of 2 lots worth 2000 quote, entered at the current price: nothing to settle
+    mango_account.perp_accounts[0].base_position = 2;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-2_000);
+    assert!(!has_unsettled_pnl(&mango_group, &mango_cache, &mango_account));
+
+    // The price moves
+    mango_cache.price_cache[0].price = I80F48::from_num(11);
+    assert!(has_unsettled_pnl(&mango_group, &mango_cache, &mango_account));
+
+    // The position is back at break even, but owes funding it hasn't settled
+    mango_cache.price_cache[0].price = I80F48::from_num(10);
+    mango_cache.perp_market_cache[0].long_funding = I80F48::from_num(1);
+    assert!(has_unsettled_pnl(&mango_group, &mango_cache, &mango_account));
+}
+
+#[tokio::test]
+async fn test_open_orders_settleable() {
+    let mut open_orders = OpenOrders::zeroed();
+    open_orders.account_flags = (AccountFlag::Initialized | AccountFlag::OpenOrders).bits();
+    open_orders.native_coin_free = 3_000;
+    open_orders.native_coin_total = 5_000;
+    open_orders.native_pc_free = 70_000;
+    open_orders.native_pc_total = 100_000;
+    open_orders.referrer_rebates_accrued = 25;
+
+    // Lay it out the way the dex stores it, with the head and tail padding
+    let mut data = b"serum".to_vec();
+    data.extend_from_slice(bytes_of(&open_orders));
+    data.extend_from_slice(b"padding");
+    let key = Pubkey::new_unique();
+    let mut lamports = 0u64;
+    let open_orders_ai =
+        AccountInfo::new(&key, false, false, &mut lamports, &mut data[..], &key, false, 0);
+
+    let loaded = load_open_orders(&open_orders_ai).unwrap();
+    // Locked balances stay with the orders; rebates are paid out with the quote
+    assert_eq!(open_orders_settleable(&loaded), (3_000, 70_025));
+}
+
+#[tokio::test]
+async fn test_collateral_breakdown() {
+    let (mut mango_group, mut mango_cache, mut mango_account) = liquidation_fixture();
+    mango_group.num_oracles = 2;
+    mango_group.spot_markets[1].spot_market = Pubkey::new_unique();
+    mango_group.spot_markets[1].init_asset_weight = I80F48::from_num(0.8);
+    mango_cache.price_cache[1].price = I80F48::from_num(2);
+    mango_cache.root_bank_cache[0].deposit_index = I80F48::from_num(1.5);
+    mango_account.borrows[0] = ZERO_I80F48;
+    mango_account.deposits[0] = I80F48::from_num(50);
+    mango_account.borrows[1] = I80F48::from_num(20);
+
+    // 75 native base at 10, and the 1000 quote; the borrowed token is left out
+    let breakdown = collateral_breakdown(&mango_group, &mango_cache, &mango_account).unwrap();
+    assert_eq!(
+        breakdown,
+        vec![
+            (0, I80F48::from_num(75), I80F48::from_num(750), I80F48::from_num(0.9)),
+            (QUOTE_INDEX, I80F48::from_num(1_000), I80F48::from_num(1_000), ONE_I80F48),
+        ]
+    );
+
+    // A second collateral token slots in by token index
+    mango_account.borrows[1] = ZERO_I80F48;
+    mango_account.deposits[1] = I80F48::from_num(300);
+    let breakdown = collateral_breakdown(&mango_group, &mango_cache, &mango_account).unwrap();
+    assert_eq!(breakdown.len(), 3);
+    assert_eq!(
+        breakdown[1],
+        (1, I80F48::from_num(300), I80F48::from_num(600), I80F48::from_num(0.8))
+    );
+    let total: I80F48 = breakdown.iter().map(|(_, _, value, _)| *value).sum();
+    assert_eq!(total, I80F48::from_num(2_350));
+}
+
+#[tokio::test]
+async fn test_health_at_prices() {
+    let (mut mango_group, mango_cache, mango_account) = liquidation_fixture();
+    mango_group.spot_markets[0].init_liab_weight = I80F48::from_num(1.25);
+    mango_group.spot_markets[0].maint_liab_weight = I80F48::from_num(1.125);
+
+    let key = Pubkey::default();
+    let mut lamports = vec![0u64; MAX_PAIRS];
+    let mut data = vec![[0u8; 0]; MAX_PAIRS];
+    let ais: Vec<AccountInfo> = lamports
+        .iter_mut()
+        .zip(data.iter_mut())
+        .map(|(l, d)| AccountInfo::new(&key, false, false, l, &mut d[..], &key, false, 0))
+        .collect();
+    let open_orders_ais: &[AccountInfo; MAX_PAIRS] = ais.as_slice().try_into().unwrap();
+    let health = |price_overrides: &[(usize, I80F48)]| {
+        health_at_prices(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            open_orders_ais,
+            price_overrides,
+        )
+    };
+
+    // 1000 quote against 100 base borrowed at the cached price of 10
+    let (init, maint) = health(&[]).unwrap();
+    assert_eq!(init, I80F48::from_num(-250));
+    assert_eq!(maint, I80F48::from_num(-125));
+
+    // +20%: the borrow is worth 200 more, weighted by the liab weights
+    let (up_init, up_maint) = health(&[(0, I80F48::from_num(12))]).unwrap();
+    assert_eq!(up_init - init, I80F48::from_num(-250));
+    assert_eq!(up_maint - maint, I80F48::from_num(-225));
+
+    // -20%: the borrow is worth 200 less
+    let (down_init, down_maint) = health(&[(0, I80F48::from_num(8))]).unwrap();
+    assert_eq!(down_init - init, I80F48::from_num(250));
+    assert_eq!(down_maint - maint, I80F48::from_num(225));
+
+    // The cached price is untouched, and only listed oracles with a positive price are accepted
+    assert_eq!(mango_cache.price_cache[0].price, I80F48::from_num(10));
+    for price_overrides in &[[(1, I80F48::from_num(12))], [(0, ZERO_I80F48)]] {
+        assert!(matches!(
+            health(price_overrides),
+            Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::InvalidParam, .. })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_weighted_deposit_value() {
+    let (mut mango_group, mut mango_cache, mut mango_account) = liquidation_fixture();
+    mango_group.spot_markets[0].init_asset_weight = I80F48::from_num(0.75);
+    mango_group.spot_markets[0].maint_asset_weight = I80F48::from_num(0.875);
+    mango_cache.root_bank_cache[0].deposit_index = I80F48::from_num(1.5);
+    mango_account.borrows[0] = ZERO_I80F48;
+    mango_account.deposits[0] = I80F48::from_num(50);
+
+    let value = |token_index, health_type| {
+        weighted_deposit_value(&mango_group, &mango_cache, &mango_account, token_index, health_type)
+            .unwrap()
+    };
+
+    // 75 native base at a price of 10
+    assert_eq!(value(0, HealthType::Init), I80F48::from_num(562.5));
+    assert_eq!(value(0, HealthType::Maint), I80F48::from_num(656.25));
+
+    // Quote counts at full value under both
+    assert_eq!(value(QUOTE_INDEX, HealthType::Init), I80F48::from_num(1_000));
+    assert_eq!(value(QUOTE_INDEX, HealthType::Maint), I80F48::from_num(1_000));
+}
+
+#[tokio::test]
+async fn test_resolve_perp_bankruptcy_builder() {
+    let program_id = Pubkey::new_unique();
+    let keys: Vec<Pubkey> = (0..11).map(|_| Pubkey::new_unique()).collect();
+    let liqor_open_orders_pks = [Pubkey::default(); MAX_PAIRS];
+    let instruction = resolve_perp_bankruptcy(
+        &program_id,
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &keys[8],
+        &keys[9],
+        &keys[10],
+        &liqor_open_orders_pks,
+        2,
+        I80F48::from_num(100),
+    )
+    .unwrap();
+
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::ResolvePerpBankruptcy {
+            liab_index: 2,
+            max_liab_transfer: I80F48::from_num(100)
+        })
+    );
+
+    // Accounts in the order the processor reads them, with only the liqor signing
+    let fixed = &instruction.accounts[..12];
+    let pubkeys: Vec<Pubkey> = fixed.iter().map(|meta| meta.pubkey).collect();
+    assert_eq!(pubkeys[..11], keys[..]);
+    assert_eq!(pubkeys[11], spl_token::ID);
+    let writable: Vec<bool> = fixed.iter().map(|meta| meta.is_writable).collect();
+    assert_eq!(
+        writable,
+        [false, true, true, true, false, false, true, true, true, false, true, false]
+    );
+    let signers: Vec<usize> = (0..12).filter(|&i| fixed[i].is_signer).collect();
+    assert_eq!(signers, [4]);
+    assert_eq!(instruction.accounts.len(), 12 + MAX_PAIRS);
+}
+
+#[tokio::test]
+async fn test_resolve_token_bankruptcy_builder() {
+    let program_id = Pubkey::new_unique();
+    let keys: Vec<Pubkey> = (0..12).map(|_| Pubkey::new_unique()).collect();
+    let liqor_open_orders_pks = [Pubkey::default(); MAX_PAIRS];
+    let liab_node_bank_pks = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let instruction = resolve_token_bankruptcy(
+        &program_id,
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &keys[8],
+        &keys[9],
+        &keys[10],
+        &keys[11],
+        &liqor_open_orders_pks,
+        &liab_node_bank_pks,
+        I80F48::from_num(100),
+    )
+    .unwrap();
+
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::ResolveTokenBankruptcy { max_liab_transfer: I80F48::from_num(100) })
+    );
+    assert_eq!(
+        instruction.accounts.len(),
+        13 + liqor_open_orders_pks.len() + liab_node_bank_pks.len()
+    );
+
+    // The fixed accounts, then the liqor's open orders, then the liab node banks
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|meta| meta.pubkey).collect();
+    assert_eq!(pubkeys[..12], keys[..]);
+    assert_eq!(pubkeys[12], spl_token::ID);
+    assert_eq!(pubkeys[13..13 + MAX_PAIRS], liqor_open_orders_pks[..]);
+    assert_eq!(pubkeys[13 + MAX_PAIRS..], liab_node_bank_pks[..]);
+
+    let writable: Vec<bool> = instruction.accounts[..13].iter().map(|m| m.is_writable).collect();
+    assert_eq!(
+        writable,
+        [false, true, true, true, false, false, true, true, true, false, true, true, false]
+    );
+    assert!(instruction.accounts[13 + MAX_PAIRS..].iter().all(|m| m.is_writable));
+    assert!(instruction.accounts.iter().enumerate().all(|(i, m)| m.is_signer == (i == 4)));
+}
+
+#[tokio::test]
+async fn test_margin_basket_mask() {
+    let mut mango_account = MangoAccount::zeroed();
+    assert_eq!(mango_account.margin_basket_mask(), 0);
+
+    mango_account.in_margin_basket[1] = true;
+    mango_account.in_margin_basket[MAX_PAIRS - 1] = true;
+    mango_account.num_in_margin_basket = 2;
+    assert_eq!(mango_account.margin_basket_mask(), 0b10 | 1 << (MAX_PAIRS - 1));
+}
+
+#[tokio::test]
+async fn test_settle_fees_builder() {
+    let keys: Vec<Pubkey> = (0..10).map(|_| Pubkey::new_unique()).collect();
+    let instruction = settle_fees(
+        &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], &keys[7], &keys[8],
+        &keys[9],
+    )
+    .unwrap();
+
+    assert_eq!(instruction.data[..4], 29u32.to_le_bytes());
+    assert_eq!(MangoInstruction::unpack(&instruction.data), Some(MangoInstruction::SettleFees));
+    assert_eq!(instruction.accounts.len(), 10);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, false, true, true, false, true, true, true, false, false]);
+    assert!(instruction.accounts.iter().all(|m| !m.is_signer));
+}
+
+#[tokio::test]
+async fn test_pegged_price() {
+    // The price follows the oracle at the offset
+    assert_eq!(pegged_price(Side::Bid, 1_000, -10, 2_000), Some(990));
+    assert_eq!(pegged_price(Side::Bid, 1_500, -10, 2_000), Some(1_490));
+    assert_eq!(pegged_price(Side::Ask, 1_000, 10, 500), Some(1_010));
+    assert_eq!(pegged_price(Side::Ask, 800, 10, 500), Some(810));
+
+    // Bids stop trading above the peg limit and asks below it
+    assert_eq!(pegged_price(Side::Bid, 2_010, -10, 2_000), Some(2_000));
+    assert_eq!(pegged_price(Side::Bid, 2_011, -10, 2_000), None);
+    assert_eq!(pegged_price(Side::Ask, 490, 10, 500), Some(500));
+    assert_eq!(pegged_price(Side::Ask, 489, 10, 500), None);
+
+    // Never a non-positive price
+    assert_eq!(pegged_price(Side::Bid, 10, -10, 2_000), None);
+}
+
+#[tokio::test]
+async fn test_pegged_leaf_node() {
+    for offset in [-25i64, 0, 25].iter() {
+        let key = pegged_order_key(Side::Bid, *offset, 7);
+        let leaf = LeafNode::new(0, key, Pubkey::default(), 1, 0, 0, 0);
+        assert!(leaf.is_pegged());
+        assert_eq!(leaf.peg_offset(), *offset);
+        assert_eq!(leaf.seq_num(Side::Bid), 7);
+        assert_eq!(leaf.effective_price(Side::Bid, 1_000, 1_050), Some(1_000 + offset));
+        assert_eq!(leaf.effective_price(Side::Bid, 1_100, 1_050), None);
+    }
+
+    let fixed = LeafNode::new(0, (1_000i128) << 64, Pubkey::default(), 1, 0, 0, 0);
+    assert!(!fixed.is_pegged());
+    assert_eq!(fixed.effective_price(Side::Ask, 5, 0), Some(1_000));
+
+    // Min and max only look at fixed price orders
+    let mut asks = book_side_with_orders(DataType::Asks, &[(110, 10), (120, 10)]);
+    let pegged =
+        LeafNode::new(0, pegged_order_key(Side::Ask, -50, 9), Pubkey::default(), 10, 0, 0, 0);
+    let ext = LeafExt { peg_limit: 1, ..LeafExt::default() };
+    asks.insert_leaf_with_ext(&pegged, &ext).unwrap();
+    assert_eq!(asks.get_min().unwrap().price(), 110);
+    assert_eq!(asks.get_max().unwrap().price(), 120);
+
+    // The best price and impact price include the pegged order wherever the oracle puts it
+    assert_eq!(asks.find_best(Side::Ask, 150).unwrap().1, 100);
+    assert_eq!(impact_price(&asks, 0, 150), Some(I80F48::from_num(100)));
+    // 1000 at the pegged 100 and 1100 at 110
+    assert_eq!(impact_price(&asks, 2_100, 150), Some(I80F48::from_num(105)));
+    assert_eq!(asks.find_best(Side::Ask, 200).unwrap().1, 110);
+    assert_eq!(impact_price(&asks, 0, 200), Some(I80F48::from_num(110)));
+    // 1100 at 110, 1200 at 120 and 1500 at the pegged 150
+    let impact_ask = impact_price(&asks, 3_800, 200).unwrap();
+    assert!((impact_ask.to_num::<f64>() - 3_800.0 / 30.0).abs() < 1e-6);
+}
+
+#[tokio::test]
+async fn test_place_perp_pegged_order_builder() {
+    let keys: Vec<Pubkey> = (0..9).map(|_| Pubkey::new_unique()).collect();
+    let instruction = place_perp_pegged_order(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &keys[8],
+        &[Pubkey::default(); MAX_PAIRS],
+        Side::Ask,
+        -15,
+        900,
+        3,
+        42,
+        OrderType::PostOnly,
+    )
+    .unwrap();
+
+    assert_eq!(instruction.data[..4], 62u32.to_le_bytes());
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::PlacePerpPeggedOrder {
+            offset_ticks: -15,
+            peg_limit: 900,
+            quantity: 3,
+            client_order_id: 42,
+            side: Side::Ask,
+            order_type: OrderType::PostOnly,
+        })
+    );
+    assert_eq!(instruction.accounts.len(), 8 + MAX_PAIRS);
+}
+
+#[tokio::test]
+async fn test_msrm_builders() {
+    let keys: Vec<Pubkey> = (0..7).map(|_| Pubkey::new_unique()).collect();
+
+    let instruction =
+        deposit_msrm(&keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], 10).unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::DepositMsrm { quantity: 10 })
+    );
+    assert_eq!(instruction.accounts.len(), 6);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, true, false, true, true, false]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, true, false, false, false]);
+    assert_eq!(instruction.accounts[5].pubkey, spl_token::ID);
+
+    let instruction =
+        withdraw_msrm(&keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], 10)
+            .unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::WithdrawMsrm { quantity: 10 })
+    );
+    assert_eq!(instruction.accounts.len(), 7);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, true, false, true, true, false, false]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, true, false, false, false, false]);
+    assert_eq!(instruction.accounts[5].pubkey, keys[6]);
+    assert_eq!(instruction.accounts[6].pubkey, spl_token::ID);
+}
+
+#[tokio::test]
+async fn test_change_perp_market_params_builder() {
+    let keys: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+    let instruction = change_perp_market_params(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        Some(I80F48::from_num(20)),
+        None,
+        Some(I80F48::from_num(0.025)),
+        None,
+        Some(I80F48::from_num(-0.0005)),
+        None,
+        Some(I80F48::from_num(100)),
+        None,
+        Some(1_000),
+    )
+    .unwrap();
+
+    // Every option takes its full width, Some or None
+    assert_eq!(instruction.data.len(), 4 + 137);
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::ChangePerpMarketParams {
+            maint_leverage: Some(I80F48::from_num(20)),
+            init_leverage: None,
+            liquidation_fee: Some(I80F48::from_num(0.025)),
+            maker_fee: None,
+            taker_fee: Some(I80F48::from_num(-0.0005)),
+            rate: None,
+            max_depth_bps: Some(I80F48::from_num(100)),
+            target_period_length: None,
+            mngo_per_period: Some(1_000),
+        })
+    );
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [true, true, false]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, true]);
+}
+
+#[tokio::test]
+async fn test_borrow_native() {
+    let mut mango_account = MangoAccount::zeroed();
+    let mut root_bank = RootBank::zeroed();
+    root_bank.borrow_index = I80F48::from_num(1.2345);
+    assert_eq!(borrow_native(&mango_account, 0, &root_bank).unwrap(), 0);
+
+    // 100.5 * 1.2345 = 124.06725, rounded up
+    mango_account.borrows[0] = I80F48::from_num(100.5);
+    let amount = borrow_native(&mango_account, 0, &root_bank).unwrap();
+    assert_eq!(amount, 125);
+
+    // Depositing one less leaves less than one native unit borrowed
+    let short = I80F48::from_num(amount - 1) / root_bank.borrow_index;
+    assert!((mango_account.borrows[0] - short) * root_bank.borrow_index < ONE_I80F48);
+
+    // Depositing the amount repays the whole borrow, like Deposit does
+    let repaid = (I80F48::from_num(amount) / root_bank.borrow_index).min(mango_account.borrows[0]);
+    assert_eq!(repaid, mango_account.borrows[0]);
+    mango_account.checked_sub_borrow(0, repaid).unwrap();
+    assert!(mango_account.borrows[0].is_zero());
+}
+
+#[tokio::test]
+async fn test_set_group_admin_builder() {
+    let keys: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+    let instruction = set_group_admin(&keys[0], &keys[1], &keys[2], &keys[3]).unwrap();
+
+    assert_eq!(MangoInstruction::unpack(&instruction.data), Some(MangoInstruction::SetGroupAdmin));
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys, keys[1..]);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [true, false, false]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, true]);
+}
+
+#[tokio::test]
+async fn test_post_trade_risk_details() {
+    let mango_account_pk = Pubkey::new_unique();
+    let details = post_trade_risk_details(
+        &mango_account_pk,
+        1,
+        I80F48::from_num(2.5),
+        I80F48::from_num(-10),
+        I80F48::from_num(-4.25),
+        true,
+        false,
+        100,
+    );
+    assert_eq!(
+        details,
+        format!(
+            "PostTradeRisk details: {{ \"mango_account\": {}, \"market_index\": 1, \
+             \"leverage\": 2.5, \"pre_init_health\": -10, \"init_health\": -4.25, \
+             \"reduce_only\": true, \"safe_mode\": false, \"min_mngo_to_trade\": 100 }}",
+            mango_account_pk
+        )
+    );
+
+    let instruction = set_post_trade_risk_log_enabled(
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        true,
+    )
+    .unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::SetPostTradeRiskLogEnabled { enabled: true })
+    );
+}
+
+#[tokio::test]
+async fn test_position_history_anchor() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 2;
+    mango_group.tokens[0].mint = Pubkey::new_unique();
+    mango_group.tokens[1].mint = Pubkey::new_unique();
+    mango_group.tokens[QUOTE_INDEX].mint = Pubkey::new_unique();
+    mango_group.perp_markets[0].perp_market = Pubkey::new_unique();
+    mango_group.perp_markets[1].perp_market = Pubkey::new_unique();
+
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.root_bank_cache[0].deposit_index = I80F48::from_num(1.5);
+    mango_cache.root_bank_cache[0].borrow_index = I80F48::from_num(1.75);
+    mango_cache.root_bank_cache[0].last_update = 100;
+    mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index = I80F48::from_num(1.25);
+    mango_cache.root_bank_cache[QUOTE_INDEX].borrow_index = I80F48::from_num(2);
+    mango_cache.root_bank_cache[QUOTE_INDEX].last_update = 101;
+    mango_cache.perp_market_cache[0].long_funding = I80F48::from_num(0.5);
+    mango_cache.perp_market_cache[0].short_funding = I80F48::from_num(-0.25);
+    mango_cache.perp_market_cache[0].last_update = 102;
+
+    // Token 1 and perp market 1 are untouched and left out
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.borrows[0] = I80F48::from_num(3);
+    mango_account.deposits[QUOTE_INDEX] = I80F48::from_num(40);
+    mango_account.perp_accounts[0].base_position = -2;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(20.5);
+    mango_account.perp_accounts[0].long_settled_funding = I80F48::from_num(0.375);
+    mango_account.perp_accounts[0].short_settled_funding = I80F48::from_num(-0.125);
+
+    let mango_account_pk = Pubkey::new_unique();
+    let details =
+        position_history_anchor(&mango_account_pk, &mango_group, &mango_cache, &mango_account, 7);
+    assert_eq!(
+        details,
+        format!(
+            "PositionHistoryAnchor details: {{ \"mango_account\": {}, \"slot\": 7, \
+             \"tokens\": [\
+             {{ \"token_index\": 0, \"deposits\": 0, \"borrows\": 3, \"deposit_index\": 1.5, \
+             \"borrow_index\": 1.75, \"last_update\": 100 }}, \
+             {{ \"token_index\": {}, \"deposits\": 40, \"borrows\": 0, \"deposit_index\": 1.25, \
+             \"borrow_index\": 2, \"last_update\": 101 }}], \
+             \"perps\": [\
+             {{ \"market_index\": 0, \"base_position\": -2, \"quote_position\": 20.5, \
+             \"long_settled_funding\": 0.375, \"short_settled_funding\": -0.125, \
+             \"long_funding\": 0.5, \"short_funding\": -0.25, \"last_update\": 102 }}] }}",
+            mango_account_pk, QUOTE_INDEX
+        )
+    );
+
+    let instruction = get_position_history_anchor(
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &mango_account_pk,
+        &Pubkey::new_unique(),
+    )
+    .unwrap();
+    assert!(instruction.accounts.iter().all(|meta| !meta.is_writable && !meta.is_signer));
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::GetPositionHistoryAnchor)
+    );
+}
+
+#[tokio::test]
+async fn test_close_spot_open_orders_builder() {
+    let keys: Vec<Pubkey> = (0..8).map(|_| Pubkey::new_unique()).collect();
+    let instruction = close_spot_open_orders(
+        &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], &keys[7],
+    )
+    .unwrap();
+
+    assert_eq!(instruction.program_id, keys[0]);
+    let layout: Vec<(Pubkey, bool, bool)> = instruction
+        .accounts
+        .iter()
+        .map(|meta| (meta.pubkey, meta.is_writable, meta.is_signer))
+        .collect();
+    assert_eq!(
+        layout,
+        vec![
+            (keys[1], false, false), // mango_group
+            (keys[2], true, false),  // mango_account
+            (keys[3], true, true),   // owner, receives the rent
+            (keys[4], false, false), // dex program
+            (keys[5], true, false),  // open_orders
+            (keys[6], false, false), // spot_market
+            (keys[7], false, false), // group signer
+        ]
+    );
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::CloseSpotOpenOrders)
+    );
+}
+
+#[tokio::test]
+async fn test_force_cancel_spot_orders_builder() {
+    let keys: Vec<Pubkey> = (0..19).map(|_| Pubkey::new_unique()).collect();
+    let open_orders_pks: Vec<Pubkey> = (0..MAX_PAIRS).map(|_| Pubkey::new_unique()).collect();
+    let market_index = 2;
+    let instruction = force_cancel_spot_orders(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &keys[8],
+        &keys[9],
+        &keys[10],
+        &keys[11],
+        &keys[12],
+        &keys[13],
+        &keys[14],
+        &keys[15],
+        &keys[16],
+        &keys[17],
+        &keys[18],
+        &open_orders_pks,
+        market_index,
+        5,
+    )
+    .unwrap();
+
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::ForceCancelSpotOrders { limit: 5 })
+    );
+    assert_eq!(instruction.accounts.len(), 19 + MAX_PAIRS);
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys[..18], keys[1..]);
+    assert_eq!(pubkeys[18], spl_token::ID);
+    assert_eq!(pubkeys[19..], open_orders_pks[..]);
+
+    let writable: Vec<bool> = instruction.accounts[..19].iter().map(|m| m.is_writable).collect();
+    assert_eq!(
+        writable,
+        [
+            false, false, true, false, true, true, false, true, true, true, true, true, false,
+            true, true, true, false, false, false
+        ]
+    );
+    for (i, meta) in instruction.accounts[19..].iter().enumerate() {
+        assert_eq!(meta.is_writable, i == market_index);
+    }
+    assert!(instruction.accounts.iter().all(|m| !m.is_signer));
+}
+
+#[tokio::test]
+async fn test_decode_mango_accounts() {
+    let owners: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+    let blobs: Vec<Vec<u8>> = owners
+        .iter()
+        .map(|owner| {
+            let mut mango_account = MangoAccount::zeroed();
+            mango_account.meta_data = MetaData::new(DataType::MangoAccount, 0, true);
+            mango_account.owner = *owner;
+            bytes_of(&mango_account).to_vec()
+        })
+        .collect();
+
+    // Shift the last blob by a byte so it can't be borrowed in place and has to be copied
+    let mut unaligned = vec![0u8];
+    unaligned.extend_from_slice(&blobs[2]);
+    let corrupt = &blobs[1][..size_of::<MangoAccount>() - 1];
+    let views = decode_mango_accounts(&[&blobs[0], corrupt, &unaligned[1..]]);
+
+    // The corrupt blob is skipped, the others decode in order
+    let indexes: Vec<usize> = views.iter().map(|view| view.index).collect();
+    assert_eq!(indexes, [0, 2]);
+    assert_eq!(views[0].mango_account.owner, owners[0]);
+    assert_eq!(views[1].mango_account.owner, owners[2]);
+    assert!(MangoAccount::load_from_bytes(corrupt).is_err());
+}
+
+#[tokio::test]
+async fn test_max_oracle_age() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.valid_interval = 10;
+    mango_group.tokens[1].max_oracle_age = 3;
+    assert_eq!(mango_group.max_oracle_age(0), 10);
+    assert_eq!(mango_group.max_oracle_age(1), 3);
+
+    let keys: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+    let instruction = set_max_oracle_age(&keys[0], &keys[1], &keys[2], &keys[3], 3).unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::SetMaxOracleAge { max_oracle_age: 3 })
+    );
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [true, false, false]);
+}
+
+#[tokio::test]
+async fn test_add_mango_account_info() {
+    let keys: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+
+    let mut info = [0u8; INFO_LEN];
+    info[..7].copy_from_slice(b"trading");
+    assert_eq!(info_from_str("trading"), info);
+
+    let instruction =
+        add_mango_account_info(&keys[0], &keys[1], &keys[2], &keys[3], info_from_str("trading"))
+            .unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::AddMangoAccountInfo { info })
+    );
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys, keys[1..]);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, true, false]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, true]);
+
+    // Over-length names are cut to INFO_LEN bytes, without splitting a character
+    let long_name = "a".repeat(INFO_LEN + 8);
+    assert_eq!(info_from_str(&long_name), [b'a'; INFO_LEN]);
+    let split_char = format!("{}é", "a".repeat(INFO_LEN - 1));
+    let info = info_from_str(&split_char);
+    assert_eq!(info[..INFO_LEN - 1], [b'a'; INFO_LEN - 1]);
+    assert_eq!(info[INFO_LEN - 1], 0);
+    assert_eq!(info_from_str(""), [0u8; INFO_LEN]);
+}
+
+#[tokio::test]
+async fn test_set_group_name() {
+    let keys: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+
+    let instruction = set_group_name(&keys[0], &keys[1], &keys[2], "Mango Main Group").unwrap();
+    let mut name = [0u8; GROUP_NAME_LEN];
+    name[..16].copy_from_slice(b"Mango Main Group");
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::SetGroupName { name })
+    );
+    assert_eq!(parse_group_name(&name).unwrap(), "Mango Main Group");
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, true, true, false]);
+    assert!(instruction.accounts[1].is_signer);
+    assert_eq!(instruction.accounts[2].pubkey, GroupName::find_address(&keys[0], &keys[1]).0);
+
+    // A name filling every byte needs no padding
+    let full = "é".repeat(GROUP_NAME_LEN / 2);
+    let instruction = set_group_name(&keys[0], &keys[1], &keys[2], &full).unwrap();
+    match MangoInstruction::unpack(&instruction.data) {
+        Some(MangoInstruction::SetGroupName { name }) => {
+            assert_eq!(parse_group_name(&name).unwrap(), full)
+        }
+        _ => panic!("expected SetGroupName"),
+    }
+
+    // Over-length names are rejected rather than cut
+    let result = set_group_name(&keys[0], &keys[1], &keys[2], &"a".repeat(GROUP_NAME_LEN + 1));
+    assert_eq!(result, Err(ProgramError::Custom(MangoErrorCode::InvalidParam.into())));
+
+    // On chain, the name must be UTF-8 followed only by zeros
+    let mut gap = [0u8; GROUP_NAME_LEN];
+    gap[0] = b'a';
+    gap[2] = b'b';
+    for name in &[[0xffu8; GROUP_NAME_LEN], gap] {
+        assert!(matches!(
+            parse_group_name(name),
+            Err(MangoError::MangoErrorCode { mango_error_code: MangoErrorCode::InvalidParam, .. })
+        ));
+    }
+    assert_eq!(parse_group_name(&[0u8; GROUP_NAME_LEN]).unwrap(), "");
+}
+
+#[tokio::test]
+async fn test_transfer_msrm() {
+    let keys: Vec<Pubkey> = (0..5).map(|_| Pubkey::new_unique()).collect();
+
+    let instruction = transfer_msrm(&keys[0], &keys[1], &keys[2], &keys[3], &keys[4], 7).unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::TransferMsrm { quantity: 7 })
+    );
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys, keys[1..]);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, true, true, false]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, false, true]);
+}
+
+#[tokio::test]
+async fn test_settle_borrow() {
+    let keys: Vec<Pubkey> = (0..7).map(|_| Pubkey::new_unique()).collect();
+    let instruction = settle_borrow(
+        &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], 2, 1_500,
+    )
+    .unwrap();
+
+    let mut data = 23u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&2u64.to_le_bytes());
+    data.extend_from_slice(&1_500u64.to_le_bytes());
+    assert_eq!(instruction.data, data);
+    let instr = MangoInstruction::unpack(&instruction.data).unwrap();
+    assert_eq!(instr, MangoInstruction::SettleBorrow { token_index: 2, quantity: 1_500 });
+    assert_eq!(instr.pack(), data);
+
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys, keys[1..]);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, false, true, false, false, true]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, false, true, false, false]);
+}
+
+#[tokio::test]
+async fn test_withdraw_builder() {
+    let keys: Vec<Pubkey> = (0..11).map(|_| Pubkey::new_unique()).collect();
+    let open_orders_pks: Vec<Pubkey> = (0..MAX_PAIRS).map(|_| Pubkey::new_unique()).collect();
+
+    let instruction = withdraw(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &keys[8],
+        &keys[9],
+        &open_orders_pks,
+        &keys[10],
+        1_000,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::Withdraw { quantity: 1_000, allow_borrow: false })
+    );
+    assert_eq!(instruction.accounts.len(), 10 + MAX_PAIRS + 2);
+
+    // The insurance vault, which receives any withdraw fee, and the WithdrawCaps of the account
+    // follow the open orders
+    let insurance_vault = &instruction.accounts[10 + MAX_PAIRS];
+    assert_eq!(insurance_vault.pubkey, keys[10]);
+    assert!(insurance_vault.is_writable);
+    let withdraw_caps = &instruction.accounts[10 + MAX_PAIRS + 1];
+    assert_eq!(withdraw_caps.pubkey, WithdrawCaps::find_address(&keys[0], &keys[2]).0);
+    assert!(!withdraw_caps.is_writable);
+}
+
+#[tokio::test]
+async fn test_force_settle_quote_positions() {
+    let keys: Vec<Pubkey> = (0..8).map(|_| Pubkey::new_unique()).collect();
+    let liqee_open_orders_pks: Vec<Pubkey> = (0..MAX_PAIRS).map(|_| Pubkey::new_unique()).collect();
+
+    let instruction = force_settle_quote_positions(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &liqee_open_orders_pks,
+    )
+    .unwrap();
+    assert_eq!(instruction.data, 40u32.to_le_bytes().to_vec());
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::ForceSettleQuotePositions)
+    );
+
+    // 7 + MAX_PAIRS accounts, with both mango accounts and the node bank writable
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys.len(), 7 + MAX_PAIRS);
+    assert_eq!(pubkeys[..7], keys[1..]);
+    assert_eq!(pubkeys[7..], liqee_open_orders_pks[..]);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable[..7], [false, false, true, true, false, false, true]);
+    assert!(writable[7..].iter().all(|w| !w));
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers.iter().filter(|&&s| s).count(), 1);
+    assert!(signers[4]);
+}
+
+#[tokio::test]
+async fn test_liquidation_builders() {
+    let keys: Vec<Pubkey> = (0..8).map(|_| Pubkey::new_unique()).collect();
+    let liqee_open_orders_pks: Vec<Pubkey> = (0..MAX_PAIRS).map(|_| Pubkey::new_unique()).collect();
+    let liqor_open_orders_pks: Vec<Pubkey> = (0..MAX_PAIRS).map(|_| Pubkey::new_unique()).collect();
+    let open_orders_pks: Vec<Pubkey> =
+        liqee_open_orders_pks.iter().chain(liqor_open_orders_pks.iter()).cloned().collect();
+
+    let instruction = liquidate_token_and_perp(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &liqee_open_orders_pks,
+        &liqor_open_orders_pks,
+        AssetType::Token,
+        QUOTE_INDEX,
+        AssetType::Perp,
+        1,
+        I80F48::from_num(100.5),
+    )
+    .unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::LiquidateTokenAndPerp {
+            asset_type: AssetType::Token,
+            asset_index: QUOTE_INDEX,
+            liab_type: AssetType::Perp,
+            liab_index: 1,
+            max_liab_transfer: I80F48::from_num(100.5),
+        })
+    );
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys[..7], keys[1..]);
+    assert_eq!(pubkeys[7..], open_orders_pks[..]);
+    let writable: Vec<bool> = instruction.accounts[..7].iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, false, true, true, false, false, true]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers.iter().filter(|&&s| s).count(), 1);
+    assert!(signers[4]);
+
+    let instruction = liquidate_perp_market(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        &keys[7],
+        &liqee_open_orders_pks,
+        &liqor_open_orders_pks,
+        -3,
+    )
+    .unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::LiquidatePerpMarket { base_transfer_request: -3 })
+    );
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys[..7], keys[1..]);
+    assert_eq!(pubkeys[7..], open_orders_pks[..]);
+    let writable: Vec<bool> = instruction.accounts[..7].iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, false, true, true, true, true, false]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers.iter().filter(|&&s| s).count(), 1);
+    assert!(signers[6]);
+}
+
+#[tokio::test]
+async fn test_cancel_perp_orders_side() {
+    let keys: Vec<Pubkey> = (0..7).map(|_| Pubkey::new_unique()).collect();
+
+    for &side in [Side::Bid, Side::Ask].iter() {
+        for &limit in [0u8, 1, u8::MAX].iter() {
+            let instr = MangoInstruction::CancelPerpOrdersSide { side, limit };
+            let data = instr.pack();
+            assert_eq!(data.len(), 6);
+            assert_eq!(data[4], side as u8);
+            assert_eq!(MangoInstruction::unpack(&data), Some(instr));
+        }
+    }
+
+    // Only Bid and Ask are valid sides
+    let mut data = MangoInstruction::CancelPerpOrdersSide { side: Side::Ask, limit: 0 }.pack();
+    data[4] = 2;
+    assert_eq!(MangoInstruction::unpack_checked(&data), Err(ProgramError::InvalidInstructionData));
+
+    // Same accounts as cancel_all_perp_orders
+    let instruction = cancel_perp_orders_side(
+        &keys[0],
+        &keys[1],
+        &keys[2],
+        &keys[3],
+        &keys[4],
+        &keys[5],
+        &keys[6],
+        Side::Bid,
+        0,
+    )
+    .unwrap();
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::CancelPerpOrdersSide { side: Side::Bid, limit: 0 })
+    );
+    let pubkeys: Vec<Pubkey> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+    assert_eq!(pubkeys, keys[1..]);
+    let writable: Vec<bool> = instruction.accounts.iter().map(|m| m.is_writable).collect();
+    assert_eq!(writable, [false, true, false, true, true, true]);
+    let signers: Vec<bool> = instruction.accounts.iter().map(|m| m.is_signer).collect();
+    assert_eq!(signers, [false, false, true, false, false, false]);
+}
+
+#[tokio::test]
+async fn test_unpack_checked() {
+    let too_short = ProgramError::Custom(MangoErrorCode::InstructionDataTooShort.into());
+
+    let data = MangoInstruction::Deposit { quantity: 5 }.pack();
+    assert_eq!(
+        MangoInstruction::unpack_checked(&data),
+        Ok(MangoInstruction::Deposit { quantity: 5 })
+    );
+
+    // Missing discriminant or payload bytes
+    assert_eq!(MangoInstruction::unpack_checked(&[]), Err(too_short.clone()));
+    assert_eq!(MangoInstruction::unpack_checked(&data[..3]), Err(too_short.clone()));
+    assert_eq!(MangoInstruction::unpack_checked(&data[..data.len() - 1]), Err(too_short));
+    assert_eq!(MangoInstruction::unpack(&data[..data.len() - 1]), None);
+
+    // Unknown discriminant
+    assert_eq!(
+        MangoInstruction::unpack_checked(&u32::MAX.to_le_bytes()),
+        Err(ProgramError::InvalidInstructionData)
+    );
+
+    // Out of range enum value
+    let mut data = MangoInstruction::PlacePerpPeggedOrder {
+        offset_ticks: -1,
+        peg_limit: 100,
+        quantity: 1,
+        client_order_id: 0,
+        side: Side::Bid,
+        order_type: OrderType::Limit,
+    }
+    .pack();
+    assert!(MangoInstruction::unpack_checked(&data).is_ok());
+    data[4 + 32] = 7;
+    assert_eq!(MangoInstruction::unpack_checked(&data), Err(ProgramError::InvalidInstructionData));
+}
+
+/// One instance of every MangoInstruction variant, with field values derived from `seed`
+fn sample_instructions(seed: u64) -> Vec<MangoInstruction> {
+    let n = seed;
+    let i = -(seed as i64);
+    let fixed = I80F48::from_num(seed) / 3;
+    let opt_fixed = if seed % 2 == 0 { Some(-fixed) } else { None };
+    let opt_u64 = if seed % 2 == 0 { None } else { Some(n) };
+    let flag = seed % 2 == 0;
+    let side = if flag { Side::Bid } else { Side::Ask };
+    let order_type = [
+        OrderType::Limit,
+        OrderType::ImmediateOrCancel,
+        OrderType::PostOnly,
+        OrderType::PostOnlySlide,
+    ][(seed % 4) as usize];
+    let asset_type = if flag { AssetType::Token } else { AssetType::Perp };
+    let dex_side =
+        if flag { serum_dex::matching::Side::Bid } else { serum_dex::matching::Side::Ask };
+    let non_zero = NonZeroU64::new(n.max(1)).unwrap();
+    let cancel_order =
+        serum_dex::instruction::CancelOrderInstructionV2 { side: dex_side, order_id: n as u128 };
+
+    vec![
+        MangoInstruction::InitMangoGroup {
+            signer_nonce: n,
+            valid_interval: n / 2,
+            quote_optimal_util: fixed,
+            quote_optimal_rate: -fixed,
+            quote_max_rate: fixed * 2,
+        },
+        MangoInstruction::InitMangoAccount,
+        MangoInstruction::Deposit { quantity: n },
+        MangoInstruction::Withdraw { quantity: n, allow_borrow: flag },
+        MangoInstruction::AddSpotMarket {
+            maint_leverage: fixed,
+            init_leverage: fixed / 2,
+            liquidation_fee: -fixed,
+            optimal_util: fixed * 2,
+            optimal_rate: fixed / 4,
+            max_rate: fixed,
+        },
+        MangoInstruction::AddToBasket { market_index: n as usize },
+        MangoInstruction::Borrow { quantity: n },
+        MangoInstruction::CachePrices,
+        MangoInstruction::CacheRootBanks,
+        MangoInstruction::PlaceSpotOrder {
+            order: serum_dex::instruction::NewOrderInstructionV3 {
+                side: dex_side,
+                limit_price: non_zero,
+                max_coin_qty: non_zero,
+                max_native_pc_qty_including_fees: non_zero,
+                self_trade_behavior: serum_dex::instruction::SelfTradeBehavior::AbortTransaction,
+                order_type: serum_dex::matching::OrderType::PostOnly,
+                client_order_id: n,
+                limit: n as u16,
+            },
+        },
+        MangoInstruction::AddOracle,
+        MangoInstruction::AddPerpMarket {
+            maint_leverage: fixed,
+            init_leverage: fixed / 2,
+            liquidation_fee: -fixed,
+            maker_fee: -fixed / 4,
+            taker_fee: fixed / 4,
+            base_lot_size: i,
+            quote_lot_size: -i,
+            rate: fixed * 2,
+            max_depth_bps: fixed,
+            target_period_length: n,
+            mngo_per_period: n / 3,
+        },
+        MangoInstruction::PlacePerpOrder {
+            price: i,
+            quantity: -i,
+            client_order_id: n,
+            side,
+            order_type,
+        },
+        MangoInstruction::CancelPerpOrderByClientId { client_order_id: n, invalid_id_ok: flag },
+        MangoInstruction::CancelPerpOrder {
+            order_id: ((n as i128) << 64) | n as i128,
+            invalid_id_ok: !flag,
+        },
+        MangoInstruction::ConsumeEvents { limit: n as usize },
+        MangoInstruction::CachePerpMarkets,
+        MangoInstruction::UpdateFunding,
+        MangoInstruction::SetOracle { price: fixed },
+        MangoInstruction::SettleFunds,
+        MangoInstruction::CancelSpotOrder { order: cancel_order.clone() },
+        MangoInstruction::UpdateRootBank,
+        MangoInstruction::SettlePnl { market_index: n as usize },
+        MangoInstruction::SettleBorrow { token_index: n as usize, quantity: n },
+        MangoInstruction::ForceCancelSpotOrders { limit: n as u8 },
+        MangoInstruction::ForceCancelPerpOrders { limit: n as u8 },
+        MangoInstruction::LiquidateTokenAndToken { max_liab_transfer: fixed },
+        MangoInstruction::LiquidateTokenAndPerp {
+            asset_type,
+            asset_index: n as usize,
+            liab_type: asset_type,
+            liab_index: n as usize / 2,
+            max_liab_transfer: fixed,
+        },
+        MangoInstruction::LiquidatePerpMarket { base_transfer_request: i },
+        MangoInstruction::SettleFees,
+        MangoInstruction::ResolvePerpBankruptcy {
+            liab_index: n as usize,
+            max_liab_transfer: fixed,
+        },
+        MangoInstruction::ResolveTokenBankruptcy { max_liab_transfer: fixed },
+        MangoInstruction::InitSpotOpenOrders,
+        MangoInstruction::RedeemMngo,
+        MangoInstruction::AddMangoAccountInfo { info: [n as u8; INFO_LEN] },
+        MangoInstruction::DepositMsrm { quantity: n },
+        MangoInstruction::WithdrawMsrm { quantity: n },
+        MangoInstruction::ChangePerpMarketParams {
+            maint_leverage: opt_fixed,
+            init_leverage: Some(fixed),
+            liquidation_fee: opt_fixed,
+            maker_fee: None,
+            taker_fee: opt_fixed,
+            rate: Some(-fixed),
+            max_depth_bps: opt_fixed,
+            target_period_length: opt_u64,
+            mngo_per_period: Some(n),
+        },
+        MangoInstruction::SetGroupAdmin,
+        MangoInstruction::CancelAllPerpOrders { limit: n as u8 },
+        MangoInstruction::ForceSettleQuotePositions,
+        MangoInstruction::TransferCollateral { token_index: n as usize, quantity: n },
+        MangoInstruction::PlacePerpOrder2 {
+            price: i,
+            quantity: -i,
+            client_order_id: n,
+            side,
+            order_type,
+            safe_mode: flag,
+            tag: n.to_le_bytes(),
+            match_limit: n as u8,
+        },
+        MangoInstruction::ExecuteRebalanceLeg {
+            legs: (0..(seed % 5) as usize)
+                .map(|k| PerpRebalanceLeg { market_index: k, side, price: i, quantity: k as i64 })
+                .collect(),
+        },
+        MangoInstruction::SetLiquidationTargetHealth { liquidation_target_health: fixed },
+        MangoInstruction::SetPerpMarketFees { maker_fee: -fixed, taker_fee: fixed },
+        MangoInstruction::GetAccountAuthority,
+        MangoInstruction::AddToDepositWhitelist { owner: Pubkey::new_unique() },
+        MangoInstruction::RemoveFromDepositWhitelist { owner: Pubkey::new_unique() },
+        MangoInstruction::SetDepositWhitelistEnabled { enabled: flag },
+        MangoInstruction::GetInsuranceCoverage,
+        MangoInstruction::GetRealizedPnl,
+        MangoInstruction::SetMaxWithdrawPerTx { token_index: n as usize, max_withdraw: n },
+        MangoInstruction::SetMinMngoToTrade { min_mngo_to_trade: n },
+        MangoInstruction::RebalanceNodeBanks { quantity: n },
+        MangoInstruction::PreviewWithdraw { quantity: n, allow_borrow: !flag },
+        MangoInstruction::SetWithdrawFee { withdraw_fee_bps: n as u16 },
+        MangoInstruction::CancelPerpOrdersOlderThan { cutoff_ts: n, limit: n as u8 },
+        MangoInstruction::SetPerpImpactDepth { quote_notional: i },
+        MangoInstruction::UpdateRootBanks,
+        MangoInstruction::HasUnsettledPnl,
+        MangoInstruction::CancelSpotOrderAndSettle { order: cancel_order },
+        MangoInstruction::PlacePerpPeggedOrder {
+            offset_ticks: i,
+            peg_limit: -i,
+            quantity: n as i64 / 2,
+            client_order_id: n,
+            side,
+            order_type,
+        },
+        MangoInstruction::SetPostTradeRiskLogEnabled { enabled: flag },
+        MangoInstruction::SetMaxOracleAge { max_oracle_age: n as u32 },
+        MangoInstruction::UpdateAllFunding,
+        MangoInstruction::CloseMangoAccount,
+        MangoInstruction::GetPositionHistoryAnchor,
+        MangoInstruction::CloseSpotOpenOrders,
+        MangoInstruction::SetMaxIndexGrowth { max_index_growth: fixed / 100 },
+        MangoInstruction::SetDelegate,
+        MangoInstruction::GetPerpSpread,
+        MangoInstruction::PlacePerpOrder3 {
+            price: i,
+            quantity: -i,
+            client_order_id: n,
+            side,
+            order_type,
+            safe_mode: flag,
+            tag: n.to_be_bytes(),
+            expiry_timestamp: n,
+        },
+        MangoInstruction::CheckGroupInvariants,
+        MangoInstruction::SetPerpMngoVault,
+        MangoInstruction::DepositBatch { quantities: vec![n, n / 2] },
+        MangoInstruction::GetMiningRate { market_index: 2 },
+        MangoInstruction::ChangeMangoAccountOwner,
+        MangoInstruction::UpgradeMangoAccount,
+        MangoInstruction::SetReferrerMemory { referrer_id: [n as u8; INFO_LEN] },
+        MangoInstruction::CancelSpotOrderByClientId { client_order_id: n },
+        MangoInstruction::InitLossRecovery,
+        MangoInstruction::ClaimLossRecovery,
+        MangoInstruction::SetGroupName { name: [n as u8; GROUP_NAME_LEN] },
+        MangoInstruction::GetOpenInterest,
+        MangoInstruction::TransferMsrm { quantity: n },
+        MangoInstruction::GetInterestRates,
+        MangoInstruction::CancelPerpOrdersSide { side, limit: n as u8 },
+        MangoInstruction::InitPnlTracker,
+        MangoInstruction::RegisterLossShare,
+    ]
+}
+
+#[tokio::test]
+async fn test_pack_unpack_round_trip() {
+    for &seed in [1, 2, 7, 1_000_003, u32::MAX as u64, i64::MAX as u64].iter() {
+        let instructions = sample_instructions(seed);
+
+        // Every discriminant unpack knows about is covered
+        let discriminants: Vec<u32> = instructions
+            .iter()
+            .map(|instr| u32::from_le_bytes(instr.pack()[..4].try_into().unwrap()))
+            .collect();
+        assert_eq!(discriminants, (0..=89).collect::<Vec<u32>>());
+
+        for instr in instructions {
+            let data = instr.pack();
+            assert_eq!(MangoInstruction::unpack_checked(&data), Ok(instr.clone()));
+            assert_eq!(MangoInstruction::unpack(&data), Some(instr));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_packed_len_and_try_from() {
+    for &seed in [1, 2, 7, 1_000_003, u32::MAX as u64, i64::MAX as u64].iter() {
+        let instructions = sample_instructions(seed);
+        for instr in instructions.iter() {
+            assert_eq!(instr.packed_len(), instr.pack().len(), "{:?}", instr);
+            let data = instr.pack();
+            assert_eq!(MangoInstruction::try_from(&data[..]), Ok(instr.clone()));
+        }
+
+        // Walk a buffer of back to back instructions using packed_len
+        let buffer: Vec<u8> = instructions.iter().flat_map(|instr| instr.pack()).collect();
+        let mut offset = 0;
+        for instr in instructions.iter() {
+            let decoded = MangoInstruction::try_from(&buffer[offset..]).unwrap();
+            assert_eq!(&decoded, instr);
+            offset += decoded.packed_len();
+        }
+        assert_eq!(offset, buffer.len());
+    }
+
+    assert_eq!(
+        MangoInstruction::try_from(&[2u8, 0, 0][..]),
+        Err(ProgramError::Custom(MangoErrorCode::InstructionDataTooShort.into()))
+    );
+}
+
+#[tokio::test]
+async fn test_deposit_batch_encoding() {
+    let program_id = Pubkey::new_unique();
+    let mango_group_pk = Pubkey::new_unique();
+    let mango_account_pk = Pubkey::new_unique();
+    let owner_pk = Pubkey::new_unique();
+    let mango_cache_pk = Pubkey::new_unique();
+    let entry = |quantity| {
+        (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            quantity,
+        )
+    };
+    let build = |entries: &[(Pubkey, Pubkey, Pubkey, Pubkey, u64)]| {
+        deposit_batch(
+            &program_id,
+            &mango_group_pk,
+            &mango_account_pk,
+            &owner_pk,
+            &mango_cache_pk,
+            entries,
+        )
+        .unwrap()
+    };
+
+    // Two tokens: each entry's four accounts follow the fixed ones in order
+    let entries = [entry(100), entry(2_000)];
+    let instruction = build(&entries);
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::DepositBatch { quantities: vec![100, 2_000] })
+    );
+    assert_eq!(instruction.accounts.len(), 5 + 2 * 4 + 1);
+    for (i, (root_bank_pk, node_bank_pk, vault_pk, owner_token_account_pk, _)) in
+        entries.iter().enumerate()
+    {
+        let metas = &instruction.accounts[5 + 4 * i..9 + 4 * i];
+        assert_eq!(metas[0].pubkey, *root_bank_pk);
+        assert!(!metas[0].is_writable);
+        assert_eq!(metas[1].pubkey, *node_bank_pk);
+        assert!(metas[1].is_writable);
+        assert_eq!(metas[2].pubkey, *vault_pk);
+        assert!(metas[2].is_writable);
+        assert_eq!(metas[3].pubkey, *owner_token_account_pk);
+        assert!(metas[3].is_writable);
+    }
+
+    // The group's DepositWhitelist comes last
+    let deposit_whitelist = instruction.accounts.last().unwrap();
+    assert_eq!(
+        deposit_whitelist.pubkey,
+        DepositWhitelist::find_address(&program_id, &mango_group_pk).0
+    );
+    assert!(!deposit_whitelist.is_writable);
+
+    // No entries: just the fixed accounts, the DepositWhitelist and a zero count
+    let instruction = build(&[]);
+    assert_eq!(instruction.accounts.len(), 5 + 1);
+    assert_eq!(instruction.data.len(), 4 + 8);
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::DepositBatch { quantities: vec![] })
+    );
+
+    // Quantities are checked against the declared count
+    let mut data = 75u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&2u64.to_le_bytes());
+    data.extend_from_slice(&100u64.to_le_bytes());
+    assert_eq!(
+        MangoInstruction::unpack_checked(&data),
+        Err(ProgramError::Custom(MangoErrorCode::InstructionDataTooShort.into()))
+    );
+}
+
+#[tokio::test]
+async fn test_change_mango_account_owner_encoding() {
+    let program_id = Pubkey::new_unique();
+    let mango_group_pk = Pubkey::new_unique();
+    let mango_account_pk = Pubkey::new_unique();
+    let owner_pk = Pubkey::new_unique();
+    let new_owner_pk = Pubkey::new_unique();
+    let instruction = change_mango_account_owner(
+        &program_id,
+        &mango_group_pk,
+        &mango_account_pk,
+        &owner_pk,
+        &new_owner_pk,
+    )
+    .unwrap();
+
+    assert_eq!(instruction.data, 77u32.to_le_bytes().to_vec());
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::ChangeMangoAccountOwner)
+    );
+
+    // Only the MangoAccount is written and only the current owner signs
+    let metas = &instruction.accounts;
+    assert_eq!(metas.len(), 4);
+    assert_eq!(metas[0].pubkey, mango_group_pk);
+    assert!(!metas[0].is_writable && !metas[0].is_signer);
+    assert_eq!(metas[1].pubkey, mango_account_pk);
+    assert!(metas[1].is_writable && !metas[1].is_signer);
+    assert_eq!(metas[2].pubkey, owner_pk);
+    assert!(!metas[2].is_writable && metas[2].is_signer);
+    assert_eq!(metas[3].pubkey, new_owner_pk);
+    assert!(!metas[3].is_writable && !metas[3].is_signer);
+}
+
+#[tokio::test]
+async fn test_mango_account_upgrade() {
+    let (_, _, mut mango_account) = liquidation_fixture();
+    mango_account.meta_data = MetaData::new(DataType::MangoAccount, 0, true);
+    mango_account.deposits[QUOTE_INDEX] = I80F48::from_num(1_000);
+    mango_account.delegate = Pubkey::new_unique();
+
+    // A version 0 account gets the delegate zeroed and keeps its balances
+    assert!(mango_account.upgrade());
+    assert_eq!(mango_account.meta_data.version, MANGO_ACCOUNT_VERSION);
+    assert_eq!(mango_account.delegate, Pubkey::default());
+    assert_eq!(mango_account.deposits[QUOTE_INDEX], I80F48::from_num(1_000));
+
+    // A version 1 account keeps its delegate and gets the referrer zeroed
+    let delegate_pk = Pubkey::new_unique();
+    mango_account.meta_data.version = 1;
+    mango_account.delegate = delegate_pk;
+    mango_account.referrer = Pubkey::new_unique();
+    assert!(mango_account.upgrade());
+    assert_eq!(mango_account.meta_data.version, MANGO_ACCOUNT_VERSION);
+    assert_eq!(mango_account.delegate, delegate_pk);
+    assert_eq!(mango_account.referrer, Pubkey::default());
+
+    // A version 2 account keeps its referrer and gets has_pnl_tracker cleared
+    let referrer_pk = Pubkey::new_unique();
+    mango_account.meta_data.version = 2;
+    mango_account.referrer = referrer_pk;
+    mango_account.has_pnl_tracker = true;
+    assert!(mango_account.upgrade());
+    assert_eq!(mango_account.referrer, referrer_pk);
+    assert!(!mango_account.has_pnl_tracker);
+
+    // A version 3 account keeps its tracker and gets has_withdraw_caps cleared
+    mango_account.meta_data.version = 3;
+    mango_account.has_pnl_tracker = true;
+    mango_account.has_withdraw_caps = true;
+    assert!(mango_account.upgrade());
+    assert!(mango_account.has_pnl_tracker);
+    assert!(!mango_account.has_withdraw_caps);
+
+    // A current account is left alone
+    mango_account.has_withdraw_caps = true;
+    assert!(!mango_account.upgrade());
+    assert_eq!(mango_account.meta_data.version, MANGO_ACCOUNT_VERSION);
+    assert_eq!(mango_account.delegate, delegate_pk);
+    assert_eq!(mango_account.referrer, referrer_pk);
+    assert!(mango_account.has_pnl_tracker);
+    assert!(mango_account.has_withdraw_caps);
+}
+
+#[tokio::test]
+async fn test_set_referrer_encoding() {
+    let program_id = Pubkey::new_unique();
+    let mango_group_pk = Pubkey::new_unique();
+    let mango_account_pk = Pubkey::new_unique();
+    let referrer_pk = Pubkey::new_unique();
+    let owner_pk = Pubkey::new_unique();
+    let instruction =
+        set_referrer(&program_id, &mango_group_pk, &mango_account_pk, &referrer_pk, &owner_pk)
+            .unwrap();
+
+    // The referrer's key is the payload
+    assert_eq!(instruction.data[..4], 79u32.to_le_bytes());
+    assert_eq!(instruction.data[4..], referrer_pk.to_bytes());
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::SetReferrerMemory { referrer_id: referrer_pk.to_bytes() })
+    );
+
+    // Only the MangoAccount is written and only the owner signs
+    let metas = &instruction.accounts;
+    assert_eq!(metas.len(), 4);
+    assert_eq!(metas[0].pubkey, mango_group_pk);
+    assert_eq!(metas[1].pubkey, mango_account_pk);
+    assert!(metas[1].is_writable);
+    assert_eq!(metas[2].pubkey, owner_pk);
+    assert!(metas[2].is_signer);
+    assert_eq!(metas[3].pubkey, referrer_pk);
+    assert!(!metas[3].is_writable && !metas[3].is_signer);
+}
+
+#[tokio::test]
+async fn test_cancel_spot_order_by_client_id() {
+    let pks: Vec<Pubkey> = (0..11).map(|_| Pubkey::new_unique()).collect();
+    let instruction = cancel_spot_order_by_client_id(
+        &pks[0], &pks[1], &pks[2], &pks[3], &pks[4], &pks[5], &pks[6], &pks[7], &pks[8], &pks[9],
+        &pks[10], 4_242,
+    )
+    .unwrap();
+
+    let mut data = 80u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&4_242u64.to_le_bytes());
+    assert_eq!(instruction.data, data);
+    assert_eq!(
+        MangoInstruction::unpack(&instruction.data),
+        Some(MangoInstruction::CancelSpotOrderByClientId { client_order_id: 4_242 })
+    );
+    assert_eq!(
+        MangoInstruction::unpack_checked(&data[..4 + 7]),
+        Err(ProgramError::Custom(MangoErrorCode::InstructionDataTooShort.into()))
+    );
+
+    // Same accounts as CancelSpotOrder; only the owner signs
+    let metas = &instruction.accounts;
+    assert_eq!(metas.iter().map(|m| m.pubkey).collect::<Vec<_>>(), pks[1..].to_vec());
+    assert_eq!(metas.iter().position(|m| m.is_signer), Some(1));
+    let writable: Vec<usize> =
+        metas.iter().enumerate().filter(|(_, m)| m.is_writable).map(|(i, _)| i).collect();
+    assert_eq!(writable, vec![4, 5, 6, 7, 9]);
+}
+
+#[tokio::test]
+async fn test_discriminant() {
+    let instructions = sample_instructions(7);
+    assert_eq!(
+        instructions.iter().map(|instr| instr.discriminant()).collect::<Vec<u32>>(),
+        (0..=89).collect::<Vec<u32>>()
+    );
+
+    // unpack decodes the payload of each instruction under its discriminant
+    for instr in instructions {
+        let mut data = instr.discriminant().to_le_bytes().to_vec();
+        data.extend_from_slice(&instr.pack()[4..]);
+        assert_eq!(MangoInstruction::unpack(&data), Some(instr));
+    }
+}
+
+#[tokio::test]
+async fn test_unpack_truncated_payloads() {
+    let too_short = ProgramError::Custom(MangoErrorCode::InstructionDataTooShort.into());
+
+    // (discriminant, fixed payload length) of every instruction that carries a payload
+    let payload_lens: &[(u32, usize)] = &[
+        (0, 64),
+        (2, 8),
+        (3, 9),
+        (4, 96),
+        (5, 8),
+        (6, 8),
+        (9, 46),
+        (11, 144),
+        (12, 26),
+        (13, 9),
+        (14, 17),
+        (15, 8),
+        (18, 16),
+        (20, 20),
+        (22, 8),
+        (23, 16),
+        (24, 1),
+        (25, 1),
+        (26, 16),
+        (27, 34),
+        (28, 8),
+        (30, 24),
+        (31, 16),
+        (34, INFO_LEN),
+        (35, 8),
+        (36, 8),
+        (37, 137),
+        (39, 1),
+        (41, 16),
+        (42, 27),
+        (43, 8),
+        (44, 16),
+        (45, 32),
+        (47, 32),
+        (48, 32),
+        (49, 1),
+        (52, 16),
+        (53, 8),
+        (54, 8),
+        (55, 9),
+        (56, 2),
+        (57, 9),
+        (58, 8),
+        (61, 20),
+        (62, 34),
+        (63, 1),
+        (64, 4),
+        (69, 16),
+        (72, 43),
+        (75, 8),
+        (76, 8),
+        (79, 32),
+        (80, 8),
+        (81, 16),
+        (83, 32),
+        (85, 8),
+        (87, 2),
+    ];
+
+    for &(discrim, len) in payload_lens {
+        for payload_len in [0, len - 1].iter() {
+            let mut data = discrim.to_le_bytes().to_vec();
+            data.resize(4 + payload_len, 0);
+            assert_eq!(
+                MangoInstruction::unpack_checked(&data),
+                Err(too_short.clone()),
+                "discriminant {} with {} payload bytes",
+                discrim,
+                payload_len
+            );
+            assert_eq!(MangoInstruction::unpack(&data), None);
+        }
+    }
+
+    // Instructions without a payload decode from the discriminant alone
+    for discrim in (0..=88u32).filter(|d| payload_lens.iter().all(|(p, _)| p != d)) {
+        assert!(MangoInstruction::unpack(&discrim.to_le_bytes()).is_some());
+    }
+
+    // Rebalance legs are checked against the declared leg count
+    let mut data = 43u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&1u64.to_le_bytes());
+    data.resize(data.len() + 24, 0);
+    assert_eq!(MangoInstruction::unpack_checked(&data), Err(too_short));
+}
+
+#[tokio::test]
+async fn test_place_perp_order2_tag() {
+    let tagged = MangoInstruction::PlacePerpOrder2 {
+        price: 100,
+        quantity: 5,
+        client_order_id: 7,
+        side: Side::Bid,
+        order_type: OrderType::Limit,
+        safe_mode: false,
+        tag: *b"strat001",
+        match_limit: DEFAULT_MATCH_LIMIT,
+    };
+    let data = tagged.pack();
+    assert_eq!(MangoInstruction::unpack(&data), Some(tagged));
+
+    // Data packed before the tag existed still decodes, with a zero tag
+    match MangoInstruction::unpack(&data[..4 + 27]) {
+        Some(MangoInstruction::PlacePerpOrder2 { tag, price, .. }) => {
+            assert_eq!(tag, [0; 8]);
+            assert_eq!(price, 100);
+        }
+        other => panic!("unexpected decode {:?}", other),
+    }
+
+    // The maker's tag is logged as hex next to the fill's seq_num
+    let mut fill = FillEvent::zeroed();
+    fill.seq_num = 12;
+    let details = maker_tag_details(&fill, b"strat001");
+    assert!(details.contains("\"seq_num\": 12"));
+    assert!(details.contains("\"maker_tag\": \"7374726174303031\""));
+}
+
+#[tokio::test]
+async fn test_place_perp_order2_match_limit() {
+    let order = |match_limit| MangoInstruction::PlacePerpOrder2 {
+        price: 100,
+        quantity: 5,
+        client_order_id: 7,
+        side: Side::Ask,
+        order_type: OrderType::ImmediateOrCancel,
+        safe_mode: false,
+        tag: *b"strat001",
+        match_limit,
+    };
+
+    // The limit is the last byte of the payload
+    for match_limit in [0, 3, u8::MAX].iter() {
+        let data = order(*match_limit).pack();
+        assert_eq!(data.len(), 4 + 36);
+        assert_eq!(data[4 + 35], *match_limit);
+        assert_eq!(MangoInstruction::unpack(&data), Some(order(*match_limit)));
+    }
+
+    // Data packed before the limit existed gets the default
+    let data = order(3).pack();
+    assert_eq!(MangoInstruction::unpack(&data[..4 + 35]), Some(order(DEFAULT_MATCH_LIMIT)));
+    match MangoInstruction::unpack(&data[..4 + 27]) {
+        Some(MangoInstruction::PlacePerpOrder2 { tag, match_limit, .. }) => {
+            assert_eq!(tag, [0; 8]);
+            assert_eq!(match_limit, DEFAULT_MATCH_LIMIT);
+        }
+        other => panic!("unexpected decode {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_unpack_invalid_asset_type() {
+    let data = MangoInstruction::LiquidateTokenAndPerp {
+        asset_type: AssetType::Token,
+        asset_index: QUOTE_INDEX,
+        liab_type: AssetType::Perp,
+        liab_index: 0,
+        max_liab_transfer: I80F48::from_num(10),
+    }
+    .pack();
+    assert!(MangoInstruction::unpack(&data).is_some());
+
+    // asset_type is the first byte after the discriminant, liab_type follows asset_index
+    for offset in [4, 13].iter() {
+        let mut data = data.clone();
+        data[*offset] = 99;
+        assert_eq!(MangoInstruction::unpack(&data), None);
+        assert_eq!(
+            MangoInstruction::unpack_checked(&data),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+}
@@ -0,0 +1,118 @@
+#![cfg(feature = "test-bpf")]
+// Tests related to root banks and node banks
+mod program_test;
+
+use mango::error::MangoErrorCode;
+use mango::state::RootBank;
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program_test::*;
+
+#[tokio::test]
+async fn test_rebalance_node_banks_validates_root_bank() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let user_deposits = vec![(user_index, mint_index, 10.0), (user_index, test.quote_index, 10.0)];
+    let quantity = test.quote_mint.unit as u64;
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    let mango_group = mango_group_cookie.mango_group;
+    let (quote_root_bank_pk, quote_root_bank) =
+        test.with_root_bank(&mango_group, test.quote_index).await;
+    let (quote_node_bank_pk, _) = test.with_node_bank(&quote_root_bank, 0).await;
+    let (_, base_root_bank) = test.with_root_bank(&mango_group, mint_index).await;
+    let (base_node_bank_pk, _) = test.with_node_bank(&base_root_bank, 0).await;
+
+    // === Act ===
+    // Node banks of different root banks
+    let mismatched_result = test
+        .perform_rebalance_node_banks(
+            &mango_group_cookie,
+            &quote_root_bank_pk,
+            &base_node_bank_pk,
+            &quote_node_bank_pk,
+            quantity,
+        )
+        .await;
+
+    // A node bank to itself
+    let same_node_bank_result = test
+        .perform_rebalance_node_banks(
+            &mango_group_cookie,
+            &quote_root_bank_pk,
+            &quote_node_bank_pk,
+            &quote_node_bank_pk,
+            quantity,
+        )
+        .await;
+
+    // === Assert ===
+    assert!(is_mango_error(&mismatched_result, MangoErrorCode::InvalidNodeBank));
+    assert!(is_mango_error(&same_node_bank_result, MangoErrorCode::InvalidParam));
+}
+
+#[tokio::test]
+async fn test_update_root_banks() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let mint_index: usize = 0;
+    let quote_index = test.quote_index;
+    let base_price: f64 = 10_000.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Each user borrows the token the other deposited, so both banks accrue interest
+    let user_deposits = vec![(0, quote_index, 10_000.0), (1, mint_index, 1.0)];
+    let user_withdraws = vec![(0, mint_index, 0.5, true), (1, quote_index, 1_000.0, true)];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    withdraw_scenario(&mut test, &mut mango_group_cookie, &user_withdraws).await;
+
+    let mango_group = mango_group_cookie.mango_group;
+    let (base_root_bank_pk, base_before) = test.with_root_bank(&mango_group, mint_index).await;
+    let (quote_root_bank_pk, quote_before) = test.with_root_bank(&mango_group, quote_index).await;
+
+    // === Act ===
+    test.advance_clock_by_min_timespan(100).await;
+    test.perform_update_root_banks(&mango_group_cookie, &[mint_index, quote_index]).await.unwrap();
+
+    // === Assert ===
+    let base_after = test.load_account::<RootBank>(base_root_bank_pk).await;
+    let quote_after = test.load_account::<RootBank>(quote_root_bank_pk).await;
+    for (before, after) in [(base_before, base_after), (quote_before, quote_after)].iter() {
+        assert!(after.last_updated > before.last_updated);
+        assert!(after.borrow_index > before.borrow_index);
+        assert!(after.deposit_index > before.deposit_index);
+    }
+}
@@ -0,0 +1,88 @@
+mod program_test;
+use fixed::types::I80F48;
+use mango::{instruction::*, oracle::*, state::*};
+use program_test::cookies::*;
+use program_test::*;
+use solana_program::pubkey::Pubkey;
+use solana_program_test::*;
+use std::mem::size_of;
+
+/// Serialize a pyth price account publishing `price * 10^expo`
+fn pyth_price_account(price: i64, expo: i32) -> solana_sdk::account::Account {
+    let mut pyth_price: Price = unsafe { std::mem::zeroed() };
+    pyth_price.magic = MAGIC;
+    pyth_price.ver = VERSION_2;
+    pyth_price.atype = AccountType::Price as u32;
+    pyth_price.size = size_of::<Price>() as u32;
+    pyth_price.expo = expo;
+    pyth_price.agg.price = price;
+
+    let mut account = solana_sdk::account::Account::new(
+        u32::MAX as u64,
+        size_of::<Price>(),
+        &Pubkey::new_unique(),
+    );
+    account.data.copy_from_slice(unsafe {
+        std::slice::from_raw_parts(&pyth_price as *const Price as *const u8, size_of::<Price>())
+    });
+    account
+}
+
+#[tokio::test]
+async fn test_cache_prices_pyth_exponents() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 6 };
+
+    // Mint 0 has 6 decimals and is quoted at 40_000 with expo -8,
+    // mint 4 has 9 decimals and is quoted at 150 with expo -3
+    let pyth_a_pk = Pubkey::new_unique();
+    let pyth_b_pk = Pubkey::new_unique();
+    let mut test = MangoProgramTest::start_new_with_accounts(
+        &config,
+        vec![
+            (pyth_a_pk, pyth_price_account(4_000_000_000_000, -8)),
+            (pyth_b_pk, pyth_price_account(150_000, -3)),
+        ],
+    )
+    .await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+
+    let instructions = [
+        add_oracle(&mango_program_id, &mango_group_pk, &pyth_a_pk, &admin_pk).unwrap(),
+        add_oracle(&mango_program_id, &mango_group_pk, &pyth_b_pk, &admin_pk).unwrap(),
+    ];
+    test.process_transaction(&instructions, None).await.unwrap();
+
+    // SpotMarketCookie::init picks the oracle by mint index
+    let mut oracle_pks = vec![Pubkey::default(); test.quote_index];
+    oracle_pks[0] = pyth_a_pk;
+    oracle_pks[4] = pyth_b_pk;
+    SpotMarketCookie::init(&mut test, &mut mango_group_cookie, 0, &oracle_pks).await;
+    SpotMarketCookie::init(&mut test, &mut mango_group_cookie, 4, &oracle_pks).await;
+
+    // === Act ===
+    let mango_group = test.load_account::<MangoGroup>(mango_group_pk).await;
+    let instructions = [cache_prices(
+        &mango_program_id,
+        &mango_group_pk,
+        &mango_group.mango_cache,
+        &[pyth_a_pk, pyth_b_pk],
+    )
+    .unwrap()];
+    test.process_transaction(&instructions, None).await.unwrap();
+
+    // === Assert ===
+    let mango_cache = test.load_account::<MangoCache>(mango_group.mango_cache).await;
+    assert_eq!(mango_group.tokens[0].decimals, 6);
+    assert_eq!(mango_group.tokens[1].decimals, 9);
+
+    // Both prices end up in native quote per native base
+    assert_eq!(mango_cache.price_cache[0].price, I80F48::from_num(40_000));
+    let price_b = mango_cache.price_cache[1].price;
+    assert_eq!(price_b, normalize_oracle_price(I80F48::from_num(150_000), -3, 9, 6).unwrap());
+    assert!((price_b - I80F48::from_num(0.15)).abs() < I80F48::from_num(0.000_001));
+}
@@ -1,9 +1,15 @@
 mod program_test;
-use mango::{matching::*, state::*};
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use mango::queue::{summarize_perp_event_queue, AnyEvent, EventQueueHeader, FillEvent};
+use mango::{
+    error::MangoErrorCode, ids::mngo_token, instruction::consume_events, matching::*, state::*,
+};
 use program_test::assertions::*;
 use program_test::cookies::*;
 use program_test::scenarios::*;
 use program_test::*;
+use solana_program::pubkey::Pubkey;
 use solana_program_test::*;
 use std::{mem::size_of, mem::size_of_val};
 
@@ -177,3 +183,1710 @@ async fn test_match_perp_order() {
     // assert!(asker_base_position == -base_position);
     // assert!(asker_quote_position <= quote_position); // TODO Figure this out...
 }
+
+#[tokio::test]
+async fn test_order_init_margin() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, base_price * base_size)];
+
+    // Perp Orders
+    let user_perp_orders = vec![(user_index, mint_index, Side::Bid, base_size, base_price * 0.9)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: Compute the expected margin usage
+    let order_price = test.price_number_to_lots(&mint, base_price * 0.9) as i64;
+    let order_size = test.base_size_number_to_lots(&mint, base_size) as i64;
+    let expected_margin = order_init_margin(
+        &mango_group_cookie.mango_group,
+        &mango_group_cookie.mango_cache,
+        &mango_group_cookie.mango_accounts[user_index].mango_account,
+        mint_index,
+        Side::Bid,
+        order_price,
+        order_size,
+    )
+    .unwrap();
+
+    let perp_health = |cookie: &MangoGroupCookie| {
+        cookie.mango_accounts[user_index].mango_account.perp_accounts[mint_index]
+            .get_health(
+                &cookie.mango_group.perp_markets[mint_index],
+                &cookie.mango_cache.perp_market_cache[mint_index],
+                cookie.mango_cache.price_cache[mint_index].price,
+                HealthType::Init,
+            )
+            .unwrap()
+    };
+    let health_before = perp_health(&mango_group_cookie);
+
+    // Step 3: Place perp order
+    place_perp_order_scenario(&mut test, &mut mango_group_cookie, &user_perp_orders).await;
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let health_after = perp_health(&mango_group_cookie);
+
+    println!("expected_margin: {}", expected_margin.to_string());
+    println!("actual_margin: {}", (health_before - health_after).to_string());
+    assert!(expected_margin.is_positive());
+    assert_eq!(expected_margin, health_before - health_after);
+}
+
+#[tokio::test]
+async fn test_place_perp_order_safe_mode() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.5;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 1_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: Place a bid far above the oracle price, which passes the init health check while
+    // resting but would leave the account below maint health if filled at its price
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let unsafe_price = test.price_number_to_lots(&mint, base_price * 1.5);
+    let unsafe_result = test
+        .place_perp_order2(
+            &mango_group_cookie,
+            &perp_market_cookie,
+            user_index,
+            Side::Bid,
+            order_size,
+            unsafe_price,
+            0,
+            OrderType::Limit,
+            true,
+            [0; 8],
+        )
+        .await;
+
+    // Step 3: Place a bid below the oracle price, which is safe
+    let safe_price = test.price_number_to_lots(&mint, base_price * 0.9);
+    let safe_result = test
+        .place_perp_order2(
+            &mango_group_cookie,
+            &perp_market_cookie,
+            user_index,
+            Side::Bid,
+            order_size,
+            safe_price,
+            1,
+            OrderType::Limit,
+            true,
+            [0; 8],
+        )
+        .await;
+
+    // === Assert ===
+    assert!(unsafe_result.is_err());
+    assert!(safe_result.is_ok());
+    mango_group_cookie.run_keeper(&mut test).await;
+    let perp_account =
+        mango_group_cookie.mango_accounts[user_index].mango_account.perp_accounts[mint_index];
+    assert_eq!(perp_account.bids_quantity, order_size as i64);
+}
+
+#[tokio::test]
+async fn test_place_perp_order_safe_mode_opt_out() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.5;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 1_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: Place the same unsafe bid with safe mode turned off
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let unsafe_price = test.price_number_to_lots(&mint, base_price * 1.5);
+    test.place_perp_order2(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        order_size,
+        unsafe_price,
+        0,
+        OrderType::Limit,
+        false,
+        [0; 8],
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let perp_account =
+        mango_group_cookie.mango_accounts[user_index].mango_account.perp_accounts[mint_index];
+    assert_eq!(perp_account.bids_quantity, order_size as i64);
+}
+
+#[tokio::test]
+async fn test_execute_rebalance_leg() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 400_000, num_users: 1, num_mints: 3 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let base_prices: [f64; 2] = [10_000.0, 100.0];
+
+    // Set oracles
+    for mint_index in 0..base_prices.len() {
+        mango_group_cookie.set_oracle(&mut test, mint_index, base_prices[mint_index]).await;
+    }
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 10_000.0)];
+
+    // Rebalance legs
+    let legs =
+        vec![(0, Side::Bid, base_prices[0] * 0.9, 0.1), (1, Side::Ask, base_prices[1] * 1.1, 10.0)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: Execute both legs in one instruction
+    test.execute_rebalance_leg(&mango_group_cookie, user_index, &legs).await.unwrap();
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+    let mint0 = test.with_mint(0);
+    let mint1 = test.with_mint(1);
+    assert_eq!(
+        mango_account.perp_accounts[0].bids_quantity,
+        test.base_size_number_to_lots(&mint0, 0.1) as i64
+    );
+    assert_eq!(
+        mango_account.perp_accounts[1].asks_quantity,
+        test.base_size_number_to_lots(&mint1, 10.0) as i64
+    );
+}
+
+#[tokio::test]
+async fn test_execute_rebalance_leg_reverts_all_legs() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 400_000, num_users: 1, num_mints: 3 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let base_prices: [f64; 2] = [10_000.0, 100.0];
+
+    // Set oracles
+    for mint_index in 0..base_prices.len() {
+        mango_group_cookie.set_oracle(&mut test, mint_index, base_prices[mint_index]).await;
+    }
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 1_000.0)];
+
+    // Rebalance legs; the first is fine on its own but the second breaks init health
+    let legs = vec![
+        (0, Side::Bid, base_prices[0] * 0.9, 0.01),
+        (1, Side::Bid, base_prices[1] * 0.9, 100.0),
+    ];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: Execute both legs in one instruction
+    let result = test.execute_rebalance_leg(&mango_group_cookie, user_index, &legs).await;
+
+    // === Assert ===
+    assert!(result.is_err());
+    mango_group_cookie.run_keeper(&mut test).await;
+    let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+    assert_eq!(mango_account.perp_accounts[0].bids_quantity, 0);
+    assert_eq!(mango_account.perp_accounts[1].bids_quantity, 0);
+}
+
+#[tokio::test]
+async fn test_set_perp_market_fees() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+    let maker_fee: f64 = -0.001;
+    let taker_fee: f64 = 0.002;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, mango::matching::Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, mango::matching::Side::Bid, base_size, base_price),
+    ]];
+
+    // === Act ===
+    // Step 1: A maker rebate larger than the taker fee is rejected
+    let result =
+        mango_group_cookie.set_perp_market_fees(&mut test, mint_index, -0.003, taker_fee).await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidParam));
+
+    // Step 2: Set new fees
+    mango_group_cookie
+        .set_perp_market_fees(&mut test, mint_index, maker_fee, taker_fee)
+        .await
+        .unwrap();
+
+    // Step 3: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 4: Place and match perp order
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // === Assert ===
+    let info = mango_group_cookie.mango_group.perp_markets[mint_index];
+    assert_eq!(info.maker_fee, I80F48::from_num(maker_fee));
+    assert_eq!(info.taker_fee, I80F48::from_num(taker_fee));
+
+    let notional = base_price * base_size * test.quote_mint.unit;
+    let bidder_quote_position = mango_group_cookie.mango_accounts[bidder_user_index]
+        .mango_account
+        .perp_accounts[mint_index]
+        .quote_position;
+    let asker_quote_position =
+        mango_group_cookie.mango_accounts[asker_user_index].mango_account.perp_accounts[mint_index]
+            .quote_position;
+
+    // The taker pays taker_fee and the maker receives the rebate on top of the notional
+    let expected_bidder_quote = -notional * (1.0 + taker_fee);
+    let expected_asker_quote = notional * (1.0 - maker_fee);
+    assert!((bidder_quote_position.to_num::<f64>() - expected_bidder_quote).abs() < 1.0);
+    assert!((asker_quote_position.to_num::<f64>() - expected_asker_quote).abs() < 1.0);
+}
+
+#[tokio::test]
+async fn test_get_insurance_coverage() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, mango::matching::Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, mango::matching::Side::Bid, base_size, base_price),
+    ]];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Open a position so there are losses at risk
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // === Assert ===
+    let perp_market_pk = mango_group_cookie.perp_markets[mint_index].address;
+    test.perform_get_insurance_coverage(&mango_group_cookie, &[perp_market_pk]).await.unwrap();
+
+    // Each market may only be counted once
+    let result = test
+        .perform_get_insurance_coverage(&mango_group_cookie, &[perp_market_pk, perp_market_pk])
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidParam));
+}
+
+#[tokio::test]
+async fn test_realized_pnl() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let close_price: f64 = 11_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, test.quote_index, base_price),
+    ];
+
+    // Matched Perp Orders
+    let open_perp_orders = vec![vec![
+        (asker_user_index, mint_index, mango::matching::Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, mango::matching::Side::Bid, base_size, base_price),
+    ]];
+    let close_perp_orders = vec![vec![
+        (bidder_user_index, mint_index, mango::matching::Side::Ask, base_size, close_price),
+        (asker_user_index, mint_index, mango::matching::Side::Bid, base_size, close_price),
+    ]];
+
+    // === Act ===
+    // Step 1: Make deposits and start tracking realized pnl
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    let mut pnl_tracker_pks = Vec::new();
+    for &user_index in &[bidder_user_index, asker_user_index] {
+        pnl_tracker_pks
+            .push(test.perform_init_pnl_tracker(&mango_group_cookie, user_index).await.unwrap());
+    }
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // A tracker can only be created once
+    let result = test.perform_init_pnl_tracker(&mango_group_cookie, bidder_user_index).await;
+    assert!(result.is_err());
+
+    // Step 2: Open positions
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &open_perp_orders).await;
+
+    // === Assert ===
+    for &pnl_tracker_pk in &pnl_tracker_pks {
+        let pnl_tracker = test.load_account::<PnlTracker>(pnl_tracker_pk).await;
+        assert_eq!(pnl_tracker.realized_pnl[mint_index], ZERO_I80F48);
+        assert!(!pnl_tracker.entry_quote[mint_index].is_zero());
+    }
+
+    // === Act ===
+    // Step 3: Close positions after the price moved up
+    mango_group_cookie.set_oracle(&mut test, mint_index, close_price).await;
+    place_perp_order_scenario(&mut test, &mut mango_group_cookie, &close_perp_orders[0]).await;
+
+    mango_group_cookie.run_keeper(&mut test).await;
+    mango_group_cookie.consume_perp_events(&mut test).await;
+
+    // === Assert ===
+    let profit = I80F48::from_num((close_price - base_price) * base_size * test.quote_mint.unit);
+    let bidder_perp_account = mango_group_cookie.mango_accounts[bidder_user_index]
+        .mango_account
+        .perp_accounts[mint_index];
+    assert_eq!(bidder_perp_account.base_position, 0);
+    let bidder_pnl_tracker = test.load_account::<PnlTracker>(pnl_tracker_pks[0]).await;
+    let asker_pnl_tracker = test.load_account::<PnlTracker>(pnl_tracker_pks[1]).await;
+    assert!((bidder_pnl_tracker.realized_pnl[mint_index] - profit).abs() <= ONE_I80F48);
+    assert!((asker_pnl_tracker.realized_pnl[mint_index] + profit).abs() <= ONE_I80F48);
+    assert!(bidder_pnl_tracker.entry_quote[mint_index].abs() <= ONE_I80F48);
+
+    test.perform_get_realized_pnl(&mango_group_cookie, bidder_user_index, mint_index)
+        .await
+        .unwrap();
+
+    // === Act ===
+    // Step 4: Reopen positions and consume the fill without passing the trackers
+    let reopen_perp_orders = vec![
+        (asker_user_index, mint_index, mango::matching::Side::Ask, base_size, close_price),
+        (bidder_user_index, mint_index, mango::matching::Side::Bid, base_size, close_price),
+    ];
+    place_perp_order_scenario(&mut test, &mut mango_group_cookie, &reopen_perp_orders).await;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let mut mango_account_pks: Vec<Pubkey> = [bidder_user_index, asker_user_index]
+        .iter()
+        .map(|&user_index| mango_group_cookie.mango_accounts[user_index].address)
+        .collect();
+    test.consume_perp_events(&mango_group_cookie, &perp_market_cookie, &mut mango_account_pks)
+        .await;
+
+    // === Assert ===
+    // The fill is applied and only the trackers miss it
+    let data = test.get_account(perp_market_cookie.perp_market.event_queue).await.data;
+    let (_, _, _, count) = summarize_perp_event_queue(&data).unwrap();
+    assert_eq!(count, 0);
+    let bidder = test
+        .load_account::<MangoAccount>(mango_group_cookie.mango_accounts[bidder_user_index].address)
+        .await;
+    assert!(bidder.perp_accounts[mint_index].base_position > 0);
+    let pnl_tracker = test.load_account::<PnlTracker>(pnl_tracker_pks[0]).await;
+    assert_eq!(pnl_tracker.realized_pnl, bidder_pnl_tracker.realized_pnl);
+    assert_eq!(pnl_tracker.entry_quote, bidder_pnl_tracker.entry_quote);
+}
+
+#[tokio::test]
+async fn test_min_mngo_to_trade() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 10_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits and require a MNGO stake the user doesn't have
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.set_min_mngo_to_trade(&mut test, 1_000_000).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    assert_eq!(mango_group_cookie.mango_group.min_mngo_to_trade, 1_000_000);
+
+    // Step 2: Orders are rejected while the requirement is enforced
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let order_price = test.price_number_to_lots(&mint, base_price * 0.9);
+    let result = test
+        .place_perp_order2(
+            &mango_group_cookie,
+            &perp_market_cookie,
+            user_index,
+            Side::Bid,
+            order_size,
+            order_price,
+            0,
+            OrderType::Limit,
+            false,
+            [0; 8],
+        )
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::InsufficientMngoStake));
+
+    // Step 3: Setting the minimum to 0 disables the requirement
+    mango_group_cookie.set_min_mngo_to_trade(&mut test, 0).await;
+    test.place_perp_order2(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        order_size,
+        order_price,
+        1,
+        OrderType::Limit,
+        false,
+        [0; 8],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_cancel_perp_orders_older_than() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 10_000.0)];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let old_price = test.price_number_to_lots(&mint, base_price * 0.9);
+    let new_price = test.price_number_to_lots(&mint, base_price * 0.8);
+
+    // === Act ===
+    // Step 1: Place an order, let time pass, then place a second one
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        order_size,
+        old_price,
+        0,
+        OrderType::Limit,
+    )
+    .await;
+    test.advance_clock_by_min_timespan(10).await;
+    let cutoff_ts = test.get_clock().await.unix_timestamp as u64;
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        order_size,
+        new_price,
+        1,
+        OrderType::Limit,
+    )
+    .await;
+
+    // Step 2: Cancel the orders placed before the second one
+    test.cancel_perp_orders_older_than(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        cutoff_ts,
+        10,
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let mango_account = test.load_account::<MangoAccount>(mango_account_pk).await;
+    let remaining: Vec<u64> = (0..MAX_PERP_OPEN_ORDERS)
+        .filter(|&i| mango_account.order_market[i] == mint_index as u8)
+        .map(|i| mango_account.client_order_ids[i])
+        .collect();
+    assert_eq!(remaining, vec![1]);
+    assert_eq!(mango_account.perp_accounts[mint_index].bids_quantity, order_size as i64);
+}
+
+#[tokio::test]
+async fn test_cancel_perp_orders_side() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 10_000.0)];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let bid_prices = [
+        test.price_number_to_lots(&mint, base_price * 0.9),
+        test.price_number_to_lots(&mint, base_price * 0.8),
+    ];
+    let ask_price = test.price_number_to_lots(&mint, base_price * 1.1);
+
+    // === Act ===
+    // Step 1: Place two bids and an ask
+    for (i, &price) in bid_prices.iter().enumerate() {
+        test.place_perp_order(
+            &mango_group_cookie,
+            &perp_market_cookie,
+            user_index,
+            Side::Bid,
+            order_size,
+            price,
+            i as u64,
+            OrderType::Limit,
+        )
+        .await;
+    }
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Ask,
+        order_size,
+        ask_price,
+        2,
+        OrderType::Limit,
+    )
+    .await;
+
+    // Step 2: A limit of zero cancels nothing
+    test.cancel_perp_orders_side(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        0,
+    )
+    .await
+    .unwrap();
+
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let mango_account = test.load_account::<MangoAccount>(mango_account_pk).await;
+    assert_eq!(mango_account.perp_accounts[mint_index].bids_quantity, 2 * order_size as i64);
+
+    // Step 3: Pull the bids only
+    test.cancel_perp_orders_side(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        10,
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    let mango_account = test.load_account::<MangoAccount>(mango_account_pk).await;
+    let remaining: Vec<u64> = (0..MAX_PERP_OPEN_ORDERS)
+        .filter(|&i| mango_account.order_market[i] == mint_index as u8)
+        .map(|i| mango_account.client_order_ids[i])
+        .collect();
+    assert_eq!(remaining, vec![2]);
+    assert_eq!(mango_account.perp_accounts[mint_index].bids_quantity, 0);
+    assert_eq!(mango_account.perp_accounts[mint_index].asks_quantity, order_size as i64);
+}
+
+#[tokio::test]
+async fn test_perp_order_timestamp() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 10_000.0)];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let order_price = test.price_number_to_lots(&mint, base_price * 0.9);
+
+    // === Act ===
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        order_size,
+        order_price,
+        0,
+        OrderType::Limit,
+    )
+    .await;
+    let now_ts = test.get_clock().await.unix_timestamp as u64;
+
+    // === Assert ===
+    // The resting order is stamped with the cluster time it was placed at
+    let bids = test.load_account::<BookSide>(perp_market_cookie.perp_market.bids).await;
+    let order = bids.get_max().unwrap();
+    assert_eq!(order.client_order_id, 0);
+    assert_eq!(order.timestamp, now_ts);
+}
+
+#[tokio::test]
+async fn test_place_post_only_slide_order() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let asker_user_index: usize = 0;
+    let bidder_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (asker_user_index, test.quote_index, 10_000.0),
+        (bidder_user_index, test.quote_index, 10_000.0),
+    ];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let ask_price = test.price_number_to_lots(&mint, base_price);
+    let bid_price = test.price_number_to_lots(&mint, base_price * 1.1);
+
+    // === Act ===
+    // Step 1: Rest an ask
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        asker_user_index,
+        Side::Ask,
+        order_size,
+        ask_price,
+        0,
+        OrderType::Limit,
+    )
+    .await;
+
+    // Step 2: A post-only-slide bid above the ask would cross the book
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        bidder_user_index,
+        Side::Bid,
+        order_size,
+        bid_price,
+        1,
+        OrderType::PostOnlySlide,
+    )
+    .await;
+
+    // === Assert ===
+    // The bid rests one tick below the ask instead of trading against it
+    let bids = test.load_account::<BookSide>(perp_market_cookie.perp_market.bids).await;
+    let bid = bids.get_max().unwrap();
+    assert_eq!(bid.client_order_id, 1);
+    assert_eq!(bid.price(), ask_price as i64 - 1);
+    assert_eq!(bid.quantity, order_size as i64);
+
+    let asks = test.load_account::<BookSide>(perp_market_cookie.perp_market.asks).await;
+    let ask = asks.get_min().unwrap();
+    assert_eq!(ask.price(), ask_price as i64);
+    assert_eq!(ask.quantity, order_size as i64);
+
+    let bidder_pk = mango_group_cookie.mango_accounts[bidder_user_index].address;
+    let bidder = test.load_account::<MangoAccount>(bidder_pk).await;
+    assert_eq!(bidder.perp_accounts[mint_index].taker_base, 0);
+}
+
+#[tokio::test]
+async fn test_match_pegged_perp_order() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let maker_user_index: usize = 0;
+    let taker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+    let offset_ticks: i64 = -10;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (maker_user_index, test.quote_index, 10_000.0),
+        (taker_user_index, test.quote_index, 10_000.0),
+    ];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let perp_market = perp_market_cookie.perp_market;
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let peg_limit = test.price_number_to_lots(&mint, base_price * 1.1) as i64;
+    let taker_price = test.price_number_to_lots(&mint, base_price * 0.5);
+
+    // === Act ===
+    // Step 1: Rest a pegged bid for twice the size the taker sells at a time
+    test.place_perp_pegged_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        maker_user_index,
+        Side::Bid,
+        2 * order_size,
+        offset_ticks,
+        peg_limit,
+        0,
+        OrderType::Limit,
+    )
+    .await
+    .unwrap();
+
+    // Step 2: A fixed price ask below the oracle fills against it at the oracle derived price
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        taker_user_index,
+        Side::Ask,
+        order_size,
+        taker_price,
+        1,
+        OrderType::ImmediateOrCancel,
+    )
+    .await;
+
+    // === Assert ===
+    let oracle_lots = perp_market
+        .native_price_to_lot(mango_group_cookie.mango_cache.price_cache[mint_index].price);
+    let taker_pk = mango_group_cookie.mango_accounts[taker_user_index].address;
+    let taker = test.load_account::<MangoAccount>(taker_pk).await;
+    assert_eq!(taker.perp_accounts[mint_index].taker_base, -(order_size as i64));
+    assert_eq!(
+        taker.perp_accounts[mint_index].taker_quote,
+        order_size as i64 * (oracle_lots + offset_ticks)
+    );
+
+    // === Act ===
+    // Step 3: Once the oracle is past the peg limit, the rest of the bid no longer trades
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price * 1.2).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.place_perp_order(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        taker_user_index,
+        Side::Ask,
+        order_size,
+        taker_price,
+        2,
+        OrderType::ImmediateOrCancel,
+    )
+    .await;
+
+    // === Assert ===
+    let taker = test.load_account::<MangoAccount>(taker_pk).await;
+    assert_eq!(taker.perp_accounts[mint_index].taker_base, -(order_size as i64));
+}
+
+#[tokio::test]
+async fn test_post_trade_risk_log() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, Side::Bid, base_size, base_price),
+    ]];
+
+    // === Act ===
+    // Step 1: Make deposits and turn on the risk log
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    assert!(!mango_group_cookie.mango_group.post_trade_risk_log_enabled);
+    mango_group_cookie.set_post_trade_risk_log_enabled(&mut test, true).await;
+    assert!(mango_group_cookie.mango_group.post_trade_risk_log_enabled);
+
+    // Step 2: Place and match perp orders, each logging its risk state
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let mint = test.with_mint(mint_index);
+    let base_lots = test.base_size_number_to_lots(&mint, base_size) as i64;
+    let bidder_perp_account = mango_group_cookie.mango_accounts[bidder_user_index]
+        .mango_account
+        .perp_accounts[mint_index];
+    let asker_perp_account =
+        mango_group_cookie.mango_accounts[asker_user_index].mango_account.perp_accounts[mint_index];
+    assert_eq!(bidder_perp_account.base_position, base_lots);
+    assert_eq!(asker_perp_account.base_position, -base_lots);
+
+    // Step 3: Turning the log off again
+    mango_group_cookie.set_post_trade_risk_log_enabled(&mut test, false).await;
+    assert!(!mango_group_cookie.mango_group.post_trade_risk_log_enabled);
+}
+
+#[tokio::test]
+async fn test_max_oracle_age() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let max_oracle_age: u32 = 1;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 10_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits and give the market a tighter limit than the group's
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.set_max_oracle_age(&mut test, mint_index, max_oracle_age).await;
+    assert_eq!(mango_group_cookie.mango_group.tokens[mint_index].max_oracle_age, max_oracle_age);
+    let valid_interval = mango_group_cookie.mango_group.valid_interval;
+    assert!((max_oracle_age as u64) < valid_interval);
+
+    // Step 2: Let the cached price age past the override but not past the group's limit
+    mango_group_cookie.run_keeper(&mut test).await;
+    let last_update = mango_group_cookie.mango_cache.price_cache[mint_index].last_update;
+    while test.get_clock().await.unix_timestamp as u64 <= last_update + max_oracle_age as u64 {
+        test.advance_clock().await;
+    }
+    assert!(test.get_clock().await.unix_timestamp as u64 <= last_update + valid_interval);
+
+    // Step 3: The order is rejected on the stale price
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let order_price = test.price_number_to_lots(&mint, base_price * 0.9);
+    let result = test
+        .place_perp_order2(
+            &mango_group_cookie,
+            &perp_market_cookie,
+            user_index,
+            Side::Bid,
+            order_size,
+            order_price,
+            0,
+            OrderType::Limit,
+            false,
+            [0; 8],
+        )
+        .await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidPriceCache));
+
+    // Step 4: Without the override the same price is fresh enough
+    mango_group_cookie.set_max_oracle_age(&mut test, mint_index, 0).await;
+    test.place_perp_order2(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        user_index,
+        Side::Bid,
+        order_size,
+        order_price,
+        1,
+        OrderType::Limit,
+        false,
+        [0; 8],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_perp_order_tag() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let maker_index: usize = 0;
+    let taker_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+    let tag = *b"strat001";
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits =
+        vec![(maker_index, test.quote_index, 10_000.0), (taker_index, test.quote_index, 10_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: The maker rests a tagged bid and the taker sells into it
+    let mut perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let order_price = test.price_number_to_lots(&mint, base_price);
+    test.place_perp_order2(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        maker_index,
+        Side::Bid,
+        order_size,
+        order_price,
+        0,
+        OrderType::Limit,
+        false,
+        tag,
+    )
+    .await
+    .unwrap();
+
+    // The tag doesn't fit in the LeafNode, so the bid carries it in a LeafExtNode
+    let mut bids = Box::new(BookSide::zeroed());
+    let data = test.get_account(perp_market_cookie.perp_market.bids).await.data;
+    bytemuck::bytes_of_mut(bids.as_mut()).copy_from_slice(&data);
+    let maker_bid = *bids.get_max().unwrap();
+    assert_eq!(bids.leaf_ext(&maker_bid).order_tag, tag);
+    assert_eq!(bids.free_node_count(), MAX_BOOK_NODES - 2);
+
+    perp_market_cookie
+        .place_order(
+            &mut test,
+            &mut mango_group_cookie,
+            taker_index,
+            Side::Ask,
+            base_size,
+            base_price,
+        )
+        .await;
+
+    // === Assert ===
+    let data = test.get_account(perp_market_cookie.perp_market.event_queue).await.data;
+    let (fill_count, _, head, _) = summarize_perp_event_queue(&data).unwrap();
+    assert_eq!(fill_count, 1);
+
+    let event_size = size_of::<AnyEvent>();
+    let offset = size_of::<EventQueueHeader>() + head * event_size;
+    let mut fill = FillEvent::zeroed();
+    bytemuck::bytes_of_mut(&mut fill).copy_from_slice(&data[offset..offset + event_size]);
+    assert_eq!(fill.maker_order_id, maker_bid.key);
+
+    // Filling the bid freed its LeafExtNode along with it
+    let data = test.get_account(perp_market_cookie.perp_market.bids).await.data;
+    bytemuck::bytes_of_mut(bids.as_mut()).copy_from_slice(&data);
+    assert_eq!(bids.leaf_count, 0);
+    assert_eq!(bids.free_node_count(), MAX_BOOK_NODES);
+}
+
+#[tokio::test]
+async fn test_perp_order_expiry() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let maker_index: usize = 0;
+    let taker_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 0.1;
+    let mint = test.with_mint(mint_index);
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits =
+        vec![(maker_index, test.quote_index, 10_000.0), (taker_index, test.quote_index, 10_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 2: An order that is already expired never makes it onto the book
+    let mut perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let order_size = test.base_size_number_to_lots(&mint, base_size);
+    let order_price = test.price_number_to_lots(&mint, base_price);
+    let now = test.get_clock().await.unix_timestamp as u64;
+    test.place_perp_order3(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        maker_index,
+        Side::Bid,
+        order_size,
+        order_price,
+        0,
+        OrderType::Limit,
+        now - 1,
+    )
+    .await
+    .unwrap();
+    mango_group_cookie.run_keeper(&mut test).await;
+    let maker_perp_account =
+        mango_group_cookie.mango_accounts[maker_index].mango_account.perp_accounts[mint_index];
+    assert_eq!(maker_perp_account.bids_quantity, 0);
+
+    // Step 3: Rest a bid that expires shortly and let it expire
+    let expiry = test.get_clock().await.unix_timestamp as u64 + 5;
+    test.place_perp_order3(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        maker_index,
+        Side::Bid,
+        order_size,
+        order_price,
+        1,
+        OrderType::Limit,
+        expiry,
+    )
+    .await
+    .unwrap();
+    mango_group_cookie.run_keeper(&mut test).await;
+    let maker_perp_account =
+        mango_group_cookie.mango_accounts[maker_index].mango_account.perp_accounts[mint_index];
+    assert_eq!(maker_perp_account.bids_quantity, order_size as i64);
+    test.advance_clock_past_timestamp(expiry as i64).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 4: A crossing ask removes the expired bid instead of filling it
+    perp_market_cookie
+        .place_order(
+            &mut test,
+            &mut mango_group_cookie,
+            taker_index,
+            Side::Ask,
+            base_size,
+            base_price,
+        )
+        .await;
+    mango_group_cookie.users_with_perp_event[mint_index].extend([maker_index, taker_index].iter());
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // === Assert ===
+    let maker_perp_account =
+        mango_group_cookie.mango_accounts[maker_index].mango_account.perp_accounts[mint_index];
+    let taker_perp_account =
+        mango_group_cookie.mango_accounts[taker_index].mango_account.perp_accounts[mint_index];
+    assert_eq!(maker_perp_account.bids_quantity, 0);
+    assert_eq!(maker_perp_account.base_position, 0);
+    assert_eq!(taker_perp_account.base_position, 0);
+    assert_eq!(taker_perp_account.asks_quantity, order_size as i64);
+}
+
+#[tokio::test]
+async fn test_set_perp_mngo_vault() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let mint_index: usize = 0;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let signer_pk = mango_group_cookie.mango_group.signer_key;
+    let old_mngo_vault_pk = perp_market_cookie.perp_market.mngo_vault;
+
+    // === Act ===
+    // Step 1: A vault of another mint is rejected
+    let quote_mint_pk = test.quote_mint.pubkey.unwrap();
+    let wrong_mint_vault_pk = test.create_token_account(&signer_pk, &quote_mint_pk).await;
+    let result = test
+        .perform_set_perp_mngo_vault(&mango_group_cookie, &perp_market_cookie, &wrong_mint_vault_pk)
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidVault));
+
+    // Step 2: Rotate to a new MNGO vault owned by the group signer
+    let new_mngo_vault_pk = test.create_token_account(&signer_pk, &mngo_token::ID).await;
+    test.perform_set_perp_mngo_vault(&mango_group_cookie, &perp_market_cookie, &new_mngo_vault_pk)
+        .await
+        .unwrap();
+
+    // === Assert ===
+    let perp_market = test.load_account::<PerpMarket>(perp_market_cookie.address).await;
+    assert_eq!(perp_market.mngo_vault, new_mngo_vault_pk);
+    assert_eq!(test.get_token_balance(old_mngo_vault_pk).await, 0);
+
+    // The old vault is no longer the market's, so it can't be rotated away from again
+    let another_vault_pk = test.create_token_account(&signer_pk, &mngo_token::ID).await;
+    let result = test
+        .perform_set_perp_mngo_vault(&mango_group_cookie, &perp_market_cookie, &another_vault_pk)
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidVault));
+}
+
+#[tokio::test]
+async fn test_consume_events_rejects_foreign_event_queue() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 3 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // General parameters
+    let mango_program_id = test.mango_program_id;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_cache_pk = mango_group_cookie.mango_group.mango_cache;
+    let perp_market_cookie = mango_group_cookie.perp_markets[0];
+    let foreign_event_queue_pk = mango_group_cookie.perp_markets[1].perp_market.event_queue;
+    let consume = |event_queue_pk: &Pubkey| {
+        consume_events(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_cache_pk,
+            &perp_market_cookie.address,
+            event_queue_pk,
+            &mut [],
+            3,
+        )
+        .unwrap()
+    };
+
+    // === Act ===
+    // Step 1: Another market's event queue is rejected
+    let result = test.process_transaction(&[consume(&foreign_event_queue_pk)], None).await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::EventQueueMismatch));
+
+    // Step 2: The market's own event queue is accepted
+    let event_queue_pk = perp_market_cookie.perp_market.event_queue;
+    test.process_transaction(&[consume(&event_queue_pk)], None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_referrer_fee_share() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 3, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let maker_user_index: usize = 0;
+    let taker_user_index: usize = 1;
+    let referrer_user_index: usize = 2;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (maker_user_index, test.quote_index, base_price),
+        (taker_user_index, test.quote_index, base_price),
+    ];
+
+    // Perp Orders
+    let perp_orders = vec![
+        (maker_user_index, mint_index, mango::matching::Side::Ask, base_size, base_price),
+        (taker_user_index, mint_index, mango::matching::Side::Bid, base_size, base_price),
+    ];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: An account can't refer itself
+    let result =
+        test.perform_set_referrer(&mango_group_cookie, taker_user_index, taker_user_index).await;
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidParam));
+
+    // Step 3: Set the referrer of the taker
+    test.perform_set_referrer(&mango_group_cookie, taker_user_index, referrer_user_index)
+        .await
+        .unwrap();
+
+    // Step 4: Match orders
+    let fees_accrued_before = test
+        .load_account::<PerpMarket>(mango_group_cookie.perp_markets[mint_index].address)
+        .await
+        .fees_accrued;
+    place_perp_order_scenario(&mut test, &mut mango_group_cookie, &perp_orders).await;
+
+    // Step 5: Consume events, passing the referrer along with maker and taker
+    mango_group_cookie.users_with_perp_event[mint_index].push(referrer_user_index);
+    mango_group_cookie.consume_perp_events(&mut test).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // === Assert ===
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let info = mango_group_cookie.mango_group.perp_markets[mint_index];
+    let notional = I80F48::from_num(
+        test.price_number_to_lots(&perp_market_cookie.mint, base_price) as i64
+            * test.base_size_number_to_lots(&perp_market_cookie.mint, base_size) as i64
+            * perp_market_cookie.perp_market.quote_lot_size,
+    );
+    let referral = notional * info.taker_fee * REFERRER_FEE_SHARE;
+    assert!(referral.is_positive());
+
+    let taker = mango_group_cookie.mango_accounts[taker_user_index].mango_account;
+    let referrer = mango_group_cookie.mango_accounts[referrer_user_index].mango_account;
+    assert_eq!(taker.referrer, mango_group_cookie.mango_accounts[referrer_user_index].address);
+    assert_eq!(referrer.perp_accounts[mint_index].quote_position, referral);
+    assert_eq!(referrer.perp_accounts[mint_index].base_position, 0);
+
+    // The referral comes out of the fees the market accrued
+    let fees_accrued =
+        test.load_account::<PerpMarket>(perp_market_cookie.address).await.fees_accrued;
+    let fees = notional * (info.maker_fee + info.taker_fee);
+    assert!((fees_accrued - fees_accrued_before - (fees - referral)).abs() <= ONE_I80F48);
+}
+
+#[tokio::test]
+async fn test_closed_referrer_is_skipped() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 3, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let maker_user_index: usize = 0;
+    let taker_user_index: usize = 1;
+    let referrer_user_index: usize = 2;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (maker_user_index, test.quote_index, base_price),
+        (taker_user_index, test.quote_index, base_price),
+    ];
+
+    // Perp Orders
+    let perp_orders = vec![
+        (maker_user_index, mint_index, mango::matching::Side::Ask, base_size, base_price),
+        (taker_user_index, mint_index, mango::matching::Side::Bid, base_size, base_price),
+    ];
+
+    // === Act ===
+    // Step 1: Make deposits and set the referrer of the taker
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    test.perform_set_referrer(&mango_group_cookie, taker_user_index, referrer_user_index)
+        .await
+        .unwrap();
+
+    // Step 2: Match orders
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let fees_accrued_before =
+        test.load_account::<PerpMarket>(perp_market_cookie.address).await.fees_accrued;
+    place_perp_order_scenario(&mut test, &mut mango_group_cookie, &perp_orders).await;
+
+    // Step 3: Close the referrer before the fill is consumed
+    test.perform_close_mango_account(&mango_group_cookie, referrer_user_index).await.unwrap();
+
+    // Step 4: Consume events, passing the closed referrer along with maker and taker
+    let mut mango_account_pks = vec![
+        mango_group_cookie.mango_accounts[maker_user_index].address,
+        mango_group_cookie.mango_accounts[taker_user_index].address,
+        mango_group_cookie.mango_accounts[referrer_user_index].address,
+    ];
+    test.consume_perp_events(&mango_group_cookie, &perp_market_cookie, &mut mango_account_pks)
+        .await;
+
+    // === Assert ===
+    // The fill went through and the referral stayed in the market's fees
+    let data = test.get_account(perp_market_cookie.perp_market.event_queue).await.data;
+    let (_, _, _, count) = summarize_perp_event_queue(&data).unwrap();
+    assert_eq!(count, 0);
+
+    let taker = test
+        .load_account::<MangoAccount>(mango_group_cookie.mango_accounts[taker_user_index].address)
+        .await;
+    let base_lots = test.base_size_number_to_lots(&perp_market_cookie.mint, base_size) as i64;
+    assert_eq!(taker.perp_accounts[mint_index].base_position, base_lots);
+
+    let info = mango_group_cookie.mango_group.perp_markets[mint_index];
+    let notional = I80F48::from_num(
+        test.price_number_to_lots(&perp_market_cookie.mint, base_price) as i64
+            * base_lots
+            * perp_market_cookie.perp_market.quote_lot_size,
+    );
+    let fees_accrued =
+        test.load_account::<PerpMarket>(perp_market_cookie.address).await.fees_accrued;
+    let fees = notional * (info.maker_fee + info.taker_fee);
+    assert!((fees_accrued - fees_accrued_before - fees).abs() <= ONE_I80F48);
+}
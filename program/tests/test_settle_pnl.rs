@@ -0,0 +1,229 @@
+mod program_test;
+use mango::error::MangoErrorCode;
+use mango::{matching::*, state::*};
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program_test::*;
+
+#[tokio::test]
+async fn test_settle_pnl_opposite_sign() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, Side::Bid, base_size, base_price),
+    ]];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Place and match perp order
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // Step 3: Move the price up so the bidder is in profit and the asker at a loss
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price * 1.1).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    let root_bank_cache = mango_group_cookie.mango_cache.root_bank_cache[QUOTE_INDEX];
+    let bidder_deposit_before = mango_group_cookie.mango_accounts[bidder_user_index]
+        .mango_account
+        .get_native_deposit(&root_bank_cache, QUOTE_INDEX)
+        .unwrap();
+
+    // Step 4: Settle pnl
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    test.settle_perp_funds(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        bidder_user_index,
+        asker_user_index,
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let root_bank_cache = mango_group_cookie.mango_cache.root_bank_cache[QUOTE_INDEX];
+    let bidder_deposit_after = mango_group_cookie.mango_accounts[bidder_user_index]
+        .mango_account
+        .get_native_deposit(&root_bank_cache, QUOTE_INDEX)
+        .unwrap();
+    let asker_borrow_after = mango_group_cookie.mango_accounts[asker_user_index]
+        .mango_account
+        .get_native_borrow(&root_bank_cache, QUOTE_INDEX)
+        .unwrap();
+
+    println!("bidder_deposit_before: {}", bidder_deposit_before.to_string());
+    println!("bidder_deposit_after: {}", bidder_deposit_after.to_string());
+    println!("asker_borrow_after: {}", asker_borrow_after.to_string());
+    assert!(bidder_deposit_after > bidder_deposit_before);
+    assert!(asker_borrow_after.is_positive());
+}
+
+#[tokio::test]
+async fn test_settle_pnl_same_sign() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, Side::Bid, base_size, base_price),
+    ]];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Place and match perp order at the oracle price; both sides are down by their fees
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // Step 3: Settle pnl
+    mango_group_cookie.run_keeper(&mut test).await;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let result = test
+        .settle_perp_funds(
+            &mango_group_cookie,
+            &perp_market_cookie,
+            bidder_user_index,
+            asker_user_index,
+        )
+        .await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::NoPnlToSettle));
+}
+
+#[tokio::test]
+async fn test_settle_pnl_zero_pnl() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 3, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let flat_user_index: usize = 2;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+        (flat_user_index, test.quote_index, base_price),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, Side::Bid, base_size, base_price),
+    ]];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Place and match perp order
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // Step 3: Settle pnl against an account without a position
+    mango_group_cookie.run_keeper(&mut test).await;
+    let bidder_account_before = mango_group_cookie.mango_accounts[bidder_user_index].mango_account;
+    let flat_account_before = mango_group_cookie.mango_accounts[flat_user_index].mango_account;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    test.settle_perp_funds(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        bidder_user_index,
+        flat_user_index,
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let bidder_account_after = mango_group_cookie.mango_accounts[bidder_user_index].mango_account;
+    let flat_account_after = mango_group_cookie.mango_accounts[flat_user_index].mango_account;
+    assert_eq!(
+        bidder_account_before.deposits[QUOTE_INDEX],
+        bidder_account_after.deposits[QUOTE_INDEX]
+    );
+    assert_eq!(
+        bidder_account_before.borrows[QUOTE_INDEX],
+        bidder_account_after.borrows[QUOTE_INDEX]
+    );
+    assert_eq!(flat_account_before.deposits[QUOTE_INDEX], flat_account_after.deposits[QUOTE_INDEX]);
+    assert_eq!(flat_account_before.borrows[QUOTE_INDEX], flat_account_after.borrows[QUOTE_INDEX]);
+}
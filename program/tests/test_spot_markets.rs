@@ -5,7 +5,10 @@ use program_test::cookies::*;
 use program_test::scenarios::*;
 use program_test::*;
 use solana_program_test::*;
-use mango::state::{ZERO_I80F48, QUOTE_INDEX};
+use mango::state::{load_open_orders, MangoAccount, FREE_ORDER_SLOT, QUOTE_INDEX, ZERO_I80F48};
+use serum_dex::instruction::CancelOrderInstructionV2;
+use solana_program::account_info::AccountInfo;
+use solana_program::pubkey::Pubkey;
 use std::collections::HashMap;
 
 #[tokio::test]
@@ -328,3 +331,173 @@ async fn test_match_and_settle_spot_order() {
         assert_user_spot_orders(&mut test, &mango_group_cookie, expected_values).await;
     }
 }
+
+#[tokio::test]
+async fn test_cancel_spot_order_and_settle() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+    let quote_mint = test.quote_mint;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, base_price)];
+
+    // Spot Orders
+    let user_spot_orders =
+        vec![(user_index, mint_index, serum_dex::matching::Side::Bid, base_size, base_price)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Place a spot bid that locks all of the quote
+    place_spot_order_scenario(&mut test, &mut mango_group_cookie, &user_spot_orders).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Step 3: Cancel it and settle in the same instruction
+    let open_orders_pk =
+        mango_group_cookie.mango_accounts[user_index].mango_account.spot_open_orders[mint_index];
+    let mut open_orders_acc = test.get_account(open_orders_pk).await;
+    let order_id = {
+        let open_orders_ai = AccountInfo::new(
+            &open_orders_pk,
+            false,
+            false,
+            &mut open_orders_acc.lamports,
+            &mut open_orders_acc.data,
+            &test.serum_program_id,
+            false,
+            0,
+        );
+        let orders = load_open_orders(&open_orders_ai).unwrap().orders;
+        orders[0]
+    };
+    let spot_market_cookie = mango_group_cookie.spot_markets[mint_index];
+    test.cancel_spot_order_and_settle(
+        &mango_group_cookie,
+        &spot_market_cookie,
+        user_index,
+        CancelOrderInstructionV2 { side: serum_dex::matching::Side::Bid, order_id },
+    )
+    .await;
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // Nothing is left in the open orders account, not even as free funds
+    let expected_values = (
+        mint_index,
+        user_index,
+        [
+            ("quote_free", ZERO_I80F48),
+            ("quote_locked", ZERO_I80F48),
+            ("base_free", ZERO_I80F48),
+            ("base_locked", ZERO_I80F48),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    );
+    assert_user_spot_orders(&mut test, &mango_group_cookie, expected_values).await;
+
+    // The freed quote is back in the node bank as a deposit
+    let expected_deposits = (
+        user_index,
+        [(mint_index, ZERO_I80F48), (QUOTE_INDEX, test.to_native(&quote_mint, base_price))]
+            .iter()
+            .cloned()
+            .collect(),
+    );
+    assert_deposits(&mango_group_cookie, expected_deposits);
+}
+
+#[tokio::test]
+async fn test_prepare_account_close() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+    let quote_unit = test.quote_mint.unit as u64;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, 2.0 * base_price)];
+
+    // Orders
+    let user_spot_orders =
+        vec![(user_index, mint_index, serum_dex::matching::Side::Bid, base_size, base_price)];
+    let user_perp_orders =
+        vec![(user_index, mint_index, mango::matching::Side::Bid, base_size, base_price)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Rest a spot and a perp order
+    place_spot_order_scenario(&mut test, &mut mango_group_cookie, &user_spot_orders).await;
+    place_perp_order_scenario(&mut test, &mut mango_group_cookie, &user_perp_orders).await;
+    let open_orders_pk =
+        mango_group_cookie.mango_accounts[user_index].mango_account.spot_open_orders[mint_index];
+
+    // Step 3: Cancel everything, settle and close the open orders account
+    test.perform_prepare_account_close(&mango_group_cookie, user_index).await.unwrap();
+
+    // === Assert ===
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let mango_account = test.load_account::<MangoAccount>(mango_account_pk).await;
+    assert!(mango_account.order_market.iter().all(|m| *m == FREE_ORDER_SLOT));
+    assert_eq!(mango_account.perp_accounts[mint_index].bids_quantity, 0);
+    assert!(mango_account.spot_open_orders.iter().all(|pk| *pk == Pubkey::default()));
+    assert_eq!(mango_account.num_in_margin_basket, 0);
+    let open_orders = test.context.banks_client.get_account(open_orders_pk).await.unwrap();
+    assert!(open_orders.is_none());
+
+    // Only the deposit is left, and once it is withdrawn the account can be closed
+    mango_group_cookie.mango_accounts[user_index].mango_account = mango_account;
+    test.perform_withdraw(
+        &mango_group_cookie,
+        user_index,
+        test.quote_index,
+        2 * base_price as u64 * quote_unit,
+        false,
+    )
+    .await
+    .unwrap();
+    test.perform_close_mango_account(&mango_group_cookie, user_index).await.unwrap();
+}
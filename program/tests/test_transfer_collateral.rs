@@ -0,0 +1,210 @@
+mod program_test;
+use fixed::types::I80F48;
+use mango::error::MangoErrorCode;
+use mango::state::*;
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program_test::*;
+
+#[tokio::test]
+async fn test_transfer_collateral() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // Second MangoAccount owned by the same user
+    let sub_account = MangoAccountCookie::init(&mut test, &mut mango_group_cookie, 0).await;
+    mango_group_cookie.mango_accounts.push(sub_account);
+
+    // General parameters
+    let user_index: usize = 0;
+    let src_account_index: usize = 0;
+    let dst_account_index: usize = 1;
+    let quote_mint = test.quote_mint;
+    let deposit_size: f64 = 10_000.0;
+    let transfer_size: f64 = 4_000.0;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, test.quote_index, deposit_size)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Move part of the deposit into the second account
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.perform_transfer_collateral(
+        &mango_group_cookie,
+        user_index,
+        src_account_index,
+        dst_account_index,
+        test.quote_index,
+        (transfer_size * quote_mint.unit) as u64,
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    mango_group_cookie.run_keeper(&mut test).await;
+    let root_bank_cache = &mango_group_cookie.mango_cache.root_bank_cache[QUOTE_INDEX];
+    let src_deposit = mango_group_cookie.mango_accounts[src_account_index]
+        .mango_account
+        .get_native_deposit(root_bank_cache, QUOTE_INDEX)
+        .unwrap();
+    let dst_deposit = mango_group_cookie.mango_accounts[dst_account_index]
+        .mango_account
+        .get_native_deposit(root_bank_cache, QUOTE_INDEX)
+        .unwrap();
+
+    println!("src_deposit: {}", src_deposit.to_string());
+    println!("dst_deposit: {}", dst_deposit.to_string());
+    assert!(
+        src_deposit.checked_round().unwrap()
+            == I80F48::from_num((deposit_size - transfer_size) * quote_mint.unit)
+    );
+    assert!(
+        dst_deposit.checked_round().unwrap() == I80F48::from_num(transfer_size * quote_mint.unit)
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_collateral_unhealthy_source() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // Second MangoAccount owned by user 0
+    let sub_account = MangoAccountCookie::init(&mut test, &mut mango_group_cookie, 0).await;
+    mango_group_cookie.mango_accounts.push(sub_account);
+
+    // General parameters
+    let borrower_user_index: usize = 0;
+    let lender_user_index: usize = 1;
+    let src_account_index: usize = 0;
+    let dst_account_index: usize = 2;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let quote_mint = test.quote_mint;
+    let deposit_size: f64 = 1_000.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (borrower_user_index, test.quote_index, deposit_size),
+        (lender_user_index, mint_index, 1.0),
+    ];
+
+    // Withdraw amounts
+    let user_withdraws = vec![(borrower_user_index, mint_index, 0.05, true)];
+
+    // === Act ===
+    // Step 1: Make deposits
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    // Step 2: Borrow against the quote deposit
+    withdraw_scenario(&mut test, &mut mango_group_cookie, &user_withdraws).await;
+
+    // Step 3: Try to move all of the collateral out of the borrowing account
+    mango_group_cookie.run_keeper(&mut test).await;
+    let result = test
+        .perform_transfer_collateral(
+            &mango_group_cookie,
+            borrower_user_index,
+            src_account_index,
+            dst_account_index,
+            test.quote_index,
+            (deposit_size * quote_mint.unit) as u64,
+        )
+        .await;
+
+    // === Assert ===
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_transfer_msrm() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 2, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // Second MangoAccount owned by user 0
+    let sub_account = MangoAccountCookie::init(&mut test, &mut mango_group_cookie, 0).await;
+    mango_group_cookie.mango_accounts.push(sub_account);
+
+    // General parameters
+    let user_index: usize = 0;
+    let src_account_index: usize = 0;
+    let other_owner_account_index: usize = 1;
+    let dst_account_index: usize = 2;
+    let src_mango_account_pk = mango_group_cookie.mango_accounts[src_account_index].address;
+    let dst_mango_account_pk = mango_group_cookie.mango_accounts[dst_account_index].address;
+    let msrm_vault_pk = mango_group_cookie.mango_group.msrm_vault;
+
+    // === Act ===
+    // Step 1: Deposit MSRM into the first account
+    test.perform_deposit_msrm(&mango_group_cookie, user_index, src_account_index, 3).await.unwrap();
+
+    // Step 2: Move part of it into the second account of the same owner
+    test.perform_transfer_msrm(
+        &mango_group_cookie,
+        user_index,
+        src_account_index,
+        dst_account_index,
+        2,
+    )
+    .await
+    .unwrap();
+
+    // Step 3: Try to move the rest into an account of another owner
+    let result = test
+        .perform_transfer_msrm(
+            &mango_group_cookie,
+            user_index,
+            src_account_index,
+            other_owner_account_index,
+            1,
+        )
+        .await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::InvalidOwner));
+    let src_mango_account = test.load_account::<MangoAccount>(src_mango_account_pk).await;
+    let dst_mango_account = test.load_account::<MangoAccount>(dst_mango_account_pk).await;
+    assert_eq!(src_mango_account.msrm_amount, 1);
+    assert_eq!(dst_mango_account.msrm_amount, 2);
+    // The MSRM itself never left the vault
+    assert_eq!(test.get_token_balance(msrm_vault_pk).await, 3);
+}
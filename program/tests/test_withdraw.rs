@@ -0,0 +1,225 @@
+#![cfg(feature = "test-bpf")]
+// Tests related to withdrawing from mango group
+mod program_test;
+
+use mango::error::MangoErrorCode;
+use mango::instruction::borrow;
+use mango::state::{WithdrawCaps, QUOTE_INDEX};
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program_test::*;
+use solana_sdk::signature::{Keypair, Signer};
+
+#[tokio::test]
+async fn test_max_withdraw_per_tx() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index = test.quote_index;
+    let unit = test.quote_mint.unit as u64;
+    let max_withdraw = 100 * unit;
+
+    // Deposit amounts
+    let user_deposits = vec![(user_index, mint_index, 1_000.0)];
+
+    // === Act ===
+    // Step 1: Make deposits and cap withdrawals
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    test.perform_set_max_withdraw_per_tx(&mango_group_cookie, user_index, mint_index, max_withdraw)
+        .await
+        .unwrap();
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // === Assert ===
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+    assert!(mango_account.has_withdraw_caps);
+    let (withdraw_caps_pk, _) =
+        WithdrawCaps::find_address(&test.mango_program_id, &mango_account_pk);
+    let withdraw_caps = test.load_account::<WithdrawCaps>(withdraw_caps_pk).await;
+    assert_eq!(withdraw_caps.mango_account, mango_account_pk);
+    assert_eq!(withdraw_caps.max_withdraw_per_tx[QUOTE_INDEX], max_withdraw);
+
+    // Step 2: Withdrawals up to the cap go through, larger ones are rejected
+    test.perform_withdraw(&mango_group_cookie, user_index, mint_index, max_withdraw, false)
+        .await
+        .unwrap();
+    let result = test
+        .perform_withdraw(&mango_group_cookie, user_index, mint_index, 2 * max_withdraw, false)
+        .await;
+    assert!(is_mango_error(&result, MangoErrorCode::WithdrawCapExceeded));
+
+    // Step 3: A cap of 0 means unlimited
+    test.perform_set_max_withdraw_per_tx(&mango_group_cookie, user_index, mint_index, 0)
+        .await
+        .unwrap();
+    test.perform_withdraw(&mango_group_cookie, user_index, mint_index, 3 * max_withdraw, false)
+        .await
+        .unwrap();
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let deposit = test.with_mango_account_deposit(&mango_account_pk, mint_index).await;
+    assert!((deposit as i64 - (600 * unit) as i64).abs() <= 1);
+}
+
+#[tokio::test]
+async fn test_legacy_borrow_is_rejected() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index = test.quote_index;
+    let user_deposits = vec![(user_index, mint_index, 1_000.0)];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // === Act ===
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let user = Keypair::from_base58_string(&test.users[user_index].to_base58_string());
+    let (root_bank_pk, root_bank) = test.with_root_bank(&mango_group, mint_index).await;
+    let (node_bank_pk, _node_bank) = test.with_node_bank(&root_bank, 0).await;
+
+    let instructions = [borrow(
+        &test.mango_program_id,
+        &mango_group_cookie.address,
+        &mango_account_pk,
+        &mango_group.mango_cache,
+        &user.pubkey(),
+        &root_bank_pk,
+        &node_bank_pk,
+        &mango_account.spot_open_orders,
+        100 * test.quote_mint.unit as u64,
+    )
+    .unwrap()];
+    let result = test.process_transaction(&instructions, Some(&[&user])).await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::UseWithdrawWithBorrow));
+    mango_group_cookie.run_keeper(&mut test).await;
+    let mango_account = mango_group_cookie.mango_accounts[user_index].mango_account;
+    assert!(mango_account.borrows[QUOTE_INDEX].is_zero());
+}
+
+#[tokio::test]
+async fn test_preview_withdraw() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index = test.quote_index;
+    let unit = test.quote_mint.unit as u64;
+    let user_deposits = vec![(user_index, mint_index, 1_000.0)];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+    let deposit_before = test.with_mango_account_deposit(&mango_account_pk, mint_index).await;
+
+    // === Act ===
+    // Step 1: Preview a withdrawal within the deposits
+    test.perform_preview_withdraw(&mango_group_cookie, user_index, mint_index, 400 * unit, false)
+        .await
+        .unwrap();
+
+    // Step 2: Preview a withdrawal that would need a borrow, without allowing one
+    let result = test
+        .perform_preview_withdraw(&mango_group_cookie, user_index, mint_index, 2_000 * unit, false)
+        .await;
+
+    // === Assert ===
+    assert!(is_mango_error(&result, MangoErrorCode::InsufficientFunds));
+    let deposit_after = test.with_mango_account_deposit(&mango_account_pk, mint_index).await;
+    assert_eq!(deposit_before, deposit_after);
+}
+
+#[tokio::test]
+async fn test_withdraw_fee() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { compute_limit: 200_000, num_users: 1, num_mints: 2 };
+    let mut test = MangoProgramTest::start_new(&config).await;
+    // Supress some of the logs
+    solana_logger::setup_with_default(
+        "solana_rbpf::vm=info,\
+             solana_runtime::message_processor=debug,\
+             solana_runtime::system_instruction_processor=info,\
+             solana_program_test=info",
+    );
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+
+    // General parameters
+    let user_index: usize = 0;
+    let mint_index = test.quote_index;
+    let unit = test.quote_mint.unit as u64;
+    let user_deposits = vec![(user_index, mint_index, 1_000.0)];
+    let insurance_vault_pk = mango_group_cookie.mango_group.insurance_vault;
+    let mango_account_pk = mango_group_cookie.mango_accounts[user_index].address;
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    // === Act ===
+    // Step 1: Withdraw with the default fee of 0
+    assert_eq!(mango_group_cookie.mango_group.withdraw_fee_bps, 0);
+    let insurance_before = test.get_token_balance(insurance_vault_pk).await;
+    test.perform_withdraw(&mango_group_cookie, user_index, mint_index, 100 * unit, false)
+        .await
+        .unwrap();
+
+    // === Assert ===
+    assert_eq!(test.get_token_balance(insurance_vault_pk).await, insurance_before);
+    let deposit = test.with_mango_account_deposit(&mango_account_pk, mint_index).await;
+    assert!((deposit as i64 - (900 * unit) as i64).abs() <= 1);
+
+    // Step 2: Withdraw with a 1% fee
+    mango_group_cookie.set_withdraw_fee(&mut test, 100).await;
+    test.perform_withdraw(&mango_group_cookie, user_index, mint_index, 100 * unit, false)
+        .await
+        .unwrap();
+
+    assert_eq!(test.get_token_balance(insurance_vault_pk).await, insurance_before + unit);
+    let deposit = test.with_mango_account_deposit(&mango_account_pk, mint_index).await;
+    assert!((deposit as i64 - (799 * unit) as i64).abs() <= 1);
+}